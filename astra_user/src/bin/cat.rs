@@ -0,0 +1,33 @@
+// cat.rs - Copy standard input to standard output, for pipelines
+
+#![no_std]
+#![no_main]
+
+use astra_user::io::{STDIN, STDOUT};
+use astra_user::{eprintln, read, write};
+
+astra_user::entry!(main);
+
+fn main() -> i32 {
+    let mut buffer = [0u8; 512];
+    loop {
+        let count = match read(STDIN, &mut buffer) {
+            Ok(0) => return 0,
+            Ok(count) => count,
+            Err(errno) => {
+                eprintln!("cat: read failed, {:?}", errno);
+                return 1;
+            }
+        };
+        let mut left = &buffer[..count];
+        while !left.is_empty() {
+            match write(STDOUT, left) {
+                Ok(written) => left = &left[written..],
+                Err(errno) => {
+                    eprintln!("cat: write failed, {:?}", errno);
+                    return 1;
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,33 @@
+// echod.rs - A service answering each request on its channel with the same
+// bytes in upper case
+
+#![no_std]
+#![no_main]
+
+use astra_user::syscall::{Errno, NO_TIMEOUT};
+use astra_user::{eprintln, receive, send};
+
+astra_user::entry!(main);
+
+// Whoever starts the service opens its endpoint as descriptor 3
+const SERVICE: u64 = 3;
+
+fn main() -> i32 {
+    let mut buffer = [0u8; 1024];
+    loop {
+        let count = match receive(SERVICE, &mut buffer, &mut [], 0, NO_TIMEOUT) {
+            Ok((count, _)) => count,
+            // Every client is gone
+            Err(errno) if errno == Errno::EPIPE => return 0,
+            Err(errno) => {
+                eprintln!("echod: receive failed, {:?}", errno);
+                return 1;
+            }
+        };
+        buffer[..count].make_ascii_uppercase();
+        if let Err(errno) = send(SERVICE, &buffer[..count], &[], 0, NO_TIMEOUT) {
+            eprintln!("echod: send failed, {:?}", errno);
+            return 1;
+        }
+    }
+}
@@ -0,0 +1,19 @@
+// hello.rs - Greets from ring 3 with its arguments and environment
+
+#![no_std]
+#![no_main]
+
+use astra_user::{env, getpid, getppid, println};
+
+astra_user::entry!(main);
+
+fn main() -> i32 {
+    println!("Hello from user space, pid {} child of {}", getpid(), getppid());
+    for (i, arg) in env::args().enumerate() {
+        println!("argv[{}] = {}", i, arg);
+    }
+    for var in env::vars() {
+        println!("{}", var);
+    }
+    0
+}
@@ -0,0 +1,43 @@
+// primes.rs - Sieve of Eratosthenes on the heap, up to the first argument
+
+#![no_std]
+#![no_main]
+
+extern crate alloc;
+
+use alloc::vec;
+use alloc::vec::Vec;
+use astra_user::{env, eprintln, heap, println};
+
+astra_user::entry!(main);
+
+const DEFAULT_LIMIT: usize = 1000;
+
+fn main() -> i32 {
+    let limit = match env::args().nth(1) {
+        Some(arg) => match arg.parse::<usize>() {
+            Ok(limit) => limit,
+            Err(_) => {
+                eprintln!("usage: primes [limit]");
+                return 2;
+            }
+        },
+        None => DEFAULT_LIMIT,
+    };
+
+    let mut composite = vec![false; limit + 1];
+    let mut primes = Vec::new();
+    for n in 2..=limit {
+        if composite[n] {
+            continue;
+        }
+        primes.push(n);
+        for multiple in (n * n..=limit).step_by(n) {
+            composite[multiple] = true;
+        }
+    }
+
+    println!("{} primes up to {}, the last {:?}", primes.len(), limit, primes.last());
+    println!("heap grown to {} bytes", heap::brk_size());
+    0
+}
@@ -0,0 +1,83 @@
+// env.rs - Command line arguments and environment variables
+
+use core::ffi::CStr;
+use core::sync::atomic::{AtomicPtr, AtomicUsize, Ordering};
+
+//////////////////////////////
+// Statics/Constants
+//////////////////////////////
+
+// Set by `init` from the stack the kernel starts the program with
+static ARGC: AtomicUsize = AtomicUsize::new(0);
+static ARGV: AtomicPtr<*const u8> = AtomicPtr::new(core::ptr::null_mut());
+static ENVP: AtomicPtr<*const u8> = AtomicPtr::new(core::ptr::null_mut());
+
+////////////////////////////////
+// Structs, Types, Traits, Impl
+////////////////////////////////
+
+// Iterates over a NULL terminated array of C strings. Strings that are not
+// UTF-8 come out empty.
+#[derive(Clone)]
+pub struct Strings {
+    next: *const *const u8,
+}
+
+impl Iterator for Strings {
+    type Item = &'static str;
+
+    fn next(&mut self) -> Option<&'static str> {
+        if self.next.is_null() {
+            return None;
+        }
+        let string = unsafe { *self.next };
+        if string.is_null() {
+            return None;
+        }
+        self.next = unsafe { self.next.add(1) };
+        let string = unsafe { CStr::from_ptr(string as *const _) };
+        Some(string.to_str().unwrap_or(""))
+    }
+}
+
+//////////////////////////////
+// API
+//////////////////////////////
+
+// Unsafe! The arrays must be NULL terminated, hold valid C strings and
+// never change. Called once by `rt::start`.
+pub unsafe fn init(argc: usize, argv: *const *const u8, envp: *const *const u8) {
+    ARGC.store(argc, Ordering::Relaxed);
+    ARGV.store(argv as *mut _, Ordering::Relaxed);
+    ENVP.store(envp as *mut _, Ordering::Relaxed);
+}
+
+// Arguments, the program name first
+pub fn args() -> Strings {
+    Strings {
+        next: ARGV.load(Ordering::Relaxed),
+    }
+}
+
+pub fn arg_count() -> usize {
+    ARGC.load(Ordering::Relaxed)
+}
+
+// Environment variables as KEY=VALUE
+pub fn vars() -> Strings {
+    Strings {
+        next: ENVP.load(Ordering::Relaxed),
+    }
+}
+
+// Value of the environment variable `key`
+pub fn var(key: &str) -> Option<&'static str> {
+    vars().find_map(|var| {
+        let (name, value) = var.split_at(var.find('=')?);
+        if name == key {
+            Some(&value[1..])
+        } else {
+            None
+        }
+    })
+}
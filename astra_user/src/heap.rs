@@ -0,0 +1,138 @@
+// heap.rs - Global allocator on memory from brk and mmap
+
+use crate::syscall::{self, MAP_ANONYMOUS, MAP_PRIVATE, PROT_READ, PROT_WRITE};
+
+use core::alloc::{GlobalAlloc, Layout};
+use core::ptr::{self, NonNull};
+use linked_list_allocator::Heap;
+use spin::Mutex;
+
+//////////////////////////////
+// Statics/Constants
+//////////////////////////////
+
+/*
+ *  Small allocations come from a linked list heap starting at the initial
+ *  program break, right after .bss, which `brk` moves up as the heap needs
+ *  more room, at least GROW_SIZE at a time:
+ *
+ *    | code | data | .bss | heap ...    | <- break
+ *
+ *  Allocations of MMAP_THRESHOLD bytes or more get pages of their own from
+ *  `mmap`, given back with `munmap` when freed. So does everything once the
+ *  break cannot be moved anymore.
+ */
+const GROW_SIZE: usize = 64 * 1024;
+const MMAP_THRESHOLD: usize = 128 * 1024;
+const PAGE_SIZE: usize = 4096;
+
+#[global_allocator]
+static ALLOCATOR: Allocator = Allocator {
+    brk: Mutex::new(BrkHeap {
+        heap: Heap::empty(),
+        start: 0,
+        end: 0,
+    }),
+};
+
+////////////////////////////////
+// Structs, Types, Traits, Impl
+////////////////////////////////
+
+pub struct Allocator {
+    brk: Mutex<BrkHeap>,
+}
+
+struct BrkHeap {
+    heap: Heap,
+    // Initial and current break, 0 until the heap is first grown
+    start: usize,
+    end: usize,
+}
+
+impl BrkHeap {
+    // Move the break up for at least `min` more bytes
+    fn grow(&mut self, min: usize) -> bool {
+        if self.end == 0 {
+            match syscall::brk(0) {
+                Ok(end) => {
+                    self.start = end as usize;
+                    self.end = end as usize;
+                }
+                Err(_) => return false,
+            }
+        }
+
+        let wanted = self.end + round_up(min.max(GROW_SIZE), PAGE_SIZE);
+        let end = match syscall::brk(wanted as u64) {
+            Ok(end) if end as usize >= wanted => end as usize,
+            _ => return false,
+        };
+        unsafe {
+            if self.heap.size() == 0 {
+                self.heap.init(self.start as *mut u8, end - self.start);
+            } else {
+                self.heap.extend(end - self.end);
+            }
+        }
+        self.end = end;
+        true
+    }
+
+    fn contains(&self, ptr: *mut u8) -> bool {
+        (self.start..self.end).contains(&(ptr as usize))
+    }
+}
+
+unsafe impl GlobalAlloc for Allocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        if layout.size() < MMAP_THRESHOLD {
+            let mut brk = self.brk.lock();
+            if let Ok(block) = brk.heap.allocate_first_fit(layout) {
+                return block.as_ptr();
+            }
+            if brk.grow(layout.size() + layout.align()) {
+                if let Ok(block) = brk.heap.allocate_first_fit(layout) {
+                    return block.as_ptr();
+                }
+            }
+        }
+        map(layout)
+    }
+
+    unsafe fn dealloc(&self, block: *mut u8, layout: Layout) {
+        let mut brk = self.brk.lock();
+        if brk.contains(block) {
+            brk.heap.deallocate(NonNull::new_unchecked(block), layout);
+        } else {
+            drop(brk);
+            let _ = syscall::munmap(block as u64, round_up(layout.size(), PAGE_SIZE) as u64);
+        }
+    }
+}
+
+//////////////////////////////
+// API
+//////////////////////////////
+
+// Bytes the heap has taken from `brk` so far
+pub fn brk_size() -> usize {
+    let brk = ALLOCATOR.brk.lock();
+    brk.end - brk.start
+}
+
+// Pages of their own for `layout`, which are page aligned
+fn map(layout: Layout) -> *mut u8 {
+    if layout.align() > PAGE_SIZE {
+        return ptr::null_mut();
+    }
+    let len = round_up(layout.size(), PAGE_SIZE) as u64;
+    match syscall::mmap(0, len, PROT_READ | PROT_WRITE, MAP_PRIVATE | MAP_ANONYMOUS) {
+        Ok(addr) => addr as *mut u8,
+        Err(_) => ptr::null_mut(),
+    }
+}
+
+fn round_up(size: usize, align: usize) -> usize {
+    (size + align - 1) & !(align - 1)
+}
@@ -0,0 +1,65 @@
+// io.rs - Console output through the write system call
+
+use crate::syscall;
+
+use core::fmt::{self, Write};
+
+//////////////////////////////
+// Statics/Constants
+//////////////////////////////
+
+pub const STDIN: u64 = 0;
+pub const STDOUT: u64 = 1;
+pub const STDERR: u64 = 2;
+
+////////////////////////////////
+// Structs, Types, Traits, Impl
+////////////////////////////////
+
+// Writes to a file descriptor, retrying until everything is written
+pub struct Fd(pub u64);
+
+impl Write for Fd {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        let mut bytes = s.as_bytes();
+        while !bytes.is_empty() {
+            match syscall::write(self.0, bytes) {
+                Ok(written) => bytes = &bytes[written..],
+                Err(_) => return Err(fmt::Error),
+            }
+        }
+        Ok(())
+    }
+}
+
+//////////////////////////////
+// API
+//////////////////////////////
+
+#[macro_export]
+macro_rules! print {
+    ($($arg:tt)*) => ($crate::io::_print($crate::io::STDOUT, format_args!($($arg)*)));
+}
+
+#[macro_export]
+macro_rules! println {
+    () => ($crate::print!("\n"));
+    ($($arg:tt)*) => ($crate::print!("{}\n", format_args!($($arg)*)));
+}
+
+#[macro_export]
+macro_rules! eprint {
+    ($($arg:tt)*) => ($crate::io::_print($crate::io::STDERR, format_args!($($arg)*)));
+}
+
+#[macro_export]
+macro_rules! eprintln {
+    () => ($crate::eprint!("\n"));
+    ($($arg:tt)*) => ($crate::eprint!("{}\n", format_args!($($arg)*)));
+}
+
+// Output is lost if the console refuses it, there is nowhere to report it
+#[doc(hidden)]
+pub fn _print(fd: u64, args: fmt::Arguments) {
+    let _ = Fd(fd).write_fmt(args);
+}
@@ -0,0 +1,36 @@
+// lib.rs - Runtime for Astra OS user programs
+//
+// A program is a no_std, no_main binary naming its main function with
+// `entry!`:
+//
+//     #![no_std]
+//     #![no_main]
+//
+//     use astra_user::println;
+//
+//     astra_user::entry!(main);
+//
+//     fn main() -> i32 {
+//         println!("Hello from ring 3");
+//         0
+//     }
+//
+// Build with `cargo build` from this directory, which targets
+// x86_64-astra_user.json. The kernel runs the result with `elf::spawn`.
+
+#![no_std]
+
+extern crate alloc;
+
+pub mod env;
+pub mod heap;
+pub mod io;
+pub mod rt;
+pub mod signal;
+pub mod syscall;
+
+pub use signal::{raise, signal, Handler};
+pub use syscall::{
+    channel, close, dup2, exit, getpid, getppid, kill, open, pipe, read, receive, send, waitpid,
+    write, ExitStatus,
+};
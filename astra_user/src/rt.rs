@@ -0,0 +1,70 @@
+// rt.rs - Program entry and panic handling
+
+use crate::{env, eprintln, syscall};
+
+use core::panic::PanicInfo;
+
+//////////////////////////////
+// Statics/Constants
+//////////////////////////////
+
+/*
+ *  The kernel starts a program at `_start`, which `entry!` defines in the
+ *  program itself, with rsp pointing at argc, see the kernel's elf.rs:
+ *
+ *    _start -> rt::start -> env::init
+ *                        -> main, through `astra_user_main`
+ *                        -> exit(code main returned)
+ *
+ *  A panic prints its message to stderr and exits with PANIC_EXIT_CODE.
+ */
+pub const PANIC_EXIT_CODE: i32 = 101;
+
+//////////////////////////////
+// API
+//////////////////////////////
+
+// Name the program's main function, `fn() -> i32` returning the exit code
+#[macro_export]
+macro_rules! entry {
+    ($main:path) => {
+        ::core::arch::global_asm!(
+            ".global _start",
+            "_start:",
+            // Ends backtraces, and keeps the stack aligned for the call
+            "xor ebp, ebp",
+            "mov rdi, rsp",
+            "and rsp, -16",
+            "call {start}",
+            "ud2",
+            start = sym $crate::rt::start,
+        );
+
+        #[export_name = "astra_user_main"]
+        fn __astra_user_main() -> i32 {
+            let main: fn() -> i32 = $main;
+            main()
+        }
+    };
+}
+
+// Unsafe! Only `_start` may call this, with the stack the kernel set up.
+#[doc(hidden)]
+pub unsafe extern "C" fn start(stack: *const u64) -> ! {
+    extern "Rust" {
+        fn astra_user_main() -> i32;
+    }
+
+    let argc = *stack as usize;
+    let argv = stack.add(1) as *const *const u8;
+    let envp = argv.add(argc + 1);
+    env::init(argc, argv, envp);
+
+    syscall::exit(astra_user_main());
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    eprintln!("{}", info);
+    syscall::exit(PANIC_EXIT_CODE);
+}
@@ -0,0 +1,150 @@
+// signal.rs - Signal handlers, masks and sending signals
+
+use crate::syscall::{self, Errno, SYS_SIGACTION, SYS_SIGPROCMASK, SYS_SIGRETURN};
+
+//////////////////////////////
+// Statics/Constants
+//////////////////////////////
+
+/*
+ *  Numbers, masks and `Action` follow the kernel's signal.rs, which follows
+ *  Linux x86_64. A handler is called as `handler(signal)` on the stack of
+ *  whatever it interrupted and returns to `__astra_sigreturn`, the restorer
+ *  `signal` registers, which asks the kernel to resume that.
+ */
+pub const SIGHUP: u32 = 1;
+pub const SIGINT: u32 = 2;
+pub const SIGQUIT: u32 = 3;
+pub const SIGILL: u32 = 4;
+pub const SIGTRAP: u32 = 5;
+pub const SIGABRT: u32 = 6;
+pub const SIGBUS: u32 = 7;
+pub const SIGFPE: u32 = 8;
+pub const SIGKILL: u32 = 9;
+pub const SIGUSR1: u32 = 10;
+pub const SIGSEGV: u32 = 11;
+pub const SIGUSR2: u32 = 12;
+pub const SIGPIPE: u32 = 13;
+pub const SIGALRM: u32 = 14;
+pub const SIGTERM: u32 = 15;
+pub const SIGCHLD: u32 = 17;
+pub const SIGCONT: u32 = 18;
+pub const SIGSTOP: u32 = 19;
+
+pub const SIG_DFL: u64 = 0;
+pub const SIG_IGN: u64 = 1;
+
+pub const SA_RESTORER: u64 = 0x0400_0000;
+pub const SA_NODEFER: u64 = 0x4000_0000;
+pub const SA_RESETHAND: u64 = 0x8000_0000;
+
+// `sigprocmask` operations
+pub const SIG_BLOCK: u64 = 0;
+pub const SIG_UNBLOCK: u64 = 1;
+pub const SIG_SETMASK: u64 = 2;
+
+core::arch::global_asm!(
+    ".global __astra_sigreturn",
+    "__astra_sigreturn:",
+    "mov eax, {sigreturn}",
+    "syscall",
+    "ud2",
+    sigreturn = const SYS_SIGRETURN,
+);
+
+extern "C" {
+    fn __astra_sigreturn();
+}
+
+////////////////////////////////
+// Structs, Types, Traits, Impl
+////////////////////////////////
+
+// What to do with a signal, as the kernel lays it out
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(C)]
+pub struct Action {
+    pub handler: u64,
+    pub flags: u64,
+    pub restorer: u64,
+    pub mask: u64,
+}
+
+#[derive(Clone, Copy)]
+pub enum Handler {
+    Default,
+    Ignore,
+    Function(extern "C" fn(u32)),
+}
+
+//////////////////////////////
+// API
+//////////////////////////////
+
+pub const fn mask(signal: u32) -> u64 {
+    1 << (signal - 1)
+}
+
+// Set what happens on `signal` and return what did before
+pub fn signal(signal: u32, handler: Handler) -> Result<Handler, Errno> {
+    let action = match handler {
+        Handler::Default => Action {
+            handler: SIG_DFL,
+            flags: 0,
+            restorer: 0,
+            mask: 0,
+        },
+        Handler::Ignore => Action {
+            handler: SIG_IGN,
+            flags: 0,
+            restorer: 0,
+            mask: 0,
+        },
+        Handler::Function(function) => Action {
+            handler: function as usize as u64,
+            flags: SA_RESTORER,
+            restorer: __astra_sigreturn as *const () as u64,
+            mask: 0,
+        },
+    };
+    let previous = unsafe { sigaction(signal, Some(&action))? };
+    Ok(match previous.handler {
+        SIG_DFL => Handler::Default,
+        SIG_IGN => Handler::Ignore,
+        // Only ever set from a function pointer, by `signal` above
+        address => Handler::Function(unsafe {
+            core::mem::transmute::<usize, extern "C" fn(u32)>(address as usize)
+        }),
+    })
+}
+
+// Unsafe! A handler in `action` must be a function taking the signal, and
+// its restorer must call SYS_SIGRETURN with the stack it was returned to.
+pub unsafe fn sigaction(signal: u32, action: Option<&Action>) -> Result<Action, Errno> {
+    let mut previous = Action {
+        handler: SIG_DFL,
+        flags: 0,
+        restorer: 0,
+        mask: 0,
+    };
+    let new = action.map_or(0, |action| action as *const Action as u64);
+    let old = &mut previous as *mut Action as u64;
+    syscall::syscall(SYS_SIGACTION, [u64::from(signal), new, old, 0, 0, 0])?;
+    Ok(previous)
+}
+
+// Change the blocked mask with `set` as `how` says, if given, and return
+// the previous mask
+pub fn sigprocmask(how: u64, set: Option<u64>) -> Result<u64, Errno> {
+    let mut previous = 0u64;
+    let new = set.as_ref().map_or(0, |set| set as *const u64 as u64);
+    let old = &mut previous as *mut u64 as u64;
+    unsafe { syscall::syscall(SYS_SIGPROCMASK, [how, new, old, 0, 0, 0])? };
+    Ok(previous)
+}
+
+// Send `signal` to the calling process, delivered before this returns
+// unless it is blocked
+pub fn raise(signal: u32) -> Result<(), Errno> {
+    syscall::kill(syscall::getpid(), signal)
+}
@@ -0,0 +1,328 @@
+// syscall.rs - System call wrappers
+
+use core::fmt;
+
+//////////////////////////////
+// Statics/Constants
+//////////////////////////////
+
+/*
+ *  Numbers and calling convention match the kernel's syscall.rs:
+ *
+ *    rax                     system call number
+ *    rdi, rsi, rdx, r10,     arguments 0 to 5
+ *    r8, r9
+ *    rax                     result, or -errno on failure
+ *
+ *  `syscall` clobbers rcx and r11, the kernel preserves everything else.
+ */
+pub const SYS_EXIT: u64 = 0;
+pub const SYS_WRITE: u64 = 1;
+pub const SYS_GETPID: u64 = 2;
+pub const SYS_GETPPID: u64 = 3;
+pub const SYS_WAITPID: u64 = 4;
+pub const SYS_BRK: u64 = 5;
+pub const SYS_MMAP: u64 = 6;
+pub const SYS_MUNMAP: u64 = 7;
+pub const SYS_MPROTECT: u64 = 8;
+pub const SYS_KILL: u64 = 9;
+pub const SYS_SIGACTION: u64 = 10;
+pub const SYS_SIGPROCMASK: u64 = 11;
+pub const SYS_SIGRETURN: u64 = 12;
+pub const SYS_READ: u64 = 13;
+pub const SYS_OPEN: u64 = 14;
+pub const SYS_CLOSE: u64 = 15;
+pub const SYS_DUP2: u64 = 16;
+pub const SYS_PIPE: u64 = 17;
+pub const SYS_CHANNEL: u64 = 18;
+pub const SYS_SEND: u64 = 19;
+pub const SYS_RECEIVE: u64 = 20;
+
+// `waitpid` options
+pub const WNOHANG: u64 = 1;
+
+// `open` flags, only the access mode
+pub const O_RDONLY: u64 = 0;
+pub const O_WRONLY: u64 = 1;
+pub const O_RDWR: u64 = 2;
+
+// `send` and `receive` flags, and the timeout to wait as long as it takes
+pub const MSG_SYNC: u64 = 1;
+pub const MSG_NONBLOCK: u64 = 2;
+pub const NO_TIMEOUT: u64 = u64::MAX;
+
+// `mmap` and `mprotect` protection
+pub const PROT_NONE: u64 = 0;
+pub const PROT_READ: u64 = 1;
+pub const PROT_WRITE: u64 = 2;
+pub const PROT_EXEC: u64 = 4;
+
+// `mmap` flags, only private anonymous mappings are supported
+pub const MAP_SHARED: u64 = 0x01;
+pub const MAP_PRIVATE: u64 = 0x02;
+pub const MAP_FIXED: u64 = 0x10;
+pub const MAP_ANONYMOUS: u64 = 0x20;
+pub const MAP_FIXED_NOREPLACE: u64 = 0x10_0000;
+
+// Results from -4095 to -1 are errors
+const MAX_ERRNO: u64 = 4095;
+
+////////////////////////////////
+// Structs, Types, Traits, Impl
+////////////////////////////////
+
+// Error number returned by the kernel. Values follow Linux.
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub struct Errno(pub u64);
+
+impl Errno {
+    pub const EPERM: Errno = Errno(1);
+    pub const ENOENT: Errno = Errno(2);
+    pub const ESRCH: Errno = Errno(3);
+    pub const EINTR: Errno = Errno(4);
+    pub const EIO: Errno = Errno(5);
+    pub const EBADF: Errno = Errno(9);
+    pub const ECHILD: Errno = Errno(10);
+    pub const EAGAIN: Errno = Errno(11);
+    pub const ENOMEM: Errno = Errno(12);
+    pub const EFAULT: Errno = Errno(14);
+    pub const EEXIST: Errno = Errno(17);
+    pub const EINVAL: Errno = Errno(22);
+    pub const EMFILE: Errno = Errno(24);
+    pub const EPIPE: Errno = Errno(32);
+    pub const ENAMETOOLONG: Errno = Errno(36);
+    pub const ENOSYS: Errno = Errno(38);
+    pub const EMSGSIZE: Errno = Errno(90);
+    pub const ETIMEDOUT: Errno = Errno(110);
+
+    fn name(self) -> Option<&'static str> {
+        Some(match self {
+            Errno::EPERM => "EPERM",
+            Errno::ENOENT => "ENOENT",
+            Errno::ESRCH => "ESRCH",
+            Errno::EINTR => "EINTR",
+            Errno::EIO => "EIO",
+            Errno::EBADF => "EBADF",
+            Errno::ECHILD => "ECHILD",
+            Errno::EAGAIN => "EAGAIN",
+            Errno::ENOMEM => "ENOMEM",
+            Errno::EFAULT => "EFAULT",
+            Errno::EEXIST => "EEXIST",
+            Errno::EINVAL => "EINVAL",
+            Errno::EMFILE => "EMFILE",
+            Errno::EPIPE => "EPIPE",
+            Errno::ENAMETOOLONG => "ENAMETOOLONG",
+            Errno::ENOSYS => "ENOSYS",
+            Errno::EMSGSIZE => "EMSGSIZE",
+            Errno::ETIMEDOUT => "ETIMEDOUT",
+            _ => return None,
+        })
+    }
+}
+
+impl fmt::Debug for Errno {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.name() {
+            Some(name) => f.write_str(name),
+            None => write!(f, "Errno({})", self.0),
+        }
+    }
+}
+
+pub type SyscallResult = Result<u64, Errno>;
+
+// The message argument of `send` and `receive`, see the kernel's channel.rs
+#[repr(C)]
+struct Message {
+    data: u64,
+    data_len: u64,
+    handles: u64,
+    handle_count: u64,
+}
+
+// How a child ended, decoded from the status `waitpid` stores
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ExitStatus {
+    Exited(i32),
+    Signaled { signal: u32, core: bool },
+}
+
+impl ExitStatus {
+    fn from_raw(status: i32) -> ExitStatus {
+        match status & 0x7f {
+            0 => ExitStatus::Exited((status >> 8) & 0xff),
+            signal => ExitStatus::Signaled {
+                signal: signal as u32,
+                core: status & 0x80 != 0,
+            },
+        }
+    }
+}
+
+//////////////////////////////
+// API
+//////////////////////////////
+
+// Unsafe! The arguments must be valid for system call `number`, e.g.
+// pointers to memory it may read or write.
+pub unsafe fn syscall(number: u64, args: [u64; 6]) -> SyscallResult {
+    let result: u64;
+    core::arch::asm!(
+        "syscall",
+        inlateout("rax") number => result,
+        in("rdi") args[0],
+        in("rsi") args[1],
+        in("rdx") args[2],
+        in("r10") args[3],
+        in("r8") args[4],
+        in("r9") args[5],
+        lateout("rcx") _,
+        lateout("r11") _,
+        options(nostack)
+    );
+    if result.wrapping_neg() <= MAX_ERRNO && result != 0 {
+        Err(Errno(result.wrapping_neg()))
+    } else {
+        Ok(result)
+    }
+}
+
+pub fn exit(code: i32) -> ! {
+    unsafe {
+        let _ = syscall(SYS_EXIT, [code as u64, 0, 0, 0, 0, 0]);
+    }
+    unreachable!("exit returned");
+}
+
+// Bytes written. Fails with EPIPE on a pipe nobody reads anymore, after
+// SIGPIPE, which ends the process unless handled or ignored.
+pub fn write(fd: u64, buffer: &[u8]) -> Result<usize, Errno> {
+    let args = [fd, buffer.as_ptr() as u64, buffer.len() as u64, 0, 0, 0];
+    unsafe { syscall(SYS_WRITE, args).map(|written| written as usize) }
+}
+
+// Bytes read, 0 at the end of the file
+pub fn read(fd: u64, buffer: &mut [u8]) -> Result<usize, Errno> {
+    let args = [fd, buffer.as_mut_ptr() as u64, buffer.len() as u64, 0, 0, 0];
+    unsafe { syscall(SYS_READ, args).map(|read| read as usize) }
+}
+
+// Open the device at `path`, e.g. "/dev/null", and return its descriptor
+pub fn open(path: &str, flags: u64) -> Result<u64, Errno> {
+    let args = [path.as_ptr() as u64, path.len() as u64, flags, 0, 0, 0];
+    unsafe { syscall(SYS_OPEN, args) }
+}
+
+pub fn close(fd: u64) -> Result<(), Errno> {
+    unsafe { syscall(SYS_CLOSE, [fd, 0, 0, 0, 0, 0]).map(|_| ()) }
+}
+
+// Make `new` refer to what `old` does, closing what `new` referred to
+pub fn dup2(old: u64, new: u64) -> Result<u64, Errno> {
+    unsafe { syscall(SYS_DUP2, [old, new, 0, 0, 0, 0]) }
+}
+
+// A new pipe, as the descriptors of its read end and its write end
+pub fn pipe() -> Result<(u64, u64), Errno> {
+    let mut fds = [0i32; 2];
+    unsafe { syscall(SYS_PIPE, [fds.as_mut_ptr() as u64, 0, 0, 0, 0, 0])? };
+    Ok((fds[0] as u64, fds[1] as u64))
+}
+
+// A new channel, as the descriptors of its two endpoints
+pub fn channel() -> Result<(u64, u64), Errno> {
+    let mut fds = [0i32; 2];
+    unsafe { syscall(SYS_CHANNEL, [fds.as_mut_ptr() as u64, 0, 0, 0, 0, 0])? };
+    Ok((fds[0] as u64, fds[1] as u64))
+}
+
+// Send `data` and the files of `handles` on the channel endpoint `fd`,
+// which closes `handles` here. With MSG_SYNC returns once the message was
+// received. Fails with EAGAIN instead of waiting with MSG_NONBLOCK, and with
+// ETIMEDOUT after `timeout_ms` unless that is NO_TIMEOUT.
+pub fn send(
+    fd: u64,
+    data: &[u8],
+    handles: &[u32],
+    flags: u64,
+    timeout_ms: u64,
+) -> Result<(), Errno> {
+    let message = Message {
+        data: data.as_ptr() as u64,
+        data_len: data.len() as u64,
+        handles: handles.as_ptr() as u64,
+        handle_count: handles.len() as u64,
+    };
+    let addr = &message as *const Message as u64;
+    let args = [fd, addr, flags, timeout_ms, 0, 0];
+    unsafe { syscall(SYS_SEND, args).map(|_| ()) }
+}
+
+// Receive the next message on the channel endpoint `fd`, returning how many
+// bytes and descriptors it filled in. If it does not fit, fails with
+// EMSGSIZE and the message stays queued. Flags and timeout as for `send`,
+// except that MSG_SYNC is not one.
+pub fn receive(
+    fd: u64,
+    data: &mut [u8],
+    handles: &mut [u32],
+    flags: u64,
+    timeout_ms: u64,
+) -> Result<(usize, usize), Errno> {
+    let mut message = Message {
+        data: data.as_mut_ptr() as u64,
+        data_len: data.len() as u64,
+        handles: handles.as_mut_ptr() as u64,
+        handle_count: handles.len() as u64,
+    };
+    let addr = &mut message as *mut Message as u64;
+    let args = [fd, addr, flags, timeout_ms, 0, 0];
+    unsafe { syscall(SYS_RECEIVE, args)? };
+    Ok((message.data_len as usize, message.handle_count as usize))
+}
+
+pub fn getpid() -> u64 {
+    unsafe { syscall(SYS_GETPID, [0; 6]).expect("getpid failed") }
+}
+
+pub fn getppid() -> u64 {
+    unsafe { syscall(SYS_GETPPID, [0; 6]).expect("getppid failed") }
+}
+
+// Wait for the child `pid`, or any child if -1. Returns its pid and how it
+// ended, or None with WNOHANG if it has not exited yet.
+pub fn waitpid(pid: i64, options: u64) -> Result<Option<(u64, ExitStatus)>, Errno> {
+    let mut status = 0i32;
+    let args = [pid as u64, &mut status as *mut i32 as u64, options, 0, 0, 0];
+    match unsafe { syscall(SYS_WAITPID, args)? } {
+        0 => Ok(None),
+        pid => Ok(Some((pid, ExitStatus::from_raw(status)))),
+    }
+}
+
+// Send `signal` to the process `pid`, or only check that it exists with 0
+pub fn kill(pid: u64, signal: u32) -> Result<(), Errno> {
+    unsafe { syscall(SYS_KILL, [pid, u64::from(signal), 0, 0, 0, 0]).map(|_| ()) }
+}
+
+// Move the end of the data segment to `addr` and return the new end. With
+// 0, or when the kernel refuses, returns the current end.
+pub fn brk(addr: u64) -> Result<u64, Errno> {
+    unsafe { syscall(SYS_BRK, [addr, 0, 0, 0, 0, 0]) }
+}
+
+// Map `len` bytes of zeroed memory, anywhere unless `addr` is given with
+// MAP_FIXED, which replaces what was mapped there, or MAP_FIXED_NOREPLACE
+pub fn mmap(addr: u64, len: u64, prot: u64, flags: u64) -> Result<u64, Errno> {
+    unsafe { syscall(SYS_MMAP, [addr, len, prot, flags, u64::MAX, 0]) }
+}
+
+// Unsafe! Nothing may use the memory anymore.
+pub unsafe fn munmap(addr: u64, len: u64) -> Result<(), Errno> {
+    syscall(SYS_MUNMAP, [addr, len, 0, 0, 0, 0]).map(|_| ())
+}
+
+// Unsafe! Memory in use must stay accessible the way it is used.
+pub unsafe fn mprotect(addr: u64, len: u64, prot: u64) -> Result<(), Errno> {
+    syscall(SYS_MPROTECT, [addr, len, prot, 0, 0, 0]).map(|_| ())
+}
@@ -0,0 +1,99 @@
+#!/usr/bin/env python3
+# ksyms.py - Embed the kernel symbol table into the linked kernel ELF
+#
+# Copies the function symbols of the ELF's .symtab into the space the kernel
+# reserved in its .ksyms section, see src/ksyms.rs for the layout. The file is
+# patched in place and running it twice is harmless.
+#
+# Usage: ksyms.py <kernel elf>
+
+import struct
+import sys
+
+MAGIC = b"ASTRASYM"
+HEADER = struct.Struct("<8sII")
+ENTRY = struct.Struct("<QII")
+
+SHT_SYMTAB = 2
+SHT_NOBITS = 8
+STT_FUNC = 2
+SHN_UNDEF = 0
+
+
+def sections(elf):
+    (shoff,) = struct.unpack_from("<Q", elf, 0x28)
+    shentsize, shnum, shstrndx = struct.unpack_from("<HHH", elf, 0x3A)
+    headers = [
+        struct.unpack_from("<IIQQQQIIQQ", elf, shoff + i * shentsize)
+        for i in range(shnum)
+    ]
+    names = headers[shstrndx][4]
+    for header in headers:
+        name_end = elf.index(b"\0", names + header[0])
+        yield elf[names + header[0]:name_end].decode(), header
+
+
+def function_symbols(elf, symtab, strtab):
+    (_, _, _, _, offset, size, _, _, _, entsize) = symtab
+    strings = strtab[4]
+    for i in range(size // entsize):
+        name, info, _, shndx, value, sym_size = struct.unpack_from(
+            "<IBBHQQ", elf, offset + i * entsize
+        )
+        if info & 0xF != STT_FUNC or shndx == SHN_UNDEF or sym_size == 0:
+            continue
+        name_end = elf.index(b"\0", strings + name)
+        yield value, sym_size, elf[strings + name:name_end]
+
+
+def build_table(symbols):
+    # One name per address, aliases of the same function add nothing
+    by_address = {}
+    for address, size, name in symbols:
+        by_address.setdefault(address, (size, name))
+
+    entries = bytearray()
+    strings = bytearray()
+    for address in sorted(by_address):
+        size, name = by_address[address]
+        entries += ENTRY.pack(address, min(size, 0xFFFFFFFF), len(strings))
+        strings += name + b"\0"
+
+    header = HEADER.pack(MAGIC, len(by_address), len(strings))
+    return header + entries + strings
+
+
+def main(path):
+    with open(path, "rb") as f:
+        elf = bytearray(f.read())
+    if elf[:4] != b"\x7fELF" or elf[4] != 2:
+        sys.exit("ksyms: {} is not a 64-bit ELF file".format(path))
+
+    all_sections = list(sections(elf))
+    by_name = dict(all_sections)
+    if ".ksyms" not in by_name or ".symtab" not in by_name:
+        sys.exit("ksyms: {} has no .ksyms or .symtab section".format(path))
+
+    ksyms = by_name[".ksyms"]
+    if ksyms[1] == SHT_NOBITS:
+        sys.exit("ksyms: .ksyms takes no space in the file")
+    symtab = by_name[".symtab"]
+    strtab = all_sections[symtab[6]][1]
+
+    table = build_table(function_symbols(elf, symtab, strtab))
+    offset, capacity = ksyms[4], ksyms[5]
+    if len(table) > capacity:
+        sys.exit(
+            "ksyms: symbol table needs {} bytes, raise KSYMS_CAPACITY "
+            "above {}".format(len(table), capacity)
+        )
+
+    elf[offset:offset + capacity] = table + bytes(capacity - len(table))
+    with open(path, "wb") as f:
+        f.write(elf)
+
+
+if __name__ == "__main__":
+    if len(sys.argv) < 2:
+        sys.exit("usage: ksyms.py <kernel elf>")
+    main(sys.argv[1])
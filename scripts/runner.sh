@@ -0,0 +1,9 @@
+#!/bin/sh
+# runner.sh - Cargo runner, embeds the symbol table before booting the kernel
+#
+# Cargo passes the kernel ELF followed by any extra arguments.
+
+set -e
+
+python3 "$(dirname "$0")/ksyms.py" "$1"
+exec bootimage runner "$@"
@@ -0,0 +1,205 @@
+// acpi.rs - Minimal ACPI table discovery, only what SMP bring-up needs
+
+use crate::memory;
+
+use alloc::vec::Vec;
+use core::mem::size_of;
+use x86_64::PhysAddr;
+
+//////////////////////////////
+// Statics/Constants
+//////////////////////////////
+
+const RSDP_SIGNATURE: &[u8; 8] = b"RSD PTR ";
+const MADT_SIGNATURE: &[u8; 4] = b"APIC";
+
+// The RSDP lives in the first KiB of the EBDA or in the BIOS read-only area,
+// always on a 16 byte boundary. ACPI 6.4 section 5.2.5.1
+const EBDA_SEGMENT_PTR: u64 = 0x40e;
+const BIOS_AREA_START: u64 = 0xe_0000;
+const BIOS_AREA_END: u64 = 0x10_0000;
+
+// MADT Interrupt Controller Structure types, ACPI 6.4 section 5.2.12
+const MADT_LOCAL_APIC: u8 = 0;
+const MADT_LOCAL_APIC_OVERRIDE: u8 = 5;
+const MADT_LOCAL_X2APIC: u8 = 9;
+
+// Processor is usable
+const MADT_CPU_ENABLED: u32 = 1 << 0;
+
+////////////////////////////////
+// Structs, Types, Traits, Impl
+////////////////////////////////
+
+#[allow(dead_code)]
+#[repr(C, packed)]
+#[derive(Clone, Copy)]
+struct Rsdp {
+    signature: [u8; 8],
+    checksum: u8,
+    oem_id: [u8; 6],
+    revision: u8,
+    rsdt_address: u32,
+    // Fields below are only valid for revision >= 2
+    length: u32,
+    xsdt_address: u64,
+    extended_checksum: u8,
+    reserved: [u8; 3],
+}
+
+#[allow(dead_code)]
+#[repr(C, packed)]
+#[derive(Clone, Copy)]
+struct SdtHeader {
+    signature: [u8; 4],
+    length: u32,
+    revision: u8,
+    checksum: u8,
+    oem_id: [u8; 6],
+    oem_table_id: [u8; 8],
+    oem_revision: u32,
+    creator_id: u32,
+    creator_revision: u32,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Processor {
+    pub apic_id: u32,
+    pub acpi_uid: u32,
+}
+
+// Contents of the Multiple APIC Description Table relevant to us
+#[derive(Debug, Clone)]
+pub struct Madt {
+    pub local_apic_address: PhysAddr,
+    // Enabled processors in MADT order, the first entry is usually the BSP
+    pub processors: Vec<Processor>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AcpiError {
+    RsdpNotFound,
+    BadChecksum([u8; 4]),
+    TableNotFound([u8; 4]),
+}
+
+//////////////////////////////
+// API
+//////////////////////////////
+
+// Locate and parse the MADT. Requires `memory::init`.
+pub fn madt() -> Result<Madt, AcpiError> {
+    let table = find_table(MADT_SIGNATURE)?;
+    let header: SdtHeader = unsafe { read_phys(table) };
+
+    // Local Interrupt Controller Address and Flags follow the header
+    let mut local_apic_address = u64::from(unsafe {
+        read_phys::<u32>(table + size_of::<SdtHeader>())
+    });
+    let mut processors = Vec::new();
+
+    let end = table + header.length as u64;
+    let mut entry = table + size_of::<SdtHeader>() + 8u64;
+    while entry + 2u64 <= end {
+        let entry_type: u8 = unsafe { read_phys(entry) };
+        let length: u8 = unsafe { read_phys(entry + 1u64) };
+        if length < 2 {
+            break; // Malformed table, avoid looping forever
+        }
+
+        match entry_type {
+            MADT_LOCAL_APIC => {
+                let acpi_uid: u8 = unsafe { read_phys(entry + 2u64) };
+                let apic_id: u8 = unsafe { read_phys(entry + 3u64) };
+                let flags: u32 = unsafe { read_phys(entry + 4u64) };
+                if flags & MADT_CPU_ENABLED != 0 {
+                    processors.push(Processor {
+                        apic_id: u32::from(apic_id),
+                        acpi_uid: u32::from(acpi_uid),
+                    });
+                }
+            }
+            MADT_LOCAL_X2APIC => {
+                let apic_id: u32 = unsafe { read_phys(entry + 4u64) };
+                let flags: u32 = unsafe { read_phys(entry + 8u64) };
+                let acpi_uid: u32 = unsafe { read_phys(entry + 12u64) };
+                if flags & MADT_CPU_ENABLED != 0 {
+                    processors.push(Processor { apic_id, acpi_uid });
+                }
+            }
+            MADT_LOCAL_APIC_OVERRIDE => {
+                local_apic_address = unsafe { read_phys(entry + 4u64) };
+            }
+            _ => {}
+        }
+        entry += u64::from(length);
+    }
+
+    Ok(Madt {
+        local_apic_address: PhysAddr::new(local_apic_address),
+        processors,
+    })
+}
+
+// Find the physical address of the table with the given signature through
+// the RSDT or XSDT.
+fn find_table(signature: &[u8; 4]) -> Result<PhysAddr, AcpiError> {
+    let rsdp_addr = find_rsdp().ok_or(AcpiError::RsdpNotFound)?;
+    let rsdp: Rsdp = unsafe { read_phys(rsdp_addr) };
+
+    let (root, entry_size) = match rsdp.revision {
+        0 => (PhysAddr::new(u64::from(rsdp.rsdt_address)), 4),
+        _ => (PhysAddr::new(rsdp.xsdt_address), 8),
+    };
+    let root_header: SdtHeader = unsafe { read_phys(root) };
+    if !checksum_ok(root, root_header.length as usize) {
+        return Err(AcpiError::BadChecksum(root_header.signature));
+    }
+
+    let entries = (root_header.length as usize - size_of::<SdtHeader>()) / entry_size;
+    for i in 0..entries {
+        let entry = root + size_of::<SdtHeader>() + (i * entry_size) as u64;
+        let table = match entry_size {
+            4 => PhysAddr::new(u64::from(unsafe { read_phys::<u32>(entry) })),
+            _ => PhysAddr::new(unsafe { read_phys::<u64>(entry) }),
+        };
+
+        let header: SdtHeader = unsafe { read_phys(table) };
+        if &header.signature == signature {
+            if !checksum_ok(table, header.length as usize) {
+                return Err(AcpiError::BadChecksum(header.signature));
+            }
+            return Ok(table);
+        }
+    }
+    Err(AcpiError::TableNotFound(*signature))
+}
+
+fn find_rsdp() -> Option<PhysAddr> {
+    let ebda = u64::from(unsafe { read_phys::<u16>(PhysAddr::new(EBDA_SEGMENT_PTR)) }) << 4;
+    let ebda_range = (ebda..ebda + 1024).step_by(16);
+    let bios_range = (BIOS_AREA_START..BIOS_AREA_END).step_by(16);
+
+    ebda_range
+        .chain(bios_range)
+        .map(PhysAddr::new)
+        .find(|&addr| {
+            let signature: [u8; 8] = unsafe { read_phys(addr) };
+            // Only the ACPI 1.0 part of the structure is covered by `checksum`
+            &signature == RSDP_SIGNATURE && checksum_ok(addr, 20)
+        })
+}
+
+// ACPI structures are valid when all their bytes sum to zero
+fn checksum_ok(addr: PhysAddr, length: usize) -> bool {
+    let sum = (0..length as u64)
+        .map(|i| unsafe { read_phys::<u8>(addr + i) })
+        .fold(0u8, |sum, byte| sum.wrapping_add(byte));
+    sum == 0
+}
+
+// Unsafe! `addr` must point to readable physical memory holding a `T`
+unsafe fn read_phys<T: Copy>(addr: PhysAddr) -> T {
+    let ptr: *const T = memory::phys_to_virt(addr).as_ptr();
+    ptr.read_unaligned()
+}
@@ -0,0 +1,371 @@
+// address_space.rs - Page tables of user processes, sharing the kernel's mappings
+
+use crate::memory::{self, GlobalFrameAllocator};
+use crate::spinlock::{IrqSpinlock, IrqSpinlockGuard};
+use crate::usermode::USER_SPACE_END;
+use crate::vm::Regions;
+
+use x86_64::registers::control::{Cr3, Cr3Flags};
+use x86_64::structures::paging::mapper::{
+    FlagUpdateError, MapToError, TranslateResult, UnmapError,
+};
+use x86_64::structures::paging::{
+    Mapper, OffsetPageTable, Page, PageTable, PageTableFlags, PhysFrame, Translate,
+};
+use x86_64::{PhysAddr, VirtAddr};
+
+//////////////////////////////
+// Statics/Constants
+//////////////////////////////
+
+/*
+ *  Every address space has its own level 4 table. The bootloader placed the
+ *  kernel, its heap and the physical memory mapping in the lower half too,
+ *  so the split is by level 4 entry rather than by half: entries in use in
+ *  the kernel's table are copied and so point to the very same level 3
+ *  tables, the others belong to the process alone.
+ *
+ *    level 4 table            kernel's table
+ *    |  0  | ---------------> |  0  |  kernel image     shared
+ *    |  1  | -> own tables    |     |                   user
+ *    | ... |                  | ... |
+ *    | 136 | ---------------> | 136 |  heap             shared
+ *    | ... |
+ *
+ *  User pages can therefore only be mapped in entries below USER_SPACE_END
+ *  that the kernel does not use, 512 GiB each. Kernel mappings added to a
+ *  new level 4 entry after an address space was created are not seen by
+ *  it, the kernel sets up its own mappings while booting.
+ *
+ *  Frames mapped into an address space belong to it and are freed with
+ *  it, as are its page tables.
+ *
+ *  What user mode may map and access is tracked separately, in `regions`,
+ *  see vm.rs. Its lock is taken before the page table lock.
+ */
+
+// Lowest entry of the upper half, which only the kernel uses
+const USER_ENTRIES: usize = 256;
+
+const PAGE_SIZE: u64 = 4096;
+
+////////////////////////////////
+// Structs, Types, Traits, Impl
+////////////////////////////////
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MapError {
+    OutOfMemory,
+    // Outside user space, or in a level 4 entry shared with the kernel
+    NotUserPage,
+    AlreadyMapped,
+    NotMapped,
+}
+
+pub struct AddressSpace {
+    level_4: PhysFrame,
+    // Serializes changes to the page tables
+    lock: IrqSpinlock<()>,
+    regions: IrqSpinlock<Regions>,
+}
+
+impl AddressSpace {
+    // An address space with the kernel's mappings and no user pages
+    pub fn new() -> Result<AddressSpace, MapError> {
+        let level_4 = memory::allocate_frame().ok_or(MapError::OutOfMemory)?;
+        let table = unsafe { table_mut(level_4) };
+        let kernel = unsafe { table_mut(memory::kernel_page_table()) };
+        for (entry, kernel_entry) in table.iter_mut().zip(kernel.iter()) {
+            *entry = kernel_entry.clone();
+        }
+
+        Ok(AddressSpace {
+            level_4,
+            lock: IrqSpinlock::new("AddressSpace", ()),
+            regions: IrqSpinlock::new("regions", Regions::new()),
+        })
+    }
+
+    // Frame of the level 4 table, the value for CR3
+    pub fn page_table(&self) -> PhysFrame {
+        self.level_4
+    }
+
+    pub fn is_active(&self) -> bool {
+        Cr3::read().0 == self.level_4
+    }
+
+    // Switch the calling CPU to this address space
+    //
+    // Unsafe! It must outlive its use, and the caller's code and stack must
+    // be kernel mappings.
+    pub unsafe fn activate(&self) {
+        if !self.is_active() {
+            Cr3::write(self.level_4, Cr3Flags::empty());
+        }
+    }
+
+    // Whether `page` may be mapped for user mode, see above
+    pub fn is_user_page(&self, page: Page) -> bool {
+        is_user_address(page.start_address())
+    }
+
+    // The regions user mode may use, see vm.rs
+    pub fn regions(&self) -> IrqSpinlockGuard<'_, Regions> {
+        self.regions.lock()
+    }
+
+    // Map a new zeroed frame at `page`, user accessible with `flags` added
+    pub fn map(&self, page: Page, flags: PageTableFlags) -> Result<PhysFrame, MapError> {
+        if !self.is_user_page(page) {
+            return Err(MapError::NotUserPage);
+        }
+        let frame = memory::allocate_frame().ok_or(MapError::OutOfMemory)?;
+        unsafe {
+            let data: *mut u8 = memory::phys_to_virt(frame.start_address()).as_mut_ptr();
+            core::ptr::write_bytes(data, 0, page.size() as usize);
+        }
+
+        let flags = flags | PageTableFlags::PRESENT | PageTableFlags::USER_ACCESSIBLE;
+        let table_flags =
+            PageTableFlags::PRESENT | PageTableFlags::WRITABLE | PageTableFlags::USER_ACCESSIBLE;
+        let _lock = self.lock.lock();
+        let result = unsafe {
+            self.mapper().map_to_with_table_flags(
+                page,
+                frame,
+                flags,
+                table_flags,
+                &mut GlobalFrameAllocator,
+            )
+        };
+        match result {
+            Ok(flush) => {
+                flush.flush();
+                Ok(frame)
+            }
+            Err(error) => {
+                unsafe { memory::free_frame(frame) };
+                Err(match error {
+                    MapToError::FrameAllocationFailed => MapError::OutOfMemory,
+                    _ => MapError::AlreadyMapped,
+                })
+            }
+        }
+    }
+
+    // Unmap `page` and free its frame. Only the calling CPU's TLB is
+    // flushed.
+    pub fn unmap(&self, page: Page) -> Result<(), MapError> {
+        if !self.is_user_page(page) {
+            return Err(MapError::NotUserPage);
+        }
+
+        let _lock = self.lock.lock();
+        match self.mapper().unmap(page) {
+            Ok((frame, flush)) => {
+                flush.flush();
+                unsafe { memory::free_frame(frame) };
+                Ok(())
+            }
+            Err(UnmapError::PageNotMapped) => Err(MapError::NotMapped),
+            Err(error) => panic!("corrupt user page table: {:?}", error),
+        }
+    }
+
+    // Change the flags of the mapped `page` to `flags`. It stays present,
+    // but is only user accessible if `flags` say so. Only the calling CPU's
+    // TLB is flushed.
+    pub fn protect(&self, page: Page, flags: PageTableFlags) -> Result<(), MapError> {
+        if !self.is_user_page(page) {
+            return Err(MapError::NotUserPage);
+        }
+
+        let flags = flags | PageTableFlags::PRESENT;
+        let _lock = self.lock.lock();
+        match unsafe { self.mapper().update_flags(page, flags) } {
+            Ok(flush) => {
+                flush.flush();
+                Ok(())
+            }
+            Err(FlagUpdateError::PageNotMapped) => Err(MapError::NotMapped),
+            Err(error) => panic!("corrupt user page table: {:?}", error),
+        }
+    }
+
+    // Copy `data` to the user pages at `addr`. Goes through the mapping of
+    // physical memory, so the address space need not be active.
+    pub fn write(&self, addr: VirtAddr, data: &[u8]) -> Result<(), MapError> {
+        self.for_each_chunk(addr, data.len(), |chunk, offset| {
+            chunk.copy_from_slice(&data[offset..offset + chunk.len()])
+        })
+    }
+
+    // Zero `len` bytes of user pages at `addr`
+    pub fn zero(&self, addr: VirtAddr, len: usize) -> Result<(), MapError> {
+        self.for_each_chunk(addr, len, |chunk, _| chunk.fill(0))
+    }
+
+    // Physical address `addr` is mapped to, with the page's flags
+    pub fn translate(&self, addr: VirtAddr) -> Option<(PhysAddr, PageTableFlags)> {
+        let _lock = self.lock.lock();
+        match self.mapper().translate(addr) {
+            TranslateResult::Mapped {
+                frame,
+                offset,
+                flags,
+            } => Some((frame.start_address() + offset, flags)),
+            _ => None,
+        }
+    }
+
+    // Call `f` with the memory of `addr..addr + len` one page at a time,
+    // along with the chunk's offset into the range
+    fn for_each_chunk(
+        &self,
+        addr: VirtAddr,
+        len: usize,
+        mut f: impl FnMut(&mut [u8], usize),
+    ) -> Result<(), MapError> {
+        let mut offset = 0;
+        while offset < len {
+            let current = addr + offset as u64;
+            if !self.is_user_page(Page::containing_address(current)) {
+                return Err(MapError::NotUserPage);
+            }
+            let (phys, _) = self.translate(current).ok_or(MapError::NotMapped)?;
+            let size = ((PAGE_SIZE - current.as_u64() % PAGE_SIZE) as usize).min(len - offset);
+            let chunk = unsafe {
+                core::slice::from_raw_parts_mut(memory::phys_to_virt(phys).as_mut_ptr(), size)
+            };
+            f(chunk, offset);
+            offset += size;
+        }
+        Ok(())
+    }
+
+    // The page tables are only changed with `lock` held
+    fn mapper(&self) -> OffsetPageTable<'_> {
+        let offset = memory::phys_to_virt(PhysAddr::new(0));
+        unsafe { OffsetPageTable::new(table_mut(self.level_4), offset) }
+    }
+}
+
+impl Drop for AddressSpace {
+    fn drop(&mut self) {
+        assert!(!self.is_active(), "dropping the active address space");
+
+        let table = unsafe { table_mut(self.level_4) };
+        let kernel = unsafe { table_mut(memory::kernel_page_table()) };
+        for index in 0..USER_ENTRIES {
+            if kernel[index].is_unused() && !table[index].is_unused() {
+                unsafe { free_table(table[index].frame().expect("huge page in user space"), 3) };
+            }
+        }
+        unsafe { memory::free_frame(self.level_4) };
+    }
+}
+
+//////////////////////////////
+// API
+//////////////////////////////
+
+// Whether `addr` is in a level 4 entry of user space, see above. Takes no
+// locks, so page fault handlers may call it.
+pub fn is_user_address(addr: VirtAddr) -> bool {
+    let index = usize::from(addr.p4_index());
+    let kernel = unsafe { table_mut(memory::kernel_page_table()) };
+    addr.as_u64() < USER_SPACE_END && kernel[index].is_unused()
+}
+
+// The no-execute bit is reserved, and faults, unless EFER enables it
+pub fn no_execute() -> PageTableFlags {
+    use x86_64::registers::model_specific::{Efer, EferFlags};
+
+    if Efer::read().contains(EferFlags::NO_EXECUTE_ENABLE) {
+        PageTableFlags::NO_EXECUTE
+    } else {
+        PageTableFlags::empty()
+    }
+}
+
+// Unsafe! `frame` must hold a page table nobody else is changing.
+unsafe fn table_mut(frame: PhysFrame) -> &'static mut PageTable {
+    &mut *memory::phys_to_virt(frame.start_address()).as_mut_ptr()
+}
+
+// Free the table in `frame` of the given level, with everything it maps
+//
+// Unsafe! Nothing may use the table or the frames it maps anymore.
+unsafe fn free_table(frame: PhysFrame, level: usize) {
+    for entry in table_mut(frame).iter() {
+        if entry.is_unused() {
+            continue;
+        }
+        let child = entry.frame().expect("huge page in user space");
+        if level > 1 {
+            free_table(child, level - 1);
+        } else {
+            memory::free_frame(child);
+        }
+    }
+    memory::free_frame(frame);
+}
+
+//////////////////////////////
+// Tests
+//////////////////////////////
+
+#[test_case]
+fn test_user_mapping_is_private() {
+    use x86_64::structures::paging::Size4KiB;
+
+    const ADDR: u64 = 0x5555_0000_0000;
+
+    let page = Page::<Size4KiB>::containing_address(VirtAddr::new(ADDR));
+    let space = AddressSpace::new().expect("no memory for address space");
+    let other = AddressSpace::new().expect("no memory for address space");
+
+    space
+        .map(page, PageTableFlags::WRITABLE)
+        .expect("mapping failed");
+    assert_eq!(
+        space.map(page, PageTableFlags::WRITABLE),
+        Err(MapError::AlreadyMapped)
+    );
+    let (_, flags) = space
+        .translate(page.start_address())
+        .expect("page not mapped");
+    assert!(flags.contains(PageTableFlags::USER_ACCESSIBLE | PageTableFlags::WRITABLE));
+
+    assert!(other.translate(page.start_address()).is_none());
+    assert!(!memory::is_mapped(page.start_address()));
+
+    // Kernel code is mapped everywhere, but never for user mode
+    let kernel_page =
+        Page::containing_address(VirtAddr::new(AddressSpace::new as *const () as u64));
+    assert!(!space.is_user_page(kernel_page));
+    assert_eq!(
+        space.map(kernel_page, PageTableFlags::empty()),
+        Err(MapError::NotUserPage)
+    );
+
+    space.unmap(page).expect("unmapping failed");
+    assert_eq!(space.unmap(page), Err(MapError::NotMapped));
+}
+
+#[test_case]
+fn test_drop_frees_frames() {
+    use x86_64::structures::paging::Size4KiB;
+
+    let page = Page::<Size4KiB>::containing_address(VirtAddr::new(0x5555_0000_0000));
+    let space = AddressSpace::new().expect("no memory for address space");
+    space
+        .map(page, PageTableFlags::empty())
+        .expect("mapping failed");
+
+    // The page, its level 3, 2 and 1 tables and the level 4 table
+    let before = memory::free_frame_count();
+    drop(space);
+    assert_eq!(memory::free_frame_count(), before + 5);
+}
@@ -0,0 +1,91 @@
+// allocator.rs - Kernel heap allocator
+
+use crate::spinlock::{IrqSpinlock, IrqSpinlockGuard};
+
+use core::alloc::{GlobalAlloc, Layout};
+use core::ptr::{self, NonNull};
+use linked_list_allocator::Heap;
+use x86_64::{
+    structures::paging::{
+        mapper::MapToError, FrameAllocator, Mapper, Page, PageTableFlags, Size4KiB,
+    },
+    VirtAddr,
+};
+
+//////////////////////////////
+// Statics/Constants
+//////////////////////////////
+
+pub const HEAP_START: usize = 0x_4444_4444_0000;
+pub const HEAP_SIZE: usize = 1024 * 1024; // 1 MiB
+
+#[global_allocator]
+static ALLOCATOR: Locked<Heap> = Locked::new("heap", Heap::empty());
+
+////////////////////////////////
+// Structs, Types, Traits, Impl
+////////////////////////////////
+
+// Wrapper around IrqSpinlock to permit trait implementations on foreign types
+pub struct Locked<A> {
+    inner: IrqSpinlock<A>,
+}
+
+impl<A> Locked<A> {
+    pub const fn new(name: &'static str, inner: A) -> Self {
+        Locked {
+            inner: IrqSpinlock::new(name, inner),
+        }
+    }
+
+    pub fn lock(&self) -> IrqSpinlockGuard<'_, A> {
+        self.inner.lock()
+    }
+}
+
+// Interrupts are disabled while the heap is locked, otherwise an interrupt
+// handler that allocates would deadlock on the lock held by the code it
+// interrupted. Same reasoning as the print macros.
+unsafe impl GlobalAlloc for Locked<Heap> {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        self.lock()
+            .allocate_first_fit(layout)
+            .map_or(ptr::null_mut(), |allocation| allocation.as_ptr())
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        self.lock().deallocate(NonNull::new_unchecked(ptr), layout)
+    }
+}
+
+//////////////////////////////
+// API
+//////////////////////////////
+
+// Map the heap pages to physical frames and initialize the allocator
+pub fn init_heap(
+    mapper: &mut impl Mapper<Size4KiB>,
+    frame_allocator: &mut impl FrameAllocator<Size4KiB>,
+) -> Result<(), MapToError<Size4KiB>> {
+    let page_range = {
+        let heap_start = VirtAddr::new(HEAP_START as u64);
+        let heap_end = heap_start + HEAP_SIZE - 1u64;
+        let heap_start_page = Page::containing_address(heap_start);
+        let heap_end_page = Page::containing_address(heap_end);
+        Page::range_inclusive(heap_start_page, heap_end_page)
+    };
+
+    for page in page_range {
+        let frame = frame_allocator
+            .allocate_frame()
+            .ok_or(MapToError::FrameAllocationFailed)?;
+        let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE;
+        unsafe { mapper.map_to(page, frame, flags, frame_allocator)?.flush() };
+    }
+
+    unsafe {
+        ALLOCATOR.lock().init(HEAP_START as *mut u8, HEAP_SIZE);
+    }
+
+    Ok(())
+}
@@ -0,0 +1,156 @@
+// apic.rs - Local APIC (xAPIC mode)
+
+use crate::{memory, time};
+
+use core::sync::atomic::{AtomicU64, Ordering};
+use x86_64::PhysAddr;
+
+//////////////////////////////
+// Statics/Constants
+//////////////////////////////
+
+// Vectors above the legacy PIC range, handled in interrupts.rs
+pub const TIMER_VECTOR: u8 = 0xe0;
+pub const WAKEUP_VECTOR: u8 = 0xf0;
+pub const SPURIOUS_VECTOR: u8 = 0xff;
+
+// Frequency of the per-CPU tick driven by the local APIC timer
+pub const TIMER_HZ: u32 = 100;
+
+// Register offsets from the APIC base, Intel SDM Vol. 3A Table 10-1
+const REG_ID: usize = 0x20;
+const REG_EOI: usize = 0xb0;
+const REG_SPURIOUS: usize = 0xf0;
+const REG_ERROR_STATUS: usize = 0x280;
+const REG_ICR_LOW: usize = 0x300;
+const REG_ICR_HIGH: usize = 0x310;
+const REG_LVT_TIMER: usize = 0x320;
+const REG_TIMER_INITIAL: usize = 0x380;
+const REG_TIMER_CURRENT: usize = 0x390;
+const REG_TIMER_DIVIDE: usize = 0x3e0;
+
+const SPURIOUS_APIC_ENABLE: u32 = 1 << 8;
+
+// Interrupt Command Register fields
+const ICR_DELIVERY_FIXED: u32 = 0b000 << 8;
+const ICR_DELIVERY_NMI: u32 = 0b100 << 8;
+const ICR_DELIVERY_INIT: u32 = 0b101 << 8;
+const ICR_DELIVERY_STARTUP: u32 = 0b110 << 8;
+const ICR_DELIVERY_PENDING: u32 = 1 << 12;
+const ICR_LEVEL_ASSERT: u32 = 1 << 14;
+
+const LVT_MASKED: u32 = 1 << 16;
+const LVT_TIMER_PERIODIC: u32 = 1 << 17;
+const TIMER_DIVIDE_BY_16: u32 = 0b0011;
+
+// How long the BSP measures the APIC timer against the PIT
+const CALIBRATION_US: u64 = 10_000;
+
+// Virtual address of the APIC registers, 0 until `init`
+static BASE: AtomicU64 = AtomicU64::new(0);
+
+// APIC timer ticks per second at `TIMER_DIVIDE_BY_16`, 0 until calibrated
+static TIMER_FREQUENCY: AtomicU64 = AtomicU64::new(0);
+
+//////////////////////////////
+// API
+//////////////////////////////
+
+// Unsafe! `base` must be the physical address of the local APIC registers.
+// Registers are reached through the bootloader's physical memory mapping,
+// which covers the APIC page as long as the memory map reaches past it.
+pub unsafe fn init(base: PhysAddr) {
+    BASE.store(memory::phys_to_virt(base).as_u64(), Ordering::SeqCst);
+}
+
+// Software enable the local APIC of the calling CPU. Required before it
+// accepts fixed interrupts such as `WAKEUP_VECTOR`.
+pub fn enable() {
+    unsafe {
+        write(REG_SPURIOUS, SPURIOUS_APIC_ENABLE | u32::from(SPURIOUS_VECTOR));
+        write(REG_ERROR_STATUS, 0);
+    }
+}
+
+// APIC ID of the calling CPU
+pub fn id() -> u32 {
+    unsafe { read(REG_ID) >> 24 }
+}
+
+pub fn end_of_interrupt() {
+    unsafe { write(REG_EOI, 0) };
+}
+
+// Send a fixed interrupt to the CPU with the given APIC ID
+pub fn send_ipi(apic_id: u32, vector: u8) {
+    send(apic_id, ICR_DELIVERY_FIXED | ICR_LEVEL_ASSERT | u32::from(vector));
+}
+
+// Non-maskable interrupt, delivered even if the target has interrupts disabled
+pub fn send_nmi(apic_id: u32) {
+    send(apic_id, ICR_DELIVERY_NMI | ICR_LEVEL_ASSERT);
+}
+
+// INIT IPI, resets the target CPU into the wait-for-SIPI state
+pub fn send_init(apic_id: u32) {
+    send(apic_id, ICR_DELIVERY_INIT | ICR_LEVEL_ASSERT);
+}
+
+// Startup IPI, the target starts executing in real mode at `page << 12`
+pub fn send_startup(apic_id: u32, page: u8) {
+    send(apic_id, ICR_DELIVERY_STARTUP | ICR_LEVEL_ASSERT | u32::from(page));
+}
+
+// Measure the APIC timer frequency against the PIT. The timer runs at the
+// bus or crystal clock which is the same on every CPU, so only the BSP does
+// this, before `start_timer`.
+pub fn calibrate_timer() {
+    unsafe {
+        write(REG_TIMER_DIVIDE, TIMER_DIVIDE_BY_16);
+        write(REG_LVT_TIMER, LVT_MASKED | u32::from(TIMER_VECTOR));
+        write(REG_TIMER_INITIAL, u32::MAX);
+        time::busy_wait_us(CALIBRATION_US);
+        let elapsed = u32::MAX - read(REG_TIMER_CURRENT);
+        write(REG_TIMER_INITIAL, 0);
+
+        let frequency = u64::from(elapsed) * 1_000_000 / CALIBRATION_US;
+        TIMER_FREQUENCY.store(frequency, Ordering::SeqCst);
+    }
+}
+
+// Start the periodic `TIMER_HZ` tick on the calling CPU
+pub fn start_timer() {
+    let frequency = TIMER_FREQUENCY.load(Ordering::SeqCst);
+    assert!(frequency != 0, "apic::calibrate_timer has not been called");
+
+    unsafe {
+        write(REG_TIMER_DIVIDE, TIMER_DIVIDE_BY_16);
+        write(REG_LVT_TIMER, LVT_TIMER_PERIODIC | u32::from(TIMER_VECTOR));
+        write(REG_TIMER_INITIAL, (frequency / u64::from(TIMER_HZ)) as u32);
+    }
+}
+
+fn send(apic_id: u32, command: u32) {
+    use x86_64::instructions::interrupts;
+
+    // ICR high and low must be written back to back on this CPU
+    interrupts::without_interrupts(|| unsafe {
+        write(REG_ICR_HIGH, apic_id << 24);
+        write(REG_ICR_LOW, command);
+        while read(REG_ICR_LOW) & ICR_DELIVERY_PENDING != 0 {
+            core::hint::spin_loop();
+        }
+    });
+}
+
+unsafe fn read(reg: usize) -> u32 {
+    let base = BASE.load(Ordering::Relaxed) as usize;
+    debug_assert!(base != 0, "apic::init has not been called");
+    core::ptr::read_volatile((base + reg) as *const u32)
+}
+
+unsafe fn write(reg: usize, value: u32) {
+    let base = BASE.load(Ordering::Relaxed) as usize;
+    debug_assert!(base != 0, "apic::init has not been called");
+    core::ptr::write_volatile((base + reg) as *mut u32, value);
+}
@@ -0,0 +1,168 @@
+// backtrace.rs - Frame pointer based stack unwinding
+
+use crate::{ksyms, memory};
+
+use core::fmt;
+use x86_64::structures::idt::InterruptStackFrame;
+use x86_64::VirtAddr;
+
+//////////////////////////////
+// Statics/Constants
+//////////////////////////////
+
+/*
+ *  The target is built with frame pointers, every function starts with
+ *  `push rbp; mov rbp, rsp`. The saved frame pointers form a linked list
+ *  through the stack, each next to the return address into its caller.
+ *
+ *    higher addresses
+ *    |  return address   |  <- rbp + 8    caller's frame
+ *    |  caller's rbp     |  <- rbp
+ *    |  locals           |                current frame
+ *    lower addresses
+ */
+pub const MAX_FRAMES: usize = 32;
+
+// A walk never leaves the stack it started on, no kernel stack is this large
+const MAX_STACK_SPAN: u64 = 1024 * 1024;
+
+////////////////////////////////
+// Structs, Types, Traits, Impl
+////////////////////////////////
+
+#[derive(Debug, Clone)]
+pub struct Backtrace {
+    frames: [u64; MAX_FRAMES],
+    len: usize,
+    // The first frame is a faulting instruction rather than a return address
+    exact_first: bool,
+}
+
+impl Backtrace {
+    // Backtrace of the caller
+    #[inline(never)]
+    pub fn capture() -> Backtrace {
+        let rbp: u64;
+        unsafe {
+            core::arch::asm!("mov {}, rbp", out(reg) rbp, options(nomem, nostack, preserves_flags));
+        }
+        Backtrace::empty(false).walk(rbp)
+    }
+
+    // Backtrace of the code an interrupt handler interrupted, starting at the
+    // instruction it was interrupted at. `rbp` is its frame pointer, see
+    // `interrupted_frame_pointer`. User mode stacks are not walked.
+    pub fn from_interrupt(stack_frame: &InterruptStackFrame, rbp: u64) -> Backtrace {
+        let rip = stack_frame.instruction_pointer.as_u64();
+        Backtrace::from_registers(rip, rbp, stack_frame.code_segment)
+    }
+
+    // Same as `from_interrupt` for saved register state, e.g. a `TrapFrame`
+    pub fn from_registers(rip: u64, rbp: u64, code_segment: u64) -> Backtrace {
+        let mut backtrace = Backtrace::empty(true);
+        backtrace.push(rip);
+        if code_segment & 0b11 != 0 {
+            return backtrace;
+        }
+        backtrace.walk(rbp)
+    }
+
+    pub fn frames(&self) -> &[u64] {
+        &self.frames[..self.len]
+    }
+
+    fn empty(exact_first: bool) -> Backtrace {
+        Backtrace {
+            frames: [0; MAX_FRAMES],
+            len: 0,
+            exact_first,
+        }
+    }
+
+    fn push(&mut self, addr: u64) {
+        self.frames[self.len] = addr;
+        self.len += 1;
+    }
+
+    // Follow the frame pointer chain, stopping at anything that does not look
+    // like a frame. This runs after crashes, so nothing is trusted.
+    fn walk(mut self, mut rbp: u64) -> Backtrace {
+        let bottom = rbp;
+        while self.len < MAX_FRAMES {
+            if rbp == 0 || !rbp.is_multiple_of(8) || rbp - bottom > MAX_STACK_SPAN {
+                break;
+            }
+            let readable = |addr: u64| VirtAddr::try_new(addr).is_ok_and(memory::is_mapped);
+            // rbp is 8 byte aligned, so both words are on one page
+            if !readable(rbp) {
+                break;
+            }
+
+            let (next, return_address) = unsafe {
+                let frame = rbp as *const u64;
+                (frame.read_volatile(), frame.add(1).read_volatile())
+            };
+            if return_address == 0 {
+                break;
+            }
+            self.push(return_address);
+
+            // The caller's frame is always further up the stack
+            if next <= rbp {
+                break;
+            }
+            rbp = next;
+        }
+        self
+    }
+}
+
+impl fmt::Display for Backtrace {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "Backtrace:")?;
+        for (i, &addr) in self.frames().iter().enumerate() {
+            // A return address points past the call, which may be the first
+            // byte of the next function
+            let exact = i == 0 && self.exact_first;
+            let lookup = if exact { addr } else { addr - 1 };
+
+            write!(f, "{:>4}: {:#018x}", i, addr)?;
+            match ksyms::lookup(lookup) {
+                Some(symbol) => writeln!(f, " - {}+{:#x}", symbol, addr - symbol.address)?,
+                None => writeln!(f, " - <unknown>")?,
+            }
+        }
+        Ok(())
+    }
+}
+
+//////////////////////////////
+// API
+//////////////////////////////
+
+// Frame pointer of the code an interrupt handler interrupted, which the
+// handler prologue saved at its own frame pointer. Only valid when called
+// directly from an `extern "x86-interrupt"` handler.
+#[inline(always)]
+pub fn interrupted_frame_pointer() -> u64 {
+    let rbp: u64;
+    unsafe {
+        core::arch::asm!("mov {}, [rbp]", out(reg) rbp, options(readonly, nostack, preserves_flags));
+    }
+    rbp
+}
+
+//////////////////////////////
+// Tests
+//////////////////////////////
+
+#[test_case]
+fn test_capture_reaches_the_caller() {
+    let backtrace = Backtrace::capture();
+    assert!(backtrace.frames().len() > 1);
+
+    // The first frame returns into this function
+    if let Some(symbol) = ksyms::lookup(backtrace.frames()[0] - 1) {
+        assert!(symbol.name.contains("test_capture_reaches_the_caller"));
+    }
+}
@@ -0,0 +1,506 @@
+// channel.rs - Message channels between processes, with handle transfer
+
+use crate::fd::{File, FileError, OpenFile, O_RDWR};
+use crate::sync::{Condvar, Mutex, MutexGuard};
+use crate::time;
+
+use alloc::collections::VecDeque;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::any::Any;
+
+//////////////////////////////
+// Statics/Constants
+//////////////////////////////
+
+/*
+ *  A channel has two endpoints, each an open file. What is sent on one is
+ *  received on the other as whole messages, in the order they were sent:
+ *
+ *    client --> | endpoint 0 | --> [ queue to 1 ] --> | endpoint 1 | --> driver
+ *           <--              <--  [ queue to 0 ] <--               <--
+ *
+ *  A message is up to MAX_MESSAGE_SIZE bytes and MAX_HANDLES open files.
+ *  Sending a file through a channel moves it into the receiving process,
+ *  which is how a service hands out pipes or further channels to its
+ *  clients.
+ *
+ *  An asynchronous send returns once the message is queued, waiting only
+ *  while CHANNEL_CAPACITY messages are. A synchronous one returns once the
+ *  other side received the message, and takes it back if that does not
+ *  happen in time. Receivers wait for a message, unless asked not to.
+ *
+ *  Once an endpoint is closed the other side still receives what was
+ *  queued and then gets EPIPE, and so do its senders. Messages queued to
+ *  the closed side are dropped along with the files they carry. A channel
+ *  cannot carry its own endpoints, though two channels carrying each
+ *  other's endpoints stay open until a process receives one of them.
+ */
+pub const MAX_MESSAGE_SIZE: usize = 64 * 1024;
+pub const MAX_HANDLES: usize = 16;
+pub const CHANNEL_CAPACITY: usize = 64;
+
+// `send` and `receive` flags
+pub const MSG_SYNC: u64 = 1;
+pub const MSG_NONBLOCK: u64 = 2;
+
+// Timeout to wait for as long as it takes
+pub const NO_TIMEOUT: u64 = u64::MAX;
+
+////////////////////////////////
+// Structs, Types, Traits, Impl
+////////////////////////////////
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ChannelError {
+    // Not without waiting, and waiting was not allowed
+    WouldBlock,
+    TimedOut,
+    // A signal ended a wait without timeout, see sync.rs
+    Interrupted,
+    // The other endpoint is closed
+    PeerClosed,
+    // More than MAX_MESSAGE_SIZE bytes or MAX_HANDLES files
+    TooLarge,
+    // The next message needs that much room, it stays queued
+    BufferTooSmall { data: usize, handles: usize },
+    // An endpoint sent through its own channel
+    InvalidHandle,
+    InvalidArgument,
+}
+
+// How long to wait for the other side. Only waiting forever can be
+// interrupted by a signal.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Wait {
+    Forever,
+    Timeout(u64),
+    NonBlocking,
+}
+
+pub struct Message {
+    pub data: Vec<u8>,
+    pub handles: Vec<Arc<OpenFile>>,
+}
+
+impl Message {
+    pub fn new(data: &[u8]) -> Message {
+        Message {
+            data: data.to_vec(),
+            handles: Vec::new(),
+        }
+    }
+}
+
+// Layout of the message argument of `send` and `receive`. Lengths are what
+// there is room for, `receive` stores what the message needed.
+#[repr(C)]
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct UserMessage {
+    pub data: u64,
+    pub data_len: u64,
+    // Descriptors as u32s
+    pub handles: u64,
+    pub handle_count: u64,
+}
+
+struct Channel {
+    state: Mutex<State>,
+    // Notified when a message is queued or received, or a side closes
+    changed: Condvar,
+}
+
+struct State {
+    // Messages to each side
+    queues: [VecDeque<Queued>; 2],
+    open: [bool; 2],
+    // Id of the last message each side received. Ids grow and queues are in
+    // order, so a sender knows its message was received once this reaches
+    // its id.
+    received: [u64; 2],
+    next_id: u64,
+}
+
+struct Queued {
+    id: u64,
+    message: Message,
+}
+
+pub struct Endpoint {
+    channel: Arc<Channel>,
+    side: usize,
+}
+
+impl Channel {
+    // Wait while `condition` holds, for at most as long as `wait` allows
+    // since `started_ms`. If the condition still holds, also why waiting
+    // stopped.
+    fn wait_while<'a>(
+        &self,
+        mut state: MutexGuard<'a, State>,
+        wait: Wait,
+        started_ms: u64,
+        mut condition: impl FnMut(&mut State) -> bool,
+    ) -> (MutexGuard<'a, State>, Option<ChannelError>) {
+        let (state, blocked, error) = match wait {
+            Wait::Forever => {
+                let (state, interrupted) = self.changed.wait_while_interruptible(state, condition);
+                (state, interrupted, ChannelError::Interrupted)
+            }
+            Wait::Timeout(timeout_ms) => {
+                let deadline = started_ms.saturating_add(timeout_ms);
+                let left = deadline.saturating_sub(time::uptime_ms());
+                let (state, timed_out) = self.changed.wait_timeout_while(state, left, condition);
+                (state, timed_out, ChannelError::TimedOut)
+            }
+            Wait::NonBlocking => {
+                let blocked = condition(&mut state);
+                (state, blocked, ChannelError::WouldBlock)
+            }
+        };
+        (state, Some(error).filter(|_| blocked))
+    }
+}
+
+impl Endpoint {
+    // Whether `file` is either endpoint of this channel
+    pub fn shares_channel(&self, file: &OpenFile) -> bool {
+        file.downcast::<Endpoint>()
+            .is_some_and(|endpoint| Arc::ptr_eq(&endpoint.channel, &self.channel))
+    }
+
+    // Send `message` to the other side. If it is synchronous, wait until
+    // it was received. On failure the message is dropped.
+    pub fn send(&self, message: Message, sync: bool, wait: Wait) -> Result<(), ChannelError> {
+        if message.data.len() > MAX_MESSAGE_SIZE || message.handles.len() > MAX_HANDLES {
+            return Err(ChannelError::TooLarge);
+        }
+        if message.handles.iter().any(|file| self.shares_channel(file)) {
+            return Err(ChannelError::InvalidHandle);
+        }
+        // Nobody is waiting to receive it right away
+        if sync && wait == Wait::NonBlocking {
+            return Err(ChannelError::InvalidArgument);
+        }
+
+        let channel = &*self.channel;
+        let (to, started_ms) = (1 - self.side, time::uptime_ms());
+        let (mut state, stopped) =
+            channel.wait_while(channel.state.lock(), wait, started_ms, |state| {
+                state.open[to] && state.queues[to].len() >= CHANNEL_CAPACITY
+            });
+        if !state.open[to] {
+            return Err(ChannelError::PeerClosed);
+        }
+        if let Some(error) = stopped {
+            return Err(error);
+        }
+
+        let id = state.next_id;
+        state.next_id += 1;
+        state.queues[to].push_back(Queued { id, message });
+        channel.changed.notify_all();
+        if !sync {
+            return Ok(());
+        }
+
+        let (mut state, stopped) = channel.wait_while(state, wait, started_ms, |state| {
+            state.open[to] && state.received[to] < id
+        });
+        if state.received[to] >= id {
+            return Ok(());
+        }
+        // Closing the other side dropped the message already, otherwise
+        // take it back. Either way not while holding the lock, a file in it
+        // may be the endpoint of another channel.
+        let withdrawn = match state.queues[to].iter().position(|queued| queued.id == id) {
+            Some(index) => state.queues[to].remove(index),
+            None => None,
+        };
+        let error = stopped.unwrap_or(ChannelError::PeerClosed);
+        drop(state);
+        drop(withdrawn);
+        Err(error)
+    }
+
+    // The next message, if it has at most `max_data` bytes and `max_handles`
+    // files. A larger one stays queued.
+    pub fn receive(
+        &self,
+        max_data: usize,
+        max_handles: usize,
+        wait: Wait,
+    ) -> Result<Message, ChannelError> {
+        let channel = &*self.channel;
+        let side = self.side;
+        let (mut state, stopped) =
+            channel.wait_while(channel.state.lock(), wait, time::uptime_ms(), |state| {
+                state.queues[side].is_empty() && state.open[1 - side]
+            });
+
+        // Empty with the other side open only if waiting stopped early
+        let (data, handles) = match state.queues[side].front() {
+            Some(queued) => (queued.message.data.len(), queued.message.handles.len()),
+            None => return Err(stopped.unwrap_or(ChannelError::PeerClosed)),
+        };
+        if data > max_data || handles > max_handles {
+            return Err(ChannelError::BufferTooSmall { data, handles });
+        }
+        let queued = state.queues[side]
+            .pop_front()
+            .expect("queue emptied while locked");
+        state.received[side] = queued.id;
+        drop(state);
+        channel.changed.notify_all();
+        Ok(queued.message)
+    }
+}
+
+// Channels carry messages, not bytes
+impl File for Endpoint {
+    fn read(&self, _buffer: &mut [u8]) -> Result<usize, FileError> {
+        Err(FileError::InvalidArgument)
+    }
+
+    fn write(&self, _buffer: &[u8]) -> Result<usize, FileError> {
+        Err(FileError::InvalidArgument)
+    }
+
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+}
+
+impl Drop for Endpoint {
+    fn drop(&mut self) {
+        let mut state = self.channel.state.lock();
+        state.open[self.side] = false;
+        let queue = core::mem::take(&mut state.queues[self.side]);
+        drop(state);
+        self.channel.changed.notify_all();
+        // The files in there may close other endpoints
+        drop(queue);
+    }
+}
+
+//////////////////////////////
+// API
+//////////////////////////////
+
+// A new channel as its two endpoints
+pub fn channel() -> (Arc<OpenFile>, Arc<OpenFile>) {
+    let channel = Arc::new(Channel {
+        state: Mutex::new(State {
+            queues: [VecDeque::new(), VecDeque::new()],
+            open: [true; 2],
+            received: [0; 2],
+            next_id: 1,
+        }),
+        changed: Condvar::new(),
+    });
+    let first = OpenFile::new(
+        Endpoint {
+            channel: channel.clone(),
+            side: 0,
+        },
+        O_RDWR,
+    );
+    let second = OpenFile::new(Endpoint { channel, side: 1 }, O_RDWR);
+    (first, second)
+}
+
+//////////////////////////////
+// Tests
+//////////////////////////////
+
+#[cfg(test)]
+fn endpoint(file: &Arc<OpenFile>) -> &Endpoint {
+    file.downcast().expect("not a channel")
+}
+
+#[test_case]
+fn test_channel_messages() {
+    let (client, server) = channel();
+    let (client_end, server_end) = (endpoint(&client), endpoint(&server));
+    let mut buffer = [0u8; 4];
+    assert_eq!(client.read(&mut buffer), Err(FileError::InvalidArgument));
+
+    // In order, whole messages, both ways
+    for data in [&b"one"[..], b"two", b"three"].iter() {
+        assert!(client_end
+            .send(Message::new(data), false, Wait::Forever)
+            .is_ok());
+    }
+    assert!(server_end
+        .send(Message::new(b"back"), false, Wait::Forever)
+        .is_ok());
+    assert_eq!(
+        client_end.receive(16, 0, Wait::Forever).unwrap().data,
+        b"back"
+    );
+    assert_eq!(
+        server_end.receive(16, 0, Wait::Forever).unwrap().data,
+        b"one"
+    );
+    assert_eq!(
+        server_end.receive(16, 0, Wait::NonBlocking).unwrap().data,
+        b"two"
+    );
+
+    // Too large for the buffer, it stays queued
+    assert_eq!(
+        server_end.receive(4, 0, Wait::Forever).err(),
+        Some(ChannelError::BufferTooSmall {
+            data: 5,
+            handles: 0
+        })
+    );
+    assert_eq!(
+        server_end.receive(5, 0, Wait::Forever).unwrap().data,
+        b"three"
+    );
+    assert_eq!(
+        server_end.receive(16, 0, Wait::NonBlocking).err(),
+        Some(ChannelError::WouldBlock)
+    );
+
+    let large = Message {
+        data: alloc::vec![0; MAX_MESSAGE_SIZE + 1],
+        handles: Vec::new(),
+    };
+    assert_eq!(
+        client_end.send(large, false, Wait::Forever).err(),
+        Some(ChannelError::TooLarge)
+    );
+    for _ in 0..CHANNEL_CAPACITY {
+        assert!(client_end
+            .send(Message::new(b"fill"), false, Wait::NonBlocking)
+            .is_ok());
+    }
+    assert_eq!(
+        client_end
+            .send(Message::new(b"full"), false, Wait::NonBlocking)
+            .err(),
+        Some(ChannelError::WouldBlock)
+    );
+
+    // The server gets what was queued before the client left, then EPIPE
+    drop(client);
+    assert_eq!(
+        server_end.receive(16, 0, Wait::Forever).unwrap().data,
+        b"fill"
+    );
+    assert_eq!(
+        server_end
+            .send(Message::new(b"gone"), false, Wait::Forever)
+            .err(),
+        Some(ChannelError::PeerClosed)
+    );
+    for _ in 1..CHANNEL_CAPACITY {
+        assert!(server_end.receive(16, 0, Wait::Forever).is_ok());
+    }
+    assert_eq!(
+        server_end.receive(16, 0, Wait::Forever).err(),
+        Some(ChannelError::PeerClosed)
+    );
+}
+
+#[test_case]
+fn test_channel_handles() {
+    use crate::pipe;
+
+    let (client, server) = channel();
+    let (read_end, write_end) = pipe::pipe();
+    let message = Message {
+        data: b"log".to_vec(),
+        handles: alloc::vec![write_end],
+    };
+    assert!(endpoint(&client)
+        .send(message, false, Wait::Forever)
+        .is_ok());
+
+    // Too many files for the receiver, then the pipe end arrives
+    assert_eq!(
+        endpoint(&server).receive(16, 0, Wait::Forever).err(),
+        Some(ChannelError::BufferTooSmall {
+            data: 3,
+            handles: 1
+        })
+    );
+    let mut message = endpoint(&server).receive(16, 1, Wait::Forever).unwrap();
+    let write_end = message.handles.pop().expect("no handle");
+    assert_eq!(write_end.write(b"hi"), Ok(2));
+    drop((message, write_end));
+    let mut buffer = [0u8; 4];
+    assert_eq!(read_end.read(&mut buffer), Ok(2));
+    assert_eq!(read_end.read(&mut buffer), Ok(0));
+
+    // Neither endpoint can travel through its own channel
+    for file in [&client, &server].iter() {
+        let message = Message {
+            data: Vec::new(),
+            handles: alloc::vec![Arc::clone(file)],
+        };
+        assert_eq!(
+            endpoint(&client).send(message, false, Wait::Forever).err(),
+            Some(ChannelError::InvalidHandle)
+        );
+    }
+
+    // Files queued to a closed endpoint are closed with it
+    let (read_end, write_end) = pipe::pipe();
+    let message = Message {
+        data: Vec::new(),
+        handles: alloc::vec![write_end],
+    };
+    assert!(endpoint(&client)
+        .send(message, false, Wait::Forever)
+        .is_ok());
+    drop(server);
+    assert_eq!(read_end.read(&mut buffer), Ok(0));
+}
+
+#[test_case]
+fn test_channel_sync_and_timeout() {
+    use crate::thread;
+
+    let (client, server) = channel();
+    assert_eq!(
+        endpoint(&client)
+            .send(Message::new(b"now"), true, Wait::NonBlocking)
+            .err(),
+        Some(ChannelError::InvalidArgument)
+    );
+
+    // Nobody receives, the message is taken back after the timeout
+    let start = time::ticks();
+    assert_eq!(
+        endpoint(&client)
+            .send(Message::new(b"late"), true, Wait::Timeout(20))
+            .err(),
+        Some(ChannelError::TimedOut)
+    );
+    assert!(time::ticks() - start >= time::ms_to_ticks(20));
+    assert_eq!(
+        endpoint(&server).receive(16, 0, Wait::Timeout(10)).err(),
+        Some(ChannelError::TimedOut)
+    );
+
+    // Returns once the server thread received it
+    let handle = thread::spawn("channel server", move || {
+        let message = endpoint(&server).receive(16, 0, Wait::Forever);
+        message.map(|message| message.data)
+    });
+    assert!(endpoint(&client)
+        .send(Message::new(b"sync"), true, Wait::Forever)
+        .is_ok());
+    assert_eq!(handle.join().ok(), Some(b"sync".to_vec()));
+
+    // The server thread is gone with its endpoint
+    assert_eq!(
+        endpoint(&client)
+            .send(Message::new(b"alone"), true, Wait::Forever)
+            .err(),
+        Some(ChannelError::PeerClosed)
+    );
+}
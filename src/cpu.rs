@@ -0,0 +1,334 @@
+// cpu.rs - CPUID based processor identification and feature detection
+
+use core::arch::x86_64::{__cpuid_count, CpuidResult};
+use core::fmt;
+use lazy_static::lazy_static;
+
+//////////////////////////////
+// Statics/Constants
+//////////////////////////////
+
+/*
+ *   _______________________________________________________________
+ *  | Leaf        | Description                                     |
+ *  |---------------------------------------------------------------|
+ *  | 0x0         | Highest basic leaf, vendor string               |
+ *  | 0x1         | Family/model/stepping, basic feature flags      |
+ *  | 0x7         | Structured extended feature flags (subleaf 0)   |
+ *  | 0x8000_0000 | Highest extended leaf                           |
+ *  | 0x8000_0001 | Extended feature flags                          |
+ *  | 0x8000_0002 | Brand string, 16 bytes per leaf through 0x..04  |
+ *  | 0x8000_0007 | Advanced power management, invariant TSC        |
+ *  |_______________________________________________________________|
+ *
+ *  Leaves above the reported maximum return garbage, so they are only
+ *  queried when present. The result is the same on every CPU.
+ */
+const LEAF_BASIC_MAX: u32 = 0x0;
+const LEAF_VERSION: u32 = 0x1;
+const LEAF_EXTENDED_FEATURES: u32 = 0x7;
+const LEAF_EXTENDED_MAX: u32 = 0x8000_0000;
+const LEAF_EXTENDED_INFO: u32 = 0x8000_0001;
+const LEAF_BRAND: u32 = 0x8000_0002;
+const LEAF_POWER_MANAGEMENT: u32 = 0x8000_0007;
+
+lazy_static! {
+    static ref CPU_INFO: CpuInfo = CpuInfo::detect();
+}
+
+////////////////////////////////
+// Structs, Types, Traits, Impl
+////////////////////////////////
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Feature {
+    Fpu,
+    Tsc,
+    Apic,
+    Fxsr,
+    Sse,
+    Sse2,
+    X2Apic,
+    Pcid,
+    Xsave,
+    Avx,
+    Rdrand,
+    Smep,
+    Smap,
+    Avx2,
+    Nx,
+    Page1Gb,
+    Rdtscp,
+    InvariantTsc,
+}
+
+#[derive(Debug, Clone, Copy)]
+enum Register {
+    Ebx,
+    Ecx,
+    Edx,
+}
+
+impl Feature {
+    pub const ALL: [Feature; 18] = [
+        Feature::Fpu,
+        Feature::Tsc,
+        Feature::Apic,
+        Feature::Fxsr,
+        Feature::Sse,
+        Feature::Sse2,
+        Feature::X2Apic,
+        Feature::Pcid,
+        Feature::Xsave,
+        Feature::Avx,
+        Feature::Rdrand,
+        Feature::Smep,
+        Feature::Smap,
+        Feature::Avx2,
+        Feature::Nx,
+        Feature::Page1Gb,
+        Feature::Rdtscp,
+        Feature::InvariantTsc,
+    ];
+
+    // Same as the flags in Linux /proc/cpuinfo
+    pub fn name(self) -> &'static str {
+        match self {
+            Feature::Fpu => "fpu",
+            Feature::Tsc => "tsc",
+            Feature::Apic => "apic",
+            Feature::Fxsr => "fxsr",
+            Feature::Sse => "sse",
+            Feature::Sse2 => "sse2",
+            Feature::X2Apic => "x2apic",
+            Feature::Pcid => "pcid",
+            Feature::Xsave => "xsave",
+            Feature::Avx => "avx",
+            Feature::Rdrand => "rdrand",
+            Feature::Smep => "smep",
+            Feature::Smap => "smap",
+            Feature::Avx2 => "avx2",
+            Feature::Nx => "nx",
+            Feature::Page1Gb => "pdpe1gb",
+            Feature::Rdtscp => "rdtscp",
+            Feature::InvariantTsc => "constant_tsc",
+        }
+    }
+
+    // CPUID leaf, register and bit reporting the feature
+    fn location(self) -> (u32, Register, u32) {
+        match self {
+            Feature::Fpu => (LEAF_VERSION, Register::Edx, 0),
+            Feature::Tsc => (LEAF_VERSION, Register::Edx, 4),
+            Feature::Apic => (LEAF_VERSION, Register::Edx, 9),
+            Feature::Fxsr => (LEAF_VERSION, Register::Edx, 24),
+            Feature::Sse => (LEAF_VERSION, Register::Edx, 25),
+            Feature::Sse2 => (LEAF_VERSION, Register::Edx, 26),
+            Feature::Pcid => (LEAF_VERSION, Register::Ecx, 17),
+            Feature::X2Apic => (LEAF_VERSION, Register::Ecx, 21),
+            Feature::Xsave => (LEAF_VERSION, Register::Ecx, 26),
+            Feature::Avx => (LEAF_VERSION, Register::Ecx, 28),
+            Feature::Rdrand => (LEAF_VERSION, Register::Ecx, 30),
+            Feature::Avx2 => (LEAF_EXTENDED_FEATURES, Register::Ebx, 5),
+            Feature::Smep => (LEAF_EXTENDED_FEATURES, Register::Ebx, 7),
+            Feature::Smap => (LEAF_EXTENDED_FEATURES, Register::Ebx, 20),
+            Feature::Nx => (LEAF_EXTENDED_INFO, Register::Edx, 20),
+            Feature::Page1Gb => (LEAF_EXTENDED_INFO, Register::Edx, 26),
+            Feature::Rdtscp => (LEAF_EXTENDED_INFO, Register::Edx, 27),
+            Feature::InvariantTsc => (LEAF_POWER_MANAGEMENT, Register::Edx, 8),
+        }
+    }
+
+    fn mask(self) -> u64 {
+        1 << self as u64
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct FeatureSet(u64);
+
+impl FeatureSet {
+    pub fn contains(self, feature: Feature) -> bool {
+        self.0 & feature.mask() != 0
+    }
+
+    pub fn insert(&mut self, feature: Feature) {
+        self.0 |= feature.mask();
+    }
+
+    pub fn iter(self) -> impl Iterator<Item = Feature> {
+        Feature::ALL
+            .iter()
+            .copied()
+            .filter(move |&feature| self.contains(feature))
+    }
+}
+
+impl fmt::Display for FeatureSet {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for (i, feature) in self.iter().enumerate() {
+            if i > 0 {
+                write!(f, " ")?;
+            }
+            write!(f, "{}", feature.name())?;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct CpuInfo {
+    vendor: [u8; 12],
+    brand: [u8; 48],
+    pub family: u32,
+    pub model: u32,
+    pub stepping: u32,
+    pub features: FeatureSet,
+}
+
+impl CpuInfo {
+    fn detect() -> CpuInfo {
+        let basic = cpuid(LEAF_BASIC_MAX);
+        let max_basic = basic.eax;
+        let max_extended = cpuid(LEAF_EXTENDED_MAX).eax;
+        let present = |leaf: u32| {
+            if leaf >= LEAF_EXTENDED_MAX {
+                leaf <= max_extended
+            } else {
+                leaf <= max_basic
+            }
+        };
+
+        // The vendor string is spread over ebx, edx, ecx in that order
+        let mut vendor = [0u8; 12];
+        vendor[0..4].copy_from_slice(&basic.ebx.to_le_bytes());
+        vendor[4..8].copy_from_slice(&basic.edx.to_le_bytes());
+        vendor[8..12].copy_from_slice(&basic.ecx.to_le_bytes());
+
+        let mut brand = [0u8; 48];
+        if present(LEAF_BRAND + 2) {
+            for (i, chunk) in brand.chunks_mut(16).enumerate() {
+                let result = cpuid(LEAF_BRAND + i as u32);
+                for (j, register) in [result.eax, result.ebx, result.ecx, result.edx]
+                    .iter()
+                    .enumerate()
+                {
+                    chunk[j * 4..j * 4 + 4].copy_from_slice(&register.to_le_bytes());
+                }
+            }
+        }
+
+        let mut features = FeatureSet::default();
+        for &feature in Feature::ALL.iter() {
+            let (leaf, register, bit) = feature.location();
+            if !present(leaf) {
+                continue;
+            }
+            let result = cpuid(leaf);
+            let value = match register {
+                Register::Ebx => result.ebx,
+                Register::Ecx => result.ecx,
+                Register::Edx => result.edx,
+            };
+            if value & (1 << bit) != 0 {
+                features.insert(feature);
+            }
+        }
+
+        let (family, model, stepping) = decode_version(cpuid(LEAF_VERSION).eax);
+        CpuInfo {
+            vendor,
+            brand,
+            family,
+            model,
+            stepping,
+            features,
+        }
+    }
+
+    // e.g. "GenuineIntel" or "AuthenticAMD"
+    pub fn vendor(&self) -> &str {
+        core::str::from_utf8(&self.vendor).unwrap_or("unknown")
+    }
+
+    // Marketing name, empty if the CPU does not report one
+    pub fn brand(&self) -> &str {
+        let len = self.brand.iter().position(|&b| b == 0).unwrap_or(48);
+        core::str::from_utf8(&self.brand[..len])
+            .unwrap_or("")
+            .trim()
+    }
+}
+
+impl fmt::Display for CpuInfo {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(
+            f,
+            "CPU: {} family {:#x} model {:#x} stepping {}",
+            self.vendor(),
+            self.family,
+            self.model,
+            self.stepping
+        )?;
+        if !self.brand().is_empty() {
+            writeln!(f, "     {}", self.brand())?;
+        }
+        write!(f, "     {}", self.features)
+    }
+}
+
+//////////////////////////////
+// API
+//////////////////////////////
+
+pub fn info() -> &'static CpuInfo {
+    &CPU_INFO
+}
+
+pub fn has(feature: Feature) -> bool {
+    CPU_INFO.features.contains(feature)
+}
+
+fn cpuid(leaf: u32) -> CpuidResult {
+    // Subleaf 0 is the one wanted for every leaf above
+    __cpuid_count(leaf, 0)
+}
+
+// Family, model and stepping from leaf 1 eax. The extended fields only
+// count for the families that ran out of base values.
+fn decode_version(eax: u32) -> (u32, u32, u32) {
+    let stepping = eax & 0xf;
+    let base_model = (eax >> 4) & 0xf;
+    let base_family = (eax >> 8) & 0xf;
+    let extended_model = (eax >> 16) & 0xf;
+    let extended_family = (eax >> 20) & 0xff;
+
+    let family = match base_family {
+        0xf => base_family + extended_family,
+        _ => base_family,
+    };
+    let model = match base_family {
+        0x6 | 0xf => (extended_model << 4) | base_model,
+        _ => base_model,
+    };
+    (family, model, stepping)
+}
+
+//////////////////////////////
+// Tests
+//////////////////////////////
+
+#[test_case]
+fn test_decode_version() {
+    // Intel Coffee Lake and AMD Zen 3
+    assert_eq!(decode_version(0x0009_06ea), (0x6, 0x9e, 0xa));
+    assert_eq!(decode_version(0x00a2_0f10), (0x19, 0x21, 0x0));
+}
+
+#[test_case]
+fn test_long_mode_baseline_features() {
+    // Every x86_64 CPU has these
+    assert!(has(Feature::Fpu));
+    assert!(has(Feature::Sse2));
+    assert!(info().vendor().is_ascii());
+}
@@ -0,0 +1,107 @@
+// deferred.rs - Deferred interrupt work (bottom halves)
+//
+// Interrupt handlers should only do the minimum work required to service the
+// device, everything else is queued here and executed later with interrupts
+// enabled. Work items are a function pointer and a word of data so that
+// queueing one never allocates or blocks inside the interrupt handler.
+
+use conquer_once::spin::OnceCell;
+use core::sync::atomic::{AtomicU64, Ordering};
+use crossbeam_queue::ArrayQueue;
+
+//////////////////////////////
+// Statics/Constants
+//////////////////////////////
+
+const QUEUE_CAPACITY: usize = 256;
+
+static QUEUE: OnceCell<ArrayQueue<Work>> = OnceCell::uninit();
+
+// Number of work items dropped because the queue was full or uninitialized
+static DROPPED: AtomicU64 = AtomicU64::new(0);
+
+////////////////////////////////
+// Structs, Types, Traits, Impl
+////////////////////////////////
+
+#[derive(Debug, Clone, Copy)]
+struct Work {
+    func: fn(u64),
+    data: u64,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DeferError {
+    Uninitialized,
+    QueueFull,
+}
+
+//////////////////////////////
+// API
+//////////////////////////////
+
+// Allocates the work queue, requires the heap to be initialized
+pub fn init() {
+    QUEUE
+        .try_init_once(|| ArrayQueue::new(QUEUE_CAPACITY))
+        .expect("deferred::init should only be called once");
+}
+
+// Queue `func(data)` to run later with interrupts enabled. Safe to call from
+// interrupt context.
+pub fn schedule(func: fn(u64), data: u64) -> Result<(), DeferError> {
+    let result = match QUEUE.try_get() {
+        Ok(queue) => queue.push(Work { func, data }).map_err(|_| DeferError::QueueFull),
+        Err(_) => Err(DeferError::Uninitialized),
+    };
+    if result.is_err() {
+        DROPPED.fetch_add(1, Ordering::Relaxed);
+    }
+    result
+}
+
+// Runs queued work until the queue is empty, returns the number of items run.
+// Must be called with interrupts enabled, never from an interrupt handler.
+pub fn run_pending() -> usize {
+    debug_assert!(x86_64::instructions::interrupts::are_enabled());
+
+    let queue = match QUEUE.try_get() {
+        Ok(queue) => queue,
+        Err(_) => return 0,
+    };
+
+    let mut ran = 0;
+    while let Some(work) = queue.pop() {
+        (work.func)(work.data);
+        ran += 1;
+    }
+    ran
+}
+
+pub fn has_pending() -> bool {
+    QUEUE.try_get().map_or(false, |queue| !queue.is_empty())
+}
+
+pub fn dropped() -> u64 {
+    DROPPED.load(Ordering::Relaxed)
+}
+
+//////////////////////////////
+// Tests
+//////////////////////////////
+
+#[test_case]
+fn test_scheduled_work_runs_in_order() {
+    static LAST: AtomicU64 = AtomicU64::new(0);
+    fn record(data: u64) {
+        assert_eq!(LAST.load(Ordering::SeqCst) + 1, data);
+        LAST.store(data, Ordering::SeqCst);
+    }
+
+    for i in 1..=3 {
+        schedule(record, i).expect("schedule failed");
+    }
+    run_pending();
+    assert_eq!(LAST.load(Ordering::SeqCst), 3);
+    assert!(!has_pending());
+}
@@ -0,0 +1,781 @@
+// elf.rs - Parsing ELF64 executables and loading them into new processes
+
+use crate::address_space::{no_execute, AddressSpace, MapError};
+use crate::fd::FileTable;
+use crate::process::{self, Process};
+use crate::usermode::{self, USER_SPACE_END, USER_STACK_SIZE, USER_STACK_TOP};
+use crate::vm::{self, Kind, Region, PROT_EXEC, PROT_READ, PROT_WRITE};
+
+use alloc::sync::Arc;
+use alloc::vec;
+use alloc::vec::Vec;
+use core::convert::TryInto;
+use x86_64::structures::paging::{Page, PageTableFlags};
+use x86_64::VirtAddr;
+
+//////////////////////////////
+// Statics/Constants
+//////////////////////////////
+
+/*
+ *  Only statically linked x86_64 executables are supported. The parts of
+ *  the file header the loader looks at, all little endian:
+ *
+ *    offset  size
+ *    0       4     magic, 7f 'E' 'L' 'F'
+ *    4       1     class, 2 for 64 bit
+ *    5       1     data encoding, 1 for little endian
+ *    6       1     version, 1
+ *    16      2     type, ET_EXEC
+ *    18      2     machine, EM_X86_64
+ *    20      4     version, 1
+ *    24      8     entry point
+ *    32      8     offset of the program headers
+ *    54      2     size of a program header, 56
+ *    56      2     number of program headers
+ *
+ *  and of each program header:
+ *
+ *    0       4     type, PT_LOAD for the segments to load
+ *    4       4     flags, PF_R, PF_W and PF_X
+ *    8       8     offset in the file
+ *    16      8     virtual address
+ *    32      8     size in the file
+ *    40      8     size in memory, the rest is zeroed (.bss)
+ *    48      8     alignment
+ *
+ *  Everything is checked against the size of the file before it is read,
+ *  so a malformed file is reported as an `ElfError` and never panics.
+ */
+const MAGIC: [u8; 4] = [0x7f, b'E', b'L', b'F'];
+const ELFCLASS64: u8 = 2;
+const ELFDATA2LSB: u8 = 1;
+const EV_CURRENT: u8 = 1;
+const ET_EXEC: u16 = 2;
+const ET_DYN: u16 = 3;
+const EM_X86_64: u16 = 62;
+
+const HEADER_SIZE: usize = 64;
+const PROGRAM_HEADER_SIZE: usize = 56;
+
+pub const PT_LOAD: u32 = 1;
+pub const PT_DYNAMIC: u32 = 2;
+pub const PT_INTERP: u32 = 3;
+pub const PT_PHDR: u32 = 6;
+
+pub const PF_X: u32 = 1;
+pub const PF_W: u32 = 2;
+pub const PF_R: u32 = 4;
+
+/*
+ *  The stack a program starts with, as the System V ABI lays it out:
+ *
+ *    USER_STACK_TOP
+ *    |  argument and environment strings  |
+ *    |  padding                           |
+ *    |  AT_NULL, 0                        |
+ *    |  auxv, type and value pairs        |
+ *    |  0                                 |
+ *    |  envp[0..]                         |
+ *    |  0                                 |
+ *    |  argv[0..]                         |
+ *    |  argc                              |  <- rsp, 16 byte aligned
+ *
+ *  The auxiliary vector tells the program where its program headers are,
+ *  the page size and its entry point.
+ */
+pub const AT_NULL: u64 = 0;
+pub const AT_PHDR: u64 = 3;
+pub const AT_PHENT: u64 = 4;
+pub const AT_PHNUM: u64 = 5;
+pub const AT_PAGESZ: u64 = 6;
+pub const AT_ENTRY: u64 = 9;
+
+// Most bytes of arguments and environment, including the pointers to them
+pub const ARG_MAX: usize = 32 * 1024;
+
+const PAGE_SIZE: u64 = 4096;
+
+////////////////////////////////
+// Structs, Types, Traits, Impl
+////////////////////////////////
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ElfError {
+    // Shorter than the file header
+    Truncated,
+    BadMagic,
+    NotElf64,
+    NotLittleEndian,
+    BadVersion,
+    // Not an executable, e.g. a relocatable object
+    NotExecutable,
+    // Needs a dynamic linker, i.e. position independent or with PT_INTERP
+    DynamicallyLinked,
+    WrongMachine,
+    BadProgramHeaderSize,
+    ProgramHeadersOutOfFile,
+    NoLoadableSegments,
+    // The errors below name the index of the program header
+    SegmentOutOfFile(usize),
+    // Larger in the file than in memory
+    SegmentSizes(usize),
+    SegmentNotInUserSpace(usize),
+    SegmentMisaligned(usize),
+    OverlappingSegments(usize, usize),
+    // The entry point is not in an executable segment
+    BadEntry,
+    ArgumentsTooLarge,
+    // Setting up the address space failed
+    Memory(MapError),
+}
+
+impl From<MapError> for ElfError {
+    fn from(error: MapError) -> Self {
+        ElfError::Memory(error)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ProgramHeader {
+    pub kind: u32,
+    pub flags: u32,
+    pub offset: u64,
+    pub vaddr: u64,
+    pub file_size: u64,
+    pub mem_size: u64,
+    pub align: u64,
+}
+
+impl ProgramHeader {
+    fn parse(data: &[u8]) -> ProgramHeader {
+        ProgramHeader {
+            kind: read_u32(data, 0),
+            flags: read_u32(data, 4),
+            offset: read_u64(data, 8),
+            vaddr: read_u64(data, 16),
+            file_size: read_u64(data, 32),
+            mem_size: read_u64(data, 40),
+            align: read_u64(data, 48),
+        }
+    }
+
+    // End of the segment in memory, checked not to overflow by `Elf::parse`
+    pub fn end(&self) -> u64 {
+        self.vaddr + self.mem_size
+    }
+
+    // Flags of the pages holding the segment. Without the no-execute bit
+    // enabled in EFER every mapped page is executable.
+    pub fn page_flags(&self) -> PageTableFlags {
+        let mut flags = PageTableFlags::empty();
+        if self.flags & PF_W != 0 {
+            flags |= PageTableFlags::WRITABLE;
+        }
+        if self.flags & PF_X == 0 {
+            flags |= no_execute();
+        }
+        flags
+    }
+
+    // The same as `mmap` protection, see vm.rs
+    pub fn prot(&self) -> u64 {
+        let mut prot = 0;
+        if self.flags & PF_R != 0 {
+            prot |= PROT_READ;
+        }
+        if self.flags & PF_W != 0 {
+            prot |= PROT_WRITE;
+        }
+        if self.flags & PF_X != 0 {
+            prot |= PROT_EXEC;
+        }
+        prot
+    }
+}
+
+// A validated ELF64 executable, borrowing the file's bytes
+pub struct Elf<'a> {
+    data: &'a [u8],
+    entry: u64,
+    program_headers: &'a [u8],
+}
+
+impl<'a> Elf<'a> {
+    pub fn parse(data: &'a [u8]) -> Result<Elf<'a>, ElfError> {
+        if data.len() < HEADER_SIZE {
+            return Err(ElfError::Truncated);
+        }
+        if data[0..4] != MAGIC {
+            return Err(ElfError::BadMagic);
+        }
+        if data[4] != ELFCLASS64 {
+            return Err(ElfError::NotElf64);
+        }
+        if data[5] != ELFDATA2LSB {
+            return Err(ElfError::NotLittleEndian);
+        }
+        if data[6] != EV_CURRENT || read_u32(data, 20) != u32::from(EV_CURRENT) {
+            return Err(ElfError::BadVersion);
+        }
+        match read_u16(data, 16) {
+            ET_EXEC => {}
+            ET_DYN => return Err(ElfError::DynamicallyLinked),
+            _ => return Err(ElfError::NotExecutable),
+        }
+        if read_u16(data, 18) != EM_X86_64 {
+            return Err(ElfError::WrongMachine);
+        }
+        if usize::from(read_u16(data, 54)) != PROGRAM_HEADER_SIZE {
+            return Err(ElfError::BadProgramHeaderSize);
+        }
+
+        let offset = read_u64(data, 32);
+        let size = (PROGRAM_HEADER_SIZE * usize::from(read_u16(data, 56))) as u64;
+        let program_headers = match offset.checked_add(size) {
+            Some(end) if end <= data.len() as u64 => &data[offset as usize..end as usize],
+            _ => return Err(ElfError::ProgramHeadersOutOfFile),
+        };
+
+        let elf = Elf {
+            data,
+            entry: read_u64(data, 24),
+            program_headers,
+        };
+        elf.check_segments()?;
+        Ok(elf)
+    }
+
+    pub fn entry(&self) -> u64 {
+        self.entry
+    }
+
+    pub fn program_headers(&self) -> impl Iterator<Item = ProgramHeader> + 'a {
+        self.program_headers
+            .chunks_exact(PROGRAM_HEADER_SIZE)
+            .map(ProgramHeader::parse)
+    }
+
+    // The PT_LOAD segments, which make up the program's memory
+    pub fn segments(&self) -> impl Iterator<Item = ProgramHeader> + 'a {
+        self.program_headers()
+            .filter(|header| header.kind == PT_LOAD && header.mem_size > 0)
+    }
+
+    // The bytes of `segment` in the file, without the zeroed rest
+    pub fn segment_data(&self, segment: &ProgramHeader) -> &'a [u8] {
+        let start = segment.offset as usize;
+        &self.data[start..start + segment.file_size as usize]
+    }
+
+    // Where the program headers are in the program's memory, if loaded
+    pub fn program_headers_addr(&self) -> Option<u64> {
+        if let Some(phdr) = self.program_headers().find(|header| header.kind == PT_PHDR) {
+            return Some(phdr.vaddr);
+        }
+        let offset = self.program_headers.as_ptr() as u64 - self.data.as_ptr() as u64;
+        self.segments()
+            .find(|segment| {
+                offset >= segment.offset
+                    && offset + self.program_headers.len() as u64
+                        <= segment.offset + segment.file_size
+            })
+            .map(|segment| segment.vaddr + (offset - segment.offset))
+    }
+
+    fn check_segments(&self) -> Result<(), ElfError> {
+        for (index, header) in self.program_headers().enumerate() {
+            match header.kind {
+                PT_DYNAMIC | PT_INTERP => return Err(ElfError::DynamicallyLinked),
+                PT_LOAD if header.mem_size > 0 => check_segment(index, &header, self.data.len())?,
+                _ => {}
+            }
+        }
+
+        let segments: Vec<(usize, ProgramHeader)> = self
+            .program_headers()
+            .enumerate()
+            .filter(|(_, header)| header.kind == PT_LOAD && header.mem_size > 0)
+            .collect();
+        if segments.is_empty() {
+            return Err(ElfError::NoLoadableSegments);
+        }
+        for (i, (first, a)) in segments.iter().enumerate() {
+            for (second, b) in segments[i + 1..].iter() {
+                if a.vaddr < b.end() && b.vaddr < a.end() {
+                    return Err(ElfError::OverlappingSegments(*first, *second));
+                }
+            }
+        }
+
+        let executable = segments.iter().any(|(_, segment)| {
+            segment.flags & PF_X != 0 && (segment.vaddr..segment.end()).contains(&self.entry)
+        });
+        if !executable {
+            return Err(ElfError::BadEntry);
+        }
+        Ok(())
+    }
+}
+
+// Where a loaded program starts
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Image {
+    pub entry: VirtAddr,
+    pub stack_pointer: VirtAddr,
+}
+
+//////////////////////////////
+// API
+//////////////////////////////
+
+// Run the executable in `image` as a new process, a child of the calling
+// thread's process. `argv` and `envp` are passed on its stack. The process
+// is only created once the program is loaded, so errors leave nothing
+// behind.
+pub fn spawn(
+    name: &str,
+    image: &[u8],
+    argv: &[&str],
+    envp: &[&str],
+) -> Result<Arc<Process>, ElfError> {
+    let files = process::current().files().clone();
+    spawn_with(name, image, argv, envp, files)
+}
+
+// Same as `spawn`, with `files` as the descriptors of the process instead
+// of a copy of the caller's, e.g. to connect a pipeline
+pub fn spawn_with(
+    name: &str,
+    image: &[u8],
+    argv: &[&str],
+    envp: &[&str],
+    files: FileTable,
+) -> Result<Arc<Process>, ElfError> {
+    let elf = Elf::parse(image)?;
+    let address_space = AddressSpace::new()?;
+    let loaded = load(&elf, &address_space, argv, envp)?;
+
+    let process = process::create_in(name, address_space);
+    let inherited = core::mem::replace(&mut *process.files(), files);
+    drop(inherited);
+    process::spawn_thread(&process, name, move || unsafe {
+        usermode::enter_user_mode(loaded.entry, loaded.stack_pointer)
+    })
+    .expect("new process has exited");
+    Ok(process)
+}
+
+// Map the segments of `elf` into `address_space` with their permissions,
+// zero their .bss, and set up the stack with `argv`, `envp` and the
+// auxiliary vector. Segments and stack are recorded as regions, and the
+// heap starts right after the highest segment.
+pub fn load(
+    elf: &Elf,
+    address_space: &AddressSpace,
+    argv: &[&str],
+    envp: &[&str],
+) -> Result<Image, ElfError> {
+    for segment in elf.segments() {
+        let flags = segment.page_flags();
+        let first = Page::containing_address(VirtAddr::new(segment.vaddr));
+        let last = Page::containing_address(VirtAddr::new(segment.end() - 1));
+        for page in Page::range_inclusive(first, last) {
+            match address_space.translate(page.start_address()) {
+                // Shared with the previous segment, allow what either needs
+                Some((_, existing)) => address_space.protect(page, merge_flags(existing, flags))?,
+                None => {
+                    address_space.map(page, flags)?;
+                }
+            }
+        }
+
+        let start = VirtAddr::new(segment.vaddr);
+        address_space.write(start, elf.segment_data(&segment))?;
+        let bss = (segment.mem_size - segment.file_size) as usize;
+        address_space.zero(start + segment.file_size, bss)?;
+    }
+
+    let stack_flags = PageTableFlags::WRITABLE | no_execute();
+    let bottom = Page::containing_address(VirtAddr::new(USER_STACK_TOP - USER_STACK_SIZE));
+    let top = Page::containing_address(VirtAddr::new(USER_STACK_TOP - 1));
+    for page in Page::range_inclusive(bottom, top) {
+        address_space.map(page, stack_flags)?;
+    }
+
+    let mut auxv = vec![
+        (AT_PHENT, PROGRAM_HEADER_SIZE as u64),
+        (AT_PHNUM, elf.program_headers().count() as u64),
+        (AT_PAGESZ, PAGE_SIZE),
+        (AT_ENTRY, elf.entry()),
+    ];
+    if let Some(addr) = elf.program_headers_addr() {
+        auxv.push((AT_PHDR, addr));
+    }
+    let (stack_pointer, stack) = build_stack(USER_STACK_TOP, argv, envp, &auxv)?;
+    address_space.write(VirtAddr::new(stack_pointer), &stack)?;
+
+    let mut regions = address_space.regions();
+    let mut image_end = 0;
+    for segment in elf.segments() {
+        // A page shared with another segment already has its region
+        let mut start = segment.vaddr & !(PAGE_SIZE - 1);
+        let mut end = vm::round_up(segment.end());
+        if regions.find(start).is_some() {
+            start += PAGE_SIZE;
+        }
+        if start < end && regions.find(end - PAGE_SIZE).is_some() {
+            end -= PAGE_SIZE;
+        }
+        if start < end {
+            regions.insert(Region::new(start, end, segment.prot(), Kind::Image));
+        }
+        image_end = image_end.max(vm::round_up(segment.end()));
+    }
+    regions.insert(Region::new(
+        USER_STACK_TOP - USER_STACK_SIZE,
+        USER_STACK_TOP,
+        PROT_READ | PROT_WRITE,
+        Kind::Stack,
+    ));
+    regions.set_heap_start(image_end);
+    drop(regions);
+
+    Ok(Image {
+        entry: VirtAddr::new(elf.entry()),
+        stack_pointer: VirtAddr::new(stack_pointer),
+    })
+}
+
+// The initial stack below `top`, see the diagram above. Returns the stack
+// pointer and the bytes from there up to `top`.
+pub fn build_stack(
+    top: u64,
+    argv: &[&str],
+    envp: &[&str],
+    auxv: &[(u64, u64)],
+) -> Result<(u64, Vec<u8>), ElfError> {
+    let strings_size: usize = argv.iter().chain(envp).map(|s| s.len() + 1).sum();
+    let words = 1 + (argv.len() + 1) + (envp.len() + 1) + 2 * (auxv.len() + 1);
+    if strings_size + 8 * words > ARG_MAX {
+        return Err(ElfError::ArgumentsTooLarge);
+    }
+
+    let strings_start = top - strings_size as u64;
+    let stack_pointer = (strings_start - 8 * words as u64) & !0xf;
+    let mut stack = vec![0u8; (top - stack_pointer) as usize];
+
+    // Pointers first, then the strings they point to
+    let mut pointers = Vec::with_capacity(words);
+    let mut string_addr = strings_start;
+    let mut strings = Vec::with_capacity(strings_size);
+    pointers.push(argv.len() as u64);
+    for list in [argv, envp].iter() {
+        for s in list.iter() {
+            pointers.push(string_addr);
+            strings.extend_from_slice(s.as_bytes());
+            strings.push(0);
+            string_addr += s.len() as u64 + 1;
+        }
+        pointers.push(0);
+    }
+    for &(kind, value) in auxv.iter().chain(&[(AT_NULL, 0)]) {
+        pointers.push(kind);
+        pointers.push(value);
+    }
+
+    for (slot, pointer) in stack.chunks_exact_mut(8).zip(pointers.iter()) {
+        slot.copy_from_slice(&pointer.to_le_bytes());
+    }
+    let strings_offset = (strings_start - stack_pointer) as usize;
+    stack[strings_offset..].copy_from_slice(&strings);
+    Ok((stack_pointer, stack))
+}
+
+// Also takes segments that straddle user and kernel space, or page zero,
+// as not in user space
+fn check_segment(index: usize, segment: &ProgramHeader, file_size: usize) -> Result<(), ElfError> {
+    if segment.file_size > segment.mem_size {
+        return Err(ElfError::SegmentSizes(index));
+    }
+    match segment.offset.checked_add(segment.file_size) {
+        Some(end) if end <= file_size as u64 => {}
+        _ => return Err(ElfError::SegmentOutOfFile(index)),
+    }
+    match segment.vaddr.checked_add(segment.mem_size) {
+        Some(end) if segment.vaddr >= PAGE_SIZE && end <= USER_SPACE_END => {}
+        _ => return Err(ElfError::SegmentNotInUserSpace(index)),
+    }
+    let align = segment.align;
+    if align > 1 && (!align.is_power_of_two() || segment.vaddr % align != segment.offset % align) {
+        return Err(ElfError::SegmentMisaligned(index));
+    }
+    Ok(())
+}
+
+// A page used by two segments is writable or executable if either is
+fn merge_flags(a: PageTableFlags, b: PageTableFlags) -> PageTableFlags {
+    let mut flags = (a | b) & !PageTableFlags::NO_EXECUTE;
+    if a.contains(PageTableFlags::NO_EXECUTE) && b.contains(PageTableFlags::NO_EXECUTE) {
+        flags |= PageTableFlags::NO_EXECUTE;
+    }
+    flags
+}
+
+// Callers check `offset` against the length first
+fn read_u16(data: &[u8], offset: usize) -> u16 {
+    u16::from_le_bytes(data[offset..offset + 2].try_into().unwrap())
+}
+
+fn read_u32(data: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes(data[offset..offset + 4].try_into().unwrap())
+}
+
+fn read_u64(data: &[u8], offset: usize) -> u64 {
+    u64::from_le_bytes(data[offset..offset + 8].try_into().unwrap())
+}
+
+//////////////////////////////
+// Tests
+//////////////////////////////
+
+// Load address of the test programs, in a level 4 entry the kernel does not
+// use
+#[cfg(test)]
+pub(crate) const TEST_BASE: u64 = 0x5555_0000_0000;
+
+// An executable with one segment per (flags, vaddr, data, mem_size) and the
+// program headers right after the file header
+#[cfg(test)]
+pub(crate) fn test_image(entry: u64, segments: &[(u32, u64, &[u8], u64)]) -> Vec<u8> {
+    let data_start = HEADER_SIZE + PROGRAM_HEADER_SIZE * segments.len();
+    let mut image = vec![0u8; data_start];
+    image[0..4].copy_from_slice(&MAGIC);
+    image[4] = ELFCLASS64;
+    image[5] = ELFDATA2LSB;
+    image[6] = EV_CURRENT;
+    image[16..18].copy_from_slice(&ET_EXEC.to_le_bytes());
+    image[18..20].copy_from_slice(&EM_X86_64.to_le_bytes());
+    image[20..24].copy_from_slice(&1u32.to_le_bytes());
+    image[24..32].copy_from_slice(&entry.to_le_bytes());
+    image[32..40].copy_from_slice(&(HEADER_SIZE as u64).to_le_bytes());
+    image[52..54].copy_from_slice(&(HEADER_SIZE as u16).to_le_bytes());
+    image[54..56].copy_from_slice(&(PROGRAM_HEADER_SIZE as u16).to_le_bytes());
+    image[56..58].copy_from_slice(&(segments.len() as u16).to_le_bytes());
+
+    for (i, &(flags, vaddr, data, mem_size)) in segments.iter().enumerate() {
+        let header = HEADER_SIZE + i * PROGRAM_HEADER_SIZE;
+        let offset = image.len() as u64;
+        let fields: [(usize, u64); 6] = [
+            (8, offset),
+            (16, vaddr),
+            (24, vaddr),
+            (32, data.len() as u64),
+            (40, mem_size),
+            (48, 1),
+        ];
+        image[header..header + 4].copy_from_slice(&PT_LOAD.to_le_bytes());
+        image[header + 4..header + 8].copy_from_slice(&flags.to_le_bytes());
+        for &(at, value) in fields.iter() {
+            image[header + at..header + at + 8].copy_from_slice(&value.to_le_bytes());
+        }
+        image.extend_from_slice(data);
+    }
+    image
+}
+
+#[test_case]
+fn test_parse_valid_executable() {
+    let code = [0x90u8; 16];
+    let data = [1u8, 2, 3, 4];
+    let image = test_image(
+        TEST_BASE + 4,
+        &[
+            (PF_R | PF_X, TEST_BASE, &code, 16),
+            (PF_R | PF_W, TEST_BASE + 0x1000, &data, 0x2000),
+        ],
+    );
+
+    let elf = Elf::parse(&image).expect("valid image rejected");
+    assert_eq!(elf.entry(), TEST_BASE + 4);
+    let segments: Vec<ProgramHeader> = elf.segments().collect();
+    assert_eq!(segments.len(), 2);
+    assert_eq!(elf.segment_data(&segments[1]), &data);
+    assert_eq!(segments[1].end(), TEST_BASE + 0x3000);
+    assert!(segments[1].page_flags().contains(PageTableFlags::WRITABLE));
+    assert!(!segments[0].page_flags().contains(PageTableFlags::WRITABLE));
+}
+
+#[test_case]
+fn test_malformed_executables() {
+    let code = [0x90u8; 16];
+    let valid = test_image(TEST_BASE, &[(PF_R | PF_X, TEST_BASE, &code, 16)]);
+    let with = |at: usize, bytes: &[u8]| {
+        let mut image = valid.clone();
+        image[at..at + bytes.len()].copy_from_slice(bytes);
+        Elf::parse(&image).err()
+    };
+    // Fields of the only program header
+    let ph = HEADER_SIZE;
+
+    assert_eq!(Elf::parse(&valid[..10]).err(), Some(ElfError::Truncated));
+    assert_eq!(with(0, b"\x7fELG"), Some(ElfError::BadMagic));
+    assert_eq!(with(4, &[1]), Some(ElfError::NotElf64));
+    assert_eq!(with(5, &[2]), Some(ElfError::NotLittleEndian));
+    assert_eq!(
+        with(16, &ET_DYN.to_le_bytes()),
+        Some(ElfError::DynamicallyLinked)
+    );
+    assert_eq!(with(16, &1u16.to_le_bytes()), Some(ElfError::NotExecutable));
+    assert_eq!(with(18, &3u16.to_le_bytes()), Some(ElfError::WrongMachine));
+    assert_eq!(
+        with(54, &32u16.to_le_bytes()),
+        Some(ElfError::BadProgramHeaderSize)
+    );
+    assert_eq!(
+        with(56, &2u16.to_le_bytes()),
+        Some(ElfError::ProgramHeadersOutOfFile)
+    );
+    assert_eq!(
+        with(32, &u64::MAX.to_le_bytes()),
+        Some(ElfError::ProgramHeadersOutOfFile)
+    );
+    assert_eq!(
+        with(ph, &PT_INTERP.to_le_bytes()),
+        Some(ElfError::DynamicallyLinked)
+    );
+    assert_eq!(
+        with(ph, &4u32.to_le_bytes()),
+        Some(ElfError::NoLoadableSegments)
+    );
+    assert_eq!(
+        with(ph + 8, &u64::MAX.to_le_bytes()),
+        Some(ElfError::SegmentOutOfFile(0))
+    );
+    assert_eq!(
+        with(ph + 40, &8u64.to_le_bytes()),
+        Some(ElfError::SegmentSizes(0))
+    );
+    assert_eq!(
+        with(ph + 48, &3u64.to_le_bytes()),
+        Some(ElfError::SegmentMisaligned(0))
+    );
+    assert_eq!(
+        with(24, &(TEST_BASE + 16).to_le_bytes()),
+        Some(ElfError::BadEntry)
+    );
+    assert_eq!(with(ph + 4, &PF_R.to_le_bytes()), Some(ElfError::BadEntry));
+
+    let kernel = USER_SPACE_END.to_le_bytes();
+    assert_eq!(
+        with(ph + 16, &kernel),
+        Some(ElfError::SegmentNotInUserSpace(0))
+    );
+    let wrapping = (u64::MAX - 4).to_le_bytes();
+    assert_eq!(
+        with(ph + 16, &wrapping),
+        Some(ElfError::SegmentNotInUserSpace(0))
+    );
+
+    let overlapping = test_image(
+        TEST_BASE,
+        &[
+            (PF_R | PF_X, TEST_BASE, &code, 16),
+            (PF_R | PF_W, TEST_BASE + 8, &code, 16),
+        ],
+    );
+    assert_eq!(
+        Elf::parse(&overlapping).err(),
+        Some(ElfError::OverlappingSegments(0, 1))
+    );
+
+    // No prefix of a valid file makes the parser panic
+    for len in 0..valid.len() {
+        assert!(Elf::parse(&valid[..len]).is_err());
+    }
+}
+
+#[test_case]
+fn test_stack_layout() {
+    let top = 0x10_0000;
+    let auxv = [(AT_PAGESZ, PAGE_SIZE)];
+    let (sp, stack) =
+        build_stack(top, &["prog", "-v"], &["HOME=/"], &auxv).expect("stack too large");
+    assert_eq!(sp % 16, 0);
+    assert_eq!(sp + stack.len() as u64, top);
+
+    let word = |i: usize| read_u64(&stack, 8 * i);
+    let string = |addr: u64| {
+        let start = (addr - sp) as usize;
+        let len = stack[start..]
+            .iter()
+            .position(|&b| b == 0)
+            .expect("no terminator");
+        core::str::from_utf8(&stack[start..start + len]).expect("not UTF-8")
+    };
+    assert_eq!(word(0), 2);
+    assert_eq!(string(word(1)), "prog");
+    assert_eq!(string(word(2)), "-v");
+    assert_eq!(word(3), 0);
+    assert_eq!(string(word(4)), "HOME=/");
+    assert_eq!(word(5), 0);
+    assert_eq!((word(6), word(7)), (AT_PAGESZ, PAGE_SIZE));
+    assert_eq!((word(8), word(9)), (AT_NULL, 0));
+
+    let huge = vec!["x"; ARG_MAX];
+    assert_eq!(
+        build_stack(top, &huge, &[], &[]).err(),
+        Some(ElfError::ArgumentsTooLarge)
+    );
+}
+
+#[test_case]
+fn test_load_maps_segments() {
+    let code = [0xccu8; 16];
+    let data = [7u8; 8];
+    let image = test_image(
+        TEST_BASE,
+        &[
+            (PF_R | PF_X, TEST_BASE, &code, 16),
+            // Shares its first page with the code, its .bss spans two more
+            (PF_R | PF_W, TEST_BASE + 0x800, &data, 0x2000),
+        ],
+    );
+    let elf = Elf::parse(&image).expect("valid image rejected");
+    let space = AddressSpace::new().expect("no memory for address space");
+    let loaded = load(&elf, &space, &["test"], &[]).expect("loading failed");
+    assert_eq!(loaded.entry.as_u64(), TEST_BASE);
+
+    let read = |addr: u64| {
+        let (phys, flags) = space.translate(VirtAddr::new(addr)).expect("not mapped");
+        let byte = unsafe { *crate::memory::phys_to_virt(phys).as_ptr::<u8>() };
+        (byte, flags)
+    };
+    let (byte, flags) = read(TEST_BASE);
+    assert_eq!(byte, 0xcc);
+    assert!(flags.contains(PageTableFlags::USER_ACCESSIBLE | PageTableFlags::WRITABLE));
+    assert!(!flags.contains(PageTableFlags::NO_EXECUTE));
+    assert_eq!(read(TEST_BASE + 0x800).0, 7);
+    assert_eq!(read(TEST_BASE + 0x808).0, 0);
+    assert_eq!(read(TEST_BASE + 0x27ff).0, 0);
+    assert!(space.translate(VirtAddr::new(TEST_BASE + 0x3000)).is_none());
+
+    // argc at the stack pointer
+    assert_eq!(read(loaded.stack_pointer.as_u64()).0, 1);
+}
+
+#[test_case]
+fn test_program_runs_in_ring_3() {
+    // mov rdi, [rsp]; xor eax, eax; syscall; exits with argc
+    let code = [0x48, 0x8b, 0x3c, 0x24, 0x31, 0xc0, 0x0f, 0x05, 0xeb, 0xfe];
+    let image = test_image(TEST_BASE, &[(PF_R | PF_X, TEST_BASE, &code, 10)]);
+
+    let process = spawn("argc", &image, &["argc", "a", "b"], &[]).expect("spawn failed");
+    let pid = process.pid();
+    assert_eq!(process::waitpid(Some(pid), false), Ok(Some((pid, process::ExitStatus::Exited(3)))));
+
+    assert_eq!(
+        spawn("bad", &image[..20], &[], &[]).err(),
+        Some(ElfError::Truncated)
+    );
+}
@@ -0,0 +1,333 @@
+// fd.rs - Open files and the per-process file descriptor table
+
+use crate::print;
+
+use alloc::boxed::Box;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::any::Any;
+
+//////////////////////////////
+// Statics/Constants
+//////////////////////////////
+
+/*
+ *  A file descriptor is an index into the table of the process. Entries
+ *  point to open files, which `dup2` and process creation share between
+ *  descriptors and processes, the way POSIX open file descriptions are:
+ *
+ *    shell      cat          open file
+ *    0, 1, 2    1, 2    -->  console
+ *    3          0       -->  pipe read end
+ *    4                  -->  pipe write end
+ *
+ *  An open file is closed once no descriptor refers to it anymore, which
+ *  for a pipe end is what its other end sees as EOF or EPIPE, see pipe.rs.
+ *  A new process gets a copy of its creator's table, the kernel process
+ *  starts with the console as 0, 1 and 2.
+ *
+ *  There is no file system yet, `open` only knows a few devices.
+ */
+pub const STDIN: u64 = 0;
+pub const STDOUT: u64 = 1;
+pub const STDERR: u64 = 2;
+
+// Descriptors per process
+pub const MAX_FILES: usize = 64;
+
+// `open` flags, only the access mode
+pub const O_RDONLY: u64 = 0;
+pub const O_WRONLY: u64 = 1;
+pub const O_RDWR: u64 = 2;
+const O_ACCMODE: u64 = 3;
+
+////////////////////////////////
+// Structs, Types, Traits, Impl
+////////////////////////////////
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FileError {
+    // Not an open descriptor, or not open for the access
+    BadDescriptor,
+    // Every descriptor is in use
+    TooManyFiles,
+    NotFound,
+    InvalidArgument,
+    // Writing to a pipe without readers
+    BrokenPipe,
+    // A signal ended the wait for the other end, see sync.rs
+    Interrupted,
+}
+
+// Something to read from or write to. Either may block until it can make
+// progress, returning fewer bytes than asked for is not an error.
+pub trait File: Send + Sync {
+    // Bytes read into `buffer`, 0 at the end of the file
+    fn read(&self, buffer: &mut [u8]) -> Result<usize, FileError>;
+
+    fn write(&self, buffer: &[u8]) -> Result<usize, FileError>;
+
+    // For `OpenFile::downcast`, return `self`
+    fn as_any(&self) -> &dyn Any;
+}
+
+// A file with the access it was opened for
+pub struct OpenFile {
+    file: Box<dyn File>,
+    flags: u64,
+}
+
+impl OpenFile {
+    pub fn new(file: impl File + 'static, flags: u64) -> Arc<OpenFile> {
+        Arc::new(OpenFile {
+            file: Box::new(file),
+            flags,
+        })
+    }
+
+    pub fn is_readable(&self) -> bool {
+        self.flags & O_ACCMODE != O_WRONLY
+    }
+
+    pub fn is_writable(&self) -> bool {
+        self.flags & O_ACCMODE != O_RDONLY
+    }
+
+    pub fn read(&self, buffer: &mut [u8]) -> Result<usize, FileError> {
+        if !self.is_readable() {
+            return Err(FileError::BadDescriptor);
+        }
+        self.file.read(buffer)
+    }
+
+    pub fn write(&self, buffer: &[u8]) -> Result<usize, FileError> {
+        if !self.is_writable() {
+            return Err(FileError::BadDescriptor);
+        }
+        self.file.write(buffer)
+    }
+
+    // The file if it is a `T`, e.g. a channel endpoint for the system calls
+    // that only work on those
+    pub fn downcast<T: File + 'static>(&self) -> Option<&T> {
+        self.file.as_any().downcast_ref()
+    }
+}
+
+// Descriptors of one process. Closing a file may wake threads, so files
+// are never dropped in here: entries taken out are returned for the caller
+// to drop once it released the lock around the table.
+#[derive(Clone, Default)]
+pub struct FileTable {
+    files: Vec<Option<Arc<OpenFile>>>,
+}
+
+impl FileTable {
+    pub const fn new() -> FileTable {
+        FileTable { files: Vec::new() }
+    }
+
+    // The console as stdin, stdout and stderr
+    pub fn standard() -> FileTable {
+        let console = OpenFile::new(Console, O_RDWR);
+        let mut table = FileTable::new();
+        for fd in [STDIN, STDOUT, STDERR].iter() {
+            table
+                .install(*fd, &console)
+                .expect("no room for standard files");
+        }
+        table
+    }
+
+    pub fn get(&self, fd: u64) -> Result<Arc<OpenFile>, FileError> {
+        self.files
+            .get(fd as usize)
+            .and_then(Option::clone)
+            .ok_or(FileError::BadDescriptor)
+    }
+
+    // Open `file` as the lowest free descriptor
+    pub fn insert(&mut self, file: &Arc<OpenFile>) -> Result<u64, FileError> {
+        let fd = match self.files.iter().position(Option::is_none) {
+            Some(fd) => fd,
+            None if self.files.len() < MAX_FILES => {
+                self.files.push(None);
+                self.files.len() - 1
+            }
+            None => return Err(FileError::TooManyFiles),
+        };
+        self.files[fd] = Some(file.clone());
+        Ok(fd as u64)
+    }
+
+    // Open `file` as `fd`, returning what was open there before
+    pub fn install(
+        &mut self,
+        fd: u64,
+        file: &Arc<OpenFile>,
+    ) -> Result<Option<Arc<OpenFile>>, FileError> {
+        let index = fd as usize;
+        if index >= MAX_FILES {
+            return Err(FileError::BadDescriptor);
+        }
+        if index >= self.files.len() {
+            self.files.resize(index + 1, None);
+        }
+        Ok(self.files[index].replace(file.clone()))
+    }
+
+    pub fn remove(&mut self, fd: u64) -> Result<Arc<OpenFile>, FileError> {
+        self.files
+            .get_mut(fd as usize)
+            .and_then(Option::take)
+            .ok_or(FileError::BadDescriptor)
+    }
+
+    // Open descriptors
+    pub fn len(&self) -> usize {
+        self.files.iter().filter(|file| file.is_some()).count()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+// Output goes to the screen like `print!`. Threads have no way to wait for
+// keyboard input yet, so reading finds the end of the file.
+pub struct Console;
+
+impl File for Console {
+    fn read(&self, _buffer: &mut [u8]) -> Result<usize, FileError> {
+        Ok(0)
+    }
+
+    fn write(&self, buffer: &[u8]) -> Result<usize, FileError> {
+        for chunk in buffer.utf8_chunks() {
+            print!("{}", chunk.valid());
+            if !chunk.invalid().is_empty() {
+                print!("{}", char::REPLACEMENT_CHARACTER);
+            }
+        }
+        Ok(buffer.len())
+    }
+
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+}
+
+// Empty to read, discards what is written
+pub struct Null;
+
+impl File for Null {
+    fn read(&self, _buffer: &mut [u8]) -> Result<usize, FileError> {
+        Ok(0)
+    }
+
+    fn write(&self, buffer: &[u8]) -> Result<usize, FileError> {
+        Ok(buffer.len())
+    }
+
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+}
+
+// Endless zeroes to read, discards what is written
+pub struct Zero;
+
+impl File for Zero {
+    fn read(&self, buffer: &mut [u8]) -> Result<usize, FileError> {
+        buffer.fill(0);
+        Ok(buffer.len())
+    }
+
+    fn write(&self, buffer: &[u8]) -> Result<usize, FileError> {
+        Ok(buffer.len())
+    }
+
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+}
+
+//////////////////////////////
+// API
+//////////////////////////////
+
+// Open the device at `path` for the access in `flags`
+pub fn open(path: &str, flags: u64) -> Result<Arc<OpenFile>, FileError> {
+    if flags & !O_ACCMODE != 0 || flags & O_ACCMODE == O_ACCMODE {
+        return Err(FileError::InvalidArgument);
+    }
+    match path {
+        "/dev/console" => Ok(OpenFile::new(Console, flags)),
+        "/dev/null" => Ok(OpenFile::new(Null, flags)),
+        "/dev/zero" => Ok(OpenFile::new(Zero, flags)),
+        _ => Err(FileError::NotFound),
+    }
+}
+
+//////////////////////////////
+// Tests
+//////////////////////////////
+
+#[test_case]
+fn test_table_descriptors() {
+    let mut table = FileTable::standard();
+    assert_eq!(table.len(), 3);
+    assert!(Arc::ptr_eq(
+        &table.get(STDIN).unwrap(),
+        &table.get(STDERR).unwrap()
+    ));
+
+    // Lowest free descriptor first
+    let null = open("/dev/null", O_WRONLY).expect("no /dev/null");
+    assert_eq!(table.insert(&null), Ok(3));
+    assert!(table.remove(STDIN).is_ok());
+    assert_eq!(table.remove(STDIN).err(), Some(FileError::BadDescriptor));
+    assert_eq!(table.insert(&null), Ok(0));
+
+    // Replacing returns the previous file to close
+    let previous = table.install(STDOUT, &null).unwrap();
+    assert!(previous.is_some_and(|file| file.is_readable()));
+    assert_eq!(
+        table.install(MAX_FILES as u64, &null).err(),
+        Some(FileError::BadDescriptor)
+    );
+    assert!(table.install(10, &null).unwrap().is_none());
+    assert_eq!(table.get(9).err(), Some(FileError::BadDescriptor));
+
+    while table.len() < MAX_FILES {
+        table.insert(&open("/dev/zero", O_RDONLY).unwrap()).unwrap();
+    }
+    let zero = open("/dev/zero", O_RDONLY).unwrap();
+    assert_eq!(table.insert(&zero).err(), Some(FileError::TooManyFiles));
+}
+
+#[test_case]
+fn test_open_devices() {
+    let mut buffer = [1u8; 8];
+    let zero = open("/dev/zero", O_RDONLY).expect("no /dev/zero");
+    assert_eq!(zero.read(&mut buffer), Ok(8));
+    assert_eq!(buffer, [0; 8]);
+    assert_eq!(zero.write(b"x"), Err(FileError::BadDescriptor));
+
+    let null = open("/dev/null", O_RDWR).expect("no /dev/null");
+    assert_eq!(null.write(b"gone"), Ok(4));
+    assert_eq!(null.read(&mut buffer), Ok(0));
+
+    assert_eq!(
+        open("/dev/nothing", O_RDONLY).err(),
+        Some(FileError::NotFound)
+    );
+    assert_eq!(
+        open("/dev/null", O_ACCMODE).err(),
+        Some(FileError::InvalidArgument)
+    );
+    assert_eq!(
+        open("/dev/null", 0x40).err(),
+        Some(FileError::InvalidArgument)
+    );
+}
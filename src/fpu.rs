@@ -0,0 +1,347 @@
+// fpu.rs - x87/SSE/AVX register state for user tasks
+
+use crate::cpu::{self, Feature};
+use crate::percpu;
+
+use alloc::alloc::{alloc_zeroed, dealloc, handle_alloc_error, Layout};
+use core::ptr::NonNull;
+use core::sync::atomic::{AtomicBool, AtomicPtr, AtomicUsize, Ordering};
+
+//////////////////////////////
+// Statics/Constants
+//////////////////////////////
+
+/*
+ *  The kernel is built soft-float and never touches the FPU, so its state
+ *  only has to be switched between user tasks. Without the `user-fpu`
+ *  feature x87 instructions raise #NM and SSE/AVX instructions #UD.
+ *
+ *  With it, every thread of a user process owns an `FpuState` that the
+ *  scheduler passes to `switch`, see thread.rs:
+ *
+ *    Eager (`user-fpu-eager`)  save the previous task's registers and load
+ *                              the next task's on every switch
+ *    Lazy                      set CR0.TS on every switch, the first FPU
+ *                              instruction of the next task raises #NM and
+ *                              the handler loads its registers. Registers a
+ *                              task has loaded are saved when it is switched
+ *                              away from, so it may resume on any CPU.
+ *
+ *  Lazy switching is cheaper for tasks that rarely use the FPU, eager
+ *  switching avoids the #NM round trip for those that do.
+ *
+ *  The save area is XSAVE format when the CPU supports it, sized by CPUID
+ *  for the components enabled in XCR0, FXSAVE format otherwise.
+ */
+const ENABLED: bool = cfg!(feature = "user-fpu");
+const EAGER: bool = cfg!(feature = "user-fpu-eager");
+
+// CPUID leaf with the XSAVE area layout
+const LEAF_XSAVE: u32 = 0xd;
+
+// FXSAVE area, also the start of the XSAVE area
+const LEGACY_AREA_SIZE: usize = 512;
+const AREA_ALIGN: usize = 64;
+
+// Offsets of the control words in the legacy area
+const FCW_OFFSET: usize = 0;
+const MXCSR_OFFSET: usize = 24;
+
+// Power-on values, all exceptions masked and round to nearest
+const FCW_DEFAULT: u16 = 0x037f;
+const MXCSR_DEFAULT: u32 = 0x1f80;
+
+static AREA_SIZE: AtomicUsize = AtomicUsize::new(LEGACY_AREA_SIZE);
+static USE_XSAVE: AtomicBool = AtomicBool::new(false);
+
+////////////////////////////////
+// Structs, Types, Traits, Impl
+////////////////////////////////
+
+// Saved register state of one task
+pub struct FpuState {
+    area: NonNull<u8>,
+    layout: Layout,
+}
+
+// Only the owning task and the CPU it runs on touch the area
+unsafe impl Send for FpuState {}
+
+impl FpuState {
+    // Registers as after reset. An all zero XSAVE header marks every
+    // component as in its initial state.
+    pub fn new() -> FpuState {
+        let layout = Layout::from_size_align(AREA_SIZE.load(Ordering::Relaxed), AREA_ALIGN)
+            .expect("invalid FPU state layout");
+        let area = unsafe { alloc_zeroed(layout) };
+        let area = NonNull::new(area).unwrap_or_else(|| handle_alloc_error(layout));
+
+        let mut state = FpuState { area, layout };
+        let bytes = state.as_bytes_mut();
+        bytes[FCW_OFFSET..FCW_OFFSET + 2].copy_from_slice(&FCW_DEFAULT.to_le_bytes());
+        bytes[MXCSR_OFFSET..MXCSR_OFFSET + 4].copy_from_slice(&MXCSR_DEFAULT.to_le_bytes());
+        state
+    }
+
+    pub fn as_bytes(&self) -> &[u8] {
+        unsafe { core::slice::from_raw_parts(self.area.as_ptr(), self.layout.size()) }
+    }
+
+    fn as_bytes_mut(&mut self) -> &mut [u8] {
+        unsafe { core::slice::from_raw_parts_mut(self.area.as_ptr(), self.layout.size()) }
+    }
+
+    // Store the registers into the area. CR0.TS must be clear.
+    fn save(&mut self) {
+        let area = self.area.as_ptr();
+        unsafe {
+            if USE_XSAVE.load(Ordering::Relaxed) {
+                core::arch::asm!(
+                    "xsave64 [{}]",
+                    in(reg) area,
+                    in("eax") u32::MAX,
+                    in("edx") u32::MAX,
+                    options(nostack, preserves_flags)
+                );
+            } else {
+                core::arch::asm!("fxsave64 [{}]", in(reg) area, options(nostack, preserves_flags));
+            }
+        }
+    }
+
+    // Load the registers from the area. CR0.TS must be clear.
+    fn restore(&self) {
+        let area = self.area.as_ptr();
+        unsafe {
+            if USE_XSAVE.load(Ordering::Relaxed) {
+                core::arch::asm!(
+                    "xrstor64 [{}]",
+                    in(reg) area,
+                    in("eax") u32::MAX,
+                    in("edx") u32::MAX,
+                    options(readonly, nostack, preserves_flags)
+                );
+            } else {
+                core::arch::asm!(
+                    "fxrstor64 [{}]",
+                    in(reg) area,
+                    options(readonly, nostack, preserves_flags)
+                );
+            }
+        }
+    }
+}
+
+impl Default for FpuState {
+    fn default() -> Self {
+        FpuState::new()
+    }
+}
+
+impl Drop for FpuState {
+    fn drop(&mut self) {
+        unsafe { dealloc(self.area.as_ptr(), self.layout) };
+    }
+}
+
+// FPU bookkeeping of a single CPU, lives in its `percpu::PerCpu` area
+pub struct CpuFpu {
+    // State of the task running on this CPU, null if it has none
+    current: AtomicPtr<FpuState>,
+    // Lazy mode, whether the registers hold `current`'s state
+    loaded: AtomicBool,
+}
+
+impl CpuFpu {
+    pub const INIT: CpuFpu = CpuFpu {
+        current: AtomicPtr::new(core::ptr::null_mut()),
+        loaded: AtomicBool::new(false),
+    };
+}
+
+//////////////////////////////
+// API
+//////////////////////////////
+
+// Configure the FPU of the calling CPU, before any `FpuState` is created
+pub fn init_cpu() {
+    use x86_64::registers::control::{Cr0, Cr0Flags, Cr4, Cr4Flags};
+    use x86_64::registers::xcontrol::{XCr0, XCr0Flags};
+
+    let sse_flags = Cr4Flags::OSFXSR | Cr4Flags::OSXMMEXCPT_ENABLE;
+    if !ENABLED {
+        unsafe {
+            Cr0::update(|flags| {
+                flags.insert(Cr0Flags::EMULATE_COPROCESSOR);
+                flags.remove(Cr0Flags::MONITOR_COPROCESSOR);
+            });
+            Cr4::update(|flags| flags.remove(sse_flags | Cr4Flags::OSXSAVE));
+        }
+        return;
+    }
+
+    assert!(
+        cpu::has(Feature::Fxsr) && cpu::has(Feature::Sse),
+        "user-fpu requires FXSR and SSE"
+    );
+    unsafe {
+        Cr0::update(|flags| {
+            flags.remove(Cr0Flags::EMULATE_COPROCESSOR);
+            // Report x87 errors as #MF, and let WAIT honour CR0.TS
+            flags.insert(Cr0Flags::MONITOR_COPROCESSOR | Cr0Flags::NUMERIC_ERROR);
+        });
+        Cr4::update(|flags| flags.insert(sse_flags));
+    }
+
+    if cpu::has(Feature::Xsave) {
+        let mut components = XCr0Flags::X87 | XCr0Flags::SSE;
+        if cpu::has(Feature::Avx) {
+            components |= XCr0Flags::AVX;
+        }
+        unsafe {
+            Cr4::update(|flags| flags.insert(Cr4Flags::OSXSAVE));
+            XCr0::write(components);
+        }
+        // Size of the area for the components now enabled in XCR0
+        let size = core::arch::x86_64::__cpuid_count(LEAF_XSAVE, 0).ebx as usize;
+        AREA_SIZE.store(size, Ordering::Relaxed);
+        USE_XSAVE.store(true, Ordering::Relaxed);
+    }
+
+    if EAGER {
+        clear_task_switched();
+    } else {
+        set_task_switched();
+    }
+}
+
+pub fn is_enabled() -> bool {
+    ENABLED
+}
+
+// Bytes needed per task to save its state
+pub fn area_size() -> usize {
+    AREA_SIZE.load(Ordering::Relaxed)
+}
+
+// Called by the scheduler on every context switch, with interrupts disabled.
+// Unsafe! `next` is the state of the task about to run, null if it never
+// runs user code, and must stay valid until that task is switched away
+// from.
+pub unsafe fn switch(next: *mut FpuState) {
+    if !ENABLED {
+        return;
+    }
+
+    let fpu = &percpu::this_cpu().fpu;
+    let prev = fpu.current.swap(next, Ordering::Relaxed);
+    if EAGER {
+        if let Some(prev) = prev.as_mut() {
+            prev.save();
+        }
+        if let Some(next) = next.as_ref() {
+            next.restore();
+        }
+        return;
+    }
+
+    if fpu.loaded.swap(false, Ordering::Relaxed) {
+        if let Some(prev) = prev.as_mut() {
+            clear_task_switched();
+            prev.save();
+        }
+    }
+    set_task_switched();
+}
+
+// #NM, raised by an FPU instruction while CR0.TS is set. Returns false if
+// the code that raised it may not use the FPU.
+pub fn handle_device_not_available() -> bool {
+    if !ENABLED || EAGER {
+        return false;
+    }
+
+    let fpu = &percpu::this_cpu().fpu;
+    let current = fpu.current.load(Ordering::Relaxed);
+    if current.is_null() {
+        return false;
+    }
+    clear_task_switched();
+    unsafe { (*current).restore() };
+    fpu.loaded.store(true, Ordering::Relaxed);
+    true
+}
+
+fn set_task_switched() {
+    use x86_64::registers::control::{Cr0, Cr0Flags};
+
+    unsafe { Cr0::update(|flags| flags.insert(Cr0Flags::TASK_SWITCHED)) };
+}
+
+fn clear_task_switched() {
+    unsafe { core::arch::asm!("clts", options(nomem, nostack, preserves_flags)) };
+}
+
+//////////////////////////////
+// Tests
+//////////////////////////////
+
+#[test_case]
+fn test_new_state_has_reset_values() {
+    use core::convert::TryInto;
+
+    let state = FpuState::new();
+    assert!((state.area.as_ptr() as usize).is_multiple_of(AREA_ALIGN));
+    assert_eq!(state.as_bytes().len(), area_size());
+
+    let bytes = state.as_bytes();
+    let fcw = &bytes[FCW_OFFSET..FCW_OFFSET + 2];
+    assert_eq!(u16::from_le_bytes(fcw.try_into().unwrap()), FCW_DEFAULT);
+    let mxcsr = &bytes[MXCSR_OFFSET..MXCSR_OFFSET + 4];
+    assert_eq!(u32::from_le_bytes(mxcsr.try_into().unwrap()), MXCSR_DEFAULT);
+}
+
+#[test_case]
+fn test_user_processes_keep_their_registers() {
+    use crate::elf::{self, PF_R, PF_X, TEST_BASE};
+    use crate::process::{self, ExitStatus};
+    use alloc::vec::Vec;
+
+    // Without the feature SSE instructions raise #UD in user mode
+    if !ENABLED {
+        return;
+    }
+
+    // mov eax, value; movd xmm0, eax; mov ecx, 0x2000000
+    // loop: movd edx, xmm0; cmp edx, eax; jne fail; dec ecx; jnz loop
+    // exit(0), fail: exit(1)
+    // Long enough to be preempted by the other process many times
+    let program = |value: u32| {
+        let mut code = Vec::from([0xb8u8]);
+        code.extend_from_slice(&value.to_le_bytes());
+        code.extend_from_slice(&[
+            0x66, 0x0f, 0x6e, 0xc0, 0xb9, 0x00, 0x00, 0x00, 0x02, 0x66, 0x0f, 0x7e, 0xc2, 0x39,
+            0xc2, 0x75, 0x08, 0xff, 0xc9, 0x75, 0xf4, 0x31, 0xff, 0xeb, 0x05, 0xbf, 0x01, 0x00,
+            0x00, 0x00, 0x31, 0xc0, 0x0f, 0x05, 0xeb, 0xfe,
+        ]);
+        code
+    };
+
+    let pids: Vec<_> = [0x1111_1111, 0x2222_2222]
+        .iter()
+        .map(|&value| {
+            let code = program(value);
+            let segment = (PF_R | PF_X, TEST_BASE, &code[..], code.len() as u64);
+            let image = elf::test_image(TEST_BASE, &[segment]);
+            elf::spawn("xmm0", &image, &["xmm0"], &[])
+                .expect("spawn failed")
+                .pid()
+        })
+        .collect();
+    for pid in pids {
+        assert_eq!(
+            process::waitpid(Some(pid), false),
+            Ok(Some((pid, ExitStatus::Exited(0))))
+        );
+    }
+}
@@ -0,0 +1,630 @@
+// gdbstub.rs - GDB remote serial protocol stub over COM2
+
+use crate::interrupts::{self, BREAKPOINT_VECTOR, DEBUG_VECTOR};
+use crate::serial::{self, COM2};
+use crate::trap::{self, TrapFrame};
+use crate::{memory, percpu, println, watchdog};
+
+use core::fmt::{self, Write};
+use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use spin::Mutex;
+use x86_64::VirtAddr;
+
+//////////////////////////////
+// Statics/Constants
+//////////////////////////////
+
+/*
+ *  Build with `--features gdbstub`, give QEMU a second serial port and point
+ *  GDB at it, e.g.
+ *
+ *      -serial stdio -serial tcp::1234,server,nowait
+ *      (gdb) target remote localhost:1234
+ *
+ *  Built with the `gdbstub` feature and a UART found at COM2, breakpoints
+ *  and debug exceptions stop here instead of in kdb.rs. Bytes arriving while
+ *  the kernel runs, GDB's Ctrl-C or a new connection, raise IRQ3 which stops
+ *  the code it interrupted. The stopped CPU is the only thread GDB sees,
+ *  other CPUs keep running.
+ *
+ *  Packets are `$<data>#<checksum>`, acknowledged with `+`, see the GDB
+ *  manual appendix "Remote Serial Protocol". Nothing here takes a lock
+ *  another CPU could hold or allocates.
+ */
+const CONFIGURED: bool = cfg!(feature = "gdbstub");
+
+const PACKET_CAPACITY: usize = 1024;
+const MAX_BREAKPOINTS: usize = 32;
+
+const INTERRUPT: u8 = 0x03;
+const INT3: u8 = 0xcc;
+
+// RFLAGS Trap Flag, raises #DB after the next instruction
+const RFLAGS_TF: u64 = 1 << 8;
+
+// Register numbers of GDB's amd64 layout
+const REG_RIP: usize = 16;
+const REG_EFLAGS: usize = 17;
+const REG_GS: usize = 23;
+
+static ENABLED: AtomicBool = AtomicBool::new(false);
+
+// GDB resumed the kernel with `c` or `s` and waits for a stop reply
+static RESUMED: AtomicBool = AtomicBool::new(false);
+
+// Id of the CPU talking to GDB, others trapping meanwhile wait their turn
+const NO_CPU: usize = usize::MAX;
+static OWNER: AtomicUsize = AtomicUsize::new(NO_CPU);
+
+// Only touched by the owning CPU, the lock is never contended
+static BREAKPOINTS: Mutex<[Option<Breakpoint>; MAX_BREAKPOINTS]> =
+    Mutex::new([None; MAX_BREAKPOINTS]);
+
+////////////////////////////////
+// Structs, Types, Traits, Impl
+////////////////////////////////
+
+// Software breakpoint, `int3` patched over the first byte of an instruction
+#[derive(Debug, Clone, Copy)]
+struct Breakpoint {
+    addr: u64,
+    original: u8,
+}
+
+enum Incoming {
+    Packet(usize),
+    // Ctrl-C outside of a packet
+    Interrupt,
+}
+
+// Reply under construction, without framing
+struct Reply {
+    data: [u8; PACKET_CAPACITY],
+    len: usize,
+}
+
+impl Reply {
+    fn new() -> Reply {
+        Reply {
+            data: [0; PACKET_CAPACITY],
+            len: 0,
+        }
+    }
+
+    fn push_hex(&mut self, bytes: &[u8]) {
+        for byte in bytes {
+            let _ = write!(self, "{:02x}", byte);
+        }
+    }
+
+    fn as_bytes(&self) -> &[u8] {
+        &self.data[..self.len]
+    }
+}
+
+impl Write for Reply {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        let end = self.len + s.len();
+        if end > self.data.len() {
+            return Err(fmt::Error);
+        }
+        self.data[self.len..end].copy_from_slice(s.as_bytes());
+        self.len = end;
+        Ok(())
+    }
+}
+
+//////////////////////////////
+// API
+//////////////////////////////
+
+// Take over breakpoints if built with the `gdbstub` feature and there is a
+// UART at COM2. Requires the IDT and the PICs to be set up.
+pub fn init() -> bool {
+    use uart_16550::SerialPort;
+
+    if !CONFIGURED {
+        return false;
+    }
+    if !serial::is_present(COM2) {
+        println!("gdbstub: no UART at COM2, using kdb");
+        return false;
+    }
+    // Also enables the receive interrupt
+    unsafe { SerialPort::new(COM2).init() };
+
+    let irq = interrupts::InterruptIndex::Com2.as_u8() - interrupts::PIC_1_OFFSET;
+    let mut pics = interrupts::PICS.lock();
+    unsafe {
+        let [primary, secondary] = pics.read_masks();
+        pics.write_masks(primary & !(1 << irq), secondary);
+    }
+
+    ENABLED.store(true, Ordering::SeqCst);
+    true
+}
+
+pub fn is_enabled() -> bool {
+    ENABLED.load(Ordering::SeqCst)
+}
+
+// IRQ3, GDB sent something while the kernel was running. Stops in the
+// interrupted code, see trap.rs.
+pub fn handle_interrupt(frame: &mut TrapFrame) {
+    if is_enabled() && serial::has_input(COM2) {
+        stop(frame, false);
+    }
+}
+
+// #DB and #BP, see trap.rs
+pub fn handle_trap(frame: &mut TrapFrame) {
+    let vector = frame.vector as u8;
+    if vector == DEBUG_VECTOR {
+        trap::take_dr6();
+    }
+    stop(frame, vector == BREAKPOINT_VECTOR);
+}
+
+// Report the stop to GDB and serve it until it resumes `frame`
+fn stop(frame: &mut TrapFrame, breakpoint: bool) {
+    let cpu = percpu::cpu_id();
+    while OWNER
+        .compare_exchange(NO_CPU, cpu, Ordering::Acquire, Ordering::Relaxed)
+        .is_err()
+    {
+        watchdog::touch();
+        core::hint::spin_loop();
+    }
+
+    // Report our own breakpoints at their address, as `swbreak+` promises
+    let mut swbreak = false;
+    if breakpoint && find_breakpoint(frame.rip - 1).is_some() {
+        frame.rip -= 1;
+        swbreak = true;
+    }
+
+    // A Ctrl-C that made us stop is answered by the stop reply. Anything else
+    // is the start of a packet.
+    let mut pending = None;
+    if let Some(byte) = serial::try_read_byte(COM2) {
+        if byte != INTERRUPT {
+            pending = Some(byte);
+        }
+    }
+
+    if RESUMED.swap(false, Ordering::SeqCst) {
+        send_stop_reply(swbreak);
+    }
+    session(frame, pending);
+
+    OWNER.store(NO_CPU, Ordering::Release);
+}
+
+// Serve packets until GDB resumes the kernel
+fn session(frame: &mut TrapFrame, mut pending: Option<u8>) {
+    let mut packet = [0u8; PACKET_CAPACITY];
+    loop {
+        let len = match read_packet(&mut packet, pending.take()) {
+            Incoming::Packet(len) => len,
+            Incoming::Interrupt => {
+                send_stop_reply(false);
+                continue;
+            }
+        };
+        let packet = &packet[..len];
+
+        let mut reply = Reply::new();
+        let (command, args) = match packet.split_first() {
+            Some((&command, args)) => (command, args),
+            None => {
+                send_packet(b"");
+                continue;
+            }
+        };
+
+        match command {
+            b'?' => {
+                send_stop_reply(false);
+                continue;
+            }
+            b'g' => read_registers(frame, &mut reply),
+            b'G' => {
+                write_registers(frame, args);
+                let _ = reply.write_str("OK");
+            }
+            b'p' => match parse_hex(args).map(|n| n as usize) {
+                Some(n) if n <= REG_GS => {
+                    let (value, size) = register(frame, n);
+                    reply.push_hex(&value.to_le_bytes()[..size]);
+                }
+                _ => {
+                    let _ = reply.write_str("E01");
+                }
+            },
+            b'P' => {
+                let result = split_once(args, b'=').and_then(|(n, value)| {
+                    let n = parse_hex(n)? as usize;
+                    let value = decode_le(value)?;
+                    set_register(frame, n, value).then_some(())
+                });
+                let _ = reply.write_str(if result.is_some() { "OK" } else { "E01" });
+            }
+            b'm' => read_memory(args, &mut reply),
+            b'M' => write_memory(args, &mut reply),
+            b'Z' | b'z' => set_breakpoint(command == b'Z', args, &mut reply),
+            b'c' | b's' => {
+                if let Some(addr) = parse_hex(args) {
+                    frame.rip = addr;
+                }
+                if command == b's' {
+                    frame.rflags |= RFLAGS_TF;
+                } else {
+                    frame.rflags &= !RFLAGS_TF;
+                }
+                RESUMED.store(true, Ordering::SeqCst);
+                return;
+            }
+            b'D' | b'k' => {
+                if command == b'D' {
+                    send_packet(b"OK");
+                }
+                remove_all_breakpoints();
+                frame.rflags &= !RFLAGS_TF;
+                return;
+            }
+            b'H' => {
+                let _ = reply.write_str("OK");
+            }
+            b'q' => query(args, &mut reply),
+            // Unsupported, answered with an empty reply
+            _ => {}
+        }
+        send_packet(reply.as_bytes());
+    }
+}
+
+fn query(args: &[u8], reply: &mut Reply) {
+    let _ = if args.starts_with(b"Supported") {
+        write!(reply, "PacketSize={:x};swbreak+", PACKET_CAPACITY)
+    } else if args == b"Attached" {
+        reply.write_str("1")
+    } else if args == b"C" {
+        reply.write_str("QC1")
+    } else if args == b"fThreadInfo" {
+        reply.write_str("m1")
+    } else if args == b"sThreadInfo" {
+        reply.write_str("l")
+    } else {
+        Ok(())
+    };
+}
+
+fn send_stop_reply(swbreak: bool) {
+    if swbreak {
+        send_packet(b"T05swbreak:;");
+    } else {
+        send_packet(b"S05");
+    }
+}
+
+// Value and size in bytes of register `n` in GDB's amd64 numbering
+fn register(frame: &TrapFrame, n: usize) -> (u64, usize) {
+    use x86_64::instructions::segmentation::{Segment, DS, ES, FS, GS};
+
+    let value = match n {
+        0 => frame.rax,
+        1 => frame.rbx,
+        2 => frame.rcx,
+        3 => frame.rdx,
+        4 => frame.rsi,
+        5 => frame.rdi,
+        6 => frame.rbp,
+        7 => frame.rsp,
+        8 => frame.r8,
+        9 => frame.r9,
+        10 => frame.r10,
+        11 => frame.r11,
+        12 => frame.r12,
+        13 => frame.r13,
+        14 => frame.r14,
+        15 => frame.r15,
+        REG_RIP => frame.rip,
+        REG_EFLAGS => frame.rflags,
+        18 => frame.cs,
+        19 => frame.ss,
+        20 => u64::from(DS::get_reg().0),
+        21 => u64::from(ES::get_reg().0),
+        22 => u64::from(FS::get_reg().0),
+        _ => u64::from(GS::get_reg().0),
+    };
+    let size = if n <= REG_RIP { 8 } else { 4 };
+    (value, size)
+}
+
+// Only general purpose registers, rip and rflags can be changed
+fn set_register(frame: &mut TrapFrame, n: usize, value: u64) -> bool {
+    let register = match n {
+        0 => &mut frame.rax,
+        1 => &mut frame.rbx,
+        2 => &mut frame.rcx,
+        3 => &mut frame.rdx,
+        4 => &mut frame.rsi,
+        5 => &mut frame.rdi,
+        6 => &mut frame.rbp,
+        7 => &mut frame.rsp,
+        8 => &mut frame.r8,
+        9 => &mut frame.r9,
+        10 => &mut frame.r10,
+        11 => &mut frame.r11,
+        12 => &mut frame.r12,
+        13 => &mut frame.r13,
+        14 => &mut frame.r14,
+        15 => &mut frame.r15,
+        REG_RIP => &mut frame.rip,
+        REG_EFLAGS => &mut frame.rflags,
+        _ => return false,
+    };
+    *register = value;
+    true
+}
+
+fn read_registers(frame: &TrapFrame, reply: &mut Reply) {
+    for n in 0..=REG_GS {
+        let (value, size) = register(frame, n);
+        reply.push_hex(&value.to_le_bytes()[..size]);
+    }
+}
+
+fn write_registers(frame: &mut TrapFrame, mut hex: &[u8]) {
+    for n in 0..=REG_EFLAGS {
+        let size = if n <= REG_RIP { 8 } else { 4 };
+        if hex.len() < size * 2 {
+            return;
+        }
+        if let Some(value) = decode_le(&hex[..size * 2]) {
+            set_register(frame, n, value);
+        }
+        hex = &hex[size * 2..];
+    }
+}
+
+// m<addr>,<len>
+fn read_memory(args: &[u8], reply: &mut Reply) {
+    let (addr, len) = match parse_addr_len(args) {
+        Some(range) => range,
+        None => return error(reply, 1),
+    };
+    // Each byte takes two characters in the reply
+    let len = len.min((PACKET_CAPACITY / 2) as u64);
+    if !(addr..addr + len).all(is_mapped) {
+        return error(reply, 14);
+    }
+
+    for addr in addr..addr + len {
+        let byte = unsafe { (addr as *const u8).read_volatile() };
+        reply.push_hex(&[byte]);
+    }
+}
+
+// M<addr>,<len>:<hex bytes>
+fn write_memory(args: &[u8], reply: &mut Reply) {
+    let (range, data) = match split_once(args, b':') {
+        Some(split) => split,
+        None => return error(reply, 1),
+    };
+    let (addr, len) = match parse_addr_len(range) {
+        Some((addr, len)) if data.len() as u64 == len * 2 => (addr, len),
+        _ => return error(reply, 1),
+    };
+    if !(addr..addr + len).all(is_mapped) {
+        return error(reply, 14);
+    }
+
+    for (i, pair) in data.chunks(2).enumerate() {
+        match parse_hex(pair) {
+            Some(byte) => poke(addr + i as u64, byte as u8),
+            None => return error(reply, 1),
+        }
+    }
+    let _ = reply.write_str("OK");
+}
+
+// Z0,<addr>,<kind> and z0,<addr>,<kind>, other breakpoint types are not
+// supported and get an empty reply
+fn set_breakpoint(insert: bool, args: &[u8], reply: &mut Reply) {
+    let addr = match args.strip_prefix(b"0,") {
+        Some(rest) => split_once(rest, b',').and_then(|(addr, _)| parse_hex(addr)),
+        None => return,
+    };
+    let addr = match addr {
+        Some(addr) => addr,
+        None => return error(reply, 1),
+    };
+
+    let mut breakpoints = BREAKPOINTS.lock();
+    let existing = breakpoints
+        .iter()
+        .position(|bp| matches!(bp, Some(bp) if bp.addr == addr));
+    let done = match (insert, existing) {
+        (true, Some(_)) => true,
+        (true, None) if is_mapped(addr) => match breakpoints.iter().position(Option::is_none) {
+            Some(slot) => {
+                let original = unsafe { (addr as *const u8).read_volatile() };
+                breakpoints[slot] = Some(Breakpoint { addr, original });
+                poke(addr, INT3);
+                true
+            }
+            None => false,
+        },
+        (true, None) => false,
+        (false, Some(slot)) => {
+            if let Some(bp) = breakpoints[slot].take() {
+                poke(bp.addr, bp.original);
+            }
+            true
+        }
+        (false, None) => true,
+    };
+
+    if done {
+        let _ = reply.write_str("OK");
+    } else {
+        error(reply, 14);
+    }
+}
+
+fn find_breakpoint(addr: u64) -> Option<Breakpoint> {
+    BREAKPOINTS
+        .lock()
+        .iter()
+        .flatten()
+        .find(|bp| bp.addr == addr)
+        .copied()
+}
+
+fn remove_all_breakpoints() {
+    for slot in BREAKPOINTS.lock().iter_mut() {
+        if let Some(bp) = slot.take() {
+            poke(bp.addr, bp.original);
+        }
+    }
+}
+
+// Write a byte even to read-only kernel text, with CR0.WP briefly cleared.
+// Runs with interrupts disabled.
+fn poke(addr: u64, byte: u8) {
+    use x86_64::registers::control::{Cr0, Cr0Flags};
+
+    let cr0 = Cr0::read();
+    unsafe {
+        Cr0::write(cr0 - Cr0Flags::WRITE_PROTECT);
+        (addr as *mut u8).write_volatile(byte);
+        Cr0::write(cr0);
+    }
+}
+
+fn is_mapped(addr: u64) -> bool {
+    VirtAddr::try_new(addr).is_ok_and(memory::is_mapped)
+}
+
+fn error(reply: &mut Reply, errno: u8) {
+    let _ = write!(reply, "E{:02x}", errno);
+}
+
+fn read_byte() -> u8 {
+    loop {
+        if let Some(byte) = serial::try_read_byte(COM2) {
+            return byte;
+        }
+        watchdog::touch();
+        core::hint::spin_loop();
+    }
+}
+
+// Receive the next packet into `packet`, acknowledging it. `first` is a byte
+// already read from the port.
+fn read_packet(packet: &mut [u8; PACKET_CAPACITY], mut first: Option<u8>) -> Incoming {
+    loop {
+        // Skip acknowledgements and noise up to the start of a packet
+        match first.take().unwrap_or_else(read_byte) {
+            b'$' => {}
+            INTERRUPT => return Incoming::Interrupt,
+            _ => continue,
+        }
+
+        let mut len = 0;
+        let mut overflow = false;
+        loop {
+            match read_byte() {
+                b'#' => break,
+                byte if len < packet.len() => {
+                    packet[len] = byte;
+                    len += 1;
+                }
+                _ => overflow = true,
+            }
+        }
+        let received = parse_hex(&[read_byte(), read_byte()]);
+
+        if !overflow && received == Some(u64::from(checksum(&packet[..len]))) {
+            serial::write_byte(COM2, b'+');
+            return Incoming::Packet(len);
+        }
+        serial::write_byte(COM2, b'-');
+    }
+}
+
+// Send `data` framed, until GDB acknowledges it
+fn send_packet(data: &[u8]) {
+    loop {
+        serial::write_byte(COM2, b'$');
+        for &byte in data {
+            serial::write_byte(COM2, byte);
+        }
+        serial::write_byte(COM2, b'#');
+        for &byte in format_hex_u8(checksum(data)).iter() {
+            serial::write_byte(COM2, byte);
+        }
+
+        match read_byte() {
+            b'-' => continue,
+            _ => return,
+        }
+    }
+}
+
+fn checksum(data: &[u8]) -> u8 {
+    data.iter().fold(0u8, |sum, &byte| sum.wrapping_add(byte))
+}
+
+fn format_hex_u8(value: u8) -> [u8; 2] {
+    const DIGITS: &[u8; 16] = b"0123456789abcdef";
+    [
+        DIGITS[usize::from(value >> 4)],
+        DIGITS[usize::from(value & 0xf)],
+    ]
+}
+
+fn parse_hex(hex: &[u8]) -> Option<u64> {
+    if hex.is_empty() || hex.len() > 16 {
+        return None;
+    }
+    let hex = core::str::from_utf8(hex).ok()?;
+    u64::from_str_radix(hex, 16).ok()
+}
+
+// Register values are sent in target byte order
+fn decode_le(hex: &[u8]) -> Option<u64> {
+    if !hex.len().is_multiple_of(2) || hex.len() > 16 {
+        return None;
+    }
+    hex.chunks(2)
+        .rev()
+        .try_fold(0u64, |value, pair| Some(value << 8 | parse_hex(pair)?))
+}
+
+fn parse_addr_len(args: &[u8]) -> Option<(u64, u64)> {
+    let (addr, len) = split_once(args, b',')?;
+    let addr = parse_hex(addr)?;
+    let len = parse_hex(len)?;
+    addr.checked_add(len)?;
+    Some((addr, len))
+}
+
+fn split_once(bytes: &[u8], separator: u8) -> Option<(&[u8], &[u8])> {
+    let at = bytes.iter().position(|&byte| byte == separator)?;
+    Some((&bytes[..at], &bytes[at + 1..]))
+}
+
+//////////////////////////////
+// Tests
+//////////////////////////////
+
+#[test_case]
+fn test_packet_encoding() {
+    assert_eq!(checksum(b"OK"), 0x9a);
+    assert_eq!(format_hex_u8(0x9a), *b"9a");
+    assert_eq!(parse_hex(b"ffff8000"), Some(0xffff_8000));
+    assert_eq!(decode_le(b"efbeadde"), Some(0xdead_beef));
+    assert_eq!(parse_addr_len(b"1000,20"), Some((0x1000, 0x20)));
+}
@@ -1,6 +1,11 @@
 // gdt.rs - Global Descriptor Table
 
-use lazy_static::lazy_static;
+use crate::percpu;
+use crate::smp::MAX_CPUS;
+
+use core::cell::UnsafeCell;
+use core::ptr::addr_of;
+use spin::Once;
 use x86_64::structures::gdt::{Descriptor, GlobalDescriptorTable, SegmentSelector};
 use x86_64::structures::tss::TaskStateSegment;
 use x86_64::VirtAddr;
@@ -17,63 +22,167 @@ use x86_64::VirtAddr;
 // which will cause a triple fault and system reset.
 pub const DOUBLE_FAULT_IST_INDEX: u16 = 0;
 
-lazy_static! {
-    static ref TSS: TaskStateSegment = {
-        let mut tss = TaskStateSegment::new();
-        tss.interrupt_stack_table[DOUBLE_FAULT_IST_INDEX as usize] = {
-            const STACK_SIZE: usize = 4096 * 5; // 2Kb
+// NMIs can arrive at any instruction, including right after a `syscall` or
+// before a stack switch, so the handler can never trust the current stack.
+pub const NMI_IST_INDEX: u16 = 1;
 
-            // Initialize stack to zero
-            static mut STACK: [u8; STACK_SIZE] = [0; STACK_SIZE];
+/*
+ *  Every CPU's GDT has the same layout. The order of the data and code
+ *  segments is fixed by `syscall`/`sysret`, which derive the selectors from
+ *  the kernel code and user data selectors in the STAR MSR.
+ *
+ *   ______________________________
+ *  | Selector | Segment           |
+ *  |------------------------------|
+ *  |   0x00   | Null              |
+ *  |   0x08   | Kernel code       |
+ *  |   0x10   | Kernel data       |
+ *  |   0x18   | User data   (RPL3)|
+ *  |   0x20   | User code   (RPL3)|
+ *  |   0x28   | TSS (2 entries)   |
+ *  |______________________________|
+ */
+pub const USER_DATA_SELECTOR: u16 = 0x1b;
+pub const USER_CODE_SELECTOR: u16 = 0x23;
 
-            // TODO add guard page
+const IST_STACK_SIZE: usize = 4096 * 5; // 20Kb
 
-            let stack_start = VirtAddr::from_ptr(unsafe { &STACK });
-            let stack_end   = stack_start + STACK_SIZE;
-            // Stack grows 'downward' so we initialize the stack to to the end
-            stack_end
-        };
-        tss
-    };
-}
+// Every CPU gets its own TSS, GDT and IST stacks. The TSS is referenced by the
+// GDT and holds the stack pointers the CPU switches to, so it can not be shared.
+static CPU_TABLES: [CpuTables; MAX_CPUS] = [CpuTables::INIT; MAX_CPUS];
 
-lazy_static! {
-    static ref GDT: (GlobalDescriptorTable, Selectors) = {
-        let mut gdt = GlobalDescriptorTable::new();
-        let code_selector = gdt.add_entry(Descriptor::kernel_code_segment());
-        let tss_selector = gdt.add_entry(Descriptor::tss_segment(&TSS));
-        (
-            gdt,
-            Selectors {
-                code_selector,
-                tss_selector,
-            },
-        )
-    };
-}
+// TODO add guard pages
+static mut DOUBLE_FAULT_STACKS: [Stack; MAX_CPUS] = [Stack::INIT; MAX_CPUS];
+static mut NMI_STACKS: [Stack; MAX_CPUS] = [Stack::INIT; MAX_CPUS];
+
+// Stack the CPU switches to when an interrupt or exception arrives in ring 3,
+// until `set_kernel_stack` installs another one
+static mut PRIVILEGE_STACKS: [Stack; MAX_CPUS] = [Stack::INIT; MAX_CPUS];
 
 ////////////////////////////////
 // Structs, Types, Traits, Impl
 ////////////////////////////////
 
-struct Selectors {
-    code_selector: SegmentSelector,
-    tss_selector: SegmentSelector,
+#[derive(Debug, Clone, Copy)]
+pub struct Selectors {
+    pub code_selector: SegmentSelector,
+    pub data_selector: SegmentSelector,
+    pub user_data_selector: SegmentSelector,
+    pub user_code_selector: SegmentSelector,
+    pub tss_selector: SegmentSelector,
+}
+
+struct CpuTables {
+    tss: UnsafeCell<TaskStateSegment>,
+    gdt: Once<(GlobalDescriptorTable, Selectors)>,
+}
+
+// Each CPU only touches its own entry, during `init_cpu` or to switch stacks
+unsafe impl Sync for CpuTables {}
+
+impl CpuTables {
+    const INIT: CpuTables = CpuTables {
+        tss: UnsafeCell::new(TaskStateSegment::new()),
+        gdt: Once::new(),
+    };
+}
+
+#[repr(C, align(16))]
+struct Stack([u8; IST_STACK_SIZE]);
+
+impl Stack {
+    const INIT: Stack = Stack([0; IST_STACK_SIZE]);
 }
 
 //////////////////////////////
 // API
 //////////////////////////////
 
+// Load the GDT and TSS of the bootstrap processor
 pub fn init() {
-    use x86_64::instructions::segmentation::set_cs;
+    init_cpu(0);
+}
+
+// Build and load the GDT and TSS for `cpu`. Must run on that CPU.
+pub fn init_cpu(cpu: usize) {
+    use x86_64::instructions::segmentation::{Segment, CS, DS, ES, SS};
     use x86_64::instructions::tables::load_tss;
 
-    GDT.0.load();
+    let tables = &CPU_TABLES[cpu];
+    let (gdt, selectors) = tables.gdt.call_once(|| {
+        let tss = unsafe { &mut *tables.tss.get() };
+        tss.interrupt_stack_table[DOUBLE_FAULT_IST_INDEX as usize] = {
+            let stack_start = VirtAddr::from_ptr(unsafe { addr_of!(DOUBLE_FAULT_STACKS[cpu]) });
+            // Stack grows 'downward' so we initialize the stack to to the end
+            stack_start + IST_STACK_SIZE
+        };
+        tss.interrupt_stack_table[NMI_IST_INDEX as usize] = {
+            let stack_start = VirtAddr::from_ptr(unsafe { addr_of!(NMI_STACKS[cpu]) });
+            stack_start + IST_STACK_SIZE
+        };
+        tss.privilege_stack_table[0] = privilege_stack_top(cpu);
+
+        let mut gdt = GlobalDescriptorTable::new();
+        let code_selector = gdt.add_entry(Descriptor::kernel_code_segment());
+        let data_selector = gdt.add_entry(Descriptor::kernel_data_segment());
+        let user_data_selector = gdt.add_entry(Descriptor::user_data_segment());
+        let user_code_selector = gdt.add_entry(Descriptor::user_code_segment());
+        let tss_selector = gdt.add_entry(Descriptor::tss_segment(unsafe { &*tables.tss.get() }));
+        (
+            gdt,
+            Selectors {
+                code_selector,
+                data_selector,
+                user_data_selector,
+                user_code_selector,
+                tss_selector,
+            },
+        )
+    });
+
+    gdt.load();
     unsafe {
         // Reload the code segment register
-        set_cs(GDT.1.code_selector);
+        CS::set_reg(selectors.code_selector);
+        // Data segments are ignored in long mode apart from SS, which must be
+        // valid for `iretq`. FS and GS are left alone, writing them would
+        // clear their base.
+        SS::set_reg(selectors.data_selector);
+        DS::set_reg(selectors.data_selector);
+        ES::set_reg(selectors.data_selector);
         // Load the TaskStateSegment
-        load_tss(GDT.1.tss_selector);
+        load_tss(selectors.tss_selector);
+    }
+    debug_assert_eq!(selectors.user_code_selector.0, USER_CODE_SELECTOR);
+    debug_assert_eq!(selectors.user_data_selector.0, USER_DATA_SELECTOR);
+
+    // `syscall` needs the stack too, but only once there is a per-CPU area.
+    // Tests that only need the GDT and TSS run without one.
+    if let Some(this) = percpu::try_this_cpu() {
+        this.set_kernel_stack(privilege_stack_top(cpu));
     }
 }
+
+// Segment selectors of the calling CPU's GDT, the same on every CPU
+pub fn selectors() -> &'static Selectors {
+    let (_, selectors) = CPU_TABLES[percpu::cpu_id()]
+        .gdt
+        .r#try()
+        .expect("gdt::init_cpu has not been called");
+    selectors
+}
+
+// Stack the calling CPU switches to on entry from ring 3. Every thread that
+// runs user code needs its own, installed whenever it is switched to.
+pub fn set_kernel_stack(stack_top: VirtAddr) {
+    let this = percpu::this_cpu();
+    let tss = CPU_TABLES[this.id()].tss.get();
+    unsafe { (*tss).privilege_stack_table[0] = stack_top };
+    // `syscall` does not look at the TSS, see syscall.rs
+    this.set_kernel_stack(stack_top);
+}
+
+fn privilege_stack_top(cpu: usize) -> VirtAddr {
+    let stack_start = VirtAddr::from_ptr(unsafe { addr_of!(PRIVILEGE_STACKS[cpu]) });
+    stack_start + IST_STACK_SIZE
+}
@@ -1,11 +1,18 @@
 // interrupts.rs - x86 Interrupt Descriptor Table definition and handlers
 
-use crate::{gdt, hlt_loop, print, println};
+use crate::backtrace::{self, Backtrace};
+use crate::signal::{self, SIGBUS, SIGFPE, SIGILL, SIGSEGV};
+use crate::trap::TrapFrame;
+use crate::{
+    apic, fpu, gdbstub, gdt, hlt_loop, irq_stats, keyboard, percpu, println,
+    serial_emergency_println, thread, time, timer, trap, vm, watchdog,
+};
 
 use lazy_static::lazy_static;
 use pic8259::ChainedPics;
 use spin;
 use x86_64::structures::idt::{InterruptDescriptorTable, InterruptStackFrame, PageFaultErrorCode};
+use x86_64::PrivilegeLevel;
 
 ////////////////////////////////
 // Statics/Constants
@@ -31,15 +38,33 @@ use x86_64::structures::idt::{InterruptDescriptorTable, InterruptStackFrame, Pag
 pub const PIC_1_OFFSET: u8 = 32;
 pub const PIC_2_OFFSET: u8 = PIC_1_OFFSET + 8;
 
+// Exception vectors, see Intel SDM Vol. 3A Table 6-1
+pub const DIVIDE_ERROR_VECTOR: u8 = 0;
+pub const DEBUG_VECTOR: u8 = 1;
+pub const NMI_VECTOR: u8 = 2;
+pub const BREAKPOINT_VECTOR: u8 = 3;
+pub const INVALID_OPCODE_VECTOR: u8 = 6;
+pub const DEVICE_NOT_AVAILABLE_VECTOR: u8 = 7;
+pub const DOUBLE_FAULT_VECTOR: u8 = 8;
+pub const SEGMENT_NOT_PRESENT_VECTOR: u8 = 11;
+pub const STACK_SEGMENT_VECTOR: u8 = 12;
+pub const GENERAL_PROTECTION_VECTOR: u8 = 13;
+pub const PAGE_FAULT_VECTOR: u8 = 14;
+pub const X87_FLOATING_POINT_VECTOR: u8 = 16;
+pub const ALIGNMENT_CHECK_VECTOR: u8 = 17;
+pub const SIMD_FLOATING_POINT_VECTOR: u8 = 19;
+pub const SYSCALL_VECTOR: u8 = 0x80;
+
 #[derive(Debug, Clone, Copy)]
 #[repr(u8)]
 pub enum InterruptIndex {
     Timer = PIC_1_OFFSET,
     Keyboard,
+    Com2 = PIC_1_OFFSET + 3,
 }
 
 impl InterruptIndex {
-    fn as_u8(self) -> u8 {
+    pub fn as_u8(self) -> u8 {
         self as u8
     }
 
@@ -54,17 +79,54 @@ pub static PICS: spin::Mutex<ChainedPics> =
 lazy_static! {
     static ref IDT: InterruptDescriptorTable = {
         let mut idt = InterruptDescriptorTable::new();
-        idt.breakpoint.set_handler_fn(breakpoint_handler);
+        // Routed through trap.rs, the debugger needs every register and
+        // so does signal delivery on the way back to user mode
+        unsafe {
+            idt.debug.set_handler_addr(trap::debug_entry());
+            idt.breakpoint.set_handler_addr(trap::breakpoint_entry());
+            // Legacy system call gate, see syscall.rs
+            idt[usize::from(SYSCALL_VECTOR)]
+                .set_handler_addr(trap::syscall_entry())
+                .set_privilege_level(PrivilegeLevel::Ring3);
+
+            idt.divide_error.set_handler_addr(trap::divide_error_entry());
+            idt.invalid_opcode.set_handler_addr(trap::invalid_opcode_entry());
+            idt.device_not_available
+                .set_handler_addr(trap::device_not_available_entry());
+            idt.segment_not_present
+                .set_handler_addr(trap::segment_not_present_entry());
+            idt.stack_segment_fault
+                .set_handler_addr(trap::stack_segment_entry());
+            idt.general_protection_fault
+                .set_handler_addr(trap::general_protection_entry());
+            idt.page_fault.set_handler_addr(trap::page_fault_entry());
+            idt.x87_floating_point
+                .set_handler_addr(trap::x87_floating_point_entry());
+            idt.alignment_check
+                .set_handler_addr(trap::alignment_check_entry());
+            idt.simd_floating_point
+                .set_handler_addr(trap::simd_floating_point_entry());
+            idt[InterruptIndex::Timer.as_usize()].set_handler_addr(trap::timer_entry());
+            // GDB stops the interrupted code, not this handler
+            idt[InterruptIndex::Com2.as_usize()].set_handler_addr(trap::com2_entry());
+        }
 
         unsafe {
             idt.double_fault
                 .set_handler_fn(double_fault_handler)
                 .set_stack_index(gdt::DOUBLE_FAULT_IST_INDEX);
         }
-        idt.page_fault.set_handler_fn(page_fault_handler);
+        // Through trap.rs as well, for the watchdog to dump every register
+        unsafe {
+            idt.non_maskable_interrupt
+                .set_handler_addr(trap::nmi_entry())
+                .set_stack_index(gdt::NMI_IST_INDEX);
+        }
 
-        idt[InterruptIndex::Timer.as_usize()].set_handler_fn(timer_interrupt_handler);
         idt[InterruptIndex::Keyboard.as_usize()].set_handler_fn(keyboard_interrupt_handler);
+        idt[usize::from(apic::TIMER_VECTOR)].set_handler_fn(apic_timer_interrupt_handler);
+        idt[usize::from(apic::WAKEUP_VECTOR)].set_handler_fn(wakeup_interrupt_handler);
+        idt[usize::from(apic::SPURIOUS_VECTOR)].set_handler_fn(spurious_interrupt_handler);
 
         idt
     };
@@ -78,64 +140,141 @@ pub fn init_idt() {
     IDT.load();
 }
 
-// {:#?} - Pretty print debug info
-extern "x86-interrupt" fn breakpoint_handler(stack_frame: InterruptStackFrame) {
-    println!("EXCEPTION: BREAKPOINT\n{:#?}", stack_frame);
+// Bookkeeping on entry of every handler, must be its first statement. Keep
+// the returned guard alive until the handler returns, it restores the user
+// GS base if user mode was interrupted.
+fn enter(vector: u8, stack_frame: &InterruptStackFrame) -> percpu::KernelGs {
+    let gs = unsafe { percpu::KernelGs::enter(stack_frame.code_segment) };
+    irq_stats::record(vector);
+    gs
+}
+
+// From trap.rs, watchdog NMIs or hardware errors reported through system
+// control port B. Nothing here may take a lock, the NMI can interrupt any
+// code holding one.
+pub fn nmi_handler(frame: &TrapFrame) {
+    use x86_64::instructions::port::Port;
+
+    let _gs = unsafe { percpu::KernelGs::enter_paranoid() };
+    irq_stats::record(NMI_VECTOR);
+
+    if !watchdog::handle_nmi(frame) {
+        let mut port = Port::<u8>::new(0x61);
+        let reason = unsafe { port.read() };
+        serial_emergency_println!(
+            "NMI: unexpected on CPU {}, port 0x61 = {:#04x}",
+            percpu::cpu_id(),
+            reason
+        );
+    }
 }
 
 extern "x86-interrupt" fn double_fault_handler(
     stack_frame: InterruptStackFrame,
     error_code: u64,
 ) -> ! {
+    let _entry = enter(DOUBLE_FAULT_VECTOR, &stack_frame);
+    let backtrace = Backtrace::from_interrupt(&stack_frame, backtrace::interrupted_frame_pointer());
     panic!(
-        "EXCEPTION: DOUBLE FAULT - Err {}\n{:#?}",
-        error_code, stack_frame
+        "EXCEPTION: DOUBLE FAULT - Err {}\n{:#?}\n{}",
+        error_code, stack_frame, backtrace
     );
 }
 
-extern "x86-interrupt" fn page_fault_handler(
-    stack_frame: InterruptStackFrame,
-    error_code: PageFaultErrorCode,
-) {
+// Faults and errors of the running code, from trap.rs. In user mode they
+// raise a signal for the process, anywhere else they are fatal.
+pub fn exception_handler(frame: &mut TrapFrame) {
     use x86_64::registers::control::Cr2; // CR2 has the virtual address that caused the page fault
 
-    println!("EXCEPTION: PAGE FAULT");
-    println!("Accesssed Address: {:?}", Cr2::read());
-    println!("Error Code: {:?}", error_code);
-    println!("{:#?}", stack_frame);
-    hlt_loop();
+    let vector = frame.vector as u8;
+    if vector == PAGE_FAULT_VECTOR {
+        let error_code = PageFaultErrorCode::from_bits_truncate(frame.error_code);
+        if vm::handle_page_fault(Cr2::read(), error_code) {
+            return;
+        }
+    }
+
+    if frame.from_user_process() {
+        let signal = match vector {
+            DIVIDE_ERROR_VECTOR | X87_FLOATING_POINT_VECTOR | SIMD_FLOATING_POINT_VECTOR => SIGFPE,
+            // The FPU is off, see fpu.rs
+            INVALID_OPCODE_VECTOR | DEVICE_NOT_AVAILABLE_VECTOR => SIGILL,
+            ALIGNMENT_CHECK_VECTOR => SIGBUS,
+            _ => SIGSEGV,
+        };
+        signal::force(signal);
+        return;
+    }
+
+    let backtrace = Backtrace::from_registers(frame.rip, frame.rbp, frame.cs);
+    if vector == PAGE_FAULT_VECTOR {
+        println!("EXCEPTION: PAGE FAULT");
+        println!("Accesssed Address: {:?}", Cr2::read());
+        println!(
+            "Error Code: {:?}",
+            PageFaultErrorCode::from_bits_truncate(frame.error_code)
+        );
+        println!("{:#x?}", frame);
+        println!("{}", backtrace);
+        hlt_loop();
+    }
+    panic!(
+        "EXCEPTION: {} - Err {}\n{:#x?}\n{}",
+        exception_name(vector),
+        frame.error_code,
+        frame,
+        backtrace
+    );
+}
+
+fn exception_name(vector: u8) -> &'static str {
+    match vector {
+        DIVIDE_ERROR_VECTOR => "DIVIDE ERROR",
+        INVALID_OPCODE_VECTOR => "INVALID OPCODE",
+        DEVICE_NOT_AVAILABLE_VECTOR => "DEVICE NOT AVAILABLE",
+        SEGMENT_NOT_PRESENT_VECTOR => "SEGMENT NOT PRESENT",
+        STACK_SEGMENT_VECTOR => "STACK SEGMENT FAULT",
+        GENERAL_PROTECTION_VECTOR => "GENERAL PROTECTION FAULT",
+        PAGE_FAULT_VECTOR => "PAGE FAULT",
+        X87_FLOATING_POINT_VECTOR => "X87 FLOATING POINT",
+        ALIGNMENT_CHECK_VECTOR => "ALIGNMENT CHECK",
+        SIMD_FLOATING_POINT_VECTOR => "SIMD FLOATING POINT",
+        _ => "UNKNOWN",
+    }
 }
 
-extern "x86-interrupt" fn timer_interrupt_handler(_stack_frame: InterruptStackFrame) {
+// From trap.rs, the first FPU use of a thread since it was switched to, see
+// fpu.rs. Anything else is code that may not use the FPU.
+pub fn device_not_available_handler(frame: &mut TrapFrame) {
+    if !fpu::handle_device_not_available() {
+        exception_handler(frame);
+    }
+}
+
+// From trap.rs, which delivers signals to user mode code it interrupted
+pub fn timer_interrupt_handler() {
+    time::tick();
     unsafe {
         PICS.lock()
             .notify_end_of_interrupt(InterruptIndex::Timer.as_u8());
     }
+    // Timer callbacks wake threads and the scheduler may switch to one, so
+    // only after the end of interrupt
+    timer::tick();
+    thread::tick();
 }
 
-extern "x86-interrupt" fn keyboard_interrupt_handler(_stack_frame: InterruptStackFrame) {
-    use pc_keyboard::{layouts, DecodedKey, HandleControl, Keyboard, ScancodeSet1};
-    use spin::Mutex;
+extern "x86-interrupt" fn keyboard_interrupt_handler(stack_frame: InterruptStackFrame) {
     use x86_64::instructions::port::Port;
 
-    lazy_static! {
-        static ref KEYBOARD: Mutex<Keyboard<layouts::Us104Key, ScancodeSet1>> = Mutex::new(
-            Keyboard::new(layouts::Us104Key, ScancodeSet1, HandleControl::Ignore)
-        );
-    }
-    let mut keyboard = KEYBOARD.lock();
+    let _entry = enter(InterruptIndex::Keyboard.as_u8(), &stack_frame);
 
-    // Read from the PS/2 Controller I/O port, 0x60, and process the scan code
+    // Read from the PS/2 Controller I/O port, 0x60, and leave decoding the
+    // scan code to the task reading `keyboard::ScancodeStream`. If the queue
+    // is full the key press is lost, see `keyboard::dropped`.
     let mut port = Port::new(0x60);
     let scancode: u8 = unsafe { port.read() };
-    if let Ok(Some(key_event)) = keyboard.add_byte(scancode) {
-        if let Some(key) = keyboard.process_keyevent(key_event) {
-            match key {
-                DecodedKey::Unicode(character) => print!("{}", character),
-                DecodedKey::RawKey(key) => print!("{:?}", key),
-            }
-        }
-    }
+    keyboard::add_scancode(scancode);
 
     unsafe {
         PICS.lock()
@@ -143,6 +282,34 @@ extern "x86-interrupt" fn keyboard_interrupt_handler(_stack_frame: InterruptStac
     }
 }
 
+// Per-CPU tick, see `apic::start_timer`
+extern "x86-interrupt" fn apic_timer_interrupt_handler(stack_frame: InterruptStackFrame) {
+    let _entry = enter(apic::TIMER_VECTOR, &stack_frame);
+    watchdog::tick();
+    apic::end_of_interrupt();
+}
+
+// From trap.rs, only unmasked while the GDB stub owns COM2
+pub fn com2_interrupt_handler(frame: &mut TrapFrame) {
+    unsafe {
+        PICS.lock()
+            .notify_end_of_interrupt(InterruptIndex::Com2.as_u8());
+    }
+    gdbstub::handle_interrupt(frame);
+}
+
+// Sent to idle application processors when work is queued for them, see
+// `smp::run_on`. Getting out of `hlt` is all that is needed.
+extern "x86-interrupt" fn wakeup_interrupt_handler(stack_frame: InterruptStackFrame) {
+    let _entry = enter(apic::WAKEUP_VECTOR, &stack_frame);
+    apic::end_of_interrupt();
+}
+
+// Spurious interrupts must not be acknowledged, Intel SDM Vol. 3A 10.9
+extern "x86-interrupt" fn spurious_interrupt_handler(stack_frame: InterruptStackFrame) {
+    let _entry = enter(apic::SPURIOUS_VECTOR, &stack_frame);
+}
+
 //////////////////////////////
 // Tests
 //////////////////////////////
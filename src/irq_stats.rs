@@ -0,0 +1,214 @@
+// irq_stats.rs - Per-vector interrupt and exception statistics
+
+use crate::interrupts::{InterruptIndex, SYSCALL_VECTOR};
+use crate::{apic, percpu, smp};
+
+use core::fmt;
+use core::sync::atomic::{AtomicU64, Ordering};
+
+//////////////////////////////
+// Statics/Constants
+//////////////////////////////
+
+const VECTOR_COUNT: usize = 256;
+
+// Names of the architecturally defined exceptions, Intel SDM Vol. 3A Table 6-1
+const EXCEPTION_NAMES: [&str; 32] = [
+    "Divide Error",
+    "Debug",
+    "Non-Maskable Interrupt",
+    "Breakpoint",
+    "Overflow",
+    "BOUND Range Exceeded",
+    "Invalid Opcode",
+    "Device Not Available",
+    "Double Fault",
+    "Coprocessor Segment Overrun",
+    "Invalid TSS",
+    "Segment Not Present",
+    "Stack-Segment Fault",
+    "General Protection",
+    "Page Fault",
+    "Reserved",
+    "x87 Floating-Point Error",
+    "Alignment Check",
+    "Machine Check",
+    "SIMD Floating-Point",
+    "Virtualization",
+    "Control Protection",
+    "Reserved",
+    "Reserved",
+    "Reserved",
+    "Reserved",
+    "Reserved",
+    "Reserved",
+    "Hypervisor Injection",
+    "VMM Communication",
+    "Security",
+    "Reserved",
+];
+
+////////////////////////////////
+// Structs, Types, Traits, Impl
+////////////////////////////////
+
+// Counters of a single CPU, lives in its `percpu::PerCpu` area
+pub struct CpuStats {
+    vectors: [VectorStats; VECTOR_COUNT],
+}
+
+impl CpuStats {
+    pub const INIT: CpuStats = CpuStats {
+        vectors: [VectorStats::INIT; VECTOR_COUNT],
+    };
+}
+
+struct VectorStats {
+    count: AtomicU64,
+    last_tsc: AtomicU64,
+}
+
+impl VectorStats {
+    const INIT: VectorStats = VectorStats {
+        count: AtomicU64::new(0),
+        last_tsc: AtomicU64::new(0),
+    };
+}
+
+// Point in time copy of the statistics for a single vector, on one CPU or
+// summed over all of them
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct VectorSnapshot {
+    pub vector: u8,
+    pub count: u64,
+    // Time Stamp Counter value of the most recent occurrence, None if the
+    // vector never fired
+    pub last_tsc: Option<u64>,
+}
+
+// Displays every vector that fired at least once, similar to /proc/interrupts
+pub struct Table;
+
+impl fmt::Display for Table {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let cpus = smp::cpu_count();
+
+        write!(f, "{:>4}", "VEC")?;
+        for cpu in 0..cpus {
+            write!(f, " {:>8}{:<2}", "CPU", cpu)?;
+        }
+        writeln!(f, "  {:<18}  {}", "LAST (TSC)", "DESCRIPTION")?;
+
+        for total in (0..VECTOR_COUNT).map(|v| snapshot(v as u8)) {
+            if total.count == 0 {
+                continue;
+            }
+            write!(f, "{:>3}:", total.vector)?;
+            for cpu in 0..cpus {
+                write!(f, " {:>10}", cpu_snapshot(cpu, total.vector).count)?;
+            }
+            writeln!(
+                f,
+                "  {:#018x}  {}",
+                total.last_tsc.unwrap_or(0),
+                Description(total.vector)
+            )?;
+        }
+        Ok(())
+    }
+}
+
+struct Description(u8);
+
+impl fmt::Display for Description {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let vector = self.0;
+        if let Some(name) = EXCEPTION_NAMES.get(usize::from(vector)) {
+            return write!(f, "{}", name);
+        }
+        match vector {
+            v if v == InterruptIndex::Timer.as_u8() => write!(f, "PIC IRQ0 Timer"),
+            v if v == InterruptIndex::Keyboard.as_u8() => write!(f, "PIC IRQ1 Keyboard"),
+            v if v == InterruptIndex::Com2.as_u8() => write!(f, "PIC IRQ3 COM2"),
+            SYSCALL_VECTOR => write!(f, "System Call"),
+            apic::TIMER_VECTOR => write!(f, "APIC Timer"),
+            apic::WAKEUP_VECTOR => write!(f, "APIC Wakeup IPI"),
+            apic::SPURIOUS_VECTOR => write!(f, "APIC Spurious"),
+            v => write!(f, "Vector {:#04x}", v),
+        }
+    }
+}
+
+//////////////////////////////
+// API
+//////////////////////////////
+
+// Records an occurrence of `vector` on the calling CPU. Called on entry of
+// every interrupt and exception handler, must not take any locks.
+pub fn record(vector: u8) {
+    let stats = &percpu::this_cpu().irq_stats.vectors[usize::from(vector)];
+    stats.count.fetch_add(1, Ordering::Relaxed);
+    stats.last_tsc.store(read_tsc(), Ordering::Relaxed);
+}
+
+// Occurrences of `vector` summed over all CPUs
+pub fn count(vector: u8) -> u64 {
+    snapshot(vector).count
+}
+
+// Statistics of `vector` summed over all CPUs, with the most recent
+// occurrence on any of them
+pub fn snapshot(vector: u8) -> VectorSnapshot {
+    (0..smp::cpu_count())
+        .map(|cpu| cpu_snapshot(cpu, vector))
+        .fold(VectorSnapshot { vector, count: 0, last_tsc: None }, |total, cpu| {
+            VectorSnapshot {
+                vector,
+                count: total.count + cpu.count,
+                last_tsc: total.last_tsc.max(cpu.last_tsc),
+            }
+        })
+}
+
+pub fn cpu_snapshot(cpu: usize, vector: u8) -> VectorSnapshot {
+    let stats = &percpu::cpu(cpu).irq_stats.vectors[usize::from(vector)];
+    let count = stats.count.load(Ordering::Relaxed);
+    VectorSnapshot {
+        vector,
+        count,
+        last_tsc: match count {
+            0 => None,
+            _ => Some(stats.last_tsc.load(Ordering::Relaxed)),
+        },
+    }
+}
+
+// Usage: println!("{}", irq_stats::table());
+pub fn table() -> Table {
+    Table
+}
+
+fn read_tsc() -> u64 {
+    unsafe { core::arch::x86_64::_rdtsc() }
+}
+
+//////////////////////////////
+// Tests
+//////////////////////////////
+
+#[test_case]
+fn test_breakpoint_is_counted() {
+    use crate::interrupts::BREAKPOINT_VECTOR;
+
+    let before = snapshot(BREAKPOINT_VECTOR);
+    x86_64::instructions::interrupts::int3();
+    let after = snapshot(BREAKPOINT_VECTOR);
+
+    assert_eq!(after.count, before.count + 1);
+    assert!(after.last_tsc.is_some());
+}
+
+#[test_case]
+fn test_unused_vector_has_no_timestamp() {
+    assert_eq!(snapshot(0xfe).last_tsc, None);
+}
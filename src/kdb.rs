@@ -0,0 +1,359 @@
+// kdb.rs - Interactive kernel debugger over the serial port
+
+use crate::backtrace::Backtrace;
+use crate::interrupts::{BREAKPOINT_VECTOR, DEBUG_VECTOR};
+use crate::trap::{self, TrapFrame};
+use crate::{
+    ksyms, memory, percpu, println, serial, serial_emergency_print, serial_emergency_println,
+    watchdog,
+};
+
+use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use x86_64::VirtAddr;
+
+//////////////////////////////
+// Statics/Constants
+//////////////////////////////
+
+/*
+ *  Entered on `int3` once enabled, or on Ctrl+Alt+F12 which executes one.
+ *  Commands are read from COM1 with interrupts disabled, the CPU stays in
+ *  the debugger until `step` or `continue`. Other CPUs keep running.
+ *
+ *  Nothing here takes a lock or allocates, the trapped code may hold the
+ *  heap, serial or VGA locks.
+ */
+const HELP: &str = "\
+Commands:
+  r, regs                  show registers
+  m, mem <addr> [len]      dump memory, 64 bytes by default
+  w, write <addr> <byte>.. write bytes to memory
+  pt <addr>                walk the page tables for an address
+  bt                       show backtrace
+  s, step                  single step one instruction
+  c, continue              leave the debugger
+  h, help                  show this text";
+
+// RFLAGS Trap Flag, raises #DB after the next instruction
+const RFLAGS_TF: u64 = 1 << 8;
+
+// DR6 bit set when #DB was caused by single stepping
+const DR6_SINGLE_STEP: u64 = 1 << 14;
+
+const LINE_CAPACITY: usize = 80;
+const DEFAULT_DUMP_LEN: u64 = 64;
+const MAX_DUMP_LEN: u64 = 4096;
+
+static ENABLED: AtomicBool = AtomicBool::new(false);
+
+// Id of the CPU in the debugger, others trapping meanwhile wait their turn
+const NO_CPU: usize = usize::MAX;
+static OWNER: AtomicUsize = AtomicUsize::new(NO_CPU);
+
+//////////////////////////////
+// API
+//////////////////////////////
+
+// Stop on breakpoints from now on. Until then `int3` only prints the trap
+// frame and continues, which is what the test kernels rely on.
+pub fn enable() {
+    ENABLED.store(true, Ordering::SeqCst);
+}
+
+pub fn is_enabled() -> bool {
+    ENABLED.load(Ordering::SeqCst)
+}
+
+// Enter the debugger from the current context
+pub fn break_in() {
+    if is_enabled() {
+        x86_64::instructions::interrupts::int3();
+    }
+}
+
+// #DB and #BP, see trap.rs
+pub fn handle_trap(frame: &mut TrapFrame) {
+    let vector = frame.vector as u8;
+    let mut dr6 = 0;
+    if vector == DEBUG_VECTOR {
+        // DR6 bits are sticky, clear them for the next #DB
+        dr6 = trap::take_dr6();
+    }
+
+    if !is_enabled() {
+        if vector == BREAKPOINT_VECTOR {
+            println!("EXCEPTION: BREAKPOINT\n{:#?}", frame);
+        }
+        return;
+    }
+
+    let cpu = percpu::cpu_id();
+    while OWNER
+        .compare_exchange(NO_CPU, cpu, Ordering::Acquire, Ordering::Relaxed)
+        .is_err()
+    {
+        watchdog::touch();
+        core::hint::spin_loop();
+    }
+
+    let reason = match vector {
+        BREAKPOINT_VECTOR => "breakpoint",
+        _ if dr6 & DR6_SINGLE_STEP != 0 => "single step",
+        _ => "debug exception",
+    };
+    serial_emergency_print!("\nkdb: {} on CPU {} at ", reason, cpu);
+    print_location(frame.rip);
+    serial_emergency_println!();
+
+    session(frame);
+    OWNER.store(NO_CPU, Ordering::Release);
+}
+
+fn session(frame: &mut TrapFrame) {
+    let mut line = [0u8; LINE_CAPACITY];
+    loop {
+        serial_emergency_print!("kdb> ");
+        let mut args = read_line(&mut line).split_whitespace();
+
+        match args.next() {
+            None => {}
+            Some("r") | Some("regs") => print_registers(frame),
+            Some("m") | Some("mem") => match parse_args(&mut args) {
+                [Some(addr), len] => {
+                    dump_memory(addr, len.unwrap_or(DEFAULT_DUMP_LEN).min(MAX_DUMP_LEN));
+                }
+                _ => serial_emergency_println!("usage: mem <addr> [len]"),
+            },
+            Some("w") | Some("write") => match parse_number(args.next()) {
+                Some(addr) => write_memory(addr, args),
+                None => serial_emergency_println!("usage: write <addr> <byte>.."),
+            },
+            Some("pt") => match parse_number(args.next()) {
+                Some(addr) => print_page_walk(addr),
+                None => serial_emergency_println!("usage: pt <addr>"),
+            },
+            Some("bt") => {
+                let backtrace = Backtrace::from_registers(frame.rip, frame.rbp, frame.cs);
+                serial_emergency_print!("{}", backtrace);
+            }
+            Some("s") | Some("step") => {
+                frame.rflags |= RFLAGS_TF;
+                return;
+            }
+            Some("c") | Some("continue") => {
+                frame.rflags &= !RFLAGS_TF;
+                return;
+            }
+            Some("h") | Some("help") => serial_emergency_println!("{}", HELP),
+            Some(command) => serial_emergency_println!("unknown command '{}', try help", command),
+        }
+    }
+}
+
+// Read a line from serial, echoing it back. Keeps the watchdog from
+// mistaking the wait for a lockup.
+fn read_line(line: &mut [u8; LINE_CAPACITY]) -> &str {
+    let mut len = 0;
+    loop {
+        let byte = loop {
+            if let Some(byte) = serial::try_read_byte(serial::COM1) {
+                break byte;
+            }
+            watchdog::touch();
+            core::hint::spin_loop();
+        };
+
+        match byte {
+            b'\r' | b'\n' => {
+                serial_emergency_println!();
+                break;
+            }
+            // Backspace and delete
+            0x08 | 0x7f if len > 0 => {
+                len -= 1;
+                serial_emergency_print!("\x08 \x08");
+            }
+            0x20..=0x7e if len < line.len() => {
+                line[len] = byte;
+                len += 1;
+                serial_emergency_print!("{}", byte as char);
+            }
+            _ => {}
+        }
+    }
+    core::str::from_utf8(&line[..len]).unwrap_or("")
+}
+
+// Hexadecimal with a 0x prefix, decimal otherwise
+fn parse_number(arg: Option<&str>) -> Option<u64> {
+    let arg = arg?;
+    match arg.strip_prefix("0x") {
+        Some(hex) => u64::from_str_radix(hex, 16).ok(),
+        None => arg.parse().ok(),
+    }
+}
+
+fn parse_args<'a>(args: &mut impl Iterator<Item = &'a str>) -> [Option<u64>; 2] {
+    [parse_number(args.next()), parse_number(args.next())]
+}
+
+fn print_location(addr: u64) {
+    serial_emergency_print!("{:#018x}", addr);
+    if let Some(symbol) = ksyms::lookup(addr) {
+        serial_emergency_print!(" <{}+{:#x}>", symbol, addr - symbol.address);
+    }
+}
+
+fn print_registers(frame: &TrapFrame) {
+    use x86_64::registers::control::{Cr0, Cr2, Cr3, Cr4};
+
+    let rows = [
+        [("RAX", frame.rax), ("RBX", frame.rbx), ("RCX", frame.rcx)],
+        [("RDX", frame.rdx), ("RSI", frame.rsi), ("RDI", frame.rdi)],
+        [("RBP", frame.rbp), ("RSP", frame.rsp), ("R8 ", frame.r8)],
+        [("R9 ", frame.r9), ("R10", frame.r10), ("R11", frame.r11)],
+        [("R12", frame.r12), ("R13", frame.r13), ("R14", frame.r14)],
+    ];
+    for row in rows.iter() {
+        for (name, value) in row.iter() {
+            serial_emergency_print!("{} {:#018x}  ", name, value);
+        }
+        serial_emergency_println!();
+    }
+    serial_emergency_println!("R15 {:#018x}", frame.r15);
+
+    serial_emergency_print!("RIP ");
+    print_location(frame.rip);
+    serial_emergency_println!();
+    serial_emergency_println!(
+        "RFLAGS {:#018x}  CS {:#06x}  SS {:#06x}",
+        frame.rflags,
+        frame.cs,
+        frame.ss
+    );
+    serial_emergency_println!(
+        "CR0 {:#018x}  CR2 {:#018x}  CR3 {:#018x}  CR4 {:#018x}",
+        Cr0::read_raw(),
+        Cr2::read().as_u64(),
+        Cr3::read().0.start_address().as_u64(),
+        Cr4::read_raw()
+    );
+}
+
+// Hex dump, 16 bytes per line. Unmapped lines are skipped rather than
+// faulting.
+fn dump_memory(start: u64, len: u64) {
+    let end = start.saturating_add(len);
+    let mut line = start;
+    while line < end {
+        let line_end = end.min(line + 16);
+        serial_emergency_print!("{:#018x}:", line);
+
+        if !is_readable(line) || !is_readable(line_end - 1) {
+            serial_emergency_println!(" <not mapped>");
+            line = line_end;
+            continue;
+        }
+
+        let mut bytes = [0u8; 16];
+        for (i, addr) in (line..line_end).enumerate() {
+            bytes[i] = unsafe { (addr as *const u8).read_volatile() };
+            serial_emergency_print!(" {:02x}", bytes[i]);
+        }
+        for _ in line_end..line + 16 {
+            serial_emergency_print!("   ");
+        }
+
+        serial_emergency_print!("  ");
+        for &byte in &bytes[..(line_end - line) as usize] {
+            let printable = if byte.is_ascii_graphic() || byte == b' ' {
+                byte
+            } else {
+                b'.'
+            };
+            serial_emergency_print!("{}", printable as char);
+        }
+        serial_emergency_println!();
+        line = line_end;
+    }
+}
+
+fn write_memory<'a>(start: u64, bytes: impl Iterator<Item = &'a str>) {
+    let mut addr = start;
+    for arg in bytes {
+        let byte = match parse_number(Some(arg)).filter(|&value| value <= 0xff) {
+            Some(byte) => byte as u8,
+            None => {
+                serial_emergency_println!("'{}' is not a byte", arg);
+                return;
+            }
+        };
+        if !is_writable(addr) {
+            serial_emergency_println!("{:#018x} is not writable", addr);
+            return;
+        }
+        unsafe { (addr as *mut u8).write_volatile(byte) };
+        addr += 1;
+    }
+    serial_emergency_println!("wrote {} bytes at {:#018x}", addr - start, start);
+}
+
+fn print_page_walk(addr: u64) {
+    let virt = match VirtAddr::try_new(addr) {
+        Ok(virt) => virt,
+        Err(_) => {
+            serial_emergency_println!("{:#x} is not canonical", addr);
+            return;
+        }
+    };
+
+    let indexes = [
+        virt.p4_index(),
+        virt.p3_index(),
+        virt.p2_index(),
+        virt.p1_index(),
+    ];
+    let mapped = memory::walk_page_tables(virt, |level, entry| {
+        serial_emergency_println!(
+            "P{}[{:>3}] {:#018x} {:?}",
+            level,
+            u16::from(indexes[4 - level]),
+            entry.addr().as_u64(),
+            entry.flags()
+        );
+    });
+    if !mapped {
+        serial_emergency_println!("{:#018x} is not mapped", addr);
+    }
+}
+
+fn is_readable(addr: u64) -> bool {
+    VirtAddr::try_new(addr).is_ok_and(memory::is_mapped)
+}
+
+// Mapped, and writable at every level of the page tables
+fn is_writable(addr: u64) -> bool {
+    use x86_64::structures::paging::PageTableFlags;
+
+    let virt = match VirtAddr::try_new(addr) {
+        Ok(virt) => virt,
+        Err(_) => return false,
+    };
+    let mut writable = true;
+    let mapped = memory::walk_page_tables(virt, |_, entry| {
+        writable &= entry.flags().contains(PageTableFlags::WRITABLE);
+    });
+    mapped && writable
+}
+
+//////////////////////////////
+// Tests
+//////////////////////////////
+
+#[test_case]
+fn test_parse_number() {
+    assert_eq!(parse_number(Some("0x1f")), Some(0x1f));
+    assert_eq!(parse_number(Some("42")), Some(42));
+    assert_eq!(parse_number(Some("0xzz")), None);
+    assert_eq!(parse_number(None), None);
+}
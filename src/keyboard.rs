@@ -0,0 +1,146 @@
+// keyboard.rs - PS/2 keyboard scancode queue and decoding
+
+use crate::{kdb, print};
+
+use conquer_once::spin::OnceCell;
+use core::pin::Pin;
+use core::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use core::task::{Context, Poll};
+use crossbeam_queue::ArrayQueue;
+use futures_util::stream::{Stream, StreamExt};
+use futures_util::task::AtomicWaker;
+use pc_keyboard::{layouts, DecodedKey, HandleControl, KeyCode, KeyEvent, KeyState, Keyboard, ScancodeSet1};
+
+//////////////////////////////
+// Statics/Constants
+//////////////////////////////
+
+/*
+ *  The interrupt handler only reads the scancode and pushes it here, the
+ *  queue is lock-free so that never blocks. Decoding happens in the task
+ *  reading `ScancodeStream`:
+ *
+ *    keyboard_interrupt_handler -> add_scancode -> SCANCODE_QUEUE
+ *                                       |
+ *                                       v  WAKER.wake()
+ *    print_keypresses <- ScancodeStream::poll_next
+ */
+const SCANCODE_QUEUE_CAPACITY: usize = 100;
+
+static SCANCODE_QUEUE: OnceCell<ArrayQueue<u8>> = OnceCell::uninit();
+static WAKER: AtomicWaker = AtomicWaker::new();
+
+// Number of scancodes dropped because the queue was full or uninitialized
+static DROPPED: AtomicU64 = AtomicU64::new(0);
+
+// Modifier state for the debugger key combo, Ctrl+Alt+F12
+static CONTROL_DOWN: AtomicBool = AtomicBool::new(false);
+static ALT_DOWN: AtomicBool = AtomicBool::new(false);
+
+////////////////////////////////
+// Structs, Types, Traits, Impl
+////////////////////////////////
+
+// The scancodes read by the interrupt handler, in order. There is only one
+// queue, so only one stream may be created.
+pub struct ScancodeStream {
+    _private: (),
+}
+
+impl ScancodeStream {
+    // Allocates the queue, requires the heap to be initialized
+    pub fn new() -> ScancodeStream {
+        SCANCODE_QUEUE
+            .try_init_once(|| ArrayQueue::new(SCANCODE_QUEUE_CAPACITY))
+            .expect("ScancodeStream::new should only be called once");
+        ScancodeStream { _private: () }
+    }
+}
+
+impl Default for ScancodeStream {
+    fn default() -> Self {
+        ScancodeStream::new()
+    }
+}
+
+impl Stream for ScancodeStream {
+    type Item = u8;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<u8>> {
+        let queue = SCANCODE_QUEUE.try_get().expect("scancode queue not initialized");
+
+        // Skip registering the waker when a scancode is already there
+        if let Some(scancode) = queue.pop() {
+            return Poll::Ready(Some(scancode));
+        }
+
+        // Check again after registering, a scancode pushed in between would
+        // otherwise wake the previous waker and never this one
+        WAKER.register(cx.waker());
+        match queue.pop() {
+            Some(scancode) => {
+                WAKER.take();
+                Poll::Ready(Some(scancode))
+            }
+            None => Poll::Pending,
+        }
+    }
+}
+
+//////////////////////////////
+// API
+//////////////////////////////
+
+// Called by the keyboard interrupt handler, must not block or allocate
+pub(crate) fn add_scancode(scancode: u8) {
+    let pushed = match SCANCODE_QUEUE.try_get() {
+        Ok(queue) => queue.push(scancode).is_ok(),
+        Err(_) => false,
+    };
+    if pushed {
+        WAKER.wake();
+    } else {
+        DROPPED.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+// Scancodes lost since boot
+pub fn dropped() -> u64 {
+    DROPPED.load(Ordering::Relaxed)
+}
+
+// Task decoding the scancodes and echoing the keys, handles the debugger
+// break-in key combo
+pub async fn print_keypresses() {
+    let mut scancodes = ScancodeStream::new();
+    let mut keyboard = Keyboard::new(layouts::Us104Key, ScancodeSet1, HandleControl::Ignore);
+
+    while let Some(scancode) = scancodes.next().await {
+        if let Ok(Some(key_event)) = keyboard.add_byte(scancode) {
+            if is_break_in(&key_event) {
+                kdb::break_in();
+                continue;
+            }
+            if let Some(key) = keyboard.process_keyevent(key_event) {
+                match key {
+                    DecodedKey::Unicode(character) => print!("{}", character),
+                    DecodedKey::RawKey(key) => print!("{:?}", key),
+                }
+            }
+        }
+    }
+}
+
+// Track Ctrl and Alt, true when F12 is pressed while both are held
+fn is_break_in(key_event: &KeyEvent) -> bool {
+    let down = key_event.state == KeyState::Down;
+    match key_event.code {
+        KeyCode::ControlLeft | KeyCode::ControlRight => CONTROL_DOWN.store(down, Ordering::Relaxed),
+        KeyCode::AltLeft | KeyCode::AltRight => ALT_DOWN.store(down, Ordering::Relaxed),
+        KeyCode::F12 => {
+            return down && CONTROL_DOWN.load(Ordering::Relaxed) && ALT_DOWN.load(Ordering::Relaxed);
+        }
+        _ => {}
+    }
+    false
+}
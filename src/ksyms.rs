@@ -0,0 +1,156 @@
+// ksyms.rs - Kernel symbol table, embedded after linking by scripts/ksyms.py
+
+use core::convert::TryInto;
+use core::fmt;
+use core::ptr::addr_of;
+
+//////////////////////////////
+// Statics/Constants
+//////////////////////////////
+
+/*
+ *  The table can only be built once the kernel is linked, so the kernel
+ *  reserves room for it in its own `.ksyms` section and the cargo runner,
+ *  scripts/runner.sh, fills it in before the image is built. Kernels that
+ *  did not go through the runner have an empty table and backtraces show
+ *  bare addresses.
+ *
+ *   _______________________________________________
+ *  | magic "ASTRASYM"                      8 bytes |
+ *  | count                                 u32     |
+ *  | string table size                     u32     |
+ *  |-----------------------------------------------|
+ *  | address u64 | size u32 | name offset u32      |  x count, by address
+ *  |-----------------------------------------------|
+ *  | mangled names, NUL terminated                 |
+ *  |_______________________________________________|
+ *
+ *  Keep in sync with scripts/ksyms.py.
+ */
+pub const KSYMS_CAPACITY: usize = 512 * 1024;
+
+const MAGIC: &[u8; 8] = b"ASTRASYM";
+const HEADER_SIZE: usize = 16;
+const ENTRY_SIZE: usize = 16;
+
+// Mutable and exported so the compiler can not assume it stays zeroed
+#[used]
+#[no_mangle]
+#[link_section = ".ksyms"]
+static mut KSYMS: [u8; KSYMS_CAPACITY] = [0; KSYMS_CAPACITY];
+
+////////////////////////////////
+// Structs, Types, Traits, Impl
+////////////////////////////////
+
+#[derive(Debug, Clone, Copy)]
+pub struct Symbol {
+    // Mangled name, `Display` demangles it
+    pub name: &'static str,
+    pub address: u64,
+    pub size: u64,
+}
+
+impl fmt::Display for Symbol {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        // The alternate format leaves out the hash suffix
+        write!(f, "{:#}", rustc_demangle::demangle(self.name))
+    }
+}
+
+struct Table {
+    count: usize,
+    entries: &'static [u8],
+    strings: &'static [u8],
+}
+
+impl Table {
+    fn entry(&self, index: usize) -> (u64, u64, usize) {
+        let entry = &self.entries[index * ENTRY_SIZE..(index + 1) * ENTRY_SIZE];
+        let address = u64::from_le_bytes(entry[0..8].try_into().unwrap());
+        let size = u32::from_le_bytes(entry[8..12].try_into().unwrap());
+        let name = u32::from_le_bytes(entry[12..16].try_into().unwrap());
+        (address, u64::from(size), name as usize)
+    }
+
+    fn name(&self, offset: usize) -> Option<&'static str> {
+        let bytes = self.strings.get(offset..)?;
+        let len = bytes.iter().position(|&b| b == 0)?;
+        core::str::from_utf8(&bytes[..len]).ok()
+    }
+}
+
+//////////////////////////////
+// API
+//////////////////////////////
+
+// Whether the runner embedded a symbol table
+pub fn is_loaded() -> bool {
+    table().is_some()
+}
+
+// Function containing `addr`
+pub fn lookup(addr: u64) -> Option<Symbol> {
+    let table = table()?;
+
+    // Index of the last symbol starting at or below `addr`
+    let (mut low, mut high) = (0, table.count);
+    while low < high {
+        let mid = low + (high - low) / 2;
+        if table.entry(mid).0 <= addr {
+            low = mid + 1;
+        } else {
+            high = mid;
+        }
+    }
+    let (address, size, name) = table.entry(low.checked_sub(1)?);
+
+    if addr >= address + size {
+        return None;
+    }
+    Some(Symbol {
+        name: table.name(name)?,
+        address,
+        size,
+    })
+}
+
+// Parse the header, `None` if the table is missing or malformed. Runs in the
+// panic path, so nothing here may panic.
+fn table() -> Option<Table> {
+    let blob: &'static [u8] = unsafe { &*addr_of!(KSYMS) };
+    if &blob[..MAGIC.len()] != MAGIC {
+        return None;
+    }
+
+    let count = u32::from_le_bytes(blob[8..12].try_into().ok()?) as usize;
+    let strings_size = u32::from_le_bytes(blob[12..16].try_into().ok()?) as usize;
+    let strings_start = HEADER_SIZE.checked_add(count.checked_mul(ENTRY_SIZE)?)?;
+    let strings_end = strings_start.checked_add(strings_size)?;
+
+    Some(Table {
+        count,
+        entries: blob.get(HEADER_SIZE..strings_start)?,
+        strings: blob.get(strings_start..strings_end)?,
+    })
+}
+
+//////////////////////////////
+// Tests
+//////////////////////////////
+
+#[test_case]
+fn test_lookup_finds_kernel_function() {
+    #[inline(never)]
+    fn target() {}
+
+    // Test kernels only have symbols when started through the runner
+    if !is_loaded() {
+        return;
+    }
+
+    let addr = target as *const () as u64;
+    let symbol = lookup(addr).expect("no symbol for a kernel function");
+    assert_eq!(symbol.address, addr);
+    assert!(symbol.name.contains("target"));
+}
@@ -9,10 +9,44 @@
 #![reexport_test_harness_main = "test_main"]
 
 
+extern crate alloc;
+
+pub mod cpu;
+pub mod fpu;
 pub mod gdt;
+pub mod usermode;
+pub mod ksyms;
+pub mod backtrace;
+pub mod smp;
+pub mod percpu;
+pub mod spinlock;
+pub mod acpi;
+pub mod apic;
+pub mod time;
+pub mod timer;
 pub mod memory;
+pub mod address_space;
+pub mod vm;
 pub mod serial;
+pub mod keyboard;
+pub mod deferred;
+pub mod thread;
+pub mod sync;
+pub mod process;
+pub mod signal;
+pub mod fd;
+pub mod pipe;
+pub mod channel;
+pub mod elf;
+pub mod task;
+pub mod allocator;
 pub mod interrupts;
+pub mod trap;
+pub mod syscall;
+pub mod kdb;
+pub mod gdbstub;
+pub mod irq_stats;
+pub mod watchdog;
 pub mod vga_buffer;
 
 
@@ -59,16 +93,32 @@ where T:Fn(),
 //////////////////////////////
 
 pub fn init() {
+    percpu::init(0);
     gdt::init();
+    syscall::init_cpu();
+    fpu::init_cpu();
     interrupts::init_idt();
+    time::init();
     unsafe { interrupts::PICS.lock().initialize() }; 
     x86_64::instructions::interrupts::enable();
 }
 
 
 #[cfg(test)] // Cargo xtest entry point
-fn test_kernel_main(_boot_info: &'static BootInfo) -> ! {
+fn test_kernel_main(boot_info: &'static BootInfo) -> ! {
+    use x86_64::VirtAddr;
+
     init();
+
+    let phy_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let mut mapper = unsafe { memory::init(phy_mem_offset) };
+    let mut frame_allocator = unsafe { memory::BootInfoFrameAllocator::init(&boot_info.memory_map) };
+    allocator::init_heap(&mut mapper, &mut frame_allocator).expect("heap initialization failed");
+    memory::init_frame_pool(frame_allocator);
+    deferred::init();
+    thread::init();
+    process::init();
+
     test_main();
     hlt_loop();
 }
@@ -88,6 +138,28 @@ pub fn hlt_loop() -> ! {
 }
 
 
+// Runs deferred interrupt work and sleeps until the next interrupt when there
+// is nothing left to do. Unlike `hlt_loop` this is not meant for fatal paths.
+pub fn idle_loop() -> ! {
+    use x86_64::instructions::interrupts;
+
+    loop {
+        deferred::run_pending();
+        thread::yield_now();
+
+        // Check for new work with interrupts disabled, otherwise an interrupt
+        // queueing work between the check and the hlt is not seen until the
+        // next interrupt wakes us up.
+        interrupts::disable();
+        if deferred::has_pending() || thread::has_ready() {
+            interrupts::enable();
+        } else {
+            interrupts::enable_and_hlt();
+        }
+    }
+}
+
+
 pub fn exit_qemu(exit_code: QemuExitCode) {
     use x86_64::instructions::port::Port;
 
@@ -111,6 +183,7 @@ pub fn test_runner(tests: &[&dyn Testable]) {
 pub fn test_panic_handler(info: &PanicInfo) -> ! {
     serial_println!("{}", "[ failed ]\n".fg(red()));
     serial_println!("Error: {}\n", info);
+    serial_println!("{}", backtrace::Backtrace::capture());
     exit_qemu(QemuExitCode::Failed);
     hlt_loop();
 }
@@ -16,19 +16,35 @@ use bootloader::{BootInfo, entry_point};
 entry_point!(kernel_main);
 
 fn kernel_main(boot_info: &'static BootInfo) -> ! {
-    use astra_os::memory;
+    use astra_os::task::{Executor, Task};
+    use astra_os::{allocator, cpu, deferred, gdbstub, kdb, keyboard, memory, process, smp, thread};
     use x86_64::{structures::paging::Page, VirtAddr};
 
     astra_os::init();
 
     println!("Hello {}", "astra-os");
+    println!("{}", cpu::info());
 
     let phy_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
     let mut mapper = unsafe { memory::init(phy_mem_offset) };
     let mut frame_allocator = unsafe { memory::BootInfoFrameAllocator::init(&boot_info.memory_map) };
 
+    allocator::init_heap(&mut mapper, &mut frame_allocator).expect("heap initialization failed");
+    deferred::init();
+    thread::init();
+    process::init();
+    smp::init(&mut mapper, &mut frame_allocator);
+    // Breakpoints go to GDB when built with the `gdbstub` feature and there
+    // is a COM2 port, to kdb otherwise
+    if gdbstub::init() {
+        println!("GDB stub listening on COM2, kdb disabled");
+    } else {
+        kdb::enable();
+    }
+
     let page = Page::containing_address(VirtAddr::new(0));
     memory::create_example_mapping(page, &mut mapper, &mut frame_allocator);
+    memory::init_frame_pool(frame_allocator);
 
     // write the string `New!` to the screen through the new mapping
     let page_ptr: *mut u64 = page.start_address().as_mut_ptr();
@@ -38,7 +54,10 @@ fn kernel_main(boot_info: &'static BootInfo) -> ! {
     test_main();
 
     println!("Phew, I didn't crash. . .");
-    astra_os::hlt_loop();
+
+    let mut executor = Executor::new();
+    executor.spawn(Task::new(keyboard::print_keypresses()));
+    executor.run();
 }
 
 
@@ -46,6 +65,7 @@ fn kernel_main(boot_info: &'static BootInfo) -> ! {
 #[panic_handler]
 fn panic(info: &PanicInfo) -> ! {
     println!("{}", info);
+    println!("{}", astra_os::backtrace::Backtrace::capture());
     astra_os::hlt_loop();
 }
 
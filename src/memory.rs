@@ -4,6 +4,7 @@ use x86_64::{
     structures::paging::{
         OffsetPageTable,
         FrameAllocator,
+        page_table::PageTableEntry,
         PageTable,
         PhysFrame,
         Size4KiB,
@@ -17,6 +18,31 @@ use bootloader::bootinfo::{
     MemoryMap,
     MemoryRegionType
 };
+use alloc::vec::Vec;
+use spin::Once;
+use crate::spinlock::IrqSpinlock;
+
+
+//////////////////////////////
+// Statics/Constants
+//////////////////////////////
+
+// Frames below 1 MiB are the only memory real mode code can address, e.g. the
+// application processor startup trampoline. They are kept out of the general
+// frame pool and handed out by `allocate_low_frame` instead.
+pub const LOW_MEMORY_END: u64 = 0x10_0000;
+
+static PHYSICAL_MEMORY_OFFSET: Once<VirtAddr> = Once::new();
+
+// Level 4 table the kernel booted with, active in kernel threads. Address
+// spaces share its entries, see address_space.rs.
+static KERNEL_PAGE_TABLE: Once<PhysFrame> = Once::new();
+
+// Frame pool used once booting is done, see `init_frame_pool`
+static FRAMES: IrqSpinlock<FramePool> = IrqSpinlock::new("frames", FramePool {
+    boot: None,
+    free: Vec::new(),
+});
 
 
 //////////////////////////////
@@ -27,6 +53,7 @@ use bootloader::bootinfo::{
 pub struct BootInfoFrameAllocator {
     memory_map: &'static MemoryMap,
     next: usize,
+    next_low: usize,
 }
 
 impl BootInfoFrameAllocator {
@@ -38,10 +65,27 @@ impl BootInfoFrameAllocator {
         BootInfoFrameAllocator {
             memory_map,
             next: 0, 
+            next_low: 0,
         }
     }
 
+    // Allocate a frame below `LOW_MEMORY_END`. Frame zero is never returned.
+    pub fn allocate_low_frame(&mut self) -> Option<PhysFrame> {
+        let frame = self.all_usable_frames()
+            .filter(|f| f.start_address().as_u64() != 0)
+            .filter(|f| f.start_address().as_u64() < LOW_MEMORY_END)
+            .nth(self.next_low);
+        self.next_low += 1;
+        frame
+    }
+
     fn usable_frames(&self) -> impl Iterator<Item = PhysFrame> {
+        self.all_usable_frames().filter(
+            |f| f.start_address().as_u64() >= LOW_MEMORY_END
+        )
+    }
+
+    fn all_usable_frames(&self) -> impl Iterator<Item = PhysFrame> {
         // Get usable regions
         let regions = self.memory_map.iter();
         let usable_regions = regions.filter(
@@ -74,6 +118,25 @@ unsafe impl FrameAllocator<Size4KiB> for BootInfoFrameAllocator {
 }
 
 
+// Frames given back with `free_frame` are handed out again before new ones
+// are taken from the boot allocator
+struct FramePool {
+    boot: Option<BootInfoFrameAllocator>,
+    free: Vec<PhysFrame>,
+}
+
+
+// A FrameAllocator taking frames from the global pool, for the `Mapper`
+// functions of the x86_64 crate
+pub struct GlobalFrameAllocator;
+
+unsafe impl FrameAllocator<Size4KiB> for GlobalFrameAllocator {
+    fn allocate_frame(&mut self) -> Option<PhysFrame> {
+        allocate_frame()
+    }
+}
+
+
 // A FrameAllocator that always returns `None`.
 pub struct EmptyFrameAllocator;
 
@@ -94,11 +157,109 @@ unsafe impl FrameAllocator<Size4KiB> for EmptyFrameAllocator {
 // Unsafe! Caller must guarantee the the complete physical memory is mapped to 
 // Virtual memory at the specified `physical_memory_offset`. 
 pub unsafe fn init(physical_memory_offset: VirtAddr) -> OffsetPageTable<'static> {
+    use x86_64::registers::control::Cr3;
+
+    PHYSICAL_MEMORY_OFFSET.call_once(|| physical_memory_offset);
+    KERNEL_PAGE_TABLE.call_once(|| Cr3::read().0);
     let level_4_table = active_level_4_table(physical_memory_offset);
     OffsetPageTable::new(level_4_table, physical_memory_offset)
 }
 
 
+// Hand the boot allocator over to the global pool once the heap is set up.
+// From then on frames come from `allocate_frame`, and can be given back.
+pub fn init_frame_pool(frame_allocator: BootInfoFrameAllocator) {
+    FRAMES.lock().boot = Some(frame_allocator);
+}
+
+
+// A free frame, or None when memory is exhausted or before `init_frame_pool`.
+// Its contents are undefined.
+pub fn allocate_frame() -> Option<PhysFrame> {
+    let mut frames = FRAMES.lock();
+    match frames.free.pop() {
+        Some(frame) => Some(frame),
+        None => frames.boot.as_mut()?.allocate_frame(),
+    }
+}
+
+
+// Give a frame from `allocate_frame` back to the pool
+//
+// Unsafe! Nothing may map or otherwise use `frame` anymore.
+pub unsafe fn free_frame(frame: PhysFrame) {
+    FRAMES.lock().free.push(frame);
+}
+
+
+// Frames given back and not handed out again yet
+pub fn free_frame_count() -> usize {
+    FRAMES.lock().free.len()
+}
+
+
+// Level 4 table kernel threads run with. The active one before `init`.
+pub fn kernel_page_table() -> PhysFrame {
+    use x86_64::registers::control::Cr3;
+
+    match KERNEL_PAGE_TABLE.r#try() {
+        Some(frame) => *frame,
+        None => Cr3::read().0,
+    }
+}
+
+
+// Virtual address of `addr` in the bootloader's mapping of physical memory.
+// Panics if called before `init`.
+pub fn phys_to_virt(addr: PhysAddr) -> VirtAddr {
+    let offset = PHYSICAL_MEMORY_OFFSET.r#try()
+        .expect("memory::init has not been called");
+    *offset + addr.as_u64()
+}
+
+
+// Whether `addr` is mapped in the active page table. For code that has to
+// read memory it can not vouch for, e.g. a stack walk after a crash. Reports
+// nothing as mapped before `init`.
+pub fn is_mapped(addr: VirtAddr) -> bool {
+    walk_page_tables(addr, |_, _| {})
+}
+
+
+// Visit the entries of the active page table translating `addr`, level 4
+// first, down to the one mapping the page or the first one not present.
+// Returns whether `addr` is mapped. Visits nothing before `init`.
+pub fn walk_page_tables(addr: VirtAddr, mut visit: impl FnMut(usize, &PageTableEntry)) -> bool {
+    use x86_64::registers::control::Cr3;
+    use x86_64::structures::paging::PageTableFlags as Flags;
+
+    let offset = match PHYSICAL_MEMORY_OFFSET.r#try() {
+        Some(offset) => *offset,
+        None => return false,
+    };
+
+    let (level_4_frame, _) = Cr3::read();
+    let mut table_addr = level_4_frame.start_address();
+    let indexes = [addr.p4_index(), addr.p3_index(), addr.p2_index(), addr.p1_index()];
+    for (i, &index) in indexes.iter().enumerate() {
+        let level = 4 - i;
+        let table: &PageTable = unsafe { &*(offset + table_addr.as_u64()).as_ptr() };
+        let entry = &table[index];
+        visit(level, entry);
+
+        if !entry.flags().contains(Flags::PRESENT) {
+            return false;
+        }
+        // 1 GiB and 2 MiB pages end the walk early
+        if level < 4 && entry.flags().contains(Flags::HUGE_PAGE) {
+            return true;
+        }
+        table_addr = entry.addr();
+    }
+    true
+}
+
+
 pub fn create_example_mapping(page: Page, mapper: &mut OffsetPageTable, frame_allocator: &mut impl FrameAllocator<Size4KiB>) {
     use x86_64::structures::paging::PageTableFlags as Flags;
 
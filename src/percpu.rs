@@ -0,0 +1,189 @@
+// percpu.rs - Per-CPU data reached through the GS segment base
+
+use crate::fpu::CpuFpu;
+use crate::irq_stats::CpuStats;
+use crate::smp::MAX_CPUS;
+use crate::spinlock::CpuLocks;
+use crate::thread::CpuThreads;
+use crate::watchdog::CpuWatchdog;
+
+use core::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use x86_64::VirtAddr;
+
+//////////////////////////////
+// Statics/Constants
+//////////////////////////////
+
+/*
+ *  While in kernel mode the GS base of every CPU points at its `PerCpu`
+ *  entry, whose first field points back at itself. `this_cpu` is then a
+ *  single `mov reg, gs:[0]`.
+ *
+ *  Kernel mode                         User mode
+ *    IA32_GS_BASE        = &PerCpu       IA32_GS_BASE        = user value
+ *    IA32_KERNEL_GS_BASE = user value    IA32_KERNEL_GS_BASE = &PerCpu
+ *
+ *  `swapgs` exchanges the two, every entry from user mode has to execute it
+ *  before touching per-CPU data and again before returning, see `KernelGs`.
+ */
+static CPUS: [PerCpu; MAX_CPUS] = [PerCpu::INIT; MAX_CPUS];
+
+// `gs:` relative offsets for assembly
+pub const KERNEL_STACK_OFFSET: usize = core::mem::offset_of!(PerCpu, kernel_stack);
+pub const USER_STACK_OFFSET: usize = core::mem::offset_of!(PerCpu, user_stack);
+
+////////////////////////////////
+// Structs, Types, Traits, Impl
+////////////////////////////////
+
+#[repr(C)]
+pub struct PerCpu {
+    // Must stay the first field, see `this_cpu`
+    self_addr: AtomicUsize,
+    id: AtomicUsize,
+    // Read and written by the `syscall` entry stub, see syscall.rs
+    kernel_stack: AtomicU64,
+    user_stack: AtomicU64,
+    pub irq_stats: CpuStats,
+    pub watchdog: CpuWatchdog,
+    pub fpu: CpuFpu,
+    pub threads: CpuThreads,
+    pub locks: CpuLocks,
+}
+
+impl PerCpu {
+    const INIT: PerCpu = PerCpu {
+        self_addr: AtomicUsize::new(0),
+        id: AtomicUsize::new(0),
+        kernel_stack: AtomicU64::new(0),
+        user_stack: AtomicU64::new(0),
+        irq_stats: CpuStats::INIT,
+        watchdog: CpuWatchdog::INIT,
+        fpu: CpuFpu::INIT,
+        threads: CpuThreads::INIT,
+        locks: CpuLocks::INIT,
+    };
+
+    // Index of the CPU, the BSP is 0
+    pub fn id(&self) -> usize {
+        self.id.load(Ordering::Relaxed)
+    }
+
+    // Top of the stack entries from user mode switch to, use
+    // `gdt::set_kernel_stack` which also updates the TSS
+    pub fn set_kernel_stack(&self, stack_top: VirtAddr) {
+        self.kernel_stack.store(stack_top.as_u64(), Ordering::Relaxed);
+    }
+}
+
+// Guard switching to the kernel GS base on entry from user mode, restoring
+// the user GS base when dropped. Does nothing when entered from kernel mode.
+pub struct KernelGs {
+    swapped: bool,
+}
+
+impl KernelGs {
+    // Unsafe! Must run on kernel entry before any per-CPU access, and
+    // `code_segment` must be the CS of the interrupted context.
+    pub unsafe fn enter(code_segment: u64) -> KernelGs {
+        use x86_64::instructions::segmentation::GS;
+
+        let swapped = code_segment & 0b11 == 3;
+        if swapped {
+            GS::swap();
+        }
+        KernelGs { swapped }
+    }
+
+    // Unsafe! Same as `enter`, for handlers that can interrupt the kernel
+    // between a user mode entry and its `swapgs`, i.e. NMIs. Looks at the GS
+    // base itself instead of trusting the interrupted CS.
+    pub unsafe fn enter_paranoid() -> KernelGs {
+        use x86_64::instructions::segmentation::GS;
+        use x86_64::registers::model_specific::GsBase;
+
+        let swapped = !is_percpu_area(GsBase::read().as_u64() as usize);
+        if swapped {
+            GS::swap();
+        }
+        KernelGs { swapped }
+    }
+}
+
+impl Drop for KernelGs {
+    fn drop(&mut self) {
+        use x86_64::instructions::segmentation::GS;
+
+        if self.swapped {
+            unsafe { GS::swap() };
+        }
+    }
+}
+
+//////////////////////////////
+// API
+//////////////////////////////
+
+// Point the GS base of the calling CPU at the per-CPU area of `cpu`. Must be
+// the first thing a CPU does, before interrupts are enabled.
+pub fn init(cpu: usize) {
+    use x86_64::registers::model_specific::{GsBase, KernelGsBase};
+
+    let percpu = &CPUS[cpu];
+    percpu.self_addr.store(percpu as *const PerCpu as usize, Ordering::SeqCst);
+    percpu.id.store(cpu, Ordering::SeqCst);
+
+    GsBase::write(VirtAddr::from_ptr(percpu));
+    KernelGsBase::write(VirtAddr::new(0));
+}
+
+// Per-CPU data of the calling CPU. Note the caller may be migrated to
+// another CPU unless interrupts are disabled.
+pub fn this_cpu() -> &'static PerCpu {
+    let addr: usize;
+    unsafe {
+        core::arch::asm!(
+            "mov {}, gs:[0]",
+            out(reg) addr,
+            options(nostack, preserves_flags, readonly)
+        );
+        &*(addr as *const PerCpu)
+    }
+}
+
+// Same as `this_cpu`, but None instead of a fault before `init` ran on the
+// calling CPU. Reads the GS base MSR, so slower.
+pub fn try_this_cpu() -> Option<&'static PerCpu> {
+    use x86_64::registers::model_specific::GsBase;
+
+    if is_percpu_area(GsBase::read().as_u64() as usize) {
+        Some(this_cpu())
+    } else {
+        None
+    }
+}
+
+pub fn cpu_id() -> usize {
+    this_cpu().id()
+}
+
+// Per-CPU data of any CPU, e.g. to aggregate statistics
+pub fn cpu(id: usize) -> &'static PerCpu {
+    &CPUS[id]
+}
+
+fn is_percpu_area(addr: usize) -> bool {
+    let start = CPUS.as_ptr() as usize;
+    let end = start + core::mem::size_of_val(&CPUS);
+    (start..end).contains(&addr)
+}
+
+//////////////////////////////
+// Tests
+//////////////////////////////
+
+#[test_case]
+fn test_this_cpu_is_bsp() {
+    assert_eq!(cpu_id(), 0);
+    assert!(core::ptr::eq(this_cpu(), cpu(0)));
+}
@@ -0,0 +1,318 @@
+// pipe.rs - Bounded kernel buffers between a writing and a reading end
+
+use crate::fd::{File, FileError, OpenFile, O_RDONLY, O_WRONLY};
+use crate::sync::{Condvar, Mutex};
+
+use alloc::collections::VecDeque;
+use alloc::sync::Arc;
+use core::any::Any;
+
+//////////////////////////////
+// Statics/Constants
+//////////////////////////////
+
+/*
+ *  Bytes written to the write end are read from the read end in the same
+ *  order, through a buffer of PIPE_CAPACITY bytes:
+ *
+ *    writer --> | write end | --> [ buffer ] --> | read end | --> reader
+ *
+ *  Readers wait while the buffer is empty and writers while it is full,
+ *  until a signal interrupts them.
+ *  Once every descriptor for the write end is closed, readers get what is
+ *  left and then EOF. Once every descriptor for the read end is closed,
+ *  writers get EPIPE, syscall.rs also sends them SIGPIPE.
+ *
+ *  Writes of up to PIPE_BUF bytes are not interleaved with other writes, a
+ *  writer waits until there is room for all of them. Larger ones are
+ *  written in pieces as readers make room.
+ */
+pub const PIPE_CAPACITY: usize = 4096;
+pub const PIPE_BUF: usize = PIPE_CAPACITY;
+
+////////////////////////////////
+// Structs, Types, Traits, Impl
+////////////////////////////////
+
+struct Pipe {
+    state: Mutex<State>,
+    // Notified when bytes are written or the write end closes
+    readable: Condvar,
+    // Notified when bytes are read or the read end closes
+    writable: Condvar,
+}
+
+struct State {
+    buffer: VecDeque<u8>,
+    reader_open: bool,
+    writer_open: bool,
+}
+
+pub struct ReadEnd {
+    pipe: Arc<Pipe>,
+}
+
+pub struct WriteEnd {
+    pipe: Arc<Pipe>,
+}
+
+impl File for ReadEnd {
+    fn read(&self, buffer: &mut [u8]) -> Result<usize, FileError> {
+        if buffer.is_empty() {
+            return Ok(0);
+        }
+        let pipe = &self.pipe;
+        let (mut state, interrupted) = pipe
+            .readable
+            .wait_while_interruptible(pipe.state.lock(), |state| {
+                state.buffer.is_empty() && state.writer_open
+            });
+        if interrupted {
+            return Err(FileError::Interrupted);
+        }
+
+        let count = buffer.len().min(state.buffer.len());
+        for (byte, value) in buffer.iter_mut().zip(state.buffer.drain(..count)) {
+            *byte = value;
+        }
+        drop(state);
+        if count > 0 {
+            pipe.writable.notify_all();
+        }
+        Ok(count)
+    }
+
+    fn write(&self, _buffer: &[u8]) -> Result<usize, FileError> {
+        Err(FileError::BadDescriptor)
+    }
+
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+}
+
+impl Drop for ReadEnd {
+    fn drop(&mut self) {
+        self.pipe.state.lock().reader_open = false;
+        self.pipe.writable.notify_all();
+    }
+}
+
+impl File for WriteEnd {
+    fn read(&self, _buffer: &mut [u8]) -> Result<usize, FileError> {
+        Err(FileError::BadDescriptor)
+    }
+
+    fn write(&self, buffer: &[u8]) -> Result<usize, FileError> {
+        let pipe = &self.pipe;
+        let mut written = 0;
+        while written < buffer.len() {
+            let left = &buffer[written..];
+            // Room for all of a small write, or for anything of a large one
+            let needed = if buffer.len() <= PIPE_BUF {
+                left.len()
+            } else {
+                1
+            };
+            let (mut state, interrupted) = pipe
+                .writable
+                .wait_while_interruptible(pipe.state.lock(), |state| {
+                    state.reader_open && PIPE_CAPACITY - state.buffer.len() < needed
+                });
+
+            if interrupted {
+                return if written > 0 {
+                    Ok(written)
+                } else {
+                    Err(FileError::Interrupted)
+                };
+            }
+            if !state.reader_open {
+                // What was written before the readers left still counts
+                return if written > 0 {
+                    Ok(written)
+                } else {
+                    Err(FileError::BrokenPipe)
+                };
+            }
+            let count = left.len().min(PIPE_CAPACITY - state.buffer.len());
+            state.buffer.extend(&left[..count]);
+            drop(state);
+            pipe.readable.notify_all();
+            written += count;
+        }
+        Ok(written)
+    }
+
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+}
+
+impl Drop for WriteEnd {
+    fn drop(&mut self) {
+        self.pipe.state.lock().writer_open = false;
+        self.pipe.readable.notify_all();
+    }
+}
+
+//////////////////////////////
+// API
+//////////////////////////////
+
+// A new pipe as its read end and its write end
+pub fn pipe() -> (Arc<OpenFile>, Arc<OpenFile>) {
+    let pipe = Arc::new(Pipe {
+        state: Mutex::new(State {
+            buffer: VecDeque::with_capacity(PIPE_CAPACITY),
+            reader_open: true,
+            writer_open: true,
+        }),
+        readable: Condvar::new(),
+        writable: Condvar::new(),
+    });
+    let read_end = OpenFile::new(ReadEnd { pipe: pipe.clone() }, O_RDONLY);
+    let write_end = OpenFile::new(WriteEnd { pipe }, O_WRONLY);
+    (read_end, write_end)
+}
+
+//////////////////////////////
+// Tests
+//////////////////////////////
+
+#[test_case]
+fn test_pipe_eof_and_broken_pipe() {
+    let (reader, writer) = pipe();
+    let mut buffer = [0u8; 16];
+    assert_eq!(writer.write(b"hello"), Ok(5));
+    assert_eq!(writer.read(&mut buffer), Err(FileError::BadDescriptor));
+    assert_eq!(reader.read(&mut buffer[..3]), Ok(3));
+    assert_eq!(&buffer[..3], b"hel");
+
+    // What is left, then EOF
+    drop(writer);
+    assert_eq!(reader.read(&mut buffer), Ok(2));
+    assert_eq!(&buffer[..2], b"lo");
+    assert_eq!(reader.read(&mut buffer), Ok(0));
+
+    let (reader, writer) = pipe();
+    drop(reader);
+    assert_eq!(writer.write(b"lost"), Err(FileError::BrokenPipe));
+}
+
+#[test_case]
+fn test_pipe_blocks_both_ends() {
+    use crate::thread;
+    use alloc::vec::Vec;
+
+    let (reader, writer) = pipe();
+    // More than fits, the writer waits for the reader to make room
+    let data: Vec<u8> = (0..3 * PIPE_CAPACITY).map(|i| i as u8).collect();
+    let expected = data.clone();
+    let handle = thread::spawn("pipe writer", move || writer.write(&data));
+
+    let mut received = Vec::new();
+    let mut buffer = [0u8; 1000];
+    loop {
+        // Blocks until the writer writes, or closes its end when done
+        match reader.read(&mut buffer) {
+            Ok(0) => break,
+            Ok(count) => received.extend_from_slice(&buffer[..count]),
+            Err(error) => panic!("read failed: {:?}", error),
+        }
+    }
+    assert_eq!(handle.join(), Ok(3 * PIPE_CAPACITY));
+    assert_eq!(received, expected);
+}
+
+#[test_case]
+fn test_pipeline_between_programs() {
+    use crate::elf::{self, PF_R, PF_X, TEST_BASE};
+    use crate::fd::{FileTable, STDIN, STDOUT};
+    use crate::process::{self, ExitStatus};
+    use crate::signal::SIGPIPE;
+
+    // write(1, "hello, pipe!\n", 13), exit with what write returned
+    let writer = [
+        0xb8, 0x01, 0x00, 0x00, 0x00, 0xbf, 0x01, 0x00, 0x00, 0x00, 0x48, 0x8d, 0x35, 0x0f, 0x00,
+        0x00, 0x00, 0xba, 0x0d, 0x00, 0x00, 0x00, 0x0f, 0x05, 0x89, 0xc7, 0x31, 0xc0, 0x0f, 0x05,
+        0xeb, 0xfe, 0x68, 0x65, 0x6c, 0x6c, 0x6f, 0x2c, 0x20, 0x70, 0x69, 0x70, 0x65, 0x21, 0x0a,
+    ];
+    // read(0, below the stack pointer, 5) until EOF, exit with the total
+    let reader = [
+        0x31, 0xdb, 0xb8, 0x0d, 0x00, 0x00, 0x00, 0x31, 0xff, 0x48, 0x8d, 0x74, 0x24, 0xc0, 0xba,
+        0x05, 0x00, 0x00, 0x00, 0x0f, 0x05, 0x48, 0x85, 0xc0, 0x7e, 0x04, 0x01, 0xc3, 0xeb, 0xe4,
+        0x89, 0xdf, 0x31, 0xc0, 0x0f, 0x05, 0xeb, 0xfe,
+    ];
+    let spawn = |name: &str, code: &[u8], fd: u64, file: &Arc<OpenFile>| {
+        let image = elf::test_image(TEST_BASE, &[(PF_R | PF_X, TEST_BASE, code, code.len() as u64)]);
+        let mut files = FileTable::standard();
+        files.install(fd, file).expect("install failed");
+        let pid = elf::spawn_with(name, &image, &[name], &[], files)
+            .expect("spawn failed")
+            .pid();
+        move || process::waitpid(Some(pid), false).expect("waitpid failed").map(|(_, status)| status)
+    };
+
+    // writer | reader, the reader sees EOF once the writer has exited
+    let (read_end, write_end) = pipe();
+    let writer_done = spawn("writer", &writer, STDOUT, &write_end);
+    let reader_done = spawn("reader", &reader, STDIN, &read_end);
+    drop((read_end, write_end));
+    assert_eq!(writer_done(), Some(ExitStatus::Exited(13)));
+    assert_eq!(reader_done(), Some(ExitStatus::Exited(13)));
+
+    // Without a reader the writer gets SIGPIPE
+    let (read_end, write_end) = pipe();
+    drop(read_end);
+    let writer_done = spawn("writer", &writer, STDOUT, &write_end);
+    drop(write_end);
+    let signaled = ExitStatus::Signaled {
+        signal: SIGPIPE,
+        core: false,
+    };
+    assert_eq!(writer_done(), Some(signaled));
+}
+
+#[test_case]
+fn test_kill_interrupts_blocked_read() {
+    use crate::elf::{self, PF_R, PF_X, TEST_BASE};
+    use crate::fd::{FileTable, STDIN};
+    use crate::process::{self, ExitStatus};
+    use crate::signal::{self, SIGTERM};
+    use crate::thread;
+    use core::time::Duration;
+
+    // read(0, below the stack pointer, 5) until EOF, exit with the total
+    let reader = [
+        0x31, 0xdb, 0xb8, 0x0d, 0x00, 0x00, 0x00, 0x31, 0xff, 0x48, 0x8d, 0x74, 0x24, 0xc0, 0xba,
+        0x05, 0x00, 0x00, 0x00, 0x0f, 0x05, 0x48, 0x85, 0xc0, 0x7e, 0x04, 0x01, 0xc3, 0xeb, 0xe4,
+        0x89, 0xdf, 0x31, 0xc0, 0x0f, 0x05, 0xeb, 0xfe,
+    ];
+    let image = elf::test_image(
+        TEST_BASE,
+        &[(PF_R | PF_X, TEST_BASE, &reader[..], reader.len() as u64)],
+    );
+
+    // The write end stays open, so the read waits for good
+    let (read_end, write_end) = pipe();
+    let mut files = FileTable::standard();
+    files.install(STDIN, &read_end).expect("install failed");
+    drop(read_end);
+    let pid = elf::spawn_with("reader", &image, &["reader"], &[], files)
+        .expect("spawn failed")
+        .pid();
+
+    // Time to get into the read, the signal ends it either way
+    thread::sleep(Duration::from_millis(50));
+    signal::send(pid, SIGTERM).expect("kill failed");
+    let signaled = ExitStatus::Signaled {
+        signal: SIGTERM,
+        core: false,
+    };
+    assert_eq!(
+        process::waitpid(Some(pid), false).expect("waitpid failed"),
+        Some((pid, signaled))
+    );
+    drop(write_end);
+}
@@ -0,0 +1,610 @@
+// process.rs - Processes, their threads, exit status and wait
+
+use crate::address_space::{AddressSpace, MapError};
+use crate::fd::FileTable;
+use crate::signal::{self, Signals, SIGCHLD};
+use crate::spinlock::{IrqSpinlock, IrqSpinlockGuard};
+use crate::sync::{WaitQueue, WaitResult};
+use crate::thread::{self, JoinHandle};
+
+use alloc::collections::BTreeMap;
+use alloc::string::{String, ToString};
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::fmt;
+use core::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+
+//////////////////////////////
+// Statics/Constants
+//////////////////////////////
+
+/*
+ *  A process is an address space and the threads running in it. Every
+ *  process but the kernel's has a parent, the process that created it:
+ *
+ *    kernel (0)
+ *      +-- shell (1)
+ *      |     +-- ls (3)     Zombie(Exited(0)), until shell waits for it
+ *      +-- init (2)
+ *
+ *  A process has a table of open file descriptors, see fd.rs, a copy of
+ *  its creator's when it is created.
+ *
+ *  A process exits once its last thread does, with the code passed to
+ *  `exit`, 0 if its threads just returned, or killed by a signal. It then
+ *  releases its address space and becomes a zombie that only keeps its
+ *  exit status, until the parent collects it with `wait` or `waitpid`,
+ *  which reaps it. The parent is sent SIGCHLD.
+ *
+ *  Children outliving their parent are handed to the kernel process. No
+ *  one waits for those, so they are reaped as soon as they exit.
+ *
+ *  The kernel process owns the kernel threads, runs in the kernel's page
+ *  table and never exits. Its threads may create processes and wait for
+ *  them like any other.
+ */
+pub const KERNEL_PID: Pid = Pid(0);
+
+static NEXT_PID: AtomicU64 = AtomicU64::new(1);
+
+// Every process that has not been reaped, with its place in the tree. A
+// single lock keeps parent, children and state consistent with each other.
+static PROCESSES: IrqSpinlock<BTreeMap<Pid, Node>> = IrqSpinlock::new("PROCESSES", BTreeMap::new());
+
+////////////////////////////////
+// Structs, Types, Traits, Impl
+////////////////////////////////
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Pid(u64);
+
+impl Pid {
+    pub fn new(pid: u64) -> Pid {
+        Pid(pid)
+    }
+
+    pub fn as_u64(self) -> u64 {
+        self.0
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum State {
+    Running,
+    // Exited, waiting to be reaped
+    Zombie(ExitStatus),
+}
+
+// How a process ended, as `waitpid` reports it
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ExitStatus {
+    // With the code passed to `exit`
+    Exited(i32),
+    // By the default action of the signal, `core` if that dumps core, see
+    // signal.rs
+    Signaled { signal: u32, core: bool },
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ProcessError {
+    OutOfMemory,
+    // `wait` without any children
+    NoChildren,
+    // `waitpid` for a process that is not a child of the caller
+    NotChild,
+    // A signal ended the wait, see sync.rs
+    Interrupted,
+}
+
+impl From<MapError> for ProcessError {
+    fn from(_: MapError) -> Self {
+        ProcessError::OutOfMemory
+    }
+}
+
+pub struct Process {
+    pid: Pid,
+    name: String,
+    // Taken when the process exits, the threads hold it until they are off
+    // the CPU. None for the kernel process.
+    address_space: IrqSpinlock<Option<Arc<AddressSpace>>>,
+    live_threads: AtomicUsize,
+    // Set by `terminate`, reported once the last thread is gone
+    exit_status: IrqSpinlock<Option<ExitStatus>>,
+    // Woken when a child becomes a zombie
+    child_exited: WaitQueue,
+    signals: IrqSpinlock<Signals>,
+    // Emptied when the process exits. Files must not be dropped with the
+    // lock held, see `FileTable`.
+    files: IrqSpinlock<FileTable>,
+}
+
+impl Process {
+    pub fn pid(&self) -> Pid {
+        self.pid
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn address_space(&self) -> Option<Arc<AddressSpace>> {
+        self.address_space.lock().clone()
+    }
+
+    pub fn thread_count(&self) -> usize {
+        self.live_threads.load(Ordering::Relaxed)
+    }
+
+    // Whether a thread has ended the process, and the others are to follow
+    pub fn is_exiting(&self) -> bool {
+        self.exit_status.lock().is_some()
+    }
+
+    // Pending and blocked signals and their actions, see signal.rs
+    pub fn signals(&self) -> IrqSpinlockGuard<'_, Signals> {
+        self.signals.lock()
+    }
+
+    pub fn files(&self) -> IrqSpinlockGuard<'_, FileTable> {
+        self.files.lock()
+    }
+
+    pub fn parent(&self) -> Option<Pid> {
+        PROCESSES.lock().get(&self.pid).and_then(|node| node.parent)
+    }
+
+    // Running until reaped, then gone from the table
+    pub fn state(&self) -> Option<State> {
+        PROCESSES.lock().get(&self.pid).map(|node| node.state)
+    }
+
+    pub fn children(&self) -> Vec<Pid> {
+        match PROCESSES.lock().get(&self.pid) {
+            Some(node) => node.children.clone(),
+            None => Vec::new(),
+        }
+    }
+}
+
+struct Node {
+    process: Arc<Process>,
+    parent: Option<Pid>,
+    children: Vec<Pid>,
+    state: State,
+    // Handed to the kernel process when the parent exited
+    orphan: bool,
+}
+
+// Displays every process, similar to `ps`
+pub struct Table;
+
+impl fmt::Display for Table {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let nodes: Vec<(Arc<Process>, Option<Pid>, State)> = PROCESSES
+            .lock()
+            .values()
+            .map(|node| (node.process.clone(), node.parent, node.state))
+            .collect();
+
+        writeln!(
+            f,
+            "{:>5}  {:>5}  {:<16}  {:<8}  {:>7}",
+            "PID", "PPID", "NAME", "STATE", "THREADS"
+        )?;
+        for (process, parent, state) in nodes.iter() {
+            writeln!(
+                f,
+                "{:>5}  {:>5}  {:<16}  {:<8}  {:>7}",
+                process.pid().as_u64(),
+                parent.map_or(0, Pid::as_u64),
+                process.name(),
+                // Formatting with width needs a str, not Debug
+                match state {
+                    State::Running => "Running",
+                    State::Zombie(_) => "Zombie",
+                },
+                process.thread_count()
+            )?;
+        }
+        Ok(())
+    }
+}
+
+//////////////////////////////
+// API
+//////////////////////////////
+
+// Create the kernel process, which owns every kernel thread. Requires the
+// heap.
+pub fn init() {
+    let kernel = Arc::new(Process {
+        pid: KERNEL_PID,
+        name: "kernel".to_string(),
+        address_space: IrqSpinlock::new("Process::address_space", None),
+        live_threads: AtomicUsize::new(0),
+        exit_status: IrqSpinlock::new("Process::exit_status", None),
+        child_exited: WaitQueue::new(),
+        signals: IrqSpinlock::new("signals", Signals::new()),
+        files: IrqSpinlock::new("files", FileTable::standard()),
+    });
+    PROCESSES.lock().insert(
+        KERNEL_PID,
+        Node {
+            process: kernel,
+            parent: None,
+            children: Vec::new(),
+            state: State::Running,
+            orphan: false,
+        },
+    );
+}
+
+// A new process with an empty address space and no threads yet, a child of
+// the calling thread's process with a copy of its file descriptors
+pub fn create(name: &str) -> Result<Arc<Process>, ProcessError> {
+    Ok(create_in(name, AddressSpace::new()?))
+}
+
+// Same as `create`, with an address space the caller already filled
+pub fn create_in(name: &str, address_space: AddressSpace) -> Arc<Process> {
+    let files = current().files().clone();
+    let process = Arc::new(Process {
+        pid: Pid(NEXT_PID.fetch_add(1, Ordering::Relaxed)),
+        name: name.to_string(),
+        address_space: IrqSpinlock::new("Process::address_space", Some(Arc::new(address_space))),
+        live_threads: AtomicUsize::new(0),
+        exit_status: IrqSpinlock::new("Process::exit_status", None),
+        child_exited: WaitQueue::new(),
+        signals: IrqSpinlock::new("signals", Signals::new()),
+        files: IrqSpinlock::new("files", files),
+    });
+
+    let parent = current_pid();
+    let mut processes = PROCESSES.lock();
+    processes
+        .get_mut(&parent)
+        .expect("process::init has not been called")
+        .children
+        .push(process.pid);
+    processes.insert(
+        process.pid,
+        Node {
+            process: process.clone(),
+            parent: Some(parent),
+            children: Vec::new(),
+            state: State::Running,
+            orphan: false,
+        },
+    );
+    process
+}
+
+// Start running `f` in a new thread of `process`, in its address space
+// and in ring 0. Fails once the process has exited.
+pub fn spawn_thread<F>(process: &Arc<Process>, name: &str, f: F) -> Option<JoinHandle<()>>
+where
+    F: FnOnce() + Send + 'static,
+{
+    // Counted with the address space locked, so that `thread_exited`
+    // cannot let the process exit in between
+    let address_space = process.address_space.lock();
+    let space = address_space.clone()?;
+    process.live_threads.fetch_add(1, Ordering::Relaxed);
+    drop(address_space);
+    Some(thread::spawn_in(name, process.pid, Some(space), f))
+}
+
+// Create a process running `f` in its first thread
+pub fn spawn<F>(name: &str, f: F) -> Result<Arc<Process>, ProcessError>
+where
+    F: FnOnce() + Send + 'static,
+{
+    let process = create(name)?;
+    spawn_thread(&process, name, f).expect("new process has exited");
+    Ok(process)
+}
+
+// End the calling thread's process with `code`
+pub fn exit(code: i32) -> ! {
+    terminate(ExitStatus::Exited(code))
+}
+
+// End the calling thread's process with `status`, unless another thread
+// ended it first. The caller's thread ends at once, the others of the
+// process when they next exit or return to user mode.
+pub fn terminate(status: ExitStatus) -> ! {
+    let process = current();
+    assert!(process.pid != KERNEL_PID, "the kernel process cannot exit");
+    process.exit_status.lock().get_or_insert(status);
+    drop(process);
+    thread::exit();
+}
+
+// Wait for any child to exit, reap it and return its PID and exit status
+pub fn wait() -> Result<(Pid, ExitStatus), ProcessError> {
+    waitpid(None, false).map(|reaped| reaped.expect("blocking wait returned nothing"))
+}
+
+// Wait for the child `pid` to exit, or for any child if None, then reap it
+// and return its PID and exit status. With `no_hang` returns None at once
+// if no such child has exited yet. A signal for the caller ends the wait.
+pub fn waitpid(
+    pid: Option<Pid>,
+    no_hang: bool,
+) -> Result<Option<(Pid, ExitStatus)>, ProcessError> {
+    let parent = current();
+    loop {
+        {
+            // Found and reaped under one lock, so racing waiters reap it once
+            let mut processes = PROCESSES.lock();
+            if let Some(zombie) = find_zombie(&processes, parent.pid, pid)? {
+                let status = reap(&mut processes, zombie).expect("zombie not in table");
+                return Ok(Some((zombie, status)));
+            }
+        }
+        if no_hang {
+            return Ok(None);
+        }
+        let result = parent.child_exited.wait_interruptible(None, || {
+            find_zombie(&PROCESSES.lock(), parent.pid, pid) != Ok(None)
+        });
+        if result == WaitResult::Interrupted {
+            return Err(ProcessError::Interrupted);
+        }
+    }
+}
+
+// Process of the calling thread
+pub fn current() -> Arc<Process> {
+    get(current_pid()).expect("process::init has not been called")
+}
+
+pub fn current_pid() -> Pid {
+    thread::current().process()
+}
+
+// None once the process has been reaped
+pub fn get(pid: Pid) -> Option<Arc<Process>> {
+    PROCESSES.lock().get(&pid).map(|node| node.process.clone())
+}
+
+// Processes that have not been reaped, including the kernel's
+pub fn count() -> usize {
+    PROCESSES.lock().len()
+}
+
+// Usage: println!("{}", process::table());
+pub fn table() -> Table {
+    Table
+}
+
+// Called by `thread::exit` for threads of user processes. The last one
+// makes its process a zombie.
+pub(crate) fn thread_exited(pid: Pid) {
+    let process = match get(pid) {
+        Some(process) => process,
+        None => return,
+    };
+    let last = {
+        let mut address_space = process.address_space.lock();
+        let last = process.live_threads.fetch_sub(1, Ordering::Relaxed) == 1;
+        if last {
+            // The exiting threads hold their own references until they are
+            // off the CPU
+            address_space.take();
+        }
+        last
+    };
+    if !last {
+        return;
+    }
+    // Closed before the parent hears of it, the other ends of pipes see
+    // EOF as soon as the process is gone
+    let files = core::mem::take(&mut *process.files());
+    drop(files);
+
+    let status = process.exit_status.lock().unwrap_or(ExitStatus::Exited(0));
+    let parent = {
+        let mut processes = PROCESSES.lock();
+        let node = processes
+            .get_mut(&pid)
+            .expect("exiting process not in table");
+        node.state = State::Zombie(status);
+        let children = core::mem::take(&mut node.children);
+        let (parent, orphan) = (node.parent, node.orphan);
+
+        for child in children {
+            adopt(&mut processes, child);
+        }
+        if orphan {
+            reap(&mut processes, pid);
+            return;
+        }
+        parent.and_then(|parent| processes.get(&parent).map(|node| node.process.clone()))
+    };
+    if let Some(parent) = parent {
+        parent.child_exited.wake_all();
+        if parent.pid != KERNEL_PID {
+            // Fails only if the parent has exited meanwhile
+            let _ = signal::send(parent.pid, SIGCHLD);
+        }
+    }
+}
+
+// Make the kernel process the parent of `pid`, whose parent exited
+fn adopt(processes: &mut BTreeMap<Pid, Node>, pid: Pid) {
+    let node = processes.get_mut(&pid).expect("child not in table");
+    node.parent = Some(KERNEL_PID);
+    node.orphan = true;
+    if let State::Zombie(_) = node.state {
+        processes.remove(&pid);
+    } else {
+        processes
+            .get_mut(&KERNEL_PID)
+            .expect("no kernel process")
+            .children
+            .push(pid);
+    }
+}
+
+// Remove the zombie `pid` from the table and from its parent's children
+fn reap(processes: &mut BTreeMap<Pid, Node>, pid: Pid) -> Option<ExitStatus> {
+    let node = processes.remove(&pid)?;
+    if let Some(parent) = node.parent.and_then(|parent| processes.get_mut(&parent)) {
+        parent.children.retain(|&child| child != pid);
+    }
+    match node.state {
+        State::Zombie(status) => Some(status),
+        State::Running => panic!("reaped running process {}", pid.0),
+    }
+}
+
+// A zombie among the children of `parent` that `pid` selects
+fn find_zombie(
+    processes: &BTreeMap<Pid, Node>,
+    parent: Pid,
+    pid: Option<Pid>,
+) -> Result<Option<Pid>, ProcessError> {
+    let children = match processes.get(&parent) {
+        Some(node) => &node.children,
+        None => return Err(ProcessError::NoChildren),
+    };
+    match pid {
+        Some(pid) if !children.contains(&pid) => Err(ProcessError::NotChild),
+        None if children.is_empty() => Err(ProcessError::NoChildren),
+        _ => Ok(children
+            .iter()
+            .copied()
+            .filter(|&child| pid.is_none_or(|pid| pid == child))
+            .find(|child| matches!(processes[child].state, State::Zombie(_)))),
+    }
+}
+
+//////////////////////////////
+// Tests
+//////////////////////////////
+
+#[test_case]
+fn test_exit_code_reaches_waitpid() {
+    use x86_64::structures::paging::{Page, PageTableFlags};
+    use x86_64::VirtAddr;
+
+    let child = spawn("exit42", || {
+        // The process's own memory, mapped in its address space only
+        let page = Page::containing_address(VirtAddr::new(0x5555_0000_0000));
+        let space = current()
+            .address_space()
+            .expect("process without address space");
+        space
+            .map(page, PageTableFlags::WRITABLE)
+            .expect("mapping failed");
+        unsafe { page.start_address().as_mut_ptr::<u64>().write_volatile(42) };
+        let value = unsafe { page.start_address().as_ptr::<u64>().read_volatile() };
+        exit(value as i32);
+    })
+    .expect("spawn failed");
+
+    let pid = child.pid();
+    assert_eq!(child.parent(), Some(KERNEL_PID));
+    assert_eq!(
+        waitpid(Some(pid), false),
+        Ok(Some((pid, ExitStatus::Exited(42))))
+    );
+
+    // Reaped, so no longer anyone's child
+    assert!(get(pid).is_none());
+    assert_eq!(child.state(), None);
+    assert_eq!(waitpid(Some(pid), false), Err(ProcessError::NotChild));
+}
+
+#[test_case]
+fn test_zombie_until_waited_for() {
+    let child = spawn("zombie", || {}).expect("spawn failed");
+    let pid = child.pid();
+
+    while child.state() != Some(State::Zombie(ExitStatus::Exited(0))) {
+        thread::yield_now();
+    }
+    assert!(get(pid).is_some());
+    assert!(child.address_space().is_none());
+    assert_eq!(wait(), Ok((pid, ExitStatus::Exited(0))));
+    assert!(get(pid).is_none());
+    assert_eq!(waitpid(None, true), Err(ProcessError::NoChildren));
+}
+
+#[test_case]
+fn test_waitpid_no_hang() {
+    use crate::sync::Semaphore;
+
+    let release = Arc::new(Semaphore::new(0));
+    let held = release.clone();
+    let child = spawn("held", move || held.acquire()).expect("spawn failed");
+    let pid = child.pid();
+
+    assert_eq!(waitpid(Some(pid), true), Ok(None));
+    release.release();
+    assert_eq!(
+        waitpid(Some(pid), false),
+        Ok(Some((pid, ExitStatus::Exited(0))))
+    );
+}
+
+#[test_case]
+fn test_process_exits_with_last_thread() {
+    use crate::sync::Semaphore;
+
+    let release = Arc::new(Semaphore::new(0));
+    let held = release.clone();
+    let child = spawn("threads", move || {
+        let process = current();
+        spawn_thread(&process, "second", move || held.acquire()).expect("process has exited");
+        exit(3);
+    })
+    .expect("spawn failed");
+    let pid = child.pid();
+
+    // The second thread keeps it alive after the first one exited
+    while child.thread_count() != 1 {
+        thread::yield_now();
+    }
+    assert_eq!(waitpid(Some(pid), true), Ok(None));
+    release.release();
+    assert_eq!(
+        waitpid(Some(pid), false),
+        Ok(Some((pid, ExitStatus::Exited(3))))
+    );
+}
+
+#[test_case]
+fn test_orphans_are_adopted() {
+    use crate::sync::Semaphore;
+
+    let release = Arc::new(Semaphore::new(0));
+    let held = release.clone();
+    let grandchild = Arc::new(IrqSpinlock::new("test", None));
+    let slot = grandchild.clone();
+    let child = spawn("parent", move || {
+        let orphan = spawn("orphan", move || held.acquire()).expect("spawn failed");
+        *slot.lock() = Some(orphan);
+    })
+    .expect("spawn failed");
+
+    let pid = child.pid();
+    assert_eq!(
+        waitpid(Some(pid), false),
+        Ok(Some((pid, ExitStatus::Exited(0))))
+    );
+    let orphan: Arc<Process> = grandchild.lock().take().expect("no grandchild");
+    assert_eq!(orphan.parent(), Some(KERNEL_PID));
+
+    // Reaped without anyone waiting
+    release.release();
+    while get(orphan.pid()).is_some() {
+        thread::yield_now();
+    }
+    assert_eq!(
+        waitpid(Some(orphan.pid()), true),
+        Err(ProcessError::NotChild)
+    );
+}
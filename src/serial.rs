@@ -1,8 +1,8 @@
 // serial.rs - Defines UART16550 Serial Port HAL
 
 
+use crate::spinlock::IrqSpinlock;
 use uart_16550::SerialPort;
-use spin::Mutex;
 use lazy_static::lazy_static;
 
 ////////////////////////////////
@@ -22,11 +22,22 @@ use lazy_static::lazy_static;
  * 
  */
 
+pub const COM1: u16 = 0x3F8;
+pub const COM2: u16 = 0x2F8;
+
+// Register offsets from the port base
+const SCRATCH: u16 = 7;
+const LINE_STATUS: u16 = 5;
+
+// Line Status Register bits
+const DATA_READY: u8 = 1 << 0;
+const TRANSMIT_EMPTY: u8 = 1 << 5;
+
 lazy_static! {
-    pub static ref SERIAL1: Mutex<SerialPort> = {
-        let mut serial_port = unsafe { SerialPort::new(0x3F8) };
+    pub static ref SERIAL1: IrqSpinlock<SerialPort> = {
+        let mut serial_port = unsafe { SerialPort::new(COM1) };
         serial_port.init();
-        Mutex::new(serial_port)
+        IrqSpinlock::new("SERIAL1", serial_port)
     };
 }
 
@@ -38,12 +49,68 @@ lazy_static! {
 #[doc(hidden)]
 pub fn _print(args: ::core::fmt::Arguments) {
     use core::fmt::Write;
-    use x86_64::instructions::interrupts;
 
-    interrupts::without_interrupts(|| {
-        SERIAL1.lock().write_fmt(args).expect("Serial write failure");
-    })
+    SERIAL1.lock().write_fmt(args).expect("Serial write failure");
+}
+
+// Bypasses the SERIAL1 lock, for NMI and crash paths that may have
+// interrupted its holder. Output can interleave with other writers.
+#[doc(hidden)]
+pub fn _emergency_print(args: ::core::fmt::Arguments) {
+    use core::fmt::Write;
+
+    // COM1 is already initialized, a second handle only borrows its registers
+    let mut serial_port = unsafe { SerialPort::new(COM1) };
+    let _ = serial_port.write_fmt(args);
+}
+
+// Byte received on the UART at `port`, if any. Polls the port directly
+// without any lock, for the debuggers which run with interrupts disabled.
+pub fn try_read_byte(port: u16) -> Option<u8> {
+    use x86_64::instructions::port::Port;
+
+    let mut line_status = Port::<u8>::new(port + LINE_STATUS);
+    let mut data = Port::<u8>::new(port);
+    unsafe {
+        if line_status.read() & DATA_READY == 0 {
+            return None;
+        }
+        Some(data.read())
+    }
+}
+
+// Whether a byte is waiting at `port`, without reading it
+pub fn has_input(port: u16) -> bool {
+    use x86_64::instructions::port::Port;
+
+    let mut line_status = Port::<u8>::new(port + LINE_STATUS);
+    unsafe { line_status.read() & DATA_READY != 0 }
+}
+
+// Send a byte as is, unlike `SerialPort::send` which expands backspace
+pub fn write_byte(port: u16, byte: u8) {
+    use x86_64::instructions::port::Port;
 
+    let mut line_status = Port::<u8>::new(port + LINE_STATUS);
+    let mut data = Port::<u8>::new(port);
+    unsafe {
+        while line_status.read() & TRANSMIT_EMPTY == 0 {
+            core::hint::spin_loop();
+        }
+        data.write(byte);
+    }
+}
+
+// Whether a UART answers at `port`, its scratch register reads back what was
+// written to it
+pub fn is_present(port: u16) -> bool {
+    use x86_64::instructions::port::Port;
+
+    let mut scratch = Port::<u8>::new(port + SCRATCH);
+    unsafe {
+        scratch.write(0x5a);
+        scratch.read() == 0x5a
+    }
 }
 
 // Print to host through serial interface
@@ -65,6 +132,24 @@ macro_rules! serial_println {
 }
 
 
+// Print to host through serial interface without taking any lock. Only for
+// code that must not block, see `_emergency_print`.
+#[macro_export]
+macro_rules! serial_emergency_print {
+    ($($arg:tt)*) => ($crate::serial::_emergency_print(format_args!($($arg)*)));
+}
+
+
+// Same as `serial_emergency_print!` and append a newline
+#[macro_export]
+macro_rules! serial_emergency_println {
+    () => ($crate::serial_emergency_print!("\n"));
+    ($fmt:expr) => ($crate::serial_emergency_print!(concat!($fmt, "\n")));
+    ($fmt:expr, $($arg:tt)*) => ($crate::serial_emergency_print!(
+        concat!($fmt, "\n"), $($arg)*));
+}
+
+
 ////////////////////////////////
 // Tests
 ////////////////////////////////
@@ -0,0 +1,714 @@
+// signal.rs - POSIX-like signals for user processes
+
+use crate::process::{self, ExitStatus, Pid, Process, KERNEL_PID};
+use crate::trap::TrapFrame;
+use crate::usermode::{self, USER_SPACE_END};
+use crate::{println, thread};
+
+use core::mem::size_of;
+
+//////////////////////////////
+// Statics/Constants
+//////////////////////////////
+
+/*
+ *  Signals are sent to processes, with `kill` or by the kernel for an
+ *  exception. Every process has a mask of pending signals, a mask of
+ *  blocked ones and an action per signal. A pending signal that is not
+ *  blocked is delivered when a thread of the process next returns to user
+ *  mode, from a system call, an exception or the timer interrupt. Threads
+ *  of the process in an interruptible wait, e.g. reading an empty pipe,
+ *  are woken and their call fails with EINTR, see sync.rs. Others get the
+ *  signal once their call returns.
+ *
+ *  The action is the signal's default one, to ignore it, or a handler. A
+ *  handler runs on the interrupted thread's stack, below the red zone, on
+ *  top of a frame with what is needed to resume afterwards:
+ *
+ *    |  interrupted stack  |
+ *    |  red zone           |  RED_ZONE bytes
+ *    |  registers          |  TrapFrame of the interrupted context
+ *    |  blocked            |  mask to restore
+ *    |  signal             |
+ *    |  restorer           |  <- rsp, the handler's return address
+ *
+ *  The handler is called as `handler(signal)` and returns to the restorer
+ *  given to `sigaction`, which has to call `sigreturn`. That restores the
+ *  registers and the blocked mask from the frame. While the handler runs
+ *  its signal is blocked, and so are those in the action's mask.
+ *
+ *  Numbers, masks (bit n - 1 for signal n) and the layout of an action are
+ *  those of Linux x86_64. SIGKILL and SIGSTOP cannot be caught, ignored or
+ *  blocked. Processes cannot be stopped, so stop signals are ignored. FPU
+ *  registers are not part of the frame.
+ */
+pub const SIGHUP: u32 = 1;
+pub const SIGINT: u32 = 2;
+pub const SIGQUIT: u32 = 3;
+pub const SIGILL: u32 = 4;
+pub const SIGTRAP: u32 = 5;
+pub const SIGABRT: u32 = 6;
+pub const SIGBUS: u32 = 7;
+pub const SIGFPE: u32 = 8;
+pub const SIGKILL: u32 = 9;
+pub const SIGUSR1: u32 = 10;
+pub const SIGSEGV: u32 = 11;
+pub const SIGUSR2: u32 = 12;
+pub const SIGPIPE: u32 = 13;
+pub const SIGALRM: u32 = 14;
+pub const SIGTERM: u32 = 15;
+pub const SIGSTKFLT: u32 = 16;
+pub const SIGCHLD: u32 = 17;
+pub const SIGCONT: u32 = 18;
+pub const SIGSTOP: u32 = 19;
+pub const SIGTSTP: u32 = 20;
+pub const SIGTTIN: u32 = 21;
+pub const SIGTTOU: u32 = 22;
+pub const SIGURG: u32 = 23;
+pub const SIGXCPU: u32 = 24;
+pub const SIGXFSZ: u32 = 25;
+pub const SIGVTALRM: u32 = 26;
+pub const SIGPROF: u32 = 27;
+pub const SIGWINCH: u32 = 28;
+pub const SIGIO: u32 = 29;
+pub const SIGPWR: u32 = 30;
+pub const SIGSYS: u32 = 31;
+
+// Signals are numbered 1 to NSIG - 1
+pub const NSIG: u32 = 32;
+
+// Handlers with a special meaning
+pub const SIG_DFL: u64 = 0;
+pub const SIG_IGN: u64 = 1;
+
+// `Action` flags. SA_RESTORER is required with a handler.
+pub const SA_RESTORER: u64 = 0x0400_0000;
+pub const SA_NODEFER: u64 = 0x4000_0000;
+pub const SA_RESETHAND: u64 = 0x8000_0000;
+
+// How `sigprocmask` changes the blocked mask
+pub const SIG_BLOCK: u64 = 0;
+pub const SIG_UNBLOCK: u64 = 1;
+pub const SIG_SETMASK: u64 = 2;
+
+const UNBLOCKABLE: u64 = mask(SIGKILL) | mask(SIGSTOP);
+
+// Below the stack pointer, where leaf functions may keep data, System V ABI
+const RED_ZONE: u64 = 128;
+
+// RFLAGS bits a handler may change through its frame, the arithmetic
+// flags, DF, AC and RF. IF and IOPL stay as they are, and so does TF until
+// processes can be debugged.
+const USER_RFLAGS: u64 = 0x5_0cd5;
+const RFLAGS_TF: u64 = 1 << 8;
+const RFLAGS_DF: u64 = 1 << 10;
+
+////////////////////////////////
+// Structs, Types, Traits, Impl
+////////////////////////////////
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SignalError {
+    // Not a signal, or one whose action cannot be changed
+    InvalidSignal,
+    // No such process, or it has been reaped
+    NoSuchProcess,
+    // The kernel process takes no signals
+    NotPermitted,
+    // A handler without SA_RESTORER, or an unknown `how`
+    InvalidArgument,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DefaultAction {
+    Terminate,
+    Ignore,
+    // Terminate, reporting the registers at the time
+    Core,
+}
+
+// What to do when a signal is delivered, laid out as Linux's struct
+// sigaction for the kernel
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(C)]
+pub struct Action {
+    // SIG_DFL, SIG_IGN or the address of the handler
+    pub handler: u64,
+    pub flags: u64,
+    pub restorer: u64,
+    // Also blocked while the handler runs
+    pub mask: u64,
+}
+
+impl Action {
+    pub const DEFAULT: Action = Action {
+        handler: SIG_DFL,
+        flags: 0,
+        restorer: 0,
+        mask: 0,
+    };
+
+    // Whether delivering `signal` does nothing
+    fn ignores(&self, signal: u32) -> bool {
+        self.handler == SIG_IGN
+            || (self.handler == SIG_DFL && default_action(signal) == DefaultAction::Ignore)
+    }
+}
+
+// Signal state of one process
+pub struct Signals {
+    pending: u64,
+    blocked: u64,
+    // Indexed by signal, 0 is unused
+    actions: [Action; NSIG as usize],
+}
+
+impl Signals {
+    pub const fn new() -> Signals {
+        Signals {
+            pending: 0,
+            blocked: 0,
+            actions: [Action::DEFAULT; NSIG as usize],
+        }
+    }
+
+    pub fn pending(&self) -> u64 {
+        self.pending
+    }
+
+    pub fn blocked(&self) -> u64 {
+        self.blocked
+    }
+
+    pub fn action(&self, signal: u32) -> Action {
+        self.actions[signal as usize]
+    }
+
+    // Pending signal to deliver next, lowest number first
+    fn take_next(&mut self) -> Option<u32> {
+        let deliverable = self.pending & !self.blocked;
+        if deliverable == 0 {
+            return None;
+        }
+        let signal = deliverable.trailing_zeros() + 1;
+        self.pending &= !mask(signal);
+        Some(signal)
+    }
+
+    // Make `signal` take its default action, even if it was blocked
+    fn reset(&mut self, signal: u32) {
+        self.actions[signal as usize] = Action::DEFAULT;
+        self.blocked &= !mask(signal);
+    }
+}
+
+impl Default for Signals {
+    fn default() -> Self {
+        Signals::new()
+    }
+}
+
+// Pushed on the user stack for a handler, see above
+#[repr(C)]
+struct SignalFrame {
+    restorer: u64,
+    signal: u64,
+    blocked: u64,
+    registers: TrapFrame,
+}
+
+//////////////////////////////
+// API
+//////////////////////////////
+
+pub const fn mask(signal: u32) -> u64 {
+    1 << (signal - 1)
+}
+
+pub fn default_action(signal: u32) -> DefaultAction {
+    match signal {
+        SIGCHLD | SIGCONT | SIGSTOP | SIGTSTP | SIGTTIN | SIGTTOU | SIGURG | SIGWINCH => {
+            DefaultAction::Ignore
+        }
+        SIGQUIT | SIGILL | SIGTRAP | SIGABRT | SIGBUS | SIGFPE | SIGSEGV | SIGXCPU | SIGXFSZ
+        | SIGSYS => DefaultAction::Core,
+        _ => DefaultAction::Terminate,
+    }
+}
+
+pub fn name(signal: u32) -> &'static str {
+    const NAMES: [&str; NSIG as usize] = [
+        "0",
+        "SIGHUP",
+        "SIGINT",
+        "SIGQUIT",
+        "SIGILL",
+        "SIGTRAP",
+        "SIGABRT",
+        "SIGBUS",
+        "SIGFPE",
+        "SIGKILL",
+        "SIGUSR1",
+        "SIGSEGV",
+        "SIGUSR2",
+        "SIGPIPE",
+        "SIGALRM",
+        "SIGTERM",
+        "SIGSTKFLT",
+        "SIGCHLD",
+        "SIGCONT",
+        "SIGSTOP",
+        "SIGTSTP",
+        "SIGTTIN",
+        "SIGTTOU",
+        "SIGURG",
+        "SIGXCPU",
+        "SIGXFSZ",
+        "SIGVTALRM",
+        "SIGPROF",
+        "SIGWINCH",
+        "SIGIO",
+        "SIGPWR",
+        "SIGSYS",
+    ];
+    NAMES
+        .get(signal as usize)
+        .copied()
+        .unwrap_or("unknown signal")
+}
+
+// Make `signal` pending for the process `pid`, unless it ignores it.
+// Signal 0 only checks that the process exists.
+pub fn send(pid: Pid, signal: u32) -> Result<(), SignalError> {
+    if signal >= NSIG {
+        return Err(SignalError::InvalidSignal);
+    }
+    if pid == KERNEL_PID {
+        return Err(SignalError::NotPermitted);
+    }
+    let process = process::get(pid).ok_or(SignalError::NoSuchProcess)?;
+    if signal == 0 {
+        return Ok(());
+    }
+    let deliverable = {
+        let mut signals = process.signals();
+        if signals.actions[signal as usize].ignores(signal) {
+            return Ok(());
+        }
+        signals.pending |= mask(signal);
+        signals.blocked & mask(signal) == 0
+    };
+    if deliverable {
+        thread::interrupt(pid);
+    }
+    Ok(())
+}
+
+// Whether the process `pid` has a pending signal it does not block, which
+// ends an interruptible wait of its threads
+pub fn interrupts(pid: Pid) -> bool {
+    if pid == KERNEL_PID {
+        return false;
+    }
+    process::get(pid).map_or(false, |process| {
+        let signals = process.signals();
+        signals.pending & !signals.blocked != 0
+    })
+}
+
+// Raise `signal` for the calling thread's process because of what the
+// thread just did, e.g. an exception. Blocking or ignoring it would only
+// repeat the exception, so it then takes its default action instead.
+pub fn force(signal: u32) {
+    let process = process::current();
+    let mut signals = process.signals();
+    let blocked = signals.blocked & mask(signal) != 0;
+    if blocked || signals.actions[signal as usize].handler == SIG_IGN {
+        signals.reset(signal);
+    }
+    signals.pending |= mask(signal);
+}
+
+// sigaction: change the action for `signal` if `action` is given, and
+// return the previous one. Ignoring a signal discards it if pending.
+pub fn sigaction(signal: u32, action: Option<Action>) -> Result<Action, SignalError> {
+    if signal == 0 || signal >= NSIG {
+        return Err(SignalError::InvalidSignal);
+    }
+    let process = current()?;
+    let mut signals = process.signals();
+    let previous = signals.actions[signal as usize];
+    if let Some(mut action) = action {
+        if signal == SIGKILL || signal == SIGSTOP {
+            return Err(SignalError::InvalidSignal);
+        }
+        if action.handler > SIG_IGN && action.flags & SA_RESTORER == 0 {
+            return Err(SignalError::InvalidArgument);
+        }
+        action.mask &= !UNBLOCKABLE;
+        signals.actions[signal as usize] = action;
+        if action.ignores(signal) {
+            signals.pending &= !mask(signal);
+        }
+    }
+    Ok(previous)
+}
+
+// sigprocmask: change the blocked mask with `set` as `how` says, if given,
+// and return the previous mask
+pub fn sigprocmask(how: u64, set: Option<u64>) -> Result<u64, SignalError> {
+    let process = current()?;
+    let mut signals = process.signals();
+    let previous = signals.blocked;
+    if let Some(set) = set {
+        let blocked = match how {
+            SIG_BLOCK => previous | set,
+            SIG_UNBLOCK => previous & !set,
+            SIG_SETMASK => set,
+            _ => return Err(SignalError::InvalidArgument),
+        };
+        signals.blocked = blocked & !UNBLOCKABLE;
+    }
+    Ok(previous)
+}
+
+// Called on every return to user mode with the frame returned to. Takes
+// the default action of pending signals, which may end the process, or
+// sets up the frame to run a handler first. One handler at a time, the
+// next signal is delivered once it calls `sigreturn`.
+pub fn deliver(frame: &mut TrapFrame) {
+    if !frame.from_user_mode() || process::current_pid() == KERNEL_PID {
+        return;
+    }
+    let process = process::current();
+    if process.is_exiting() {
+        drop(process);
+        thread::exit();
+    }
+
+    loop {
+        let (signal, action) = {
+            let mut signals = process.signals();
+            match signals.take_next() {
+                Some(signal) => (signal, signals.actions[signal as usize]),
+                None => return,
+            }
+        };
+
+        let core = match action.handler {
+            SIG_IGN => continue,
+            SIG_DFL => match default_action(signal) {
+                DefaultAction::Ignore => continue,
+                DefaultAction::Terminate => false,
+                DefaultAction::Core => true,
+            },
+            _ => {
+                if push_frame(&process, signal, &action, frame) {
+                    return;
+                }
+                // No room for the frame, which a SIGSEGV handler would not
+                // find either
+                let mut signals = process.signals();
+                signals.reset(SIGSEGV);
+                signals.pending |= mask(SIGSEGV);
+                continue;
+            }
+        };
+        if core {
+            dump(&process, signal, frame);
+        }
+        drop(process);
+        process::terminate(ExitStatus::Signaled { signal, core });
+    }
+}
+
+// sigreturn: resume what a handler interrupted, with the frame its return
+// to the restorer left right below the stack pointer. A frame that makes
+// no sense raises SIGSEGV.
+pub fn sigreturn(frame: &mut TrapFrame) {
+    let addr = frame.rsp.wrapping_sub(8);
+    if !usermode::is_user_range(addr, size_of::<SignalFrame>() as u64, false) {
+        force(SIGSEGV);
+        return;
+    }
+    let saved = unsafe { (addr as *const SignalFrame).read_unaligned() };
+    let registers = saved.registers;
+    if registers.rip >= USER_SPACE_END || registers.rsp >= USER_SPACE_END {
+        force(SIGSEGV);
+        return;
+    }
+
+    process::current().signals().blocked = saved.blocked & !UNBLOCKABLE;
+    *frame = TrapFrame {
+        vector: frame.vector,
+        error_code: frame.error_code,
+        cs: frame.cs,
+        rflags: (frame.rflags & !USER_RFLAGS) | (registers.rflags & USER_RFLAGS),
+        ss: frame.ss,
+        ..registers
+    };
+}
+
+// Process of the calling thread, if it takes signals
+fn current() -> Result<alloc::sync::Arc<Process>, SignalError> {
+    if process::current_pid() == KERNEL_PID {
+        return Err(SignalError::NotPermitted);
+    }
+    Ok(process::current())
+}
+
+// Push a frame for the handler of `signal` and make `frame` return to the
+// handler. False if the stack has no room for it.
+fn push_frame(process: &Process, signal: u32, action: &Action, frame: &mut TrapFrame) -> bool {
+    let size = size_of::<SignalFrame>() as u64;
+    let addr = match frame.rsp.checked_sub(RED_ZONE + size + 8) {
+        // 16 byte aligned before the call that pushed the return address
+        Some(addr) => (addr & !0xf) + 8,
+        None => return false,
+    };
+    if !usermode::is_user_range(addr, size, true) {
+        return false;
+    }
+
+    let mut signals = process.signals();
+    let signal_frame = SignalFrame {
+        restorer: action.restorer,
+        signal: u64::from(signal),
+        blocked: signals.blocked,
+        registers: *frame,
+    };
+    unsafe { (addr as *mut SignalFrame).write_unaligned(signal_frame) };
+
+    let mut blocked = action.mask;
+    if action.flags & SA_NODEFER == 0 {
+        blocked |= mask(signal);
+    }
+    signals.blocked |= blocked & !UNBLOCKABLE;
+    if action.flags & SA_RESETHAND != 0 {
+        signals.actions[signal as usize] = Action::DEFAULT;
+    }
+
+    frame.rip = action.handler;
+    frame.rsp = addr;
+    frame.rdi = u64::from(signal);
+    frame.rflags &= !(RFLAGS_TF | RFLAGS_DF);
+    true
+}
+
+// Report a process killed by `signal` along with its registers, there are
+// no core files to write them to
+fn dump(process: &Process, signal: u32, frame: &TrapFrame) {
+    println!(
+        "{} ({}): {} at {:#x}, core dumped",
+        process.name(),
+        process.pid().as_u64(),
+        name(signal),
+        frame.rip
+    );
+    println!(
+        "  rax {:016x} rbx {:016x} rcx {:016x} rdx {:016x}",
+        frame.rax, frame.rbx, frame.rcx, frame.rdx
+    );
+    println!(
+        "  rsi {:016x} rdi {:016x} rbp {:016x} rsp {:016x}",
+        frame.rsi, frame.rdi, frame.rbp, frame.rsp
+    );
+}
+
+//////////////////////////////
+// Tests
+//////////////////////////////
+
+#[test_case]
+fn test_masks_and_actions() {
+    let child = process::spawn("signals", || {
+        let pid = process::current_pid();
+        let pending = || process::current().signals().pending();
+
+        // Ignored by default, so never pending
+        assert_eq!(send(pid, SIGCHLD), Ok(()));
+        assert_eq!(pending(), 0);
+
+        // Blocked signals stay pending, SIGKILL cannot be blocked
+        let set = mask(SIGUSR1) | mask(SIGKILL);
+        assert_eq!(sigprocmask(SIG_BLOCK, Some(set)), Ok(0));
+        assert_eq!(sigprocmask(SIG_SETMASK, None), Ok(mask(SIGUSR1)));
+        assert_eq!(send(pid, SIGUSR1), Ok(()));
+        assert_eq!(pending(), mask(SIGUSR1));
+        assert_eq!(process::current().signals().take_next(), None);
+
+        // Ignoring a signal discards it
+        let ignore = Action {
+            handler: SIG_IGN,
+            ..Action::DEFAULT
+        };
+        assert_eq!(sigaction(SIGUSR1, Some(ignore)), Ok(Action::DEFAULT));
+        assert_eq!(pending(), 0);
+        assert_eq!(sigaction(SIGUSR1, None), Ok(ignore));
+
+        let handler = Action {
+            handler: 0x1000,
+            ..Action::DEFAULT
+        };
+        assert_eq!(
+            sigaction(SIGUSR2, Some(handler)),
+            Err(SignalError::InvalidArgument)
+        );
+        assert_eq!(
+            sigaction(SIGKILL, Some(ignore)),
+            Err(SignalError::InvalidSignal)
+        );
+        assert_eq!(sigaction(NSIG, None), Err(SignalError::InvalidSignal));
+        assert_eq!(sigprocmask(3, Some(0)), Err(SignalError::InvalidArgument));
+
+        // An exception's signal cannot be blocked or ignored
+        assert_eq!(
+            sigprocmask(SIG_BLOCK, Some(mask(SIGSEGV))),
+            Ok(mask(SIGUSR1))
+        );
+        assert_eq!(sigaction(SIGFPE, Some(ignore)), Ok(Action::DEFAULT));
+        force(SIGSEGV);
+        force(SIGFPE);
+        let process = process::current();
+        let signals = process.signals();
+        assert_eq!(signals.pending(), mask(SIGSEGV) | mask(SIGFPE));
+        assert_eq!(signals.blocked(), mask(SIGUSR1));
+        assert_eq!(signals.action(SIGFPE), Action::DEFAULT);
+    })
+    .expect("spawn failed");
+    let pid = child.pid();
+    assert_eq!(
+        process::waitpid(Some(pid), false),
+        Ok(Some((pid, ExitStatus::Exited(0))))
+    );
+
+    assert_eq!(send(pid, 0), Err(SignalError::NoSuchProcess));
+    assert_eq!(send(KERNEL_PID, SIGTERM), Err(SignalError::NotPermitted));
+    assert_eq!(send(pid, NSIG), Err(SignalError::InvalidSignal));
+}
+
+#[cfg(test)]
+fn run_program(name: &str, code: &[u8], flags: u32) -> (Pid, ExitStatus) {
+    use crate::elf::{self, TEST_BASE};
+
+    let image = elf::test_image(TEST_BASE, &[(flags, TEST_BASE, code, code.len() as u64)]);
+    let process = elf::spawn(name, &image, &[name], &[]).expect("spawn failed");
+    let pid = process.pid();
+    drop(process);
+    let (_, status) = process::waitpid(Some(pid), false)
+        .expect("waitpid failed")
+        .expect("blocking wait returned nothing");
+    (pid, status)
+}
+
+#[test_case]
+fn test_exceptions_raise_signals() {
+    use crate::elf::{PF_R, PF_X};
+
+    // xor edx, edx; xor eax, eax; xor ecx, ecx; div ecx
+    let divide = [0x31, 0xd2, 0x31, 0xc0, 0x31, 0xc9, 0xf7, 0xf1, 0xeb, 0xfe];
+    let status = run_program("divide", &divide, PF_R | PF_X).1;
+    let signaled = ExitStatus::Signaled {
+        signal: SIGFPE,
+        core: true,
+    };
+    assert_eq!(status, signaled);
+
+    // ud2
+    let status = run_program("ud2", &[0x0f, 0x0b], PF_R | PF_X).1;
+    let signaled = ExitStatus::Signaled {
+        signal: SIGILL,
+        core: true,
+    };
+    assert_eq!(status, signaled);
+
+    // fld1, #NM while the FPU is off
+    if !crate::fpu::is_enabled() {
+        let status = run_program("fld1", &[0xd9, 0xe8, 0xeb, 0xfe], PF_R | PF_X).1;
+        assert_eq!(status, signaled);
+    }
+
+    // mov rsp, 0x8000_0000_0000; push rax, #SS on the non-canonical stack
+    let stack = [
+        0x48, 0xbc, 0x00, 0x00, 0x00, 0x00, 0x00, 0x80, 0x00, 0x00, 0x50, 0xeb, 0xfe,
+    ];
+    let status = run_program("stack", &stack, PF_R | PF_X).1;
+    let signaled = ExitStatus::Signaled {
+        signal: SIGSEGV,
+        core: true,
+    };
+    assert_eq!(status, signaled);
+}
+
+#[test_case]
+fn test_debug_traps_raise_sigtrap() {
+    use crate::elf::{PF_R, PF_X};
+
+    let trapped = ExitStatus::Signaled {
+        signal: SIGTRAP,
+        core: true,
+    };
+    // int3; jmp $
+    let status = run_program("int3", &[0xcc, 0xeb, 0xfe], PF_R | PF_X).1;
+    assert_eq!(status, trapped);
+
+    // pushf; or qword [rsp], TF; popf; nop; jmp $, single steps into #DB
+    let step = [
+        0x9c, 0x48, 0x81, 0x0c, 0x24, 0x00, 0x01, 0x00, 0x00, 0x9d, 0x90, 0xeb, 0xfe,
+    ];
+    let status = run_program("step", &step, PF_R | PF_X).1;
+    assert_eq!(status, trapped);
+}
+
+#[test_case]
+fn test_kill_running_program() {
+    use crate::elf::{self, PF_R, PF_X, TEST_BASE};
+
+    // jmp $, the timer interrupt is its only way into the kernel
+    let code = [0xeb, 0xfe];
+    let image = elf::test_image(TEST_BASE, &[(PF_R | PF_X, TEST_BASE, &code, 2)]);
+    let pid = elf::spawn("loop", &image, &["loop"], &[])
+        .expect("spawn failed")
+        .pid();
+
+    assert_eq!(send(pid, SIGTERM), Ok(()));
+    let signaled = ExitStatus::Signaled {
+        signal: SIGTERM,
+        core: false,
+    };
+    assert_eq!(
+        process::waitpid(Some(pid), false),
+        Ok(Some((pid, signaled)))
+    );
+}
+
+#[test_case]
+fn test_handler_and_sigreturn() {
+    use crate::elf::{PF_R, PF_W, PF_X};
+
+    // sigaction(SIGUSR1, &action, 0), with the handler and restorer below
+    // mov ebx, 7; kill(getpid(), SIGUSR1)
+    // exit(ebx + rax + flag), 7 + 0 from kill + what the handler stored
+    // handler: mov eax, esp; and eax, 15; add eax, edi; mov [flag], eax
+    //          mov ebx, 99; ret
+    // restorer: mov eax, SYS_SIGRETURN; syscall; ud2
+    // action: dq 0, SA_RESTORER, 0, 0; flag: dd 0
+    let code = [
+        0x48, 0x8d, 0x05, 0x53, 0x00, 0x00, 0x00, 0x48, 0x89, 0x05, 0x6a, 0x00, 0x00, 0x00, 0x48,
+        0x8d, 0x05, 0x58, 0x00, 0x00, 0x00, 0x48, 0x89, 0x05, 0x6c, 0x00, 0x00, 0x00, 0xb8, 0x0a,
+        0x00, 0x00, 0x00, 0xbf, 0x0a, 0x00, 0x00, 0x00, 0x48, 0x8d, 0x35, 0x4b, 0x00, 0x00, 0x00,
+        0x31, 0xd2, 0x0f, 0x05, 0xbb, 0x07, 0x00, 0x00, 0x00, 0xb8, 0x02, 0x00, 0x00, 0x00, 0x0f,
+        0x05, 0x89, 0xc7, 0xbe, 0x0a, 0x00, 0x00, 0x00, 0xb8, 0x09, 0x00, 0x00, 0x00, 0x0f, 0x05,
+        0x8d, 0x3c, 0x03, 0x03, 0x3d, 0x44, 0x00, 0x00, 0x00, 0x31, 0xc0, 0x0f, 0x05, 0xeb, 0xfe,
+        0x89, 0xe0, 0x83, 0xe0, 0x0f, 0x01, 0xf8, 0x89, 0x05, 0x31, 0x00, 0x00, 0x00, 0xbb, 0x63,
+        0x00, 0x00, 0x00, 0xc3, 0xb8, 0x0c, 0x00, 0x00, 0x00, 0x0f, 0x05, 0x0f, 0x0b, 0x66, 0x90,
+        0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x04, 0x00, 0x00, 0x00,
+        0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+        0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+    ];
+
+    // The handler ran with SIGUSR1, on a stack aligned as after a call,
+    // and rbx was restored
+    let status = run_program("handler", &code, PF_R | PF_W | PF_X).1;
+    assert_eq!(status, ExitStatus::Exited(7 + SIGUSR1 as i32 + 8));
+}
@@ -0,0 +1,425 @@
+// smp.rs - Symmetric multiprocessing, application processor bring-up
+
+use crate::cpu::{self, Feature};
+use crate::{acpi, apic, fpu, gdt, interrupts, memory, percpu, println, syscall, time};
+
+use alloc::vec;
+use conquer_once::spin::OnceCell;
+use core::sync::atomic::{AtomicBool, AtomicU32, AtomicU64, AtomicUsize, Ordering};
+use crossbeam_queue::ArrayQueue;
+use x86_64::{
+    registers::control::Cr3,
+    structures::paging::{mapper::MapToError, Mapper, PageTableFlags, PhysFrame, Size4KiB},
+    PhysAddr,
+};
+
+//////////////////////////////
+// Statics/Constants
+//////////////////////////////
+
+pub const MAX_CPUS: usize = 16;
+
+const AP_STACK_SIZE: usize = 4096 * 16;
+const MAILBOX_CAPACITY: usize = 64;
+
+// How long to wait for an AP to reach `ap_main` after the second SIPI
+const AP_BOOT_TIMEOUT_MS: u64 = 100;
+
+static CPUS: [CpuSlot; MAX_CPUS] = [CpuSlot::INIT; MAX_CPUS];
+static CPU_COUNT: AtomicUsize = AtomicUsize::new(1);
+
+// Start attempt the BSP is waiting on. Whichever of the AP and the BSP's
+// timeout swaps it out first decides whether the attempt counts.
+static AP_PENDING: AtomicU64 = AtomicU64::new(NO_ATTEMPT);
+const NO_ATTEMPT: u64 = 0;
+
+/*
+ *  Application processors start in 16-bit real mode at the 4KiB aligned
+ *  physical address given in the Startup IPI. The trampoline below is copied
+ *  to such a page below 1MiB and is position independent: it derives its
+ *  load address from CS. The BSP fills in the data block at the end before
+ *  each start up, the AP then
+ *
+ *      real mode --> protected mode --> long mode (kernel page tables)
+ *                --> ap_main(cpu, attempt) on its own stack
+ *
+ *  The trampoline page must be identity mapped since paging is enabled while
+ *  executing from it. An AP that misses its timeout may still run the
+ *  trampoline later, so a page is never rewritten after that: the next AP
+ *  gets a fresh copy and the late one finds its attempt abandoned.
+ */
+core::arch::global_asm!(
+    r#"
+    .section .text.ap_trampoline, "ax"
+    .code16
+    .global ap_trampoline_start
+ap_trampoline_start:
+    cli
+    cld
+    movw %cs, %ax
+    movw %ax, %ds
+    movw %ax, %ss
+    movw $0x1000, %sp
+
+    # ebx = physical load address of the trampoline
+    xorl %ebx, %ebx
+    movw %ax, %bx
+    shll $4, %ebx
+
+    leal (ap_gdt - ap_trampoline_start)(%ebx), %eax
+    movl %eax, (ap_gdt_ptr - ap_trampoline_start + 2)
+    lgdtl (ap_gdt_ptr - ap_trampoline_start)
+
+    movl %cr0, %eax
+    orl $1, %eax
+    movl %eax, %cr0
+
+    leal (ap_protected_mode - ap_trampoline_start)(%ebx), %eax
+    pushl $0x08
+    pushl %eax
+    lretl
+
+    .code32
+ap_protected_mode:
+    movw $0x10, %ax
+    movw %ax, %ds
+    movw %ax, %es
+    movw %ax, %ss
+    leal 0x1000(%ebx), %esp
+
+    # CR4.PAE
+    movl %cr4, %eax
+    orl $(1 << 5), %eax
+    movl %eax, %cr4
+
+    movl (ap_trampoline_cr3 - ap_trampoline_start)(%ebx), %eax
+    movl %eax, %cr3
+
+    # EFER.LME | EFER.NXE
+    movl $0xc0000080, %ecx
+    rdmsr
+    orl $((1 << 8) | (1 << 11)), %eax
+    wrmsr
+
+    # CR0.PG | CR0.WP | CR0.PE
+    movl %cr0, %eax
+    orl $0x80010001, %eax
+    movl %eax, %cr0
+
+    leal (ap_long_mode - ap_trampoline_start)(%ebx), %eax
+    pushl $0x18
+    pushl %eax
+    lretl
+
+    .code64
+ap_long_mode:
+    xorw %ax, %ax
+    movw %ax, %ds
+    movw %ax, %es
+    movw %ax, %ss
+    movw %ax, %fs
+    movw %ax, %gs
+
+    movq (ap_trampoline_stack - ap_trampoline_start)(%rbx), %rsp
+    movq (ap_trampoline_cpu - ap_trampoline_start)(%rbx), %rdi
+    movq (ap_trampoline_attempt - ap_trampoline_start)(%rbx), %rsi
+    movq (ap_trampoline_entry - ap_trampoline_start)(%rbx), %rax
+    xorq %rbp, %rbp
+    callq *%rax
+    ud2
+
+    .balign 16
+ap_gdt:
+    .quad 0
+    .quad 0x00cf9a000000ffff    # 0x08: 32-bit code
+    .quad 0x00cf92000000ffff    # 0x10: 32-bit data
+    .quad 0x00af9a000000ffff    # 0x18: 64-bit code
+ap_gdt_ptr:
+    .word ap_gdt_ptr - ap_gdt - 1
+    .long 0
+
+    .balign 8
+    .global ap_trampoline_cr3
+ap_trampoline_cr3:
+    .quad 0
+    .global ap_trampoline_stack
+ap_trampoline_stack:
+    .quad 0
+    .global ap_trampoline_entry
+ap_trampoline_entry:
+    .quad 0
+    .global ap_trampoline_cpu
+ap_trampoline_cpu:
+    .quad 0
+    .global ap_trampoline_attempt
+ap_trampoline_attempt:
+    .quad 0
+    .global ap_trampoline_end
+ap_trampoline_end:
+    .text
+    "#,
+    options(att_syntax)
+);
+
+extern "C" {
+    static ap_trampoline_start: u8;
+    static ap_trampoline_end: u8;
+    static ap_trampoline_cr3: u8;
+    static ap_trampoline_stack: u8;
+    static ap_trampoline_entry: u8;
+    static ap_trampoline_cpu: u8;
+    static ap_trampoline_attempt: u8;
+}
+
+////////////////////////////////
+// Structs, Types, Traits, Impl
+////////////////////////////////
+
+#[derive(Debug, Clone, Copy)]
+struct Work {
+    func: fn(u64),
+    data: u64,
+}
+
+struct CpuSlot {
+    apic_id: AtomicU32,
+    online: AtomicBool,
+    // Work handed to the CPU while it sits in `ap_idle_loop`
+    mailbox: OnceCell<ArrayQueue<Work>>,
+}
+
+impl CpuSlot {
+    const INIT: CpuSlot = CpuSlot {
+        apic_id: AtomicU32::new(0),
+        online: AtomicBool::new(false),
+        mailbox: OnceCell::uninit(),
+    };
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SmpError {
+    NoSuchCpu,
+    MailboxFull,
+}
+
+//////////////////////////////
+// API
+//////////////////////////////
+
+// Discover the CPUs through the MADT and start every application processor.
+// Requires the heap. Falls back to running on the BSP only if the MADT can
+// not be found.
+pub fn init(
+    mapper: &mut impl Mapper<Size4KiB>,
+    frame_allocator: &mut memory::BootInfoFrameAllocator,
+) {
+    if !cpu::has(Feature::Apic) {
+        println!("SMP: no local APIC, running on the bootstrap processor only");
+        return;
+    }
+
+    let madt = match acpi::madt() {
+        Ok(madt) => madt,
+        Err(err) => {
+            println!("SMP: {:?}, running on the bootstrap processor only", err);
+            return;
+        }
+    };
+
+    unsafe { apic::init(madt.local_apic_address) };
+    apic::enable();
+    apic::calibrate_timer();
+    apic::start_timer();
+
+    let bsp_apic_id = apic::id();
+    CPUS[0].apic_id.store(bsp_apic_id, Ordering::SeqCst);
+    CPUS[0].online.store(true, Ordering::SeqCst);
+
+    let mut trampoline = match setup_trampoline(mapper, frame_allocator) {
+        Some(frame) => frame,
+        None => {
+            println!("SMP: no low memory for the AP trampoline");
+            return;
+        }
+    };
+
+    let application_processors = madt
+        .processors
+        .iter()
+        .filter(|p| p.apic_id != bsp_apic_id)
+        .take(MAX_CPUS - 1);
+
+    // Slots are handed out in order, a slot whose AP did not respond goes to
+    // the next one
+    for (attempt, processor) in (NO_ATTEMPT + 1..).zip(application_processors) {
+        let cpu = CPU_COUNT.load(Ordering::SeqCst);
+        if start_ap(cpu, processor.apic_id, trampoline, attempt) {
+            CPU_COUNT.fetch_add(1, Ordering::SeqCst);
+            continue;
+        }
+
+        println!("SMP: CPU with APIC ID {} did not respond", processor.apic_id);
+
+        // The AP may still be on its way through the old trampoline
+        trampoline = match setup_trampoline(mapper, frame_allocator) {
+            Some(frame) => frame,
+            None => {
+                println!("SMP: no low memory for another AP trampoline");
+                break;
+            }
+        };
+    }
+
+    println!("SMP: {} of {} CPUs online", cpu_count(), madt.processors.len());
+}
+
+// Number of CPUs that completed bring-up, including the BSP
+pub fn cpu_count() -> usize {
+    CPU_COUNT.load(Ordering::SeqCst)
+}
+
+// APIC ID of an online CPU, e.g. to send it an IPI
+pub fn apic_id(cpu: usize) -> Option<u32> {
+    CPUS.get(cpu)
+        .filter(|slot| slot.online.load(Ordering::SeqCst))
+        .map(|slot| slot.apic_id.load(Ordering::SeqCst))
+}
+
+// Queue `func(data)` on an idle application processor and wake it up
+pub fn run_on(cpu: usize, func: fn(u64), data: u64) -> Result<(), SmpError> {
+    let slot = CPUS.get(cpu).filter(|slot| slot.online.load(Ordering::SeqCst));
+    let slot = slot.ok_or(SmpError::NoSuchCpu)?;
+    let mailbox = slot.mailbox.try_get().map_err(|_| SmpError::NoSuchCpu)?;
+
+    mailbox
+        .push(Work { func, data })
+        .map_err(|_| SmpError::MailboxFull)?;
+    apic::send_ipi(slot.apic_id.load(Ordering::SeqCst), apic::WAKEUP_VECTOR);
+    Ok(())
+}
+
+// Copy the trampoline to a free page below 1MiB and identity map it
+fn setup_trampoline(
+    mapper: &mut impl Mapper<Size4KiB>,
+    frame_allocator: &mut memory::BootInfoFrameAllocator,
+) -> Option<PhysFrame> {
+    let frame = frame_allocator.allocate_low_frame()?;
+
+    let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE;
+    match unsafe { mapper.identity_map(frame, flags, frame_allocator) } {
+        Ok(flush) => flush.flush(),
+        Err(MapToError::PageAlreadyMapped(mapped)) if mapped == frame => {}
+        Err(err) => panic!("SMP: failed to map the AP trampoline: {:?}", err),
+    }
+
+    unsafe {
+        let start = &ap_trampoline_start as *const u8;
+        let len = &ap_trampoline_end as *const u8 as usize - start as usize;
+        let dest = memory::phys_to_virt(frame.start_address()).as_mut_ptr::<u8>();
+        core::ptr::copy_nonoverlapping(start, dest, len);
+    }
+
+    // Both 32-bit and long mode code load CR3 with a 32-bit move
+    let (level_4_frame, _) = Cr3::read();
+    assert!(level_4_frame.start_address().as_u64() <= u64::from(u32::MAX));
+    write_trampoline(frame, unsafe { &ap_trampoline_cr3 }, level_4_frame.start_address().as_u64());
+
+    Some(frame)
+}
+
+// Store `value` at the trampoline field `symbol` in the copy at `frame`
+fn write_trampoline(frame: PhysFrame, symbol: &u8, value: u64) {
+    let offset = symbol as *const u8 as u64 - unsafe { &ap_trampoline_start as *const u8 as u64 };
+    let addr = memory::phys_to_virt(PhysAddr::new(frame.start_address().as_u64() + offset));
+    unsafe { addr.as_mut_ptr::<u64>().write_volatile(value) };
+}
+
+// INIT-SIPI-SIPI sequence, Intel SDM Vol. 3A section 8.4.4.1
+fn start_ap(cpu: usize, apic_id: u32, trampoline: PhysFrame, attempt: u64) -> bool {
+    // Stacks of application processors live for as long as the kernel does
+    let stack = vec![0u8; AP_STACK_SIZE].leak();
+    let stack_top = (stack.as_ptr() as u64 + AP_STACK_SIZE as u64) & !0xf;
+
+    // A slot left behind by an AP that did not respond keeps its mailbox,
+    // nothing can have been queued on it while offline
+    CPUS[cpu].apic_id.store(apic_id, Ordering::SeqCst);
+    let _ = CPUS[cpu]
+        .mailbox
+        .try_init_once(|| ArrayQueue::new(MAILBOX_CAPACITY));
+
+    write_trampoline(trampoline, unsafe { &ap_trampoline_stack }, stack_top);
+    write_trampoline(trampoline, unsafe { &ap_trampoline_entry }, ap_main as *const () as u64);
+    write_trampoline(trampoline, unsafe { &ap_trampoline_cpu }, cpu as u64);
+    write_trampoline(trampoline, unsafe { &ap_trampoline_attempt }, attempt);
+    AP_PENDING.store(attempt, Ordering::SeqCst);
+    let started = || AP_PENDING.load(Ordering::SeqCst) != attempt;
+
+    let page = (trampoline.start_address().as_u64() >> 12) as u8;
+    apic::send_init(apic_id);
+    time::busy_wait_us(10_000);
+    for _ in 0..2 {
+        apic::send_startup(apic_id, page);
+        time::busy_wait_us(200);
+        if started() {
+            break;
+        }
+    }
+
+    for _ in 0..AP_BOOT_TIMEOUT_MS {
+        if started() {
+            return true;
+        }
+        time::busy_wait_us(1000);
+    }
+
+    // Give up on the attempt, unless the AP claimed it in the meantime
+    AP_PENDING
+        .compare_exchange(attempt, NO_ATTEMPT, Ordering::SeqCst, Ordering::SeqCst)
+        .is_err()
+}
+
+// Entry point of application processors, called by the trampoline
+extern "C" fn ap_main(cpu: u64, attempt: u64) -> ! {
+    let cpu = cpu as usize;
+
+    // Too late, the slot may already belong to another AP. Interrupts are
+    // still disabled from the trampoline.
+    if AP_PENDING
+        .compare_exchange(attempt, NO_ATTEMPT, Ordering::SeqCst, Ordering::SeqCst)
+        .is_err()
+    {
+        crate::hlt_loop();
+    }
+
+    percpu::init(cpu);
+    gdt::init_cpu(cpu);
+    syscall::init_cpu();
+    fpu::init_cpu();
+    interrupts::init_idt();
+    apic::enable();
+    apic::start_timer();
+    CPUS[cpu].online.store(true, Ordering::SeqCst);
+
+    ap_idle_loop(cpu);
+}
+
+// Run work from the mailbox, halt until the next wakeup IPI when empty
+fn ap_idle_loop(cpu: usize) -> ! {
+    use x86_64::instructions::interrupts;
+
+    let mailbox = CPUS[cpu]
+        .mailbox
+        .try_get()
+        .expect("AP started without a mailbox");
+
+    loop {
+        interrupts::disable();
+        match mailbox.pop() {
+            Some(work) => {
+                interrupts::enable();
+                (work.func)(work.data);
+            }
+            None => interrupts::enable_and_hlt(),
+        }
+    }
+}
@@ -0,0 +1,377 @@
+// spinlock.rs - Spinlock that keeps interrupts disabled while held
+
+use crate::{hlt_loop, percpu, serial_emergency_println};
+
+use core::cell::UnsafeCell;
+use core::fmt;
+use core::marker::PhantomData;
+use core::ops::{Deref, DerefMut};
+use core::sync::atomic::{AtomicBool, AtomicPtr, AtomicUsize, Ordering};
+use x86_64::instructions::interrupts;
+
+//////////////////////////////
+// Statics/Constants
+//////////////////////////////
+
+/*
+ *  A lock also taken by interrupt handlers must be held with interrupts
+ *  disabled, otherwise a handler interrupting the holder spins on it
+ *  forever. `IrqSpinlock::lock` disables them before spinning and the guard
+ *  restores the previous state when dropped, so nested guards have to be
+ *  dropped in reverse order.
+ *
+ *  Debug builds also track the locks each CPU holds and panic, naming the
+ *  locks involved, instead of hanging when
+ *
+ *    - a CPU takes a lock it already holds
+ *    - a ranked lock is taken while holding one of equal or higher rank,
+ *      the order that can deadlock against a CPU taking them the other
+ *      way round. Unranked locks are not ordered.
+ *    - a guard re-enables interrupts while other locks are still held
+ */
+const CHECKS: bool = cfg!(debug_assertions);
+
+// Rank of locks created with `IrqSpinlock::new`
+pub const UNRANKED: u8 = 0;
+
+// Locks one CPU can hold at the same time in debug builds
+const MAX_HELD: usize = 8;
+
+// Set once a violation is being reported, see `report`
+static REPORTING: AtomicBool = AtomicBool::new(false);
+
+////////////////////////////////
+// Structs, Types, Traits, Impl
+////////////////////////////////
+
+pub struct IrqSpinlock<T: ?Sized> {
+    info: LockInfo,
+    locked: AtomicBool,
+    data: UnsafeCell<T>,
+}
+
+// Same bounds as spin::Mutex, the lock serializes all access to `data`
+unsafe impl<T: ?Sized + Send> Sync for IrqSpinlock<T> {}
+unsafe impl<T: ?Sized + Send> Send for IrqSpinlock<T> {}
+
+struct LockInfo {
+    name: &'static str,
+    rank: u8,
+}
+
+impl<T> IrqSpinlock<T> {
+    pub const fn new(name: &'static str, value: T) -> IrqSpinlock<T> {
+        IrqSpinlock::with_rank(name, UNRANKED, value)
+    }
+
+    // A lock that may only be taken while holding ranked locks of a lower
+    // rank
+    pub const fn with_rank(name: &'static str, rank: u8, value: T) -> IrqSpinlock<T> {
+        IrqSpinlock {
+            info: LockInfo { name, rank },
+            locked: AtomicBool::new(false),
+            data: UnsafeCell::new(value),
+        }
+    }
+
+    pub fn into_inner(self) -> T {
+        self.data.into_inner()
+    }
+}
+
+impl<T: ?Sized> IrqSpinlock<T> {
+    pub fn name(&self) -> &'static str {
+        self.info.name
+    }
+
+    pub fn rank(&self) -> u8 {
+        self.info.rank
+    }
+
+    pub fn lock(&self) -> IrqSpinlockGuard<'_, T> {
+        let interrupts_enabled = interrupts::are_enabled();
+        interrupts::disable();
+        if CHECKS {
+            check_acquire(&self.info);
+        }
+
+        while self
+            .locked
+            .compare_exchange_weak(false, true, Ordering::Acquire, Ordering::Relaxed)
+            .is_err()
+        {
+            while self.locked.load(Ordering::Relaxed) {
+                core::hint::spin_loop();
+            }
+        }
+        self.guard(interrupts_enabled)
+    }
+
+    // Never spins, so never deadlocks and is not checked for recursion or
+    // ordering
+    pub fn try_lock(&self) -> Option<IrqSpinlockGuard<'_, T>> {
+        let interrupts_enabled = interrupts::are_enabled();
+        interrupts::disable();
+        match self
+            .locked
+            .compare_exchange(false, true, Ordering::Acquire, Ordering::Relaxed)
+        {
+            Ok(_) => Some(self.guard(interrupts_enabled)),
+            Err(_) => {
+                if interrupts_enabled {
+                    interrupts::enable();
+                }
+                None
+            }
+        }
+    }
+
+    pub fn is_locked(&self) -> bool {
+        self.locked.load(Ordering::Relaxed)
+    }
+
+    // No locking needed, the borrow is exclusive
+    pub fn get_mut(&mut self) -> &mut T {
+        self.data.get_mut()
+    }
+
+    fn guard(&self, interrupts_enabled: bool) -> IrqSpinlockGuard<'_, T> {
+        if CHECKS {
+            track_acquire(&self.info);
+        }
+        IrqSpinlockGuard {
+            lock: self,
+            interrupts_enabled,
+            _not_send: PhantomData,
+        }
+    }
+}
+
+impl<T: Default> Default for IrqSpinlock<T> {
+    fn default() -> Self {
+        IrqSpinlock::new("unnamed", T::default())
+    }
+}
+
+impl<T: ?Sized + fmt::Debug> fmt::Debug for IrqSpinlock<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.try_lock() {
+            Some(guard) => write!(f, "IrqSpinlock({:?}, {:?})", self.info.name, &*guard),
+            None => write!(f, "IrqSpinlock({:?}, <locked>)", self.info.name),
+        }
+    }
+}
+
+// Releases the lock and restores the interrupt state when dropped. Must be
+// dropped on the CPU that took the lock.
+pub struct IrqSpinlockGuard<'a, T: ?Sized> {
+    lock: &'a IrqSpinlock<T>,
+    interrupts_enabled: bool,
+    _not_send: PhantomData<*const ()>,
+}
+
+impl<T: ?Sized> Deref for IrqSpinlockGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        unsafe { &*self.lock.data.get() }
+    }
+}
+
+impl<T: ?Sized> DerefMut for IrqSpinlockGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        unsafe { &mut *self.lock.data.get() }
+    }
+}
+
+impl<T: ?Sized> Drop for IrqSpinlockGuard<'_, T> {
+    fn drop(&mut self) {
+        if CHECKS {
+            track_release(&self.lock.info, self.interrupts_enabled);
+        }
+        self.lock.locked.store(false, Ordering::Release);
+        if self.interrupts_enabled {
+            interrupts::enable();
+        }
+    }
+}
+
+// Locks held by a single CPU, lives in its `percpu::PerCpu` area. Only
+// touched by that CPU with interrupts disabled.
+pub struct CpuLocks {
+    held: [AtomicPtr<LockInfo>; MAX_HELD],
+    count: AtomicUsize,
+}
+
+impl CpuLocks {
+    const EMPTY: AtomicPtr<LockInfo> = AtomicPtr::new(core::ptr::null_mut());
+
+    pub const INIT: CpuLocks = CpuLocks {
+        held: [CpuLocks::EMPTY; MAX_HELD],
+        count: AtomicUsize::new(0),
+    };
+
+    // Locks the CPU holds, in the order they were taken
+    fn held(&self) -> impl Iterator<Item = &LockInfo> {
+        self.held[..self.count.load(Ordering::Relaxed)]
+            .iter()
+            .map(|info| unsafe { &*info.load(Ordering::Relaxed) })
+    }
+
+    pub fn count(&self) -> usize {
+        self.count.load(Ordering::Relaxed)
+    }
+}
+
+//////////////////////////////
+// API
+//////////////////////////////
+
+// Whether recursion, lock order and interrupt state are checked, only in
+// debug builds
+pub fn checks_enabled() -> bool {
+    CHECKS
+}
+
+// IrqSpinlocks held by the calling CPU, always 0 in release builds
+pub fn held_count() -> usize {
+    match percpu::try_this_cpu() {
+        Some(cpu) => cpu.locks.count(),
+        None => 0,
+    }
+}
+
+// Before `percpu::init` only the BSP runs and nothing is tracked
+fn check_acquire(info: &LockInfo) {
+    let cpu = match percpu::try_this_cpu() {
+        Some(cpu) => cpu,
+        None => return,
+    };
+
+    for held in cpu.locks.held() {
+        if core::ptr::eq(held, info) {
+            report(format_args!(
+                "IrqSpinlock '{}' locked recursively on CPU {}",
+                info.name,
+                cpu.id()
+            ));
+        }
+        if info.rank != UNRANKED && held.rank != UNRANKED && held.rank >= info.rank {
+            report(format_args!(
+                "IrqSpinlock '{}' (rank {}) locked while holding '{}' (rank {}) on CPU {}",
+                info.name,
+                info.rank,
+                held.name,
+                held.rank,
+                cpu.id()
+            ));
+        }
+    }
+}
+
+fn track_acquire(info: &LockInfo) {
+    let locks = match percpu::try_this_cpu() {
+        Some(cpu) => &cpu.locks,
+        None => return,
+    };
+
+    let count = locks.count.load(Ordering::Relaxed);
+    if count == MAX_HELD {
+        report(format_args!(
+            "IrqSpinlock '{}' locked while holding {} others",
+            info.name, MAX_HELD
+        ));
+    }
+    locks.held[count].store(info as *const LockInfo as *mut LockInfo, Ordering::Relaxed);
+    locks.count.store(count + 1, Ordering::Relaxed);
+}
+
+fn track_release(info: &LockInfo, enables_interrupts: bool) {
+    let locks = match percpu::try_this_cpu() {
+        Some(cpu) => &cpu.locks,
+        None => return,
+    };
+
+    let count = locks.count.load(Ordering::Relaxed);
+    let index = match locks.held().position(|held| core::ptr::eq(held, info)) {
+        Some(index) => index,
+        // Taken before `percpu::init`, so never tracked
+        None => return,
+    };
+    for i in index..count - 1 {
+        let next = locks.held[i + 1].load(Ordering::Relaxed);
+        locks.held[i].store(next, Ordering::Relaxed);
+    }
+    locks.count.store(count - 1, Ordering::Relaxed);
+
+    if enables_interrupts && count > 1 {
+        let other = locks.held().next().map_or("", |held| held.name);
+        report(format_args!(
+            "IrqSpinlock '{}' released before '{}', enabling interrupts with a lock held",
+            info.name, other
+        ));
+    }
+}
+
+// The panic handler prints through IrqSpinlocks too. Should it trip over
+// one of them, fall back to the raw serial port rather than panicking
+// again.
+fn report(args: fmt::Arguments) -> ! {
+    if REPORTING.swap(true, Ordering::Relaxed) {
+        serial_emergency_println!("{}", args);
+        hlt_loop();
+    }
+    panic!("{}", args);
+}
+
+//////////////////////////////
+// Tests
+//////////////////////////////
+
+#[test_case]
+fn test_guard_restores_interrupt_state() {
+    let lock = IrqSpinlock::new("test", 0);
+
+    interrupts::enable();
+    {
+        let mut guard = lock.lock();
+        *guard += 1;
+        assert!(!interrupts::are_enabled());
+    }
+    assert!(interrupts::are_enabled());
+
+    interrupts::disable();
+    drop(lock.lock());
+    assert!(!interrupts::are_enabled());
+    interrupts::enable();
+
+    assert_eq!(lock.into_inner(), 1);
+}
+
+#[test_case]
+fn test_try_lock_fails_while_locked() {
+    let lock = IrqSpinlock::new("test", ());
+    let guard = lock.lock();
+    assert!(lock.is_locked());
+    assert!(lock.try_lock().is_none());
+    drop(guard);
+    assert!(lock.try_lock().is_some());
+}
+
+#[test_case]
+fn test_ranked_locks_in_order() {
+    let outer = IrqSpinlock::with_rank("outer", 1, ());
+    let inner = IrqSpinlock::with_rank("inner", 2, ());
+    let unranked = IrqSpinlock::new("unranked", ());
+
+    let before = held_count();
+    {
+        let _outer = outer.lock();
+        let _unranked = unranked.lock();
+        let _inner = inner.lock();
+        if CHECKS {
+            assert_eq!(held_count(), before + 3);
+        }
+    }
+    assert_eq!(held_count(), before);
+}
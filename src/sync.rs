@@ -0,0 +1,739 @@
+// sync.rs - Sleeping locks, semaphores and condition variables
+
+use crate::spinlock::IrqSpinlock;
+use crate::thread::{self, Thread};
+use crate::{signal, time, timer};
+
+use alloc::collections::VecDeque;
+use alloc::sync::Arc;
+use core::cell::UnsafeCell;
+use core::ops::{Deref, DerefMut};
+use core::time::Duration;
+use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use x86_64::instructions::interrupts;
+
+//////////////////////////////
+// Statics/Constants
+//////////////////////////////
+
+/*
+ *  Unlike `spin::Mutex` and `IrqSpinlock` these put a thread that has to
+ *  wait to sleep, letting others use the CPU meanwhile. They need
+ *  `thread::init` and must not be used from interrupt handlers, apart from
+ *  waking waiters.
+ *
+ *  Everything is built on `WaitQueue`. A waiter queues itself and is marked
+ *  blocked before it checks its condition one last time, so a wake-up
+ *  racing with the check makes it run again instead of being lost:
+ *
+ *    waiter                            waker
+ *      queue itself, prepare_to_block
+ *      condition false                   make condition true
+ *      block  <------------------------  wake_one
+ *
+ *  Timeouts are in milliseconds and rounded up to timer ticks.
+ *
+ *  Waits on behalf of a user process that may take long, e.g. for input,
+ *  are interruptible: a signal the process does not block wakes the waiter,
+ *  see `thread::interrupt`, and the system call fails with EINTR so the
+ *  signal is delivered on the way back to user mode.
+ */
+
+// `RwLock` state while a writer holds it
+const WRITER: usize = usize::MAX;
+
+////////////////////////////////
+// Structs, Types, Traits, Impl
+////////////////////////////////
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WaitResult {
+    Woken,
+    // Returned without sleeping, the condition was already met
+    Ready,
+    TimedOut,
+    // A signal is pending for the caller's process, see `wait_interruptible`
+    Interrupted,
+}
+
+// Threads waiting for something, woken in the order they started waiting
+pub struct WaitQueue {
+    waiters: IrqSpinlock<VecDeque<Arc<Thread>>>,
+}
+
+impl WaitQueue {
+    pub const fn new() -> WaitQueue {
+        WaitQueue {
+            waiters: IrqSpinlock::new("WaitQueue", VecDeque::new()),
+        }
+    }
+
+    // Sleep until woken
+    pub fn wait(&self) {
+        self.wait_unless(None, || false);
+    }
+
+    // Sleep until woken, or for at most `timeout_ms`. Returns false on
+    // timeout.
+    pub fn wait_timeout(&self, timeout_ms: u64) -> bool {
+        self.wait_unless(Some(timeout_ms), || false) != WaitResult::TimedOut
+    }
+
+    // Sleep until woken unless `ready` returns true. It runs once the caller
+    // is queued, with interrupts disabled, so a waker that changes what it
+    // checks and then wakes the queue is never missed.
+    pub fn wait_unless(&self, timeout_ms: Option<u64>, ready: impl FnOnce() -> bool) -> WaitResult {
+        self.wait_inner(timeout_ms, false, ready)
+    }
+
+    // Same as `wait_unless`, but also ends early with `Interrupted` once a
+    // signal that is not blocked is pending for the caller's process
+    pub fn wait_interruptible(
+        &self,
+        timeout_ms: Option<u64>,
+        ready: impl FnOnce() -> bool,
+    ) -> WaitResult {
+        self.wait_inner(timeout_ms, true, ready)
+    }
+
+    fn wait_inner(
+        &self,
+        timeout_ms: Option<u64>,
+        interruptible: bool,
+        ready: impl FnOnce() -> bool,
+    ) -> WaitResult {
+        let deadline = timeout_ms.map(deadline_after);
+        let current = thread::current();
+        // Checked once blocked, a signal sent after that wakes the thread
+        let interrupted = || interruptible && signal::interrupts(current.process());
+
+        let interrupts_enabled = interrupts::are_enabled();
+        interrupts::disable();
+        self.waiters.lock().push_back(current.clone());
+        current.set_interruptible(interruptible);
+        thread::prepare_to_block();
+
+        let result = if ready() {
+            thread::cancel_block();
+            self.remove(&current);
+            WaitResult::Ready
+        } else if interrupted() {
+            thread::cancel_block();
+            self.remove(&current);
+            WaitResult::Interrupted
+        } else {
+            thread::block(deadline);
+            // Still queued means no waker got to it before the deadline, or
+            // the signal did
+            if !self.remove(&current) {
+                WaitResult::Woken
+            } else if interrupted() {
+                WaitResult::Interrupted
+            } else {
+                WaitResult::TimedOut
+            }
+        };
+        current.set_interruptible(false);
+
+        if interrupts_enabled {
+            interrupts::enable();
+        }
+        result
+    }
+
+    // Wake the longest waiting thread, returns false if there was none
+    pub fn wake_one(&self) -> bool {
+        let waiter = self.waiters.lock().pop_front();
+        match waiter {
+            Some(thread) => {
+                thread::wake(&thread);
+                true
+            }
+            None => false,
+        }
+    }
+
+    // Wake every waiting thread, returns how many there were
+    pub fn wake_all(&self) -> usize {
+        let waiters = core::mem::take(&mut *self.waiters.lock());
+        for thread in waiters.iter() {
+            thread::wake(thread);
+        }
+        waiters.len()
+    }
+
+    pub fn len(&self) -> usize {
+        self.waiters.lock().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    fn remove(&self, thread: &Arc<Thread>) -> bool {
+        let mut waiters = self.waiters.lock();
+        match waiters
+            .iter()
+            .position(|waiter| Arc::ptr_eq(waiter, thread))
+        {
+            Some(index) => {
+                waiters.remove(index);
+                true
+            }
+            None => false,
+        }
+    }
+}
+
+impl Default for WaitQueue {
+    fn default() -> Self {
+        WaitQueue::new()
+    }
+}
+
+// Mutual exclusion lock that sleeps while another thread holds it
+pub struct Mutex<T: ?Sized> {
+    locked: AtomicBool,
+    waiters: WaitQueue,
+    data: UnsafeCell<T>,
+}
+
+unsafe impl<T: ?Sized + Send> Sync for Mutex<T> {}
+unsafe impl<T: ?Sized + Send> Send for Mutex<T> {}
+
+impl<T> Mutex<T> {
+    pub const fn new(value: T) -> Mutex<T> {
+        Mutex {
+            locked: AtomicBool::new(false),
+            waiters: WaitQueue::new(),
+            data: UnsafeCell::new(value),
+        }
+    }
+
+    pub fn into_inner(self) -> T {
+        self.data.into_inner()
+    }
+}
+
+impl<T: ?Sized> Mutex<T> {
+    pub fn lock(&self) -> MutexGuard<'_, T> {
+        loop {
+            if let Some(guard) = self.try_lock() {
+                return guard;
+            }
+            self.waiters
+                .wait_unless(None, || !self.locked.load(Ordering::Relaxed));
+        }
+    }
+
+    // None if the lock could not be taken within `timeout_ms`
+    pub fn lock_timeout(&self, timeout_ms: u64) -> Option<MutexGuard<'_, T>> {
+        let deadline = deadline_after(timeout_ms);
+        loop {
+            if let Some(guard) = self.try_lock() {
+                return Some(guard);
+            }
+            let left = remaining_ms(deadline)?;
+            self.waiters
+                .wait_unless(Some(left), || !self.locked.load(Ordering::Relaxed));
+        }
+    }
+
+    pub fn try_lock(&self) -> Option<MutexGuard<'_, T>> {
+        self.locked
+            .compare_exchange(false, true, Ordering::Acquire, Ordering::Relaxed)
+            .ok()
+            .map(|_| MutexGuard { mutex: self })
+    }
+
+    pub fn is_locked(&self) -> bool {
+        self.locked.load(Ordering::Relaxed)
+    }
+
+    pub fn get_mut(&mut self) -> &mut T {
+        self.data.get_mut()
+    }
+
+    fn unlock(&self) {
+        self.locked.store(false, Ordering::Release);
+        self.waiters.wake_one();
+    }
+}
+
+impl<T: Default> Default for Mutex<T> {
+    fn default() -> Self {
+        Mutex::new(T::default())
+    }
+}
+
+pub struct MutexGuard<'a, T: ?Sized> {
+    mutex: &'a Mutex<T>,
+}
+
+impl<T: ?Sized> Deref for MutexGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        unsafe { &*self.mutex.data.get() }
+    }
+}
+
+impl<T: ?Sized> DerefMut for MutexGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        unsafe { &mut *self.mutex.data.get() }
+    }
+}
+
+impl<T: ?Sized> Drop for MutexGuard<'_, T> {
+    fn drop(&mut self) {
+        self.mutex.unlock();
+    }
+}
+
+// Any number of readers or a single writer. Waiting writers do not stop
+// new readers, so a steady stream of readers can starve them.
+pub struct RwLock<T: ?Sized> {
+    // Readers holding the lock, or `WRITER` while a writer does
+    state: AtomicUsize,
+    waiters: WaitQueue,
+    data: UnsafeCell<T>,
+}
+
+unsafe impl<T: ?Sized + Send + Sync> Sync for RwLock<T> {}
+unsafe impl<T: ?Sized + Send> Send for RwLock<T> {}
+
+impl<T> RwLock<T> {
+    pub const fn new(value: T) -> RwLock<T> {
+        RwLock {
+            state: AtomicUsize::new(0),
+            waiters: WaitQueue::new(),
+            data: UnsafeCell::new(value),
+        }
+    }
+
+    pub fn into_inner(self) -> T {
+        self.data.into_inner()
+    }
+}
+
+impl<T: ?Sized> RwLock<T> {
+    pub fn read(&self) -> RwLockReadGuard<'_, T> {
+        loop {
+            if let Some(guard) = self.try_read() {
+                return guard;
+            }
+            self.waiters
+                .wait_unless(None, || self.state.load(Ordering::Relaxed) != WRITER);
+        }
+    }
+
+    pub fn write(&self) -> RwLockWriteGuard<'_, T> {
+        loop {
+            if let Some(guard) = self.try_write() {
+                return guard;
+            }
+            self.waiters
+                .wait_unless(None, || self.state.load(Ordering::Relaxed) == 0);
+        }
+    }
+
+    pub fn read_timeout(&self, timeout_ms: u64) -> Option<RwLockReadGuard<'_, T>> {
+        let deadline = deadline_after(timeout_ms);
+        loop {
+            if let Some(guard) = self.try_read() {
+                return Some(guard);
+            }
+            let left = remaining_ms(deadline)?;
+            self.waiters
+                .wait_unless(Some(left), || self.state.load(Ordering::Relaxed) != WRITER);
+        }
+    }
+
+    pub fn write_timeout(&self, timeout_ms: u64) -> Option<RwLockWriteGuard<'_, T>> {
+        let deadline = deadline_after(timeout_ms);
+        loop {
+            if let Some(guard) = self.try_write() {
+                return Some(guard);
+            }
+            let left = remaining_ms(deadline)?;
+            self.waiters
+                .wait_unless(Some(left), || self.state.load(Ordering::Relaxed) == 0);
+        }
+    }
+
+    pub fn try_read(&self) -> Option<RwLockReadGuard<'_, T>> {
+        let mut readers = self.state.load(Ordering::Relaxed);
+        while readers != WRITER && readers != WRITER - 1 {
+            match self.state.compare_exchange_weak(
+                readers,
+                readers + 1,
+                Ordering::Acquire,
+                Ordering::Relaxed,
+            ) {
+                Ok(_) => return Some(RwLockReadGuard { lock: self }),
+                Err(current) => readers = current,
+            }
+        }
+        None
+    }
+
+    pub fn try_write(&self) -> Option<RwLockWriteGuard<'_, T>> {
+        self.state
+            .compare_exchange(0, WRITER, Ordering::Acquire, Ordering::Relaxed)
+            .ok()
+            .map(|_| RwLockWriteGuard { lock: self })
+    }
+
+    pub fn get_mut(&mut self) -> &mut T {
+        self.data.get_mut()
+    }
+}
+
+impl<T: Default> Default for RwLock<T> {
+    fn default() -> Self {
+        RwLock::new(T::default())
+    }
+}
+
+pub struct RwLockReadGuard<'a, T: ?Sized> {
+    lock: &'a RwLock<T>,
+}
+
+impl<T: ?Sized> Deref for RwLockReadGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        unsafe { &*self.lock.data.get() }
+    }
+}
+
+impl<T: ?Sized> Drop for RwLockReadGuard<'_, T> {
+    fn drop(&mut self) {
+        // Only a writer can be waiting for the last reader
+        if self.lock.state.fetch_sub(1, Ordering::Release) == 1 {
+            self.lock.waiters.wake_one();
+        }
+    }
+}
+
+pub struct RwLockWriteGuard<'a, T: ?Sized> {
+    lock: &'a RwLock<T>,
+}
+
+impl<T: ?Sized> Deref for RwLockWriteGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        unsafe { &*self.lock.data.get() }
+    }
+}
+
+impl<T: ?Sized> DerefMut for RwLockWriteGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        unsafe { &mut *self.lock.data.get() }
+    }
+}
+
+impl<T: ?Sized> Drop for RwLockWriteGuard<'_, T> {
+    fn drop(&mut self) {
+        self.lock.state.store(0, Ordering::Release);
+        // Either all readers or one writer can go on
+        self.lock.waiters.wake_all();
+    }
+}
+
+// Counting semaphore
+pub struct Semaphore {
+    permits: AtomicUsize,
+    waiters: WaitQueue,
+}
+
+impl Semaphore {
+    pub const fn new(permits: usize) -> Semaphore {
+        Semaphore {
+            permits: AtomicUsize::new(permits),
+            waiters: WaitQueue::new(),
+        }
+    }
+
+    // Take a permit, sleeping until one is available
+    pub fn acquire(&self) {
+        while !self.try_acquire() {
+            self.waiters
+                .wait_unless(None, || self.permits.load(Ordering::Relaxed) > 0);
+        }
+    }
+
+    // Returns false if no permit became available within `timeout_ms`
+    pub fn acquire_timeout(&self, timeout_ms: u64) -> bool {
+        let deadline = deadline_after(timeout_ms);
+        while !self.try_acquire() {
+            let left = match remaining_ms(deadline) {
+                Some(left) => left,
+                None => return false,
+            };
+            self.waiters
+                .wait_unless(Some(left), || self.permits.load(Ordering::Relaxed) > 0);
+        }
+        true
+    }
+
+    pub fn try_acquire(&self) -> bool {
+        self.permits
+            .fetch_update(Ordering::Acquire, Ordering::Relaxed, |permits| {
+                permits.checked_sub(1)
+            })
+            .is_ok()
+    }
+
+    // Return a permit, waking a waiter if there is one
+    pub fn release(&self) {
+        self.permits.fetch_add(1, Ordering::Release);
+        self.waiters.wake_one();
+    }
+
+    pub fn available(&self) -> usize {
+        self.permits.load(Ordering::Relaxed)
+    }
+}
+
+// Condition variable for `Mutex`. Wake-ups may be spurious, so callers
+// check their condition in a loop or use `wait_while`.
+pub struct Condvar {
+    waiters: WaitQueue,
+}
+
+impl Condvar {
+    pub const fn new() -> Condvar {
+        Condvar {
+            waiters: WaitQueue::new(),
+        }
+    }
+
+    // Unlock the mutex and sleep until notified, then lock it again
+    pub fn wait<'a, T: ?Sized>(&self, guard: MutexGuard<'a, T>) -> MutexGuard<'a, T> {
+        let mutex = guard.mutex;
+        // Unlocked once queued, a notify right after is not missed
+        self.waiters.wait_unless(None, || {
+            drop(guard);
+            false
+        });
+        mutex.lock()
+    }
+
+    // Same as `wait`, but a signal for the caller's process also ends it,
+    // see `WaitQueue::wait_interruptible`. The flag is true then.
+    pub fn wait_interruptible<'a, T: ?Sized>(
+        &self,
+        guard: MutexGuard<'a, T>,
+    ) -> (MutexGuard<'a, T>, bool) {
+        let mutex = guard.mutex;
+        let result = self.waiters.wait_interruptible(None, || {
+            drop(guard);
+            false
+        });
+        (mutex.lock(), result == WaitResult::Interrupted)
+    }
+
+    // Same as `wait` for at most `timeout_ms`, the flag is true on timeout
+    pub fn wait_timeout<'a, T: ?Sized>(
+        &self,
+        guard: MutexGuard<'a, T>,
+        timeout_ms: u64,
+    ) -> (MutexGuard<'a, T>, bool) {
+        let mutex = guard.mutex;
+        let result = self.waiters.wait_unless(Some(timeout_ms), || {
+            drop(guard);
+            false
+        });
+        (mutex.lock(), result == WaitResult::TimedOut)
+    }
+
+    // Wait as long as `condition` holds for the protected value
+    pub fn wait_while<'a, T: ?Sized>(
+        &self,
+        mut guard: MutexGuard<'a, T>,
+        mut condition: impl FnMut(&mut T) -> bool,
+    ) -> MutexGuard<'a, T> {
+        while condition(&mut *guard) {
+            guard = self.wait(guard);
+        }
+        guard
+    }
+
+    // Same as `wait_while`, but a signal for the caller's process also ends
+    // it. The flag is true if `condition` still held then.
+    pub fn wait_while_interruptible<'a, T: ?Sized>(
+        &self,
+        mut guard: MutexGuard<'a, T>,
+        mut condition: impl FnMut(&mut T) -> bool,
+    ) -> (MutexGuard<'a, T>, bool) {
+        let mut interrupted = false;
+        while condition(&mut *guard) {
+            if interrupted {
+                return (guard, true);
+            }
+            let (next, signaled) = self.wait_interruptible(guard);
+            guard = next;
+            interrupted = signaled;
+        }
+        (guard, false)
+    }
+
+    // Same as `wait_while` for at most `timeout_ms` in all, the flag is true
+    // if `condition` still held when the time ran out
+    pub fn wait_timeout_while<'a, T: ?Sized>(
+        &self,
+        mut guard: MutexGuard<'a, T>,
+        timeout_ms: u64,
+        mut condition: impl FnMut(&mut T) -> bool,
+    ) -> (MutexGuard<'a, T>, bool) {
+        let deadline = deadline_after(timeout_ms);
+        while condition(&mut *guard) {
+            let left = match remaining_ms(deadline) {
+                Some(left) => left,
+                None => return (guard, true),
+            };
+            guard = self.wait_timeout(guard, left).0;
+        }
+        (guard, false)
+    }
+
+    pub fn notify_one(&self) {
+        self.waiters.wake_one();
+    }
+
+    pub fn notify_all(&self) {
+        self.waiters.wake_all();
+    }
+}
+
+impl Default for Condvar {
+    fn default() -> Self {
+        Condvar::new()
+    }
+}
+
+//////////////////////////////
+// API
+//////////////////////////////
+
+// Tick after which a timeout of `timeout_ms` starting now has passed
+fn deadline_after(timeout_ms: u64) -> u64 {
+    timer::deadline_after(Duration::from_millis(timeout_ms))
+}
+
+// Milliseconds left until `deadline`, None once it has passed
+fn remaining_ms(deadline: u64) -> Option<u64> {
+    let now = time::ticks();
+    if now >= deadline {
+        return None;
+    }
+    Some((deadline - now - 1) * 1000 / time::TICK_HZ)
+}
+
+//////////////////////////////
+// Tests
+//////////////////////////////
+
+#[test_case]
+fn test_mutex_excludes_threads() {
+    let counter = Arc::new(Mutex::new(0u64));
+    let handles: alloc::vec::Vec<_> = (0..3)
+        .map(|_| {
+            let counter = counter.clone();
+            thread::spawn("mutex", move || {
+                for _ in 0..100 {
+                    let mut value = counter.lock();
+                    let read = *value;
+                    // Give the others a chance to see the lock held
+                    thread::yield_now();
+                    *value = read + 1;
+                }
+            })
+        })
+        .collect();
+    for handle in handles {
+        handle.join();
+    }
+    assert_eq!(*counter.lock(), 300);
+}
+
+#[test_case]
+fn test_condvar_wakes_waiter() {
+    let pair = Arc::new((Mutex::new(false), Condvar::new()));
+    let shared = pair.clone();
+    let handle = thread::spawn("condvar", move || {
+        let (ready, condvar) = &*shared;
+        let ready = condvar.wait_while(ready.lock(), |ready| !*ready);
+        *ready
+    });
+
+    let (ready, condvar) = &*pair;
+    *ready.lock() = true;
+    condvar.notify_all();
+    assert!(handle.join());
+}
+
+#[test_case]
+fn test_condvar_wait_timeout_while() {
+    let pair = Arc::new((Mutex::new(0u32), Condvar::new()));
+    let (count, condvar) = &*pair;
+    let start = time::ticks();
+    let (guard, timed_out) = condvar.wait_timeout_while(count.lock(), 20, |count| *count == 0);
+    assert!(timed_out);
+    assert!(time::ticks() - start >= time::ms_to_ticks(20));
+    drop(guard);
+
+    let shared = pair.clone();
+    let handle = thread::spawn("condvar", move || {
+        let (count, condvar) = &*shared;
+        *count.lock() = 1;
+        condvar.notify_all();
+    });
+    let (guard, timed_out) = condvar.wait_timeout_while(count.lock(), 1000, |count| *count == 0);
+    assert!(!timed_out);
+    assert_eq!(*guard, 1);
+    drop(guard);
+    handle.join();
+}
+
+#[test_case]
+fn test_semaphore_timeout() {
+    let semaphore = Semaphore::new(0);
+    let start = time::ticks();
+    assert!(!semaphore.acquire_timeout(20));
+    assert!(time::ticks() - start >= time::ms_to_ticks(20));
+
+    semaphore.release();
+    assert!(semaphore.acquire_timeout(20));
+    assert_eq!(semaphore.available(), 0);
+}
+
+#[test_case]
+fn test_wait_queue_wake_one() {
+    let queue = Arc::new(WaitQueue::new());
+    let shared = queue.clone();
+    let handle = thread::spawn("waiter", move || shared.wait_timeout(1000));
+
+    while queue.is_empty() {
+        thread::yield_now();
+    }
+    assert!(queue.wake_one());
+    assert!(handle.join());
+    assert!(!queue.wake_one());
+}
+
+#[test_case]
+fn test_rwlock_readers_share() {
+    let lock = RwLock::new(1);
+    {
+        let first = lock.read();
+        let second = lock.read();
+        assert_eq!(*first + *second, 2);
+        assert!(lock.try_write().is_none());
+    }
+    *lock.write() += 1;
+    assert!(lock.try_read().is_some());
+    assert_eq!(lock.into_inner(), 2);
+}
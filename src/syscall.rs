@@ -0,0 +1,984 @@
+// syscall.rs - System call entry through SYSCALL/SYSRET and int 0x80
+
+use crate::address_space::AddressSpace;
+use crate::channel::{
+    self, ChannelError, Endpoint, Message, UserMessage, Wait, MSG_NONBLOCK, MSG_SYNC,
+};
+use crate::fd::{self, FileError, OpenFile};
+use crate::interrupts::SYSCALL_VECTOR;
+use crate::trap::TrapFrame;
+use crate::process::{self, ExitStatus, Pid, ProcessError};
+use crate::signal::{self, Action, SignalError, SIGPIPE};
+use crate::vm::{self, VmError};
+use crate::{gdt, irq_stats, percpu, pipe, println, thread, usermode};
+
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::convert::TryFrom;
+use x86_64::VirtAddr;
+
+//////////////////////////////
+// Statics/Constants
+//////////////////////////////
+
+/*
+ *  Calling convention, the same for `syscall` and `int 0x80`:
+ *
+ *    rax                     system call number
+ *    rdi, rsi, rdx, r10,     arguments 0 to 5 (r10 instead of rcx, which
+ *    r8, r9                  `syscall` overwrites with the return address)
+ *    rax                     result, or -errno on failure
+ *
+ *  `syscall` also clobbers r11 with RFLAGS, all other registers are
+ *  preserved. Numbers are never reused or reordered, new calls are added
+ *  at the end of `SYSCALLS`.
+ */
+pub const SYS_EXIT: u64 = 0;
+pub const SYS_WRITE: u64 = 1;
+pub const SYS_GETPID: u64 = 2;
+pub const SYS_GETPPID: u64 = 3;
+pub const SYS_WAITPID: u64 = 4;
+pub const SYS_BRK: u64 = 5;
+pub const SYS_MMAP: u64 = 6;
+pub const SYS_MUNMAP: u64 = 7;
+pub const SYS_MPROTECT: u64 = 8;
+pub const SYS_KILL: u64 = 9;
+pub const SYS_SIGACTION: u64 = 10;
+pub const SYS_SIGPROCMASK: u64 = 11;
+pub const SYS_SIGRETURN: u64 = 12;
+pub const SYS_READ: u64 = 13;
+pub const SYS_OPEN: u64 = 14;
+pub const SYS_CLOSE: u64 = 15;
+pub const SYS_DUP2: u64 = 16;
+pub const SYS_PIPE: u64 = 17;
+pub const SYS_CHANNEL: u64 = 18;
+pub const SYS_SEND: u64 = 19;
+pub const SYS_RECEIVE: u64 = 20;
+
+const SYSCALLS: [Handler; 21] = [
+    sys_exit,
+    sys_write,
+    sys_getpid,
+    sys_getppid,
+    sys_waitpid,
+    sys_brk,
+    sys_mmap,
+    sys_munmap,
+    sys_mprotect,
+    sys_kill,
+    sys_sigaction,
+    sys_sigprocmask,
+    sys_sigreturn,
+    sys_read,
+    sys_open,
+    sys_close,
+    sys_dup2,
+    sys_pipe,
+    sys_channel,
+    sys_send,
+    sys_receive,
+];
+
+// `waitpid` option, return 0 instead of blocking
+pub const WNOHANG: u64 = 1;
+
+const PAGE_SIZE: u64 = 4096;
+
+// Longest path `open` accepts
+const PATH_MAX: u64 = 4096;
+
+/*
+ *  `syscall` loads CS and SS from STAR and RIP from LSTAR, saves the user
+ *  RIP in rcx and RFLAGS in r11, and clears the RFLAGS bits set in SFMASK.
+ *  It does not switch stacks, so the stub below does that through the
+ *  per-CPU area before anything else, then builds the same `TrapFrame` as
+ *  trap.rs:
+ *
+ *    |  USER_DATA_SELECTOR      |  ss
+ *    |  user rsp                |  rsp     saved in PerCpu.user_stack
+ *    |  r11                     |  rflags
+ *    |  USER_CODE_SELECTOR      |  cs
+ *    |  rcx                     |  rip
+ *    |  0, SYSCALL_VECTOR       |  error code, vector
+ *    |  rax ... r15             |
+ *    <- rsp, &TrapFrame          on PerCpu.kernel_stack
+ *
+ *  It returns with `sysretq` unless the frame was changed to something
+ *  `sysretq` cannot restore, see `can_sysret`.
+ */
+core::arch::global_asm!(
+    r#"
+    .text
+    .global syscall_entry
+syscall_entry:
+    swapgs
+    movq %rsp, %gs:{user_stack}
+    movq %gs:{kernel_stack}, %rsp
+
+    pushq ${user_data}
+    pushq %gs:{user_stack}
+    pushq %r11
+    pushq ${user_code}
+    pushq %rcx
+    pushq $0
+    pushq ${vector}
+
+    pushq %rax
+    pushq %rbx
+    pushq %rcx
+    pushq %rdx
+    pushq %rsi
+    pushq %rdi
+    pushq %rbp
+    pushq %r8
+    pushq %r9
+    pushq %r10
+    pushq %r11
+    pushq %r12
+    pushq %r13
+    pushq %r14
+    pushq %r15
+
+    # 22 pushes from the 16 byte aligned stack top, aligned for the call
+    movq %rsp, %rdi
+    cld
+    callq syscall_dispatch
+    # Flags survive the pops below
+    testb %al, %al
+
+    popq %r15
+    popq %r14
+    popq %r13
+    popq %r12
+    popq %r11
+    popq %r10
+    popq %r9
+    popq %r8
+    popq %rbp
+    popq %rdi
+    popq %rsi
+    popq %rdx
+    popq %rcx
+    popq %rbx
+    popq %rax
+
+    jz 1f
+    movq 16(%rsp), %rcx
+    movq 32(%rsp), %r11
+    movq 40(%rsp), %rsp
+    swapgs
+    sysretq
+
+1:
+    testb $3, 24(%rsp)
+    jz 2f
+    swapgs
+2:
+    addq $16, %rsp
+    iretq
+    "#,
+    user_stack = const percpu::USER_STACK_OFFSET,
+    kernel_stack = const percpu::KERNEL_STACK_OFFSET,
+    user_data = const gdt::USER_DATA_SELECTOR,
+    user_code = const gdt::USER_CODE_SELECTOR,
+    vector = const SYSCALL_VECTOR,
+    options(att_syntax)
+);
+
+extern "C" {
+    fn syscall_entry();
+}
+
+////////////////////////////////
+// Structs, Types, Traits, Impl
+////////////////////////////////
+
+// Error numbers, returned negated in rax. Values follow Linux.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u64)]
+pub enum Errno {
+    EPERM = 1,
+    ENOENT = 2,
+    ESRCH = 3,
+    EINTR = 4,
+    EIO = 5,
+    EBADF = 9,
+    ECHILD = 10,
+    EAGAIN = 11,
+    ENOMEM = 12,
+    EFAULT = 14,
+    EEXIST = 17,
+    EINVAL = 22,
+    EMFILE = 24,
+    EPIPE = 32,
+    ENAMETOOLONG = 36,
+    ENOSYS = 38,
+    EMSGSIZE = 90,
+    ETIMEDOUT = 110,
+}
+
+impl From<VmError> for Errno {
+    fn from(error: VmError) -> Self {
+        match error {
+            VmError::Misaligned | VmError::InvalidArgument => Errno::EINVAL,
+            VmError::NotAnonymous => Errno::EBADF,
+            VmError::Overlap => Errno::EEXIST,
+            VmError::NotMapped | VmError::OutOfMemory => Errno::ENOMEM,
+        }
+    }
+}
+
+impl From<FileError> for Errno {
+    fn from(error: FileError) -> Self {
+        match error {
+            FileError::BadDescriptor => Errno::EBADF,
+            FileError::TooManyFiles => Errno::EMFILE,
+            FileError::NotFound => Errno::ENOENT,
+            FileError::InvalidArgument => Errno::EINVAL,
+            FileError::BrokenPipe => Errno::EPIPE,
+            FileError::Interrupted => Errno::EINTR,
+        }
+    }
+}
+
+impl From<ChannelError> for Errno {
+    fn from(error: ChannelError) -> Errno {
+        match error {
+            ChannelError::WouldBlock => Errno::EAGAIN,
+            ChannelError::TimedOut => Errno::ETIMEDOUT,
+            ChannelError::PeerClosed => Errno::EPIPE,
+            ChannelError::Interrupted => Errno::EINTR,
+            ChannelError::TooLarge | ChannelError::BufferTooSmall { .. } => Errno::EMSGSIZE,
+            ChannelError::InvalidHandle | ChannelError::InvalidArgument => Errno::EINVAL,
+        }
+    }
+}
+
+impl From<SignalError> for Errno {
+    fn from(error: SignalError) -> Self {
+        match error {
+            SignalError::InvalidSignal | SignalError::InvalidArgument => Errno::EINVAL,
+            SignalError::NoSuchProcess => Errno::ESRCH,
+            SignalError::NotPermitted => Errno::EPERM,
+        }
+    }
+}
+
+pub type SyscallResult = Result<u64, Errno>;
+
+type Handler = fn(&[u64; 6]) -> SyscallResult;
+
+//////////////////////////////
+// API
+//////////////////////////////
+
+// Enable `syscall` on the calling CPU, after `gdt::init_cpu`
+pub fn init_cpu() {
+    use x86_64::registers::model_specific::{Efer, EferFlags, LStar, SFMask, Star};
+    use x86_64::registers::rflags::RFlags;
+
+    let selectors = gdt::selectors();
+    Star::write(
+        selectors.user_code_selector,
+        selectors.user_data_selector,
+        selectors.code_selector,
+        selectors.data_selector,
+    )
+    .expect("GDT layout does not match syscall/sysret");
+    LStar::write(VirtAddr::new(syscall_entry as *const () as u64));
+    // Enter with interrupts off until the stack is switched, and with the
+    // flags the kernel expects
+    SFMask::write(
+        RFlags::INTERRUPT_FLAG
+            | RFlags::TRAP_FLAG
+            | RFlags::DIRECTION_FLAG
+            | RFlags::ALIGNMENT_CHECK,
+    );
+    unsafe { Efer::update(|flags| flags.insert(EferFlags::SYSTEM_CALL_EXTENSIONS)) };
+}
+
+// Run the system call in `frame` and store its result in rax. Entered with
+// interrupts disabled, which they are again on return for the exit path.
+pub fn handle(frame: &mut TrapFrame) {
+    use x86_64::instructions::interrupts;
+
+    // Replaces the whole frame rather than returning a result
+    if frame.rax == SYS_SIGRETURN && frame.from_user_mode() {
+        signal::sigreturn(frame);
+        return;
+    }
+
+    interrupts::enable();
+    let args = [
+        frame.rdi, frame.rsi, frame.rdx, frame.r10, frame.r8, frame.r9,
+    ];
+    frame.rax = encode(dispatch(frame.rax, &args));
+    interrupts::disable();
+}
+
+pub fn dispatch(number: u64, args: &[u64; 6]) -> SyscallResult {
+    match SYSCALLS.get(number as usize) {
+        Some(handler) => handler(args),
+        None => Err(Errno::ENOSYS),
+    }
+}
+
+// Value of rax for a result
+pub fn encode(result: SyscallResult) -> u64 {
+    match result {
+        Ok(value) => value,
+        Err(errno) => (errno as u64).wrapping_neg(),
+    }
+}
+
+#[no_mangle]
+extern "C" fn syscall_dispatch(frame: &mut TrapFrame) -> bool {
+    irq_stats::record(SYSCALL_VECTOR);
+    let number = frame.rax;
+    handle(frame);
+    signal::deliver(frame);
+    // `sysretq` would clobber the rcx and r11 `sigreturn` restored
+    number != SYS_SIGRETURN && can_sysret(frame)
+}
+
+// `sysretq` always returns to ring 3 with the fixed selectors, and faults
+// in ring 0 on a non-canonical rip, after the user GS base is loaded
+fn can_sysret(frame: &TrapFrame) -> bool {
+    frame.cs == u64::from(gdt::USER_CODE_SELECTOR)
+        && frame.ss == u64::from(gdt::USER_DATA_SELECTOR)
+        && frame.rip < usermode::USER_SPACE_END
+}
+
+// exit(code)
+// Ends the calling process. Ring 3 code run by a kernel thread, without a
+// process of its own, can only be stopped for good.
+fn sys_exit(args: &[u64; 6]) -> SyscallResult {
+    let code = args[0] as i32;
+    if process::current_pid() == process::KERNEL_PID {
+        println!("User program exited with code {}", code);
+        crate::hlt_loop();
+    }
+    process::exit(code);
+}
+
+// write(fd, buffer, len) -> bytes written
+// Writing to a pipe without readers fails with EPIPE and sends SIGPIPE
+fn sys_write(args: &[u64; 6]) -> SyscallResult {
+    let (fd, addr, len) = (args[0], args[1], args[2]);
+    let file = process::current().files().get(fd)?;
+    if !usermode::is_user_range(addr, len, false) {
+        return Err(Errno::EFAULT);
+    }
+
+    let bytes = unsafe { core::slice::from_raw_parts(addr as *const u8, len as usize) };
+    match file.write(bytes) {
+        Ok(written) => Ok(written as u64),
+        Err(FileError::BrokenPipe) => {
+            // Fails for the kernel process, which gets only the error
+            let _ = signal::send(process::current_pid(), SIGPIPE);
+            Err(Errno::EPIPE)
+        }
+        Err(error) => Err(error.into()),
+    }
+}
+
+// getpid() -> pid
+fn sys_getpid(_args: &[u64; 6]) -> SyscallResult {
+    Ok(process::current_pid().as_u64())
+}
+
+// getppid() -> pid of the parent, 0 for the kernel
+fn sys_getppid(_args: &[u64; 6]) -> SyscallResult {
+    Ok(process::current()
+        .parent()
+        .map_or(0, |parent| parent.as_u64()))
+}
+
+// waitpid(pid, status, options) -> pid of the reaped child
+// A pid of -1 waits for any child. Unless `status` is 0 the int there gets
+// the exit code in bits 8 to 15, or the signal that killed the child in
+// bits 0 to 6 and 0x80 if it dumped core. With WNOHANG returns 0 when no
+// child has exited yet.
+fn sys_waitpid(args: &[u64; 6]) -> SyscallResult {
+    let (pid, status, options) = (args[0] as i64, args[1], args[2]);
+    let pid = match pid {
+        -1 => None,
+        pid if pid > 0 => Some(Pid::new(pid as u64)),
+        _ => return Err(Errno::EINVAL),
+    };
+    if options & !WNOHANG != 0 {
+        return Err(Errno::EINVAL);
+    }
+    if status != 0 && !usermode::is_user_range(status, 4, true) {
+        return Err(Errno::EFAULT);
+    }
+
+    let reaped = process::waitpid(pid, options & WNOHANG != 0).map_err(|error| match error {
+        ProcessError::OutOfMemory => Errno::ENOMEM,
+        ProcessError::NoChildren | ProcessError::NotChild => Errno::ECHILD,
+        ProcessError::Interrupted => Errno::EINTR,
+    })?;
+    match reaped {
+        Some((pid, exit_status)) => {
+            if status != 0 {
+                let value = match exit_status {
+                    ExitStatus::Exited(code) => (code & 0xff) << 8,
+                    ExitStatus::Signaled { signal, core: false } => signal as i32,
+                    ExitStatus::Signaled { signal, core: true } => signal as i32 | 0x80,
+                };
+                unsafe { (status as *mut i32).write_unaligned(value) };
+            }
+            Ok(pid.as_u64())
+        }
+        None => Ok(0),
+    }
+}
+
+// brk(addr) -> new break
+// Moves the end of the heap, which starts after the program's image. With
+// 0, or an address the heap cannot grow to, returns the current break.
+fn sys_brk(args: &[u64; 6]) -> SyscallResult {
+    let space = address_space()?;
+    Ok(vm::brk(&space, args[0]))
+}
+
+// mmap(addr, len, prot, flags, fd, offset) -> address
+// Only private anonymous memory, `fd` is ignored and `offset` must be page
+// aligned
+fn sys_mmap(args: &[u64; 6]) -> SyscallResult {
+    let (addr, len, prot, flags, offset) = (args[0], args[1], args[2], args[3], args[5]);
+    if !offset.is_multiple_of(PAGE_SIZE) {
+        return Err(Errno::EINVAL);
+    }
+    let space = address_space()?;
+    Ok(vm::mmap(&space, addr, len, prot, flags)?)
+}
+
+// munmap(addr, len) -> 0
+fn sys_munmap(args: &[u64; 6]) -> SyscallResult {
+    let space = address_space()?;
+    vm::munmap(&space, args[0], args[1])?;
+    Ok(0)
+}
+
+// mprotect(addr, len, prot) -> 0
+fn sys_mprotect(args: &[u64; 6]) -> SyscallResult {
+    let space = address_space()?;
+    vm::mprotect(&space, args[0], args[1], args[2])?;
+    Ok(0)
+}
+
+// kill(pid, signal) -> 0
+// Only to a single process, there are no process groups. Signal 0 checks
+// that `pid` exists.
+fn sys_kill(args: &[u64; 6]) -> SyscallResult {
+    let pid = match args[0] as i64 {
+        pid if pid > 0 => Pid::new(pid as u64),
+        _ => return Err(Errno::EINVAL),
+    };
+    signal::send(pid, signal_number(args[1])?)?;
+    Ok(0)
+}
+
+// sigaction(signal, action, old_action) -> 0
+// Either pointer may be 0, see `signal::Action` for the layout
+fn sys_sigaction(args: &[u64; 6]) -> SyscallResult {
+    let (signal, new, old) = (signal_number(args[0])?, args[1], args[2]);
+    let size = core::mem::size_of::<Action>() as u64;
+    if (new != 0 && !usermode::is_user_range(new, size, false))
+        || (old != 0 && !usermode::is_user_range(old, size, true))
+    {
+        return Err(Errno::EFAULT);
+    }
+
+    let action = match new {
+        0 => None,
+        new => Some(unsafe { (new as *const Action).read_unaligned() }),
+    };
+    let previous = signal::sigaction(signal, action)?;
+    if old != 0 {
+        unsafe { (old as *mut Action).write_unaligned(previous) };
+    }
+    Ok(0)
+}
+
+// sigprocmask(how, set, old_set) -> 0
+// Masks are a u64 each, either pointer may be 0
+fn sys_sigprocmask(args: &[u64; 6]) -> SyscallResult {
+    let (how, new, old) = (args[0], args[1], args[2]);
+    if (new != 0 && !usermode::is_user_range(new, 8, false))
+        || (old != 0 && !usermode::is_user_range(old, 8, true))
+    {
+        return Err(Errno::EFAULT);
+    }
+
+    let set = match new {
+        0 => None,
+        new => Some(unsafe { (new as *const u64).read_unaligned() }),
+    };
+    let previous = signal::sigprocmask(how, set)?;
+    if old != 0 {
+        unsafe { (old as *mut u64).write_unaligned(previous) };
+    }
+    Ok(0)
+}
+
+// sigreturn()
+// Needs the whole frame, `handle` calls `signal::sigreturn` for user mode.
+// Anything else has no signal handler to return from.
+fn sys_sigreturn(_args: &[u64; 6]) -> SyscallResult {
+    Err(Errno::EINVAL)
+}
+
+// read(fd, buffer, len) -> bytes read
+// Returns 0 at the end of the file. Blocks until there is something to
+// read, e.g. on an empty pipe.
+fn sys_read(args: &[u64; 6]) -> SyscallResult {
+    let (fd, addr, len) = (args[0], args[1], args[2]);
+    let file = process::current().files().get(fd)?;
+    if !usermode::is_user_range(addr, len, true) {
+        return Err(Errno::EFAULT);
+    }
+
+    let buffer = unsafe { core::slice::from_raw_parts_mut(addr as *mut u8, len as usize) };
+    Ok(file.read(buffer)? as u64)
+}
+
+// open(path, len, flags) -> fd
+// The path is `len` bytes, not NUL terminated. Only devices, see fd.rs.
+fn sys_open(args: &[u64; 6]) -> SyscallResult {
+    let (addr, len, flags) = (args[0], args[1], args[2]);
+    if len > PATH_MAX {
+        return Err(Errno::ENAMETOOLONG);
+    }
+    if !usermode::is_user_range(addr, len, false) {
+        return Err(Errno::EFAULT);
+    }
+
+    let bytes = unsafe { core::slice::from_raw_parts(addr as *const u8, len as usize) };
+    let path = core::str::from_utf8(bytes).map_err(|_| Errno::ENOENT)?;
+    let file = fd::open(path, flags)?;
+    let fd = process::current().files().insert(&file)?;
+    Ok(fd)
+}
+
+// close(fd) -> 0
+fn sys_close(args: &[u64; 6]) -> SyscallResult {
+    let file = process::current().files().remove(args[0])?;
+    // Last, the lock around the table is released by now
+    drop(file);
+    Ok(0)
+}
+
+// dup2(old_fd, new_fd) -> new_fd
+// Makes `new_fd` refer to the file of `old_fd`, closing what it referred to
+fn sys_dup2(args: &[u64; 6]) -> SyscallResult {
+    let (old, new) = (args[0], args[1]);
+    let process = process::current();
+    let mut files = process.files();
+    let file = files.get(old)?;
+    if old == new {
+        return Ok(new);
+    }
+    let previous = files.install(new, &file)?;
+    drop(files);
+    drop(previous);
+    Ok(new)
+}
+
+// pipe(fds) -> 0
+// Stores the descriptors of the read end and the write end, in that order,
+// as two ints at `fds`
+fn sys_pipe(args: &[u64; 6]) -> SyscallResult {
+    install_pair(args[0], pipe::pipe)
+}
+
+// channel(fds) -> 0
+// Stores the descriptors of the two endpoints as two ints at `fds`
+fn sys_channel(args: &[u64; 6]) -> SyscallResult {
+    install_pair(args[0], channel::channel)
+}
+
+// send(fd, message, flags, timeout_ms) -> 0
+// Sends the bytes and descriptors `message` points to, see
+// `channel::UserMessage`. The descriptors are closed once the message is
+// sent. With MSG_SYNC waits until it was received, with MSG_NONBLOCK
+// fails with EAGAIN instead of waiting for room. Waiting longer than
+// `timeout_ms` fails with ETIMEDOUT, unless it is NO_TIMEOUT.
+fn sys_send(args: &[u64; 6]) -> SyscallResult {
+    let (fd, addr, flags) = (args[0], args[1], args[2]);
+    let wait = channel_wait(flags, MSG_SYNC | MSG_NONBLOCK, args[3])?;
+    let process = process::current();
+    let file = process.files().get(fd)?;
+    let endpoint = file.downcast::<Endpoint>().ok_or(Errno::EBADF)?;
+    let header = user_message(addr, false)?;
+    let data = unsafe {
+        core::slice::from_raw_parts(header.data as *const u8, header.data_len as usize)
+    };
+    let fds = unsafe {
+        core::slice::from_raw_parts(header.handles as *const u32, header.handle_count as usize)
+    };
+
+    let handles = {
+        let files = process.files();
+        fds.iter()
+            .map(|&fd| files.get(u64::from(fd)))
+            .collect::<Result<Vec<_>, _>>()?
+    };
+    let message = Message {
+        data: data.to_vec(),
+        handles: handles.clone(),
+    };
+    endpoint.send(message, flags & MSG_SYNC != 0, wait)?;
+
+    // Moved to the receiver, unless the descriptors were reused meanwhile
+    let mut closed = Vec::new();
+    let mut files = process.files();
+    for (&fd, handle) in fds.iter().zip(&handles) {
+        let fd = u64::from(fd);
+        if files.get(fd).is_ok_and(|file| Arc::ptr_eq(&file, handle)) {
+            if let Ok(file) = files.remove(fd) {
+                closed.push(file);
+            }
+        }
+    }
+    drop(files);
+    drop(closed);
+    Ok(0)
+}
+
+// receive(fd, message, flags, timeout_ms) -> 0
+// Receives the next message into the buffers `message` points to and
+// stores its lengths there. The files it carries get the lowest free
+// descriptors. If the message does not fit it stays queued and the call
+// fails with EMSGSIZE, or EMFILE if there are too few free descriptors.
+// Only MSG_NONBLOCK is a valid flag, timeouts are as for `send`.
+fn sys_receive(args: &[u64; 6]) -> SyscallResult {
+    let (fd, addr, flags) = (args[0], args[1], args[2]);
+    let wait = channel_wait(flags, MSG_NONBLOCK, args[3])?;
+    let process = process::current();
+    let file = process.files().get(fd)?;
+    let endpoint = file.downcast::<Endpoint>().ok_or(Errno::EBADF)?;
+    let header = user_message(addr, true)?;
+    let buffer = unsafe {
+        core::slice::from_raw_parts_mut(header.data as *mut u8, header.data_len as usize)
+    };
+    let fds = unsafe {
+        core::slice::from_raw_parts_mut(header.handles as *mut u32, header.handle_count as usize)
+    };
+
+    let free = fd::MAX_FILES - process.files().len();
+    let message = match endpoint.receive(buffer.len(), fds.len().min(free), wait) {
+        Ok(message) => message,
+        Err(ChannelError::BufferTooSmall { data, handles }) => {
+            store_lengths(addr, data, handles);
+            return Err(if data <= buffer.len() && handles <= fds.len() {
+                Errno::EMFILE
+            } else {
+                Errno::EMSGSIZE
+            });
+        }
+        Err(error) => return Err(error.into()),
+    };
+
+    // Another thread may have taken the free descriptors meanwhile, then
+    // the files are closed again
+    let mut files = process.files();
+    let mut installed = Vec::new();
+    for handle in message.handles.iter() {
+        match files.insert(handle) {
+            Ok(fd) => installed.push(fd),
+            Err(error) => {
+                let closed: Vec<_> = installed
+                    .iter()
+                    .filter_map(|&fd| files.remove(fd).ok())
+                    .collect();
+                drop(files);
+                drop(closed);
+                return Err(error.into());
+            }
+        }
+    }
+    drop(files);
+
+    buffer[..message.data.len()].copy_from_slice(&message.data);
+    for (slot, &fd) in fds.iter_mut().zip(&installed) {
+        *slot = fd as u32;
+    }
+    store_lengths(addr, message.data.len(), installed.len());
+    Ok(0)
+}
+
+// Open both files of a new pipe or channel, storing their descriptors as
+// two ints at `addr`
+fn install_pair(addr: u64, create: fn() -> (Arc<OpenFile>, Arc<OpenFile>)) -> SyscallResult {
+    if !usermode::is_user_range(addr, 8, true) {
+        return Err(Errno::EFAULT);
+    }
+
+    let (first, second) = create();
+    let process = process::current();
+    let mut files = process.files();
+    let first_fd = files.insert(&first)?;
+    let second_fd = match files.insert(&second) {
+        Ok(fd) => fd,
+        Err(error) => {
+            // Not the last reference, `first` still holds one
+            let _ = files.remove(first_fd);
+            return Err(error.into());
+        }
+    };
+    drop(files);
+
+    let fds = [first_fd as i32, second_fd as i32];
+    unsafe { (addr as *mut [i32; 2]).write_unaligned(fds) };
+    Ok(0)
+}
+
+// How `send` or `receive` waits, given its flags of which `valid` are
+fn channel_wait(flags: u64, valid: u64, timeout_ms: u64) -> Result<Wait, Errno> {
+    if flags & !valid != 0 {
+        return Err(Errno::EINVAL);
+    }
+    Ok(if flags & MSG_NONBLOCK != 0 {
+        Wait::NonBlocking
+    } else if timeout_ms == channel::NO_TIMEOUT {
+        Wait::Forever
+    } else {
+        Wait::Timeout(timeout_ms)
+    })
+}
+
+// The `UserMessage` at `addr`, with its buffers checked for writing too if
+// `write`. Buffers larger than any message are fine to receive into.
+fn user_message(addr: u64, write: bool) -> Result<UserMessage, Errno> {
+    let size = core::mem::size_of::<UserMessage>() as u64;
+    if !usermode::is_user_range(addr, size, write) {
+        return Err(Errno::EFAULT);
+    }
+    let mut header = unsafe { (addr as *const UserMessage).read_unaligned() };
+    let (max_data, max_handles) = (channel::MAX_MESSAGE_SIZE as u64, channel::MAX_HANDLES as u64);
+    if header.data_len > max_data || header.handle_count > max_handles {
+        if !write {
+            return Err(Errno::EMSGSIZE);
+        }
+        header.data_len = header.data_len.min(max_data);
+        header.handle_count = header.handle_count.min(max_handles);
+    }
+    if !header.handles.is_multiple_of(4) {
+        return Err(Errno::EINVAL);
+    }
+    if !usermode::is_user_range(header.data, header.data_len, write)
+        || !usermode::is_user_range(header.handles, header.handle_count * 4, write)
+    {
+        return Err(Errno::EFAULT);
+    }
+    Ok(header)
+}
+
+// Of the `UserMessage` at `addr`, which `user_message` checked
+fn store_lengths(addr: u64, data_len: usize, handle_count: usize) {
+    let header = addr as *mut UserMessage;
+    unsafe {
+        let mut message = header.read_unaligned();
+        message.data_len = data_len as u64;
+        message.handle_count = handle_count as u64;
+        header.write_unaligned(message);
+    }
+}
+
+fn signal_number(arg: u64) -> Result<u32, Errno> {
+    u32::try_from(arg).map_err(|_| Errno::EINVAL)
+}
+
+// Of the calling thread's process. Kernel threads have none to change.
+fn address_space() -> Result<Arc<AddressSpace>, Errno> {
+    thread::current().address_space().ok_or(Errno::ENOMEM)
+}
+
+//////////////////////////////
+// Tests
+//////////////////////////////
+
+#[test_case]
+fn test_dispatch_errors() {
+    assert_eq!(dispatch(u64::MAX, &[0; 6]), Err(Errno::ENOSYS));
+    assert_eq!(dispatch(SYS_WRITE, &[3, 0, 0, 0, 0, 0]), Err(Errno::EBADF));
+
+    // Kernel memory is not accessible to user mode
+    let buffer = b"kernel";
+    let args = [1, buffer.as_ptr() as u64, buffer.len() as u64, 0, 0, 0];
+    assert_eq!(dispatch(SYS_WRITE, &args), Err(Errno::EFAULT));
+
+    assert_eq!(encode(Err(Errno::ENOSYS)), -38i64 as u64);
+}
+
+#[test_case]
+fn test_process_calls() {
+    let child = process::spawn("syscalls", || {
+        let pid = process::current_pid().as_u64();
+        assert_eq!(dispatch(SYS_GETPID, &[0; 6]), Ok(pid));
+        assert_eq!(dispatch(SYS_GETPPID, &[0; 6]), Ok(0));
+    })
+    .expect("spawn failed");
+    let pid = child.pid().as_u64();
+
+    // Kernel memory is not a valid status pointer
+    let mut status = 0i32;
+    let args = [pid, &mut status as *mut i32 as u64, 0, 0, 0, 0];
+    assert_eq!(dispatch(SYS_WAITPID, &args), Err(Errno::EFAULT));
+    assert_eq!(dispatch(SYS_WAITPID, &[pid, 0, 0, 0, 0, 0]), Ok(pid));
+    assert!(process::get(child.pid()).is_none());
+    assert_eq!(dispatch(SYS_WAITPID, &[pid, 0, 0, 0, 0, 0]), Err(Errno::ECHILD));
+    assert_eq!(dispatch(SYS_WAITPID, &[0, 0, 0, 0, 0, 0]), Err(Errno::EINVAL));
+}
+
+#[test_case]
+fn test_memory_calls() {
+    // Kernel threads have no address space to map into
+    assert_eq!(dispatch(SYS_BRK, &[0; 6]), Err(Errno::ENOMEM));
+
+    let child = process::spawn("memory", || {
+        let (prot, flags) = (vm::PROT_READ | vm::PROT_WRITE, vm::MAP_PRIVATE | vm::MAP_ANONYMOUS);
+        let addr = dispatch(SYS_MMAP, &[0, PAGE_SIZE, prot, flags, u64::MAX, 0]).expect("mmap failed");
+
+        // Allocated before the kernel writes a status there, so waitpid gets
+        // as far as finding no such child
+        let args = [1, addr, 0, 0, 0, 0];
+        assert_eq!(dispatch(SYS_WAITPID, &args), Err(Errno::ECHILD));
+        assert!(usermode::is_user_range(addr, PAGE_SIZE, true));
+
+        let args = [0, PAGE_SIZE, prot, flags, u64::MAX, 1];
+        assert_eq!(dispatch(SYS_MMAP, &args), Err(Errno::EINVAL));
+        let args = [addr, PAGE_SIZE, prot, flags | vm::MAP_FIXED_NOREPLACE, u64::MAX, 0];
+        assert_eq!(dispatch(SYS_MMAP, &args), Err(Errno::EEXIST));
+        let args = [addr + PAGE_SIZE, PAGE_SIZE, vm::PROT_READ, 0, 0, 0];
+        assert_eq!(dispatch(SYS_MPROTECT, &args), Err(Errno::ENOMEM));
+        assert_eq!(dispatch(SYS_MUNMAP, &[addr + 1, PAGE_SIZE, 0, 0, 0, 0]), Err(Errno::EINVAL));
+        assert_eq!(dispatch(SYS_MUNMAP, &[addr, PAGE_SIZE, 0, 0, 0, 0]), Ok(0));
+        assert!(!usermode::is_user_range(addr, 1, false));
+
+        // A process without an image has no heap
+        assert_eq!(dispatch(SYS_BRK, &[0; 6]), Ok(0));
+    })
+    .expect("spawn failed");
+    assert_eq!(
+        process::waitpid(Some(child.pid()), false),
+        Ok(Some((child.pid(), ExitStatus::Exited(0))))
+    );
+}
+
+#[test_case]
+fn test_file_calls() {
+    use crate::signal::mask;
+
+    let child = process::spawn("files", || {
+        let (prot, flags) = (vm::PROT_READ | vm::PROT_WRITE, vm::MAP_PRIVATE | vm::MAP_ANONYMOUS);
+        let addr = dispatch(SYS_MMAP, &[0, PAGE_SIZE, prot, flags, u64::MAX, 0]).expect("mmap failed");
+        let (buffer, path) = (addr + 16, addr + 64);
+        let bytes = |addr: u64, len: usize| unsafe { core::slice::from_raw_parts_mut(addr as *mut u8, len) };
+
+        // Descriptors 0 to 2 are inherited, the pipe gets the next two
+        assert_eq!(dispatch(SYS_PIPE, &[addr, 0, 0, 0, 0, 0]), Ok(0));
+        assert_eq!(unsafe { (addr as *const [i32; 2]).read() }, [3, 4]);
+        bytes(buffer, 4).copy_from_slice(b"ping");
+        assert_eq!(dispatch(SYS_WRITE, &[4, buffer, 4, 0, 0, 0]), Ok(4));
+        assert_eq!(dispatch(SYS_READ, &[4, buffer, 4, 0, 0, 0]), Err(Errno::EBADF));
+        assert_eq!(dispatch(SYS_READ, &[3, buffer + 8, 8, 0, 0, 0]), Ok(4));
+        assert_eq!(bytes(buffer + 8, 4), b"ping");
+
+        // The read end as stdin, then the write end closed for EOF
+        assert_eq!(dispatch(SYS_DUP2, &[3, 0, 0, 0, 0, 0]), Ok(0));
+        assert_eq!(dispatch(SYS_CLOSE, &[3, 0, 0, 0, 0, 0]), Ok(0));
+        assert_eq!(dispatch(SYS_CLOSE, &[3, 0, 0, 0, 0, 0]), Err(Errno::EBADF));
+        assert_eq!(dispatch(SYS_CLOSE, &[4, 0, 0, 0, 0, 0]), Ok(0));
+        assert_eq!(dispatch(SYS_READ, &[0, buffer, 8, 0, 0, 0]), Ok(0));
+
+        // Writing without a reader fails and raises SIGPIPE
+        assert_eq!(dispatch(SYS_PIPE, &[addr, 0, 0, 0, 0, 0]), Ok(0));
+        assert_eq!(dispatch(SYS_CLOSE, &[3, 0, 0, 0, 0, 0]), Ok(0));
+        assert_eq!(dispatch(SYS_WRITE, &[4, buffer, 4, 0, 0, 0]), Err(Errno::EPIPE));
+        assert_ne!(process::current().signals().pending() & mask(SIGPIPE), 0);
+
+        bytes(path, 9).copy_from_slice(b"/dev/zero");
+        assert_eq!(dispatch(SYS_OPEN, &[path, 9, fd::O_RDONLY, 0, 0, 0]), Ok(3));
+        assert_eq!(dispatch(SYS_READ, &[3, buffer, 8, 0, 0, 0]), Ok(8));
+        assert_eq!(bytes(buffer, 8), &[0; 8]);
+        assert_eq!(dispatch(SYS_OPEN, &[path, 4, fd::O_RDONLY, 0, 0, 0]), Err(Errno::ENOENT));
+        assert_eq!(dispatch(SYS_OPEN, &[path, 9, 7, 0, 0, 0]), Err(Errno::EINVAL));
+        assert_eq!(dispatch(SYS_OPEN, &[path, PATH_MAX + 1, 0, 0, 0, 0]), Err(Errno::ENAMETOOLONG));
+        assert_eq!(dispatch(SYS_DUP2, &[3, fd::MAX_FILES as u64, 0, 0, 0, 0]), Err(Errno::EBADF));
+    })
+    .expect("spawn failed");
+    assert_eq!(
+        process::waitpid(Some(child.pid()), false),
+        Ok(Some((child.pid(), ExitStatus::Exited(0))))
+    );
+}
+
+#[test_case]
+fn test_channel_calls() {
+    use crate::channel::NO_TIMEOUT;
+
+    let child = process::spawn("channels", || {
+        let (prot, flags) = (vm::PROT_READ | vm::PROT_WRITE, vm::MAP_PRIVATE | vm::MAP_ANONYMOUS);
+        let addr = dispatch(SYS_MMAP, &[0, PAGE_SIZE, prot, flags, u64::MAX, 0]).expect("mmap failed");
+        let (message, handles, data) = (addr + 16, addr + 64, addr + 128);
+        let bytes = |addr: u64, len: usize| unsafe { core::slice::from_raw_parts_mut(addr as *mut u8, len) };
+        let slots = || unsafe { core::slice::from_raw_parts_mut(handles as *mut u32, 2) };
+        let set = |data_len: u64, handle_count: u64| {
+            let lengths = UserMessage { data, data_len, handles, handle_count };
+            unsafe { (message as *mut UserMessage).write(lengths) };
+        };
+        let lengths = || {
+            let message = unsafe { (message as *const UserMessage).read() };
+            (message.data_len, message.handle_count)
+        };
+
+        // A channel as 3 and 4, a pipe as 5 and 6
+        assert_eq!(dispatch(SYS_CHANNEL, &[addr, 0, 0, 0, 0, 0]), Ok(0));
+        assert_eq!(unsafe { (addr as *const [i32; 2]).read() }, [3, 4]);
+        assert_eq!(dispatch(SYS_PIPE, &[addr, 0, 0, 0, 0, 0]), Ok(0));
+
+        // The pipe's write end leaves with the message
+        bytes(data, 5).copy_from_slice(b"hello");
+        slots()[0] = 6;
+        set(5, 1);
+        assert_eq!(dispatch(SYS_SEND, &[3, message, 0, NO_TIMEOUT, 0, 0]), Ok(0));
+        assert_eq!(dispatch(SYS_CLOSE, &[6, 0, 0, 0, 0, 0]), Err(Errno::EBADF));
+        assert_eq!(dispatch(SYS_SEND, &[5, message, 0, NO_TIMEOUT, 0, 0]), Err(Errno::EBADF));
+        assert_eq!(dispatch(SYS_SEND, &[3, message, 4, NO_TIMEOUT, 0, 0]), Err(Errno::EINVAL));
+        slots()[0] = 4;
+        assert_eq!(dispatch(SYS_SEND, &[3, message, 0, NO_TIMEOUT, 0, 0]), Err(Errno::EINVAL));
+        set(channel::MAX_MESSAGE_SIZE as u64 + 1, 0);
+        assert_eq!(dispatch(SYS_SEND, &[3, message, 0, NO_TIMEOUT, 0, 0]), Err(Errno::EMSGSIZE));
+
+        // Too small a buffer gets the needed lengths, then the message
+        set(2, 1);
+        assert_eq!(dispatch(SYS_RECEIVE, &[4, message, 0, NO_TIMEOUT, 0, 0]), Err(Errno::EMSGSIZE));
+        assert_eq!(lengths(), (5, 1));
+        set(64, 2);
+        assert_eq!(dispatch(SYS_RECEIVE, &[4, message, MSG_NONBLOCK, 0, 0, 0]), Ok(0));
+        assert_eq!(lengths(), (5, 1));
+        assert_eq!(bytes(data, 5), b"hello");
+        assert_eq!(slots()[0], 6);
+        assert_eq!(dispatch(SYS_WRITE, &[6, data, 5, 0, 0, 0]), Ok(5));
+        assert_eq!(dispatch(SYS_READ, &[5, data + 8, 8, 0, 0, 0]), Ok(5));
+
+        // Nothing to receive, and nobody receiving a synchronous message
+        set(64, 0);
+        assert_eq!(dispatch(SYS_RECEIVE, &[4, message, MSG_NONBLOCK, 0, 0, 0]), Err(Errno::EAGAIN));
+        assert_eq!(dispatch(SYS_RECEIVE, &[4, message, 0, 10, 0, 0]), Err(Errno::ETIMEDOUT));
+        assert_eq!(dispatch(SYS_RECEIVE, &[4, message, MSG_SYNC, 0, 0, 0]), Err(Errno::EINVAL));
+        assert_eq!(dispatch(SYS_SEND, &[3, message, MSG_SYNC, 10, 0, 0]), Err(Errno::ETIMEDOUT));
+        assert_eq!(dispatch(SYS_RECEIVE, &[4, message, MSG_NONBLOCK, 0, 0, 0]), Err(Errno::EAGAIN));
+
+        assert_eq!(dispatch(SYS_CLOSE, &[4, 0, 0, 0, 0, 0]), Ok(0));
+        assert_eq!(dispatch(SYS_SEND, &[3, message, 0, NO_TIMEOUT, 0, 0]), Err(Errno::EPIPE));
+    })
+    .expect("spawn failed");
+    assert_eq!(
+        process::waitpid(Some(child.pid()), false),
+        Ok(Some((child.pid(), ExitStatus::Exited(0))))
+    );
+}
@@ -0,0 +1,256 @@
+// task.rs - Async tasks and the executor polling them
+
+use crate::{deferred, thread};
+
+use alloc::boxed::Box;
+use alloc::collections::BTreeMap;
+use alloc::sync::Arc;
+use alloc::task::Wake;
+use core::future::Future;
+use core::pin::Pin;
+use core::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use core::task::{Context, Poll, Waker};
+use crossbeam_queue::ArrayQueue;
+use x86_64::instructions::interrupts;
+
+//////////////////////////////
+// Statics/Constants
+//////////////////////////////
+
+/*
+ *  Tasks are cooperative, a task runs until its future returns Pending and
+ *  is only polled again after its waker is called. Waking pushes the task
+ *  id onto the executor's ready queue:
+ *
+ *    Waker::wake -> TaskWaker -> task_queue -> Executor::run_ready_tasks
+ *                                                   |
+ *                                                   v
+ *                                              Task::poll
+ *
+ *  The queue is lock-free so wakers may be called from interrupt handlers.
+ *  A task is queued at most once however often it is woken before being
+ *  polled, so the queue never holds more ids than there are tasks.
+ */
+pub const MAX_TASKS: usize = 100;
+
+static NEXT_ID: AtomicU64 = AtomicU64::new(0);
+
+////////////////////////////////
+// Structs, Types, Traits, Impl
+////////////////////////////////
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct TaskId(u64);
+
+impl TaskId {
+    fn new() -> TaskId {
+        TaskId(NEXT_ID.fetch_add(1, Ordering::Relaxed))
+    }
+
+    pub fn as_u64(self) -> u64 {
+        self.0
+    }
+}
+
+pub struct Task {
+    id: TaskId,
+    future: Pin<Box<dyn Future<Output = ()>>>,
+}
+
+impl Task {
+    pub fn new(future: impl Future<Output = ()> + 'static) -> Task {
+        Task {
+            id: TaskId::new(),
+            future: Box::pin(future),
+        }
+    }
+
+    pub fn id(&self) -> TaskId {
+        self.id
+    }
+
+    fn poll(&mut self, context: &mut Context) -> Poll<()> {
+        self.future.as_mut().poll(context)
+    }
+}
+
+// Runs tasks on the CPU that calls `run`
+pub struct Executor {
+    tasks: BTreeMap<TaskId, Task>,
+    task_queue: Arc<ArrayQueue<TaskId>>,
+    wakers: BTreeMap<TaskId, Arc<TaskWaker>>,
+}
+
+impl Executor {
+    pub fn new() -> Executor {
+        Executor {
+            tasks: BTreeMap::new(),
+            task_queue: Arc::new(ArrayQueue::new(MAX_TASKS)),
+            wakers: BTreeMap::new(),
+        }
+    }
+
+    // Queue `task` to be polled for the first time
+    pub fn spawn(&mut self, task: Task) {
+        let id = task.id;
+        assert!(self.tasks.len() < MAX_TASKS, "too many tasks");
+        if self.tasks.insert(id, task).is_some() {
+            panic!("task with same ID already in tasks");
+        }
+
+        let waker = Arc::new(TaskWaker {
+            id,
+            task_queue: self.task_queue.clone(),
+            queued: AtomicBool::new(true),
+        });
+        self.wakers.insert(id, waker);
+        self.task_queue.push(id).expect("task queue full");
+    }
+
+    // Poll tasks until all are done, sleeping while none is ready. Also runs
+    // deferred interrupt work and lets other threads run, like
+    // `crate::idle_loop` which it replaces.
+    pub fn run(&mut self) -> ! {
+        loop {
+            self.run_ready_tasks();
+            deferred::run_pending();
+            thread::yield_now();
+            self.sleep_if_idle();
+        }
+    }
+
+    // Tasks that have not completed yet
+    pub fn task_count(&self) -> usize {
+        self.tasks.len()
+    }
+
+    fn run_ready_tasks(&mut self) {
+        while let Some(id) = self.task_queue.pop() {
+            let task = match self.tasks.get_mut(&id) {
+                Some(task) => task,
+                None => continue,
+            };
+            let task_waker = &self.wakers[&id];
+
+            // Cleared before polling so a wake during the poll queues the
+            // task again
+            task_waker.queued.store(false, Ordering::Release);
+            let waker = Waker::from(task_waker.clone());
+            let mut context = Context::from_waker(&waker);
+            if task.poll(&mut context).is_ready() {
+                // Wakers still held elsewhere must not queue the id again
+                task_waker.queued.store(true, Ordering::Release);
+                self.tasks.remove(&id);
+                self.wakers.remove(&id);
+            }
+        }
+    }
+
+    fn sleep_if_idle(&self) {
+        // Check for work with interrupts disabled, otherwise a wake between
+        // the check and the hlt is not seen until the next interrupt
+        interrupts::disable();
+        if self.task_queue.is_empty() && !deferred::has_pending() && !thread::has_ready() {
+            interrupts::enable_and_hlt();
+        } else {
+            interrupts::enable();
+        }
+    }
+}
+
+impl Default for Executor {
+    fn default() -> Self {
+        Executor::new()
+    }
+}
+
+struct TaskWaker {
+    id: TaskId,
+    task_queue: Arc<ArrayQueue<TaskId>>,
+    // Whether `id` is in the queue or the task has completed
+    queued: AtomicBool,
+}
+
+impl TaskWaker {
+    fn wake_task(&self) {
+        if !self.queued.swap(true, Ordering::AcqRel) {
+            self.task_queue.push(self.id).expect("task queue full");
+        }
+    }
+}
+
+impl Wake for TaskWaker {
+    fn wake(self: Arc<Self>) {
+        self.wake_task();
+    }
+
+    fn wake_by_ref(self: &Arc<Self>) {
+        self.wake_task();
+    }
+}
+
+//////////////////////////////
+// Tests
+//////////////////////////////
+
+// Returns Pending until woken through the waker it leaves in `waker`
+#[cfg(test)]
+struct WaitForWake {
+    waker: Arc<spin::Mutex<Option<Waker>>>,
+    woken: bool,
+}
+
+#[cfg(test)]
+impl Future for WaitForWake {
+    type Output = ();
+
+    fn poll(mut self: Pin<&mut Self>, context: &mut Context) -> Poll<()> {
+        if self.woken {
+            return Poll::Ready(());
+        }
+        self.woken = true;
+        *self.waker.lock() = Some(context.waker().clone());
+        Poll::Pending
+    }
+}
+
+#[test_case]
+fn test_ready_task_completes() {
+    let done = Arc::new(AtomicBool::new(false));
+    let flag = done.clone();
+
+    let mut executor = Executor::new();
+    executor.spawn(Task::new(
+        async move { flag.store(true, Ordering::Relaxed) },
+    ));
+    executor.run_ready_tasks();
+
+    assert!(done.load(Ordering::Relaxed));
+    assert_eq!(executor.task_count(), 0);
+}
+
+#[test_case]
+fn test_waker_requeues_task_once() {
+    let slot = Arc::new(spin::Mutex::new(None));
+    let mut executor = Executor::new();
+    executor.spawn(Task::new(WaitForWake {
+        waker: slot.clone(),
+        woken: false,
+    }));
+
+    executor.run_ready_tasks();
+    assert_eq!(executor.task_count(), 1);
+    assert!(executor.task_queue.is_empty());
+
+    let waker = slot.lock().take().expect("task did not store its waker");
+    waker.wake_by_ref();
+    waker.wake_by_ref();
+    assert_eq!(executor.task_queue.len(), 1);
+
+    executor.run_ready_tasks();
+    assert_eq!(executor.task_count(), 0);
+
+    // Waking a completed task does nothing
+    waker.wake();
+    assert!(executor.task_queue.is_empty());
+}
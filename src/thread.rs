@@ -0,0 +1,771 @@
+// thread.rs - Kernel threads and the context switch between them
+
+use crate::address_space::AddressSpace;
+use crate::fpu::{self, FpuState};
+use crate::process::{self, Pid};
+use crate::{gdt, memory, percpu, smp, time, timer};
+
+use alloc::boxed::Box;
+use alloc::collections::VecDeque;
+use alloc::format;
+use alloc::string::{String, ToString};
+use alloc::sync::{Arc, Weak};
+use alloc::vec;
+use alloc::vec::Vec;
+use core::cell::UnsafeCell;
+use core::fmt;
+use core::time::Duration;
+use core::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use spin::Mutex;
+use x86_64::instructions::interrupts;
+use x86_64::registers::control::{Cr3, Cr3Flags};
+use x86_64::structures::paging::PhysFrame;
+use x86_64::VirtAddr;
+
+//////////////////////////////
+// Statics/Constants
+//////////////////////////////
+
+/*
+ *  A thread that is not running is fully described by its saved stack
+ *  pointer. `thread_context_switch` pushes the callee saved registers onto
+ *  the current stack, stores rsp, loads the next thread's rsp and pops its
+ *  registers. Its `ret` then continues wherever that thread last called
+ *  `thread_context_switch`, the caller saved registers were already spilled
+ *  by the compiler around the call.
+ *
+ *  A new thread's stack is set up as if it had switched away right before
+ *  entering `thread_trampoline`:
+ *
+ *    |  thread_trampoline  |  <- stack top - 8, return address
+ *    |  0                  |  rbp, ends backtraces
+ *    |  0                  |  rbx
+ *    |  entry closure      |  r12, argument of thread_entry
+ *    |  0                  |  r13
+ *    |  0                  |  r14
+ *    |  0                  |  r15   <- saved rsp
+ */
+core::arch::global_asm!(
+    r#"
+    .text
+    .global thread_context_switch
+thread_context_switch:
+    pushq %rbp
+    pushq %rbx
+    pushq %r12
+    pushq %r13
+    pushq %r14
+    pushq %r15
+    movq %rsp, (%rdi)
+    movq %rsi, %rsp
+    popq %r15
+    popq %r14
+    popq %r13
+    popq %r12
+    popq %rbx
+    popq %rbp
+    retq
+
+thread_trampoline:
+    movq %r12, %rdi
+    callq thread_entry
+    ud2
+    "#,
+    options(att_syntax)
+);
+
+extern "C" {
+    // Save the callee saved registers and rsp into `prev_rsp`, resume the
+    // thread whose rsp is `next_rsp`
+    fn thread_context_switch(prev_rsp: *mut u64, next_rsp: u64);
+    fn thread_trampoline();
+}
+
+pub const STACK_SIZE: usize = 16 * 1024;
+
+/*
+ *  Scheduling is round-robin over a single queue of ready threads shared by
+ *  all CPUs. The running thread gives up the CPU when it yields, blocks,
+ *  exits, or when its time slice of `TIME_SLICE_TICKS` timer ticks runs out.
+ *  A CPU with nothing to run switches to its idle thread, which halts until
+ *  an interrupt makes a thread ready. Idle threads are never queued.
+ *
+ *  Blocking takes two steps so that a wake-up between deciding to sleep and
+ *  switching away is not lost, see sync.rs:
+ *
+ *    prepare_to_block    state Blocked, the thread can now be woken
+ *    block               switch away unless already woken. The thread
+ *                        stays off the queue until `wake`, or until a
+ *                        timer wakes it at its deadline tick.
+ *
+ *  Spin locks that the scheduler takes from the timer interrupt must only
+ *  be held with interrupts disabled, or a preempted holder deadlocks it.
+ *
+ *  Threads of a user process run in its address space, kernel threads in
+ *  the kernel's. Switching loads the next thread's level 4 table unless it
+ *  is already active, and for user threads makes the top of their stack
+ *  the one the CPU enters the kernel on from ring 3. With the `user-fpu`
+ *  feature user threads also own FPU register state, handed to
+ *  `fpu::switch` on every switch.
+ */
+pub const TIME_SLICE_TICKS: u64 = 2;
+
+// Registers `thread_context_switch` saves on the stack
+const SAVED_REGISTERS: usize = 6;
+
+static NEXT_ID: AtomicU64 = AtomicU64::new(0);
+
+// Threads waiting for a CPU, in the order they run
+static READY: Mutex<VecDeque<Arc<Thread>>> = Mutex::new(VecDeque::new());
+
+// Every thread that has not been freed, for the statistics
+static THREADS: Mutex<Vec<Weak<Thread>>> = Mutex::new(Vec::new());
+
+////////////////////////////////
+// Structs, Types, Traits, Impl
+////////////////////////////////
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct ThreadId(u64);
+
+impl ThreadId {
+    pub fn as_u64(self) -> u64 {
+        self.0
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum State {
+    Ready,
+    Running,
+    Blocked,
+    Exited,
+}
+
+type Entry = Box<dyn FnOnce() + Send>;
+
+pub struct Thread {
+    id: ThreadId,
+    name: String,
+    state: Mutex<State>,
+    // Blocked and switched out, so `wake` has to queue it. Only changed
+    // with `state` locked.
+    parked: AtomicBool,
+    // Waiting in a way a signal may cut short, see `interrupt`
+    interruptible: AtomicBool,
+    // Stack pointer while switched out, written by `thread_context_switch`
+    rsp: UnsafeCell<u64>,
+    // Freed once the thread has exited and is off the CPU. None for the
+    // thread that booted the kernel.
+    stack: Mutex<Option<Box<[u8]>>>,
+    stack_top: u64,
+    process: Pid,
+    // Loaded into CR3 when switching to the thread
+    page_table: PhysFrame,
+    // Kept alive until the thread has exited and is off the CPU. None for
+    // kernel threads.
+    address_space: Mutex<Option<Arc<AddressSpace>>>,
+    // Saved FPU/SSE/AVX registers, only for user threads and only with the
+    // `user-fpu` feature. Accessed through `fpu::switch` while the thread
+    // runs or is being switched.
+    fpu: Option<UnsafeCell<FpuState>>,
+    idle: bool,
+    // Times the thread was switched to, and timer ticks it was running for
+    switches: AtomicU64,
+    run_ticks: AtomicU64,
+}
+
+// `rsp` is only accessed while switching, when the thread is on no CPU, and
+// `fpu` only by the CPU running the thread
+unsafe impl Send for Thread {}
+unsafe impl Sync for Thread {}
+
+impl Thread {
+    fn new(name: &str, entry: Entry) -> Thread {
+        let mut stack = vec![0u8; STACK_SIZE].into_boxed_slice();
+        let top = (stack.as_mut_ptr() as u64 + STACK_SIZE as u64) & !0xf;
+
+        // See the diagram above, top to bottom
+        let entry = Box::into_raw(Box::new(entry)) as u64;
+        let frame = [0, 0, 0, entry, 0, 0, thread_trampoline as *const () as u64];
+        let rsp = top - 8 * (SAVED_REGISTERS as u64 + 1);
+        unsafe {
+            let slots = rsp as *mut u64;
+            for (i, &value) in frame.iter().enumerate() {
+                slots.add(i).write(value);
+            }
+        }
+
+        Thread {
+            stack: Mutex::new(Some(stack)),
+            stack_top: top,
+            ..Thread::without_stack(name, rsp)
+        }
+    }
+
+    fn without_stack(name: &str, rsp: u64) -> Thread {
+        Thread {
+            id: next_id(),
+            name: name.to_string(),
+            state: Mutex::new(State::Ready),
+            parked: AtomicBool::new(false),
+            interruptible: AtomicBool::new(false),
+            rsp: UnsafeCell::new(rsp),
+            stack: Mutex::new(None),
+            stack_top: 0,
+            process: process::KERNEL_PID,
+            page_table: memory::kernel_page_table(),
+            address_space: Mutex::new(None),
+            fpu: None,
+            idle: false,
+            switches: AtomicU64::new(0),
+            run_ticks: AtomicU64::new(0),
+        }
+    }
+
+    pub fn id(&self) -> ThreadId {
+        self.id
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn process(&self) -> Pid {
+        self.process
+    }
+
+    // None for kernel threads, and once the thread has exited
+    pub fn address_space(&self) -> Option<Arc<AddressSpace>> {
+        self.address_space.lock().clone()
+    }
+
+    // Null for threads without FPU state, see `fpu::switch`
+    fn fpu_state(&self) -> *mut FpuState {
+        self.fpu
+            .as_ref()
+            .map_or(core::ptr::null_mut(), UnsafeCell::get)
+    }
+
+    // Set by a thread before it starts a wait `interrupt` may end, and
+    // cleared once it is over, see `sync::WaitQueue::wait_interruptible`
+    pub fn set_interruptible(&self, interruptible: bool) {
+        self.interruptible.store(interruptible, Ordering::SeqCst);
+    }
+
+    // The scheduler takes the lock from the timer interrupt
+    pub fn state(&self) -> State {
+        interrupts::without_interrupts(|| *self.state.lock())
+    }
+
+    pub fn switches(&self) -> u64 {
+        self.switches.load(Ordering::Relaxed)
+    }
+
+    pub fn run_time_ms(&self) -> u64 {
+        self.run_ticks.load(Ordering::Relaxed) * 1000 / time::TICK_HZ
+    }
+}
+
+// Threads of a single CPU, lives in its `percpu::PerCpu` area
+pub struct CpuThreads {
+    current: Mutex<Option<Arc<Thread>>>,
+    // The thread switched away from, until the next one has left its stack
+    // and `finish_switch` can put it back in the queue or free it
+    prev: Mutex<Option<Arc<Thread>>>,
+    idle: Mutex<Option<Arc<Thread>>>,
+    // Ticks left in the time slice of the current thread
+    slice_left: AtomicU64,
+    context_switches: AtomicU64,
+}
+
+impl CpuThreads {
+    pub const INIT: CpuThreads = CpuThreads {
+        current: Mutex::new(None),
+        prev: Mutex::new(None),
+        idle: Mutex::new(None),
+        slice_left: AtomicU64::new(TIME_SLICE_TICKS),
+        context_switches: AtomicU64::new(0),
+    };
+
+    pub fn context_switches(&self) -> u64 {
+        self.context_switches.load(Ordering::Relaxed)
+    }
+}
+
+pub struct JoinHandle<T> {
+    thread: Arc<Thread>,
+    result: Arc<Mutex<Option<T>>>,
+}
+
+impl<T> JoinHandle<T> {
+    pub fn thread(&self) -> &Arc<Thread> {
+        &self.thread
+    }
+
+    // Wait for the thread to exit and return what it returned, yielding to
+    // other threads meanwhile
+    pub fn join(self) -> T {
+        while self.thread.state() != State::Exited {
+            yield_now();
+        }
+        self.result
+            .lock()
+            .take()
+            .expect("thread exited without a result")
+    }
+}
+
+// Displays every thread with its scheduling statistics, similar to `ps`
+pub struct Table;
+
+impl fmt::Display for Table {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for cpu in 0..smp::cpu_count() {
+            let threads = &percpu::cpu(cpu).threads;
+            writeln!(
+                f,
+                "CPU{}: {} context switches",
+                cpu,
+                threads.context_switches()
+            )?;
+        }
+
+        let threads: Vec<Arc<Thread>> = interrupts::without_interrupts(|| {
+            THREADS.lock().iter().filter_map(Weak::upgrade).collect()
+        });
+        writeln!(
+            f,
+            "{:>5}  {:>5}  {:<16}  {:<8}  {:>10}  {:>10}",
+            "TID", "PID", "NAME", "STATE", "SWITCHES", "TIME (ms)"
+        )?;
+        for thread in threads.iter() {
+            writeln!(
+                f,
+                "{:>5}  {:>5}  {:<16}  {:<8}  {:>10}  {:>10}",
+                thread.id().as_u64(),
+                thread.process().as_u64(),
+                thread.name(),
+                // Formatting with width needs a str, not Debug
+                match thread.state() {
+                    State::Ready => "Ready",
+                    State::Running => "Running",
+                    State::Blocked => "Blocked",
+                    State::Exited => "Exited",
+                },
+                thread.switches(),
+                thread.run_time_ms()
+            )?;
+        }
+        Ok(())
+    }
+}
+
+//////////////////////////////
+// API
+//////////////////////////////
+
+// Turn the code running on the calling CPU into its first thread, named
+// `main`, and create the CPU's idle thread. Requires the heap.
+pub fn init() {
+    let main = Arc::new(Thread::without_stack("main", 0));
+    *main.state.lock() = State::Running;
+
+    let cpu = percpu::cpu_id();
+    let idle = Arc::new(Thread {
+        idle: true,
+        ..Thread::new(&format!("idle{}", cpu), Box::new(|| idle_loop()))
+    });
+
+    register(&main);
+    register(&idle);
+    interrupts::without_interrupts(|| {
+        let threads = &percpu::this_cpu().threads;
+        *threads.current.lock() = Some(main);
+        *threads.idle.lock() = Some(idle);
+    });
+}
+
+// Start running `f` in a new kernel thread
+pub fn spawn<F, T>(name: &str, f: F) -> JoinHandle<T>
+where
+    F: FnOnce() -> T + Send + 'static,
+    T: Send + 'static,
+{
+    spawn_in(name, process::KERNEL_PID, None, f)
+}
+
+// Start running `f` in a new thread of `process`, in `address_space`. Used
+// by process.rs, which keeps count of the threads of each process.
+pub(crate) fn spawn_in<F, T>(
+    name: &str,
+    process: Pid,
+    address_space: Option<Arc<AddressSpace>>,
+    f: F,
+) -> JoinHandle<T>
+where
+    F: FnOnce() -> T + Send + 'static,
+    T: Send + 'static,
+{
+    let result = Arc::new(Mutex::new(None));
+    let packet = result.clone();
+    let entry: Entry = Box::new(move || {
+        let value = f();
+        *packet.lock() = Some(value);
+    });
+
+    let thread = Thread::new(name, entry);
+    let thread = Arc::new(match address_space {
+        Some(address_space) => Thread {
+            process,
+            page_table: address_space.page_table(),
+            address_space: Mutex::new(Some(address_space)),
+            fpu: if fpu::is_enabled() {
+                Some(UnsafeCell::new(FpuState::new()))
+            } else {
+                None
+            },
+            ..thread
+        },
+        None => Thread { process, ..thread },
+    });
+    register(&thread);
+    let handle = JoinHandle {
+        thread: thread.clone(),
+        result,
+    };
+    interrupts::without_interrupts(|| READY.lock().push_back(thread));
+    handle
+}
+
+// Let the next ready thread run, the caller continues once it is its turn
+// again. Returns at once if no other thread is ready.
+pub fn yield_now() {
+    interrupts::without_interrupts(|| {
+        if percpu::this_cpu().threads.current.lock().is_some() {
+            schedule();
+        }
+    });
+}
+
+// End the calling thread. Its stack is freed once another thread runs.
+pub fn exit() -> ! {
+    let process = current().process;
+    if process != process::KERNEL_PID {
+        process::thread_exited(process);
+    }
+
+    interrupts::disable();
+    *current().state.lock() = State::Exited;
+    schedule();
+    unreachable!("exited thread was scheduled again");
+}
+
+// Mark the current thread as about to block. From here on `wake` makes it
+// runnable again, so the caller may publish it to wakers before calling
+// `block`. Interrupts must be disabled until `block` or `cancel_block`.
+pub fn prepare_to_block() {
+    let cpu = &percpu::this_cpu().threads;
+    let current = cpu.current.lock();
+    let current = current.as_ref().expect("thread::init has not been called");
+    assert!(!current.idle, "idle thread cannot block");
+    *current.state.lock() = State::Blocked;
+}
+
+// Keep running after `prepare_to_block` after all
+pub fn cancel_block() {
+    let cpu = &percpu::this_cpu().threads;
+    if let Some(current) = cpu.current.lock().as_ref() {
+        *current.state.lock() = State::Running;
+    }
+}
+
+// Switch away after `prepare_to_block` until woken by `wake`, or until the
+// tick `deadline` passes. Returns at once if already woken.
+pub fn block(deadline: Option<u64>) {
+    let timeout = deadline.map(|deadline| {
+        let thread = current();
+        timer::add_at(deadline, 0, move || {
+            wake(&thread);
+        })
+    });
+
+    schedule();
+
+    if let Some(timeout) = timeout {
+        timer::cancel(timeout);
+    }
+}
+
+// Block the calling thread for at least `duration`
+pub fn sleep(duration: Duration) {
+    let deadline = timer::deadline_after(duration);
+    while time::ticks() < deadline {
+        interrupts::without_interrupts(|| {
+            prepare_to_block();
+            block(Some(deadline));
+        });
+    }
+}
+
+// Make a blocked thread runnable. Returns false if it was not blocked. Safe
+// to call from interrupt handlers.
+pub fn wake(thread: &Arc<Thread>) -> bool {
+    interrupts::without_interrupts(|| {
+        let mut state = thread.state.lock();
+        if *state != State::Blocked {
+            return false;
+        }
+        *state = State::Ready;
+
+        // Otherwise it is still switching away, and `finish_switch` queues
+        // it once it has
+        if thread.parked.swap(false, Ordering::Relaxed) {
+            drop(state);
+            READY.lock().push_back(thread.clone());
+        }
+        true
+    })
+}
+
+// Wake the threads of process `pid` that are in an interruptible wait, for
+// a signal that just became pending for it
+pub fn interrupt(pid: Pid) {
+    let threads: Vec<Arc<Thread>> = interrupts::without_interrupts(|| {
+        THREADS
+            .lock()
+            .iter()
+            .filter_map(Weak::upgrade)
+            .filter(|thread| thread.process == pid)
+            .collect()
+    });
+    for thread in threads.iter() {
+        if thread.interruptible.load(Ordering::SeqCst) {
+            wake(thread);
+        }
+    }
+}
+
+// Called from the timer interrupt after its end of interrupt, as it may
+// switch threads. Charges the tick to the running thread and preempts it
+// once its time slice is used up.
+pub fn tick() {
+    let cpu = &percpu::this_cpu().threads;
+    let idle = match cpu.current.lock().as_ref() {
+        Some(current) => {
+            current.run_ticks.fetch_add(1, Ordering::Relaxed);
+            current.idle
+        }
+        None => return,
+    };
+
+    // The idle thread checks for ready threads itself once woken
+    if !idle && cpu.slice_left.fetch_sub(1, Ordering::Relaxed) <= 1 {
+        schedule();
+    }
+}
+
+pub fn current() -> Arc<Thread> {
+    interrupts::without_interrupts(|| {
+        percpu::this_cpu()
+            .threads
+            .current
+            .lock()
+            .clone()
+            .expect("thread::init has not been called")
+    })
+}
+
+pub fn has_ready() -> bool {
+    interrupts::without_interrupts(|| !READY.lock().is_empty())
+}
+
+// Usage: println!("{}", thread::table());
+pub fn table() -> Table {
+    Table
+}
+
+fn next_id() -> ThreadId {
+    ThreadId(NEXT_ID.fetch_add(1, Ordering::Relaxed))
+}
+
+fn register(thread: &Arc<Thread>) {
+    interrupts::without_interrupts(|| {
+        let mut threads = THREADS.lock();
+        threads.retain(|thread| thread.strong_count() > 0);
+        threads.push(Arc::downgrade(thread));
+    });
+}
+
+// Switch to the next ready thread. Without one the current thread keeps
+// running, or the idle thread takes over if it cannot. Interrupts must be
+// disabled and stay so until the switch is finished.
+fn schedule() {
+    let cpu = &percpu::this_cpu().threads;
+    cpu.slice_left.store(TIME_SLICE_TICKS, Ordering::Relaxed);
+
+    // No reference to the current thread may be held across the switch, an
+    // exited thread never returns to drop it
+    let can_continue = match cpu.current.lock().as_ref() {
+        Some(current) => {
+            let mut state = current.state.lock();
+            // Woken before it got to switch away from `block`
+            if *state == State::Ready {
+                *state = State::Running;
+            }
+            current.idle || *state == State::Running
+        }
+        None => panic!("thread::init has not been called"),
+    };
+    let next = match READY.lock().pop_front() {
+        Some(next) => next,
+        None if can_continue => return,
+        None => cpu.idle.lock().clone().expect("no idle thread"),
+    };
+    *next.state.lock() = State::Running;
+    next.switches.fetch_add(1, Ordering::Relaxed);
+    cpu.context_switches.fetch_add(1, Ordering::Relaxed);
+
+    let prev = cpu
+        .current
+        .lock()
+        .replace(next.clone())
+        .expect("thread::init has not been called");
+    let prev_rsp = prev.rsp.get();
+    let next_rsp = unsafe { *next.rsp.get() };
+    *cpu.prev.lock() = Some(prev);
+    switch_address_space(&next);
+    drop(next);
+
+    unsafe { thread_context_switch(prev_rsp, next_rsp) };
+    finish_switch();
+}
+
+// First thing after a switch, possibly on another CPU than the one the
+// thread switched away on
+fn finish_switch() {
+    let prev = match percpu::this_cpu().threads.prev.lock().take() {
+        Some(prev) => prev,
+        None => return,
+    };
+
+    // Idle threads wait outside the queue
+    if prev.idle {
+        return;
+    }
+    let mut state = prev.state.lock();
+    match *state {
+        State::Running => {
+            *state = State::Ready;
+            drop(state);
+            READY.lock().push_back(prev);
+        }
+        // Woken while switching away
+        State::Ready => {
+            drop(state);
+            READY.lock().push_back(prev);
+        }
+        State::Blocked => prev.parked.store(true, Ordering::Relaxed),
+        State::Exited => {
+            drop(state);
+            prev.stack.lock().take();
+            prev.address_space.lock().take();
+        }
+    }
+}
+
+// The previous thread's address space stays valid until `finish_switch`,
+// and the kernel stacks are mapped in all of them. So does its FPU state,
+// which `fpu::switch` may still save.
+fn switch_address_space(next: &Thread) {
+    if Cr3::read().0 != next.page_table {
+        unsafe { Cr3::write(next.page_table, Cr3Flags::empty()) };
+    }
+    if next.process != process::KERNEL_PID {
+        gdt::set_kernel_stack(VirtAddr::new(next.stack_top));
+    }
+    // `next` stays alive while it runs, it is the CPU's current thread
+    unsafe { fpu::switch(next.fpu_state()) };
+}
+
+
+// Body of the idle threads
+fn idle_loop() -> ! {
+    loop {
+        // Check with interrupts disabled, otherwise a thread made ready
+        // between the check and the hlt waits for the next interrupt
+        interrupts::disable();
+        if READY.lock().is_empty() {
+            interrupts::enable_and_hlt();
+        } else {
+            schedule();
+            interrupts::enable();
+        }
+    }
+}
+
+#[no_mangle]
+extern "C" fn thread_entry(entry: *mut Entry) -> ! {
+    finish_switch();
+    interrupts::enable();
+
+    let entry = unsafe { Box::from_raw(entry) };
+    entry();
+    exit();
+}
+
+//////////////////////////////
+// Tests
+//////////////////////////////
+
+#[test_case]
+fn test_join_returns_value() {
+    let handle = spawn("answer", || 6 * 7);
+    assert_eq!(handle.join(), 42);
+}
+
+#[test_case]
+fn test_yield_now_interleaves() {
+    // Preemption may switch at any time, so each side waits for its turn
+    let order = Arc::new(Mutex::new(Vec::new()));
+    let shared = order.clone();
+    let handle = spawn("worker", move || {
+        shared.lock().push(1);
+        while shared.lock().len() < 2 {
+            yield_now();
+        }
+        shared.lock().push(3);
+    });
+
+    while order.lock().is_empty() {
+        yield_now();
+    }
+    order.lock().push(2);
+    handle.join();
+    assert_eq!(*order.lock(), [1, 2, 3]);
+}
+
+#[test_case]
+fn test_preemption_without_yield() {
+    use core::sync::atomic::AtomicBool;
+
+    let ran = Arc::new(AtomicBool::new(false));
+    let flag = ran.clone();
+    let handle = spawn("preempted", move || flag.store(true, Ordering::SeqCst));
+
+    // Busy wait, only the timer can switch to the other thread
+    let deadline = time::ticks() + time::TICK_HZ;
+    while !ran.load(Ordering::SeqCst) && time::ticks() < deadline {
+        core::hint::spin_loop();
+    }
+    assert!(ran.load(Ordering::SeqCst));
+    handle.join();
+}
+
+#[test_case]
+fn test_context_switches_are_counted() {
+    let cpu = &percpu::this_cpu().threads;
+    let before = cpu.context_switches();
+    spawn("counted", || {}).join();
+    assert!(cpu.context_switches() > before);
+}
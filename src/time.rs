@@ -0,0 +1,120 @@
+// time.rs - Programmable Interval Timer and busy waiting
+
+use core::sync::atomic::{AtomicU64, Ordering};
+use core::time::Duration;
+use spin::Mutex;
+use x86_64::instructions::port::Port;
+
+//////////////////////////////
+// Statics/Constants
+//////////////////////////////
+
+/*
+ *   ______________________________________________
+ *  | Port  | Description                          |
+ *  |----------------------------------------------|
+ *  | 0x40  | Channel 0 data, wired to IRQ0        |
+ *  | 0x42  | Channel 2 data, wired to the speaker |
+ *  | 0x43  | Mode/Command register                |
+ *  | 0x61  | Bit 0 channel 2 gate, bit 1 speaker, |
+ *  |       | bit 5 channel 2 output               |
+ *  |______________________________________________|
+ *
+ */
+pub const PIT_FREQUENCY_HZ: u64 = 1_193_182;
+
+// Rate of the channel 0 timer interrupt
+pub const TICK_HZ: u64 = 100;
+
+const PIT_CHANNEL_0: u16 = 0x40;
+const PIT_CHANNEL_2: u16 = 0x42;
+const PIT_COMMAND: u16 = 0x43;
+const PIT_GATE: u16 = 0x61;
+
+// Channel 0, lobyte/hibyte access, mode 2 (rate generator)
+const CHANNEL_0_PERIODIC: u8 = 0b0011_0100;
+
+// Channel 2, lobyte/hibyte access, mode 0 (interrupt on terminal count)
+const CHANNEL_2_ONE_SHOT: u8 = 0b1011_0000;
+
+// Serializes users of PIT channel 2
+static CHANNEL_2: Mutex<()> = Mutex::new(());
+
+// Channel 0 interrupts since `init`
+static TICKS: AtomicU64 = AtomicU64::new(0);
+
+//////////////////////////////
+// API
+//////////////////////////////
+
+// Program channel 0 to interrupt `TICK_HZ` times per second, instead of the
+// BIOS default of about 18.2
+pub fn init() {
+    let divisor = (PIT_FREQUENCY_HZ / TICK_HZ) as u16;
+    let mut command: Port<u8> = Port::new(PIT_COMMAND);
+    let mut data: Port<u8> = Port::new(PIT_CHANNEL_0);
+
+    unsafe {
+        command.write(CHANNEL_0_PERIODIC);
+        data.write(divisor as u8);
+        data.write((divisor >> 8) as u8);
+    }
+}
+
+// Called from the channel 0 interrupt
+pub fn tick() {
+    TICKS.fetch_add(1, Ordering::Relaxed);
+}
+
+pub fn ticks() -> u64 {
+    TICKS.load(Ordering::Relaxed)
+}
+
+pub fn uptime_ms() -> u64 {
+    ticks() * 1000 / TICK_HZ
+}
+
+// Ticks lasting at least `ms` milliseconds
+pub fn ms_to_ticks(ms: u64) -> u64 {
+    (ms * TICK_HZ).div_ceil(1000)
+}
+
+// Ticks lasting at least `duration`
+pub fn duration_to_ticks(duration: Duration) -> u64 {
+    (duration.as_nanos() * u128::from(TICK_HZ)).div_ceil(1_000_000_000) as u64
+}
+
+// Spin for at least `us` microseconds using PIT channel 2. Works with
+// interrupts disabled and before any clock is calibrated.
+pub fn busy_wait_us(us: u64) {
+    let mut ticks = us * PIT_FREQUENCY_HZ / 1_000_000;
+    let _guard = CHANNEL_2.lock();
+
+    while ticks > 0 {
+        let count = ticks.min(u64::from(u16::MAX)) as u16;
+        one_shot(count);
+        ticks -= u64::from(count);
+    }
+}
+
+fn one_shot(count: u16) {
+    let mut command: Port<u8> = Port::new(PIT_COMMAND);
+    let mut data: Port<u8> = Port::new(PIT_CHANNEL_2);
+    let mut gate: Port<u8> = Port::new(PIT_GATE);
+
+    unsafe {
+        // Gate low while programming, keep the speaker off
+        let value = gate.read() & !0b11;
+        gate.write(value);
+
+        command.write(CHANNEL_2_ONE_SHOT);
+        data.write(count as u8);
+        data.write((count >> 8) as u8);
+
+        // Rising edge on the gate starts the count
+        gate.write(value | 0b01);
+        while gate.read() & 0b10_0000 == 0 {
+            core::hint::spin_loop();
+        }
+    }
+}
@@ -0,0 +1,385 @@
+// timer.rs - Hierarchical timer wheel for timeouts and periodic callbacks
+
+use crate::spinlock::IrqSpinlock;
+use crate::time;
+
+use alloc::boxed::Box;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::future::Future;
+use core::pin::Pin;
+use core::sync::atomic::{AtomicU64, Ordering};
+use core::task::{Context, Poll, Waker};
+use core::time::Duration;
+
+//////////////////////////////
+// Statics/Constants
+//////////////////////////////
+
+/*
+ *  Timers are kept in LEVELS wheels of SLOTS slots each. A slot of level n
+ *  spans SLOTS^n ticks, so level 0 holds the timers of the next 64 ticks,
+ *  level 1 those of the next 4096, and so on:
+ *
+ *    level 0   |0|1|2| ... |63|    1 tick per slot
+ *    level 1   |0|1|2| ... |63|    64 ticks per slot
+ *    level 2   |0|1|2| ... |63|    4096 ticks per slot
+ *    level 3   |0|1|2| ... |63|    262144 ticks per slot
+ *
+ *  Adding and cancelling a timer touch a single slot. Every tick runs the
+ *  timers of one level 0 slot. Each time level n-1 wraps around, the next
+ *  slot of level n is emptied and its timers are put back in the wheel,
+ *  landing in a lower level now that they are closer. Timers further away
+ *  than the last level reaches wait in its furthest slot and are put back
+ *  until they are in range.
+ *
+ *  Callbacks run from the timer interrupt, after its end of interrupt, with
+ *  interrupts disabled. They must be short and must not block, waking a
+ *  thread or a task is what they are for.
+ */
+const SLOT_BITS: u32 = 6;
+const SLOTS: usize = 1 << SLOT_BITS;
+const SLOT_MASK: u64 = SLOTS as u64 - 1;
+const LEVELS: usize = 4;
+
+// Ticks covered by the wheel, about 46 hours at 100 Hz
+const RANGE: u64 = 1 << (SLOT_BITS * LEVELS as u32);
+
+static NEXT_ID: AtomicU64 = AtomicU64::new(0);
+
+static WHEEL: IrqSpinlock<Wheel> = IrqSpinlock::new("timer wheel", Wheel::new());
+
+////////////////////////////////
+// Structs, Types, Traits, Impl
+////////////////////////////////
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct TimerId(u64);
+
+type Callback = Box<dyn FnMut() + Send>;
+
+struct Timer {
+    id: TimerId,
+    // Tick the timer runs at
+    expires: u64,
+    // Ticks between runs, 0 for one-shot timers
+    period: u64,
+    callback: Callback,
+}
+
+struct Wheel {
+    levels: [[Vec<Timer>; SLOTS]; LEVELS],
+    // Last tick processed
+    now: u64,
+    // Periodic timer whose callback is running, it is out of the wheel until
+    // the callback returns
+    running: Option<TimerId>,
+    running_cancelled: bool,
+}
+
+impl Wheel {
+    const EMPTY_SLOT: Vec<Timer> = Vec::new();
+    const EMPTY_LEVEL: [Vec<Timer>; SLOTS] = [Wheel::EMPTY_SLOT; SLOTS];
+
+    const fn new() -> Wheel {
+        Wheel {
+            levels: [Wheel::EMPTY_LEVEL; LEVELS],
+            now: 0,
+            running: None,
+            running_cancelled: false,
+        }
+    }
+
+    // `expires` may be the tick being processed while cascading, otherwise
+    // it has to be in the future
+    fn insert(&mut self, timer: Timer) {
+        let delta = timer.expires - self.now;
+
+        let mut level = 0;
+        while level < LEVELS - 1 && delta >= 1 << (SLOT_BITS * (level as u32 + 1)) {
+            level += 1;
+        }
+        let slot_tick = if delta >= RANGE {
+            self.now + RANGE - 1
+        } else {
+            timer.expires
+        };
+        let slot = (slot_tick >> (SLOT_BITS * level as u32)) & SLOT_MASK;
+        self.levels[level][slot as usize].push(timer);
+    }
+
+    fn remove(&mut self, id: TimerId) -> bool {
+        for slot in self.levels.iter_mut().flatten() {
+            if let Some(index) = slot.iter().position(|timer| timer.id == id) {
+                slot.swap_remove(index);
+                return true;
+            }
+        }
+        false
+    }
+
+    // Move to the next tick and return the timers expiring at it
+    fn advance(&mut self) -> Vec<Timer> {
+        self.now += 1;
+        for level in 1..LEVELS {
+            if self.now & ((1 << (SLOT_BITS * level as u32)) - 1) != 0 {
+                break;
+            }
+            let slot = (self.now >> (SLOT_BITS * level as u32)) & SLOT_MASK;
+            for timer in core::mem::take(&mut self.levels[level][slot as usize]) {
+                self.insert(timer);
+            }
+        }
+        core::mem::take(&mut self.levels[0][(self.now & SLOT_MASK) as usize])
+    }
+
+    fn len(&self) -> usize {
+        self.levels.iter().flatten().map(Vec::len).sum()
+    }
+}
+
+// Future returned by `sleep`
+pub struct Sleep {
+    deadline: u64,
+    waker: Arc<IrqSpinlock<Option<Waker>>>,
+    timer: Option<TimerId>,
+}
+
+impl Future for Sleep {
+    type Output = ();
+
+    fn poll(mut self: Pin<&mut Self>, context: &mut Context) -> Poll<()> {
+        if time::ticks() >= self.deadline {
+            return Poll::Ready(());
+        }
+
+        // Replaced on every poll, the task may have moved to another waker
+        *self.waker.lock() = Some(context.waker().clone());
+        if self.timer.is_none() {
+            let slot = self.waker.clone();
+            let timer = add_at(self.deadline, 0, move || {
+                let waker = slot.lock().take();
+                if let Some(waker) = waker {
+                    waker.wake();
+                }
+            });
+            self.timer = Some(timer);
+        }
+        Poll::Pending
+    }
+}
+
+impl Drop for Sleep {
+    fn drop(&mut self) {
+        if let Some(timer) = self.timer {
+            cancel(timer);
+        }
+    }
+}
+
+//////////////////////////////
+// API
+//////////////////////////////
+
+// Run `callback` once, `delay` from now
+pub fn add_oneshot(delay: Duration, callback: impl FnMut() + Send + 'static) -> TimerId {
+    add_at(deadline_after(delay), 0, callback)
+}
+
+// Run `callback` every `period`, the first time `period` from now. Runs
+// never pile up, a late run does not delay the following ones.
+pub fn add_periodic(period: Duration, callback: impl FnMut() + Send + 'static) -> TimerId {
+    let period = time::duration_to_ticks(period).max(1);
+    add_at(time::ticks() + period, period, callback)
+}
+
+// Run `callback` at the tick `expires`, and every `period` ticks after that
+// unless it is 0
+pub fn add_at(expires: u64, period: u64, callback: impl FnMut() + Send + 'static) -> TimerId {
+    let id = TimerId(NEXT_ID.fetch_add(1, Ordering::Relaxed));
+    let mut timer = Timer {
+        id,
+        expires,
+        period,
+        callback: Box::new(callback),
+    };
+
+    let mut wheel = WHEEL.lock();
+    // A tick already processed runs at the next one
+    timer.expires = timer.expires.max(wheel.now + 1);
+    wheel.insert(timer);
+    id
+}
+
+// Stop a timer. Returns false if it already ran or was cancelled. Safe to
+// call from the timer's own callback.
+pub fn cancel(id: TimerId) -> bool {
+    let mut wheel = WHEEL.lock();
+    if wheel.running == Some(id) {
+        let cancelled = !wheel.running_cancelled;
+        wheel.running_cancelled = true;
+        return cancelled;
+    }
+    wheel.remove(id)
+}
+
+// Timers waiting to run
+pub fn pending() -> usize {
+    WHEEL.lock().len()
+}
+
+// Tick after which `delay` starting now has passed. The current tick is
+// already partly over, so one more is added.
+pub fn deadline_after(delay: Duration) -> u64 {
+    time::ticks() + time::duration_to_ticks(delay) + 1
+}
+
+// Future completing once `duration` has passed, for async tasks
+pub fn sleep(duration: Duration) -> Sleep {
+    Sleep {
+        deadline: deadline_after(duration),
+        waker: Arc::new(IrqSpinlock::new("Sleep", None)),
+        timer: None,
+    }
+}
+
+// Called from the timer interrupt after its end of interrupt. Runs the
+// timers of every tick since the last call.
+pub fn tick() {
+    let now = time::ticks();
+    loop {
+        let expired = {
+            let mut wheel = WHEEL.lock();
+            if wheel.now >= now {
+                return;
+            }
+            wheel.advance()
+        };
+        for timer in expired {
+            run(timer);
+        }
+    }
+}
+
+// Without the wheel locked, so callbacks may add and cancel timers
+fn run(mut timer: Timer) {
+    if timer.period == 0 {
+        (timer.callback)();
+        return;
+    }
+
+    {
+        let mut wheel = WHEEL.lock();
+        wheel.running = Some(timer.id);
+        wheel.running_cancelled = false;
+    }
+    (timer.callback)();
+
+    let mut wheel = WHEEL.lock();
+    wheel.running = None;
+    if !wheel.running_cancelled {
+        timer.expires += timer.period;
+        wheel.insert(timer);
+    }
+}
+
+//////////////////////////////
+// Tests
+//////////////////////////////
+
+#[test_case]
+fn test_wheel_expires_on_time() {
+    // Around the range of every level
+    let delays = [
+        1, 2, 63, 64, 65, 4095, 4096, 4097, 262_143, 262_144, 300_000,
+    ];
+    let mut wheel = Wheel::new();
+    wheel.now = 1000;
+    for (i, &delay) in delays.iter().enumerate() {
+        wheel.insert(Timer {
+            id: TimerId(i as u64),
+            expires: wheel.now + delay,
+            period: 0,
+            callback: Box::new(|| {}),
+        });
+    }
+
+    let start = wheel.now;
+    let mut fired = 0;
+    while fired < delays.len() {
+        for timer in wheel.advance() {
+            assert_eq!(timer.expires, wheel.now);
+            assert_eq!(wheel.now - start, delays[timer.id.0 as usize]);
+            fired += 1;
+        }
+    }
+    assert_eq!(wheel.len(), 0);
+}
+
+#[test_case]
+fn test_oneshot_and_cancel() {
+    use crate::thread;
+    use core::sync::atomic::AtomicBool;
+
+    let fired = Arc::new(AtomicBool::new(false));
+    let flag = fired.clone();
+    add_oneshot(Duration::from_millis(20), move || {
+        flag.store(true, Ordering::SeqCst)
+    });
+
+    let cancelled = Arc::new(AtomicBool::new(false));
+    let flag = cancelled.clone();
+    let id = add_oneshot(Duration::from_millis(20), move || {
+        flag.store(true, Ordering::SeqCst)
+    });
+    assert!(cancel(id));
+    assert!(!cancel(id));
+
+    thread::sleep(Duration::from_millis(50));
+    assert!(fired.load(Ordering::SeqCst));
+    assert!(!cancelled.load(Ordering::SeqCst));
+}
+
+#[test_case]
+fn test_periodic_until_cancelled() {
+    use crate::thread;
+
+    let runs = Arc::new(AtomicU64::new(0));
+    let counter = runs.clone();
+    let id = add_periodic(Duration::from_millis(10), move || {
+        counter.fetch_add(1, Ordering::SeqCst);
+    });
+
+    thread::sleep(Duration::from_millis(100));
+    assert!(cancel(id));
+    let after_cancel = runs.load(Ordering::SeqCst);
+    assert!(after_cancel >= 3);
+
+    thread::sleep(Duration::from_millis(50));
+    assert_eq!(runs.load(Ordering::SeqCst), after_cancel);
+}
+
+#[test_case]
+fn test_sleep_future_wakes_task() {
+    use crate::thread;
+    use alloc::task::Wake;
+    use core::sync::atomic::AtomicBool;
+
+    struct Flag(AtomicBool);
+
+    impl Wake for Flag {
+        fn wake(self: Arc<Self>) {
+            self.0.store(true, Ordering::SeqCst);
+        }
+    }
+
+    let flag = Arc::new(Flag(AtomicBool::new(false)));
+    let waker = Waker::from(flag.clone());
+    let mut context = Context::from_waker(&waker);
+
+    let mut sleep = sleep(Duration::from_millis(20));
+    assert_eq!(Pin::new(&mut sleep).poll(&mut context), Poll::Pending);
+    thread::sleep(Duration::from_millis(50));
+    assert!(flag.0.load(Ordering::SeqCst));
+    assert_eq!(Pin::new(&mut sleep).poll(&mut context), Poll::Ready(()));
+}
@@ -0,0 +1,348 @@
+// trap.rs - Exception entry stubs saving the complete register state
+
+use crate::interrupts::{
+    self, InterruptIndex, ALIGNMENT_CHECK_VECTOR, BREAKPOINT_VECTOR, DEBUG_VECTOR,
+    DEVICE_NOT_AVAILABLE_VECTOR, DIVIDE_ERROR_VECTOR, GENERAL_PROTECTION_VECTOR,
+    INVALID_OPCODE_VECTOR, NMI_VECTOR, PAGE_FAULT_VECTOR, SEGMENT_NOT_PRESENT_VECTOR,
+    SIMD_FLOATING_POINT_VECTOR, STACK_SEGMENT_VECTOR, SYSCALL_VECTOR, X87_FLOATING_POINT_VECTOR,
+};
+use crate::signal::{self, SIGTRAP};
+use crate::{gdbstub, irq_stats, kdb, process, syscall};
+
+use x86_64::VirtAddr;
+
+//////////////////////////////
+// Statics/Constants
+//////////////////////////////
+
+/*
+ *  `extern "x86-interrupt"` handlers only see the interrupt stack frame. The
+ *  exceptions routed through here need the general purpose registers too,
+ *  e.g. for the debugger to show and change them, or to deliver signals on
+ *  the way back to user mode, see signal.rs. So the stubs below push all of
+ *  them and hand `trap_dispatch` a `TrapFrame`. The timer interrupt comes
+ *  through here as well, it is what delivers signals to processes that
+ *  never enter the kernel otherwise, and so does COM2 for the GDB stub to
+ *  stop the code it interrupted.
+ *
+ *    |  ss, rsp, rflags, cs, rip  |  pushed by the CPU
+ *    |  error code                |  pushed by the CPU or the stub
+ *    |  vector                    |  pushed by the stub
+ *    |  rax ... r15               |  pushed by trap_common
+ *    <- rsp, &TrapFrame
+ *
+ *  Entering from user mode also swaps to the kernel GS base, see percpu.rs.
+ *  Except for NMIs: one can arrive between a user mode entry and its
+ *  `swapgs`, so the NMI stub leaves GS alone and the handler sorts it out
+ *  with `KernelGs::enter_paranoid`. It runs on its own IST stack and
+ *  returns without delivering signals, since it may not take locks.
+ */
+core::arch::global_asm!(
+    r#"
+    .macro SAVE_REGISTERS
+    pushq %rax
+    pushq %rbx
+    pushq %rcx
+    pushq %rdx
+    pushq %rsi
+    pushq %rdi
+    pushq %rbp
+    pushq %r8
+    pushq %r9
+    pushq %r10
+    pushq %r11
+    pushq %r12
+    pushq %r13
+    pushq %r14
+    pushq %r15
+    .endm
+
+    .macro RESTORE_REGISTERS
+    popq %r15
+    popq %r14
+    popq %r13
+    popq %r12
+    popq %r11
+    popq %r10
+    popq %r9
+    popq %r8
+    popq %rbp
+    popq %rdi
+    popq %rsi
+    popq %rdx
+    popq %rcx
+    popq %rbx
+    popq %rax
+    .endm
+
+    .macro TRAP_ENTRY name, vector
+    .global \name
+\name:
+    pushq $0
+    pushq $\vector
+    jmp trap_common
+    .endm
+
+    # For exceptions where the CPU pushes an error code
+    .macro TRAP_ENTRY_ERROR name, vector
+    .global \name
+\name:
+    pushq $\vector
+    jmp trap_common
+    .endm
+
+    .text
+    TRAP_ENTRY trap_debug_entry, {debug}
+    TRAP_ENTRY trap_breakpoint_entry, {breakpoint}
+    TRAP_ENTRY trap_syscall_entry, {syscall}
+    TRAP_ENTRY trap_divide_error_entry, {divide_error}
+    TRAP_ENTRY trap_invalid_opcode_entry, {invalid_opcode}
+    TRAP_ENTRY trap_device_not_available_entry, {device_not_available}
+    TRAP_ENTRY_ERROR trap_segment_not_present_entry, {segment_not_present}
+    TRAP_ENTRY_ERROR trap_stack_segment_entry, {stack_segment}
+    TRAP_ENTRY_ERROR trap_general_protection_entry, {general_protection}
+    TRAP_ENTRY_ERROR trap_page_fault_entry, {page_fault}
+    TRAP_ENTRY trap_x87_floating_point_entry, {x87_floating_point}
+    TRAP_ENTRY_ERROR trap_alignment_check_entry, {alignment_check}
+    TRAP_ENTRY trap_simd_floating_point_entry, {simd_floating_point}
+    TRAP_ENTRY trap_timer_entry, {timer}
+    TRAP_ENTRY trap_com2_entry, {com2}
+
+trap_common:
+    # CS of the interrupted context, past vector, error code and rip
+    testb $3, 24(%rsp)
+    jz 1f
+    swapgs
+1:
+    SAVE_REGISTERS
+
+    # The CPU aligned the stack before pushing its frame, 22 pushes later
+    # it is 16 byte aligned again as the call requires
+    movq %rsp, %rdi
+    cld
+    callq trap_dispatch
+
+    RESTORE_REGISTERS
+
+    testb $3, 24(%rsp)
+    jz 2f
+    swapgs
+2:
+    addq $16, %rsp
+    iretq
+
+    .global trap_nmi_entry
+trap_nmi_entry:
+    pushq $0
+    pushq ${nmi}
+    SAVE_REGISTERS
+    movq %rsp, %rdi
+    cld
+    callq trap_nmi_dispatch
+    RESTORE_REGISTERS
+    addq $16, %rsp
+    iretq
+    "#,
+    debug = const DEBUG_VECTOR,
+    breakpoint = const BREAKPOINT_VECTOR,
+    syscall = const SYSCALL_VECTOR,
+    divide_error = const DIVIDE_ERROR_VECTOR,
+    invalid_opcode = const INVALID_OPCODE_VECTOR,
+    device_not_available = const DEVICE_NOT_AVAILABLE_VECTOR,
+    segment_not_present = const SEGMENT_NOT_PRESENT_VECTOR,
+    stack_segment = const STACK_SEGMENT_VECTOR,
+    general_protection = const GENERAL_PROTECTION_VECTOR,
+    page_fault = const PAGE_FAULT_VECTOR,
+    x87_floating_point = const X87_FLOATING_POINT_VECTOR,
+    alignment_check = const ALIGNMENT_CHECK_VECTOR,
+    simd_floating_point = const SIMD_FLOATING_POINT_VECTOR,
+    timer = const InterruptIndex::Timer as u8,
+    com2 = const InterruptIndex::Com2 as u8,
+    nmi = const NMI_VECTOR,
+    options(att_syntax)
+);
+
+extern "C" {
+    fn trap_debug_entry();
+    fn trap_breakpoint_entry();
+    fn trap_syscall_entry();
+    fn trap_divide_error_entry();
+    fn trap_invalid_opcode_entry();
+    fn trap_device_not_available_entry();
+    fn trap_segment_not_present_entry();
+    fn trap_stack_segment_entry();
+    fn trap_general_protection_entry();
+    fn trap_page_fault_entry();
+    fn trap_x87_floating_point_entry();
+    fn trap_alignment_check_entry();
+    fn trap_simd_floating_point_entry();
+    fn trap_timer_entry();
+    fn trap_com2_entry();
+    fn trap_nmi_entry();
+}
+
+////////////////////////////////
+// Structs, Types, Traits, Impl
+////////////////////////////////
+
+// Register state of the interrupted context, in stack order. Changes are
+// written back to the registers on return.
+#[derive(Debug, Clone, Copy)]
+#[repr(C)]
+pub struct TrapFrame {
+    pub r15: u64,
+    pub r14: u64,
+    pub r13: u64,
+    pub r12: u64,
+    pub r11: u64,
+    pub r10: u64,
+    pub r9: u64,
+    pub r8: u64,
+    pub rbp: u64,
+    pub rdi: u64,
+    pub rsi: u64,
+    pub rdx: u64,
+    pub rcx: u64,
+    pub rbx: u64,
+    pub rax: u64,
+    pub vector: u64,
+    pub error_code: u64,
+    pub rip: u64,
+    pub cs: u64,
+    pub rflags: u64,
+    pub rsp: u64,
+    pub ss: u64,
+}
+
+impl TrapFrame {
+    pub fn from_user_mode(&self) -> bool {
+        self.cs & 0b11 == 3
+    }
+
+    // User mode code of a user process, which gets signals for its faults
+    // and traps. The kernel process cannot take signals, so its threads
+    // running ring 3 code are treated like the kernel.
+    pub fn from_user_process(&self) -> bool {
+        self.from_user_mode() && process::current_pid() != process::KERNEL_PID
+    }
+}
+
+//////////////////////////////
+// API
+//////////////////////////////
+
+// Entry points to install in the IDT with `set_handler_addr`
+pub fn debug_entry() -> VirtAddr {
+    VirtAddr::new(trap_debug_entry as *const () as u64)
+}
+
+pub fn breakpoint_entry() -> VirtAddr {
+    VirtAddr::new(trap_breakpoint_entry as *const () as u64)
+}
+
+pub fn syscall_entry() -> VirtAddr {
+    VirtAddr::new(trap_syscall_entry as *const () as u64)
+}
+
+pub fn divide_error_entry() -> VirtAddr {
+    VirtAddr::new(trap_divide_error_entry as *const () as u64)
+}
+
+pub fn invalid_opcode_entry() -> VirtAddr {
+    VirtAddr::new(trap_invalid_opcode_entry as *const () as u64)
+}
+
+pub fn device_not_available_entry() -> VirtAddr {
+    VirtAddr::new(trap_device_not_available_entry as *const () as u64)
+}
+
+pub fn segment_not_present_entry() -> VirtAddr {
+    VirtAddr::new(trap_segment_not_present_entry as *const () as u64)
+}
+
+pub fn stack_segment_entry() -> VirtAddr {
+    VirtAddr::new(trap_stack_segment_entry as *const () as u64)
+}
+
+pub fn general_protection_entry() -> VirtAddr {
+    VirtAddr::new(trap_general_protection_entry as *const () as u64)
+}
+
+pub fn page_fault_entry() -> VirtAddr {
+    VirtAddr::new(trap_page_fault_entry as *const () as u64)
+}
+
+pub fn x87_floating_point_entry() -> VirtAddr {
+    VirtAddr::new(trap_x87_floating_point_entry as *const () as u64)
+}
+
+pub fn alignment_check_entry() -> VirtAddr {
+    VirtAddr::new(trap_alignment_check_entry as *const () as u64)
+}
+
+pub fn simd_floating_point_entry() -> VirtAddr {
+    VirtAddr::new(trap_simd_floating_point_entry as *const () as u64)
+}
+
+pub fn timer_entry() -> VirtAddr {
+    VirtAddr::new(trap_timer_entry as *const () as u64)
+}
+
+pub fn com2_entry() -> VirtAddr {
+    VirtAddr::new(trap_com2_entry as *const () as u64)
+}
+
+pub fn nmi_entry() -> VirtAddr {
+    VirtAddr::new(trap_nmi_entry as *const () as u64)
+}
+
+// Read and clear DR6, the cause of a #DB. Its bits are sticky, a handler
+// has to clear them for the next #DB to be told apart.
+pub fn take_dr6() -> u64 {
+    let value: u64;
+    unsafe {
+        core::arch::asm!(
+            "mov {}, dr6",
+            "mov dr6, {}",
+            out(reg) value,
+            in(reg) 0u64,
+            options(nomem, nostack, preserves_flags)
+        );
+    }
+    value
+}
+
+#[no_mangle]
+extern "C" fn trap_dispatch(frame: &mut TrapFrame) {
+    let vector = frame.vector as u8;
+    irq_stats::record(vector);
+
+    match vector {
+        // Only the kernel's own breakpoints and single steps go to a
+        // debugger, a process could otherwise stop the machine
+        DEBUG_VECTOR | BREAKPOINT_VECTOR if frame.from_user_process() => {
+            if vector == DEBUG_VECTOR {
+                take_dr6();
+            }
+            signal::force(SIGTRAP);
+        }
+        // GDB takes over from kdb with the `gdbstub` feature and a COM2
+        DEBUG_VECTOR | BREAKPOINT_VECTOR if gdbstub::is_enabled() => gdbstub::handle_trap(frame),
+        DEBUG_VECTOR | BREAKPOINT_VECTOR => kdb::handle_trap(frame),
+        SYSCALL_VECTOR => syscall::handle(frame),
+        _ if vector == InterruptIndex::Timer.as_u8() => interrupts::timer_interrupt_handler(),
+        _ if vector == InterruptIndex::Com2.as_u8() => interrupts::com2_interrupt_handler(frame),
+        DEVICE_NOT_AVAILABLE_VECTOR => interrupts::device_not_available_handler(frame),
+        // Every other stub is for a fault or error, a signal for a user
+        // process and fatal anywhere else
+        _ => interrupts::exception_handler(frame),
+    }
+
+    // Any of the above may have left a signal pending
+    signal::deliver(frame);
+}
+
+#[no_mangle]
+extern "C" fn trap_nmi_dispatch(frame: &mut TrapFrame) {
+    interrupts::nmi_handler(frame);
+}
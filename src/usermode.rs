@@ -0,0 +1,111 @@
+// usermode.rs - Dropping from the kernel into ring 3
+
+use crate::{gdt, memory, vm};
+
+use x86_64::structures::paging::PageTableFlags;
+use x86_64::VirtAddr;
+
+//////////////////////////////
+// Statics/Constants
+//////////////////////////////
+
+/*
+ *  There is no instruction to lower the privilege level directly. Instead
+ *  `enter_user_mode` builds the frame an interrupt from ring 3 would have
+ *  pushed and returns through it with `iretq`:
+ *
+ *    |  user data selector  |  ss
+ *    |  user stack          |  rsp
+ *    |  RFLAGS_USER         |  rflags
+ *    |  user code selector  |  cs
+ *    |  entry point         |  rip   <- rsp at iretq
+ *
+ *  The next interrupt, exception or system call switches back to the stack
+ *  in the TSS, see `gdt::set_kernel_stack`.
+ */
+
+// Interrupts enabled, plus the always set reserved bit 1
+pub const RFLAGS_USER: u64 = 0x202;
+
+// User mode owns the lower half of the address space
+pub const USER_SPACE_END: u64 = 0x0000_8000_0000_0000;
+
+// Stack of the first thread of a process, growing down from the top of
+// user space
+pub const USER_STACK_TOP: u64 = USER_SPACE_END - PAGE_SIZE;
+pub const USER_STACK_SIZE: u64 = 64 * 1024;
+
+const PAGE_SIZE: u64 = 4096;
+
+//////////////////////////////
+// API
+//////////////////////////////
+
+// Unsafe! `entry` and `user_stack` must be mapped user accessible in the
+// current address space. Interrupts are enabled on arrival, and no kernel
+// register state leaks into ring 3.
+pub unsafe fn enter_user_mode(entry: VirtAddr, user_stack: VirtAddr) -> ! {
+    let selectors = gdt::selectors();
+    let code_selector = u64::from(selectors.user_code_selector.0);
+    let data_selector = u64::from(selectors.user_data_selector.0);
+
+    core::arch::asm!(
+        // No interrupt may see the user GS base while still in ring 0
+        "cli",
+        "swapgs",
+        "push {ss}",
+        "push {rsp}",
+        "push {rflags}",
+        "push {cs}",
+        "push {rip}",
+        "xor eax, eax",
+        "xor ebx, ebx",
+        "xor ecx, ecx",
+        "xor edx, edx",
+        "xor esi, esi",
+        "xor edi, edi",
+        "xor ebp, ebp",
+        "xor r8d, r8d",
+        "xor r9d, r9d",
+        "xor r10d, r10d",
+        "xor r11d, r11d",
+        "xor r12d, r12d",
+        "xor r13d, r13d",
+        "xor r14d, r14d",
+        "xor r15d, r15d",
+        "iretq",
+        ss = in(reg) data_selector,
+        rsp = in(reg) user_stack.as_u64(),
+        rflags = in(reg) RFLAGS_USER,
+        cs = in(reg) code_selector,
+        rip = in(reg) entry.as_u64(),
+        options(noreturn)
+    );
+}
+
+// Whether ring 3 may access all of `addr..addr + len`, i.e. every page is
+// mapped user accessible, and writable if `write` is set. Used to check
+// pointers passed in system calls before the kernel touches them. Pages
+// the process has not touched yet are allocated first, see vm.rs.
+pub fn is_user_range(addr: u64, len: u64, write: bool) -> bool {
+    let end = match addr.checked_add(len) {
+        Some(end) if end <= USER_SPACE_END => end,
+        _ => return false,
+    };
+    vm::populate(addr, len, write);
+
+    let mut page = addr & !(PAGE_SIZE - 1);
+    while page < end {
+        let mut allowed = true;
+        let mapped = memory::walk_page_tables(VirtAddr::new(page), |_, entry| {
+            let flags = entry.flags();
+            allowed &= flags.contains(PageTableFlags::USER_ACCESSIBLE);
+            allowed &= !write || flags.contains(PageTableFlags::WRITABLE);
+        });
+        if !mapped || !allowed {
+            return false;
+        }
+        page += PAGE_SIZE;
+    }
+    true
+}
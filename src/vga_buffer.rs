@@ -1,9 +1,9 @@
 // vga_buffer.rs - Memory Mapped IO to the VGA Buffer
 
 
+use crate::spinlock::IrqSpinlock;
 use core::fmt;
 use lazy_static::lazy_static;
-use spin::Mutex;
 use volatile::Volatile;
 
 //////////////////////////////
@@ -11,7 +11,7 @@ use volatile::Volatile;
 //////////////////////////////
 
 lazy_static! {
-    pub static ref WRITER: Mutex<Writer> = Mutex::new(Writer {
+    pub static ref WRITER: IrqSpinlock<Writer> = IrqSpinlock::new("WRITER", Writer {
         column_pos: 0,
         // TODO allow user to choose the VGA color code
         color_code: ColorCode::new(Color::Green, Color::Black),
@@ -172,12 +172,9 @@ macro_rules! println {
 #[doc(hidden)]
 pub fn _print(args: fmt::Arguments) {
     use core::fmt::Write;
-    use x86_64::instructions::interrupts;
 
-    // Avoid deadlock by turning off interrupts temporarily
-    interrupts::without_interrupts(|| {
-        WRITER.lock().write_fmt(args).unwrap();
-    })
+    // The guard keeps interrupts off, so a handler printing can't deadlock
+    WRITER.lock().write_fmt(args).unwrap();
 }
 
 
@@ -202,17 +199,14 @@ fn test_println_many_no_panic() {
 #[test_case]
 fn test_println_output() {
     use core::fmt::Write;
-    use x86_64::instructions::interrupts;
 
     let s = "Lorem ipsum dolor sit amet";
-    interrupts::without_interrupts(|| {
-        let mut writer = WRITER.lock();
-        writeln!(writer, "\n{}", s).expect("writeln failed");
-        for (i, c) in s.chars().enumerate() {
-            let screen_char = writer.buffer.chars[BUFFER_HEIGHT - 2][i].read();
-            assert_eq!(char::from(screen_char.ascii_character), c);
-        }
-    })
+    let mut writer = WRITER.lock();
+    writeln!(writer, "\n{}", s).expect("writeln failed");
+    for (i, c) in s.chars().enumerate() {
+        let screen_char = writer.buffer.chars[BUFFER_HEIGHT - 2][i].read();
+        assert_eq!(char::from(screen_char.ascii_character), c);
+    }
 }
 
 
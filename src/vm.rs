@@ -0,0 +1,741 @@
+// vm.rs - Memory regions of user processes: brk, mmap and lazy allocation
+
+use crate::address_space::{self, AddressSpace, MapError};
+use crate::thread;
+use crate::usermode::{USER_SPACE_END, USER_STACK_SIZE, USER_STACK_TOP};
+
+use alloc::collections::BTreeMap;
+use alloc::vec::Vec;
+use x86_64::structures::idt::PageFaultErrorCode;
+use x86_64::structures::paging::{Page, PageTableFlags};
+use x86_64::VirtAddr;
+
+//////////////////////////////
+// Statics/Constants
+//////////////////////////////
+
+/*
+ *  What a process may access is described by regions, page aligned ranges
+ *  of its address space with one protection each:
+ *
+ *    USER_STACK_TOP
+ *    |  stack       |  Stack, mapped by the loader
+ *    |              |  STACK_GAP
+ *    |  mmap        |  Anonymous, placed top down from MMAP_TOP
+ *    |  ...         |
+ *    |  heap        |  Heap, moved up by `brk` from the end of the image
+ *    |  image       |  Image, the program's segments
+ *    |              |
+ *    MMAP_MIN
+ *
+ *  Memory from `mmap` and `brk` is only a region at first. The first access
+ *  to a page faults and `handle_page_fault` maps a zeroed frame there, if
+ *  the region allows the access. Anything else is a bad access the process
+ *  is killed for. System calls allocate the pages of user buffers before
+ *  touching them, see `usermode::is_user_range`, so the kernel itself does
+ *  not fault on them.
+ *
+ *  Protection and flags have the values of Linux, and only private
+ *  anonymous mappings are supported. MAP_FIXED replaces whatever was mapped
+ *  at the address before, MAP_FIXED_NOREPLACE fails instead.
+ */
+pub const PROT_NONE: u64 = 0;
+pub const PROT_READ: u64 = 1;
+pub const PROT_WRITE: u64 = 2;
+pub const PROT_EXEC: u64 = 4;
+
+pub const MAP_SHARED: u64 = 0x01;
+pub const MAP_PRIVATE: u64 = 0x02;
+pub const MAP_FIXED: u64 = 0x10;
+pub const MAP_ANONYMOUS: u64 = 0x20;
+pub const MAP_FIXED_NOREPLACE: u64 = 0x10_0000;
+
+const PROT_ALL: u64 = PROT_READ | PROT_WRITE | PROT_EXEC;
+const MAP_ALL: u64 = MAP_SHARED | MAP_PRIVATE | MAP_FIXED | MAP_ANONYMOUS | MAP_FIXED_NOREPLACE;
+
+// Nothing is mapped below, so that null pointers fault even with an offset
+pub const MMAP_MIN: u64 = 0x1_0000;
+
+// Left unmapped below the stack, to catch it overflowing
+const STACK_GAP: u64 = 16 * 1024 * 1024;
+pub const MMAP_TOP: u64 = USER_STACK_TOP - USER_STACK_SIZE - STACK_GAP;
+
+const PAGE_SIZE: u64 = 4096;
+// Bytes mapped by one level 4 entry, see address_space.rs
+const LEVEL_4_ENTRY_SIZE: u64 = 1 << 39;
+
+////////////////////////////////
+// Structs, Types, Traits, Impl
+////////////////////////////////
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum VmError {
+    // Address or offset not page aligned
+    Misaligned,
+    // Zero length, or unknown protection or flags
+    InvalidArgument,
+    // Only anonymous memory can be mapped
+    NotAnonymous,
+    // Part of the range is in use, with MAP_FIXED_NOREPLACE
+    Overlap,
+    // Part of the range is not in any region
+    NotMapped,
+    // Outside of user space, or no free range is large enough
+    OutOfMemory,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Kind {
+    Image,
+    Stack,
+    Heap,
+    Anonymous,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Access {
+    Read,
+    Write,
+    Execute,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Region {
+    pub start: u64,
+    pub end: u64,
+    pub prot: u64,
+    pub kind: Kind,
+}
+
+impl Region {
+    pub fn new(start: u64, end: u64, prot: u64, kind: Kind) -> Region {
+        Region {
+            start,
+            end,
+            prot,
+            kind,
+        }
+    }
+
+    // x86 pages are always readable, so any protection but PROT_NONE is
+    pub fn allows(&self, access: Access) -> bool {
+        match access {
+            Access::Read => self.prot != PROT_NONE,
+            Access::Write => self.prot & PROT_WRITE != 0,
+            Access::Execute => self.prot & PROT_EXEC != 0,
+        }
+    }
+
+    // Flags of the region's pages. With PROT_NONE they stay present, but
+    // are not user accessible.
+    pub fn page_flags(&self) -> PageTableFlags {
+        if self.prot == PROT_NONE {
+            return PageTableFlags::empty();
+        }
+        let mut flags = PageTableFlags::USER_ACCESSIBLE;
+        if self.prot & PROT_WRITE != 0 {
+            flags |= PageTableFlags::WRITABLE;
+        }
+        if self.prot & PROT_EXEC == 0 {
+            flags |= address_space::no_execute();
+        }
+        flags
+    }
+}
+
+// The regions of one address space, and its program break
+#[derive(Default)]
+pub struct Regions {
+    // Keyed by start address, never overlapping
+    regions: BTreeMap<u64, Region>,
+    // Start of the heap, 0 if the process has none, and the current break
+    heap_start: u64,
+    brk: u64,
+}
+
+impl Regions {
+    pub const fn new() -> Regions {
+        Regions {
+            regions: BTreeMap::new(),
+            heap_start: 0,
+            brk: 0,
+        }
+    }
+
+    // Region containing `addr`
+    pub fn find(&self, addr: u64) -> Option<&Region> {
+        self.overlapping(addr, addr + 1).next()
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &Region> {
+        self.regions.values()
+    }
+
+    // Add a region where there is none yet
+    pub fn insert(&mut self, region: Region) {
+        assert!(
+            self.is_free(region.start, region.end),
+            "region overlaps another"
+        );
+        self.regions.insert(region.start, region);
+        self.merge_at(region.start);
+        self.merge_at(region.end);
+    }
+
+    // Where the heap starts, right after the program's image. Only set by
+    // the loader, before the program runs.
+    pub fn set_heap_start(&mut self, addr: u64) {
+        self.heap_start = round_up(addr);
+        self.brk = self.heap_start;
+    }
+
+    pub fn brk(&self) -> u64 {
+        self.brk
+    }
+
+    // Regions overlapping `start..end`, highest first
+    fn overlapping(&self, start: u64, end: u64) -> impl Iterator<Item = &Region> {
+        self.regions
+            .range(..end)
+            .rev()
+            .map(|(_, region)| region)
+            .take_while(move |region| region.end > start)
+    }
+
+    fn is_free(&self, start: u64, end: u64) -> bool {
+        self.overlapping(start, end).next().is_none()
+    }
+
+    // Whether regions cover every byte of `start..end`
+    fn is_covered(&self, start: u64, end: u64) -> bool {
+        let mut covered = end;
+        for region in self.overlapping(start, end) {
+            if region.end < covered {
+                return false;
+            }
+            covered = region.start;
+        }
+        covered <= start
+    }
+
+    // Split the region containing `addr` in two there
+    fn split(&mut self, addr: u64) {
+        let upper = match self.regions.range_mut(..addr).next_back() {
+            Some((_, region)) if region.end > addr => {
+                let upper = Region {
+                    start: addr,
+                    ..*region
+                };
+                region.end = addr;
+                upper
+            }
+            _ => return,
+        };
+        self.regions.insert(addr, upper);
+    }
+
+    // Join the regions ending and starting at `addr` if they are alike
+    fn merge_at(&mut self, addr: u64) {
+        let next = match self.regions.get(&addr) {
+            Some(&next) => next,
+            None => return,
+        };
+        if let Some((_, previous)) = self.regions.range_mut(..addr).next_back() {
+            if previous.end == addr && previous.prot == next.prot && previous.kind == next.kind {
+                previous.end = next.end;
+                self.regions.remove(&addr);
+            }
+        }
+    }
+
+    // Take `start..end` out of the regions, returning the parts removed
+    fn remove(&mut self, start: u64, end: u64) -> Vec<Region> {
+        self.split(start);
+        self.split(end);
+        let starts: Vec<u64> = self
+            .regions
+            .range(start..end)
+            .map(|(&start, _)| start)
+            .collect();
+        starts
+            .iter()
+            .filter_map(|start| self.regions.remove(start))
+            .collect()
+    }
+
+    // Highest free range of `len` bytes in user space below MMAP_TOP
+    fn find_free(&self, len: u64) -> Option<u64> {
+        let mut end = MMAP_TOP;
+        loop {
+            let start = end.checked_sub(len).filter(|&start| start >= MMAP_MIN)?;
+            if let Some(kernel) = kernel_entry_in(start, end) {
+                end = kernel;
+            } else if let Some(region) = self.overlapping(start, end).next() {
+                end = region.start;
+            } else {
+                return Some(start);
+            }
+        }
+    }
+}
+
+//////////////////////////////
+// API
+//////////////////////////////
+
+// brk: move the end of the heap to `addr` and return the new break. Pages
+// above it are freed. With 0, or when the heap cannot grow that far, the
+// break stays where it is and that is returned, as on Linux.
+pub fn brk(space: &AddressSpace, addr: u64) -> u64 {
+    let mut regions = space.regions();
+    let old = regions.brk;
+    if regions.heap_start == 0 || addr < regions.heap_start || addr > USER_SPACE_END {
+        return old;
+    }
+
+    let (old_end, new_end) = (round_up(old), round_up(addr));
+    if new_end > old_end {
+        if !is_user_range(old_end, new_end) || !regions.is_free(old_end, new_end) {
+            return old;
+        }
+        regions.insert(Region::new(
+            old_end,
+            new_end,
+            PROT_READ | PROT_WRITE,
+            Kind::Heap,
+        ));
+    } else if new_end < old_end {
+        for region in regions.remove(new_end, old_end) {
+            unmap_region(space, &region);
+        }
+    }
+    regions.brk = addr;
+    addr
+}
+
+// mmap: reserve `len` bytes of zeroed memory with protection `prot`, and
+// return their address. Without MAP_FIXED, `addr` is only a hint.
+pub fn mmap(
+    space: &AddressSpace,
+    addr: u64,
+    len: u64,
+    prot: u64,
+    flags: u64,
+) -> Result<u64, VmError> {
+    if len == 0 || prot & !PROT_ALL != 0 || flags & !MAP_ALL != 0 {
+        return Err(VmError::InvalidArgument);
+    }
+    if flags & (MAP_SHARED | MAP_PRIVATE) != MAP_PRIVATE {
+        return Err(VmError::InvalidArgument);
+    }
+    if flags & MAP_ANONYMOUS == 0 {
+        return Err(VmError::NotAnonymous);
+    }
+    let len = checked_round_up(len).ok_or(VmError::OutOfMemory)?;
+
+    let mut regions = space.regions();
+    let start = if flags & (MAP_FIXED | MAP_FIXED_NOREPLACE) != 0 {
+        if !addr.is_multiple_of(PAGE_SIZE) {
+            return Err(VmError::Misaligned);
+        }
+        let end = addr.checked_add(len).ok_or(VmError::OutOfMemory)?;
+        if !is_user_range(addr, end) {
+            return Err(VmError::OutOfMemory);
+        }
+        if flags & MAP_FIXED_NOREPLACE != 0 && !regions.is_free(addr, end) {
+            return Err(VmError::Overlap);
+        }
+        for region in regions.remove(addr, end) {
+            unmap_region(space, &region);
+        }
+        addr
+    } else {
+        let hint = checked_round_up(addr).filter(|&hint| {
+            hint != 0
+                && hint
+                    .checked_add(len)
+                    .is_some_and(|end| is_user_range(hint, end) && regions.is_free(hint, end))
+        });
+        match hint {
+            Some(hint) => hint,
+            None => regions.find_free(len).ok_or(VmError::OutOfMemory)?,
+        }
+    };
+
+    regions.insert(Region::new(start, start + len, prot, Kind::Anonymous));
+    Ok(start)
+}
+
+// munmap: remove `addr..addr + len` from the regions and free its pages.
+// Parts that were not mapped are skipped.
+pub fn munmap(space: &AddressSpace, addr: u64, len: u64) -> Result<(), VmError> {
+    if !addr.is_multiple_of(PAGE_SIZE) {
+        return Err(VmError::Misaligned);
+    }
+    let end = checked_round_up(len)
+        .and_then(|len| addr.checked_add(len))
+        .filter(|&end| len != 0 && end <= USER_SPACE_END)
+        .ok_or(VmError::InvalidArgument)?;
+
+    let mut regions = space.regions();
+    for region in regions.remove(addr, end) {
+        unmap_region(space, &region);
+    }
+    Ok(())
+}
+
+// mprotect: change the protection of `addr..addr + len`, every byte of
+// which must be in some region. Pages already mapped change at once.
+pub fn mprotect(space: &AddressSpace, addr: u64, len: u64, prot: u64) -> Result<(), VmError> {
+    if !addr.is_multiple_of(PAGE_SIZE) {
+        return Err(VmError::Misaligned);
+    }
+    if prot & !PROT_ALL != 0 {
+        return Err(VmError::InvalidArgument);
+    }
+    let end = checked_round_up(len)
+        .and_then(|len| addr.checked_add(len))
+        .ok_or(VmError::NotMapped)?;
+    if addr == end {
+        return Ok(());
+    }
+
+    let mut regions = space.regions();
+    if !regions.is_covered(addr, end) {
+        return Err(VmError::NotMapped);
+    }
+    regions.split(addr);
+    regions.split(end);
+    let starts: Vec<u64> = regions
+        .regions
+        .range(addr..end)
+        .map(|(&start, _)| start)
+        .collect();
+    for start in starts.iter() {
+        let region = regions.regions.get_mut(start).expect("region vanished");
+        region.prot = prot;
+        let region = *region;
+        for page in pages(&region) {
+            match space.protect(page, region.page_flags()) {
+                Ok(()) | Err(MapError::NotMapped) => {}
+                Err(error) => panic!("region outside user space: {:?}", error),
+            }
+        }
+    }
+    for &start in starts.iter().chain(core::iter::once(&end)) {
+        regions.merge_at(start);
+    }
+    Ok(())
+}
+
+// Map the page at `addr` if its region allows `access`. False if the
+// access is not allowed, or there is no memory left for the page.
+pub fn fault(space: &AddressSpace, addr: VirtAddr, access: Access) -> bool {
+    let regions = space.regions();
+    let region = match regions.find(addr.as_u64()) {
+        Some(region) if region.allows(access) => *region,
+        _ => return false,
+    };
+    matches!(
+        space.map(Page::containing_address(addr), region.page_flags()),
+        Ok(_) | Err(MapError::AlreadyMapped)
+    )
+}
+
+// Called by the page fault handler. Returns true if the page was mapped
+// lazily and the access can be retried.
+pub fn handle_page_fault(addr: VirtAddr, error_code: PageFaultErrorCode) -> bool {
+    // Checked first, a fault on kernel memory must not take any locks
+    if error_code.contains(PageFaultErrorCode::PROTECTION_VIOLATION)
+        || !address_space::is_user_address(addr)
+    {
+        return false;
+    }
+    let access = if error_code.contains(PageFaultErrorCode::INSTRUCTION_FETCH) {
+        Access::Execute
+    } else if error_code.contains(PageFaultErrorCode::CAUSED_BY_WRITE) {
+        Access::Write
+    } else {
+        Access::Read
+    };
+    match thread::current().address_space() {
+        Some(space) => fault(&space, addr, access),
+        None => false,
+    }
+}
+
+// Map the pages of `addr..addr + len` the calling thread's process has not
+// touched yet, as far as its regions allow, so that the kernel can access
+// them without faulting
+pub fn populate(addr: u64, len: u64, write: bool) {
+    let space = match thread::current().address_space() {
+        Some(space) => space,
+        None => return,
+    };
+    let access = if write { Access::Write } else { Access::Read };
+    let end = addr.saturating_add(len);
+    let mut page = addr & !(PAGE_SIZE - 1);
+    while page < end {
+        let virt = VirtAddr::new(page);
+        if !address_space::is_user_address(virt) {
+            return;
+        }
+        if space.translate(virt).is_none() && !fault(&space, virt, access) {
+            return;
+        }
+        page += PAGE_SIZE;
+    }
+}
+
+pub fn round_up(addr: u64) -> u64 {
+    checked_round_up(addr).expect("address out of range")
+}
+
+fn checked_round_up(addr: u64) -> Option<u64> {
+    Some(addr.checked_add(PAGE_SIZE - 1)? & !(PAGE_SIZE - 1))
+}
+
+// Whether user mode may have `start..end` at all, see address_space.rs
+fn is_user_range(start: u64, end: u64) -> bool {
+    start >= MMAP_MIN && end <= USER_SPACE_END && kernel_entry_in(start, end).is_none()
+}
+
+// Start of the highest level 4 entry used by the kernel in `start..end`
+fn kernel_entry_in(start: u64, end: u64) -> Option<u64> {
+    let mut entry = (end - 1) & !(LEVEL_4_ENTRY_SIZE - 1);
+    loop {
+        if !address_space::is_user_address(VirtAddr::new(entry)) {
+            return Some(entry);
+        }
+        if entry <= start {
+            return None;
+        }
+        entry -= LEVEL_4_ENTRY_SIZE;
+    }
+}
+
+fn pages(region: &Region) -> impl Iterator<Item = Page> {
+    let first = Page::containing_address(VirtAddr::new(region.start));
+    let last = Page::containing_address(VirtAddr::new(region.end - 1));
+    Page::range_inclusive(first, last)
+}
+
+// Free the pages of `region` that were mapped
+fn unmap_region(space: &AddressSpace, region: &Region) {
+    for page in pages(region) {
+        match space.unmap(page) {
+            Ok(()) | Err(MapError::NotMapped) => {}
+            Err(error) => panic!("region outside user space: {:?}", error),
+        }
+    }
+}
+
+//////////////////////////////
+// Tests
+//////////////////////////////
+
+#[cfg(test)]
+const TEST_ADDR: u64 = 0x5555_0000_0000;
+
+#[test_case]
+fn test_mmap_is_lazy() {
+    let space = AddressSpace::new().expect("no memory for address space");
+    let flags = MAP_PRIVATE | MAP_ANONYMOUS;
+    let addr = mmap(&space, 0, 3 * PAGE_SIZE, PROT_READ | PROT_WRITE, flags).expect("mmap failed");
+    assert_eq!(addr % PAGE_SIZE, 0);
+    assert!(addr + 3 * PAGE_SIZE <= MMAP_TOP);
+    assert!(space.translate(VirtAddr::new(addr)).is_none());
+
+    // The first access maps a zeroed page, writable as asked
+    let middle = VirtAddr::new(addr + PAGE_SIZE + 8);
+    assert!(fault(&space, middle, Access::Write));
+    let (_, page_flags) = space.translate(middle).expect("page not mapped");
+    assert!(page_flags.contains(PageTableFlags::USER_ACCESSIBLE | PageTableFlags::WRITABLE));
+    assert!(space.translate(VirtAddr::new(addr)).is_none());
+    assert!(!fault(&space, middle, Access::Execute));
+    assert!(!fault(
+        &space,
+        VirtAddr::new(addr + 3 * PAGE_SIZE),
+        Access::Read
+    ));
+
+    // Read only from now on, the mapped page changes at once
+    mprotect(&space, addr, 3 * PAGE_SIZE, PROT_READ).expect("mprotect failed");
+    let (_, page_flags) = space.translate(middle).expect("page not mapped");
+    assert!(!page_flags.contains(PageTableFlags::WRITABLE));
+    assert!(!fault(&space, VirtAddr::new(addr), Access::Write));
+    assert!(fault(&space, VirtAddr::new(addr), Access::Read));
+
+    // Unmapping the middle splits the region and frees the page
+    munmap(&space, addr + PAGE_SIZE, PAGE_SIZE).expect("munmap failed");
+    assert!(space.translate(middle).is_none());
+    assert!(space.regions().find(middle.as_u64()).is_none());
+    assert_eq!(space.regions().iter().count(), 2);
+    assert_eq!(
+        mprotect(&space, addr, 3 * PAGE_SIZE, PROT_READ),
+        Err(VmError::NotMapped)
+    );
+    munmap(&space, addr, 3 * PAGE_SIZE).expect("munmap failed");
+    assert_eq!(space.regions().iter().count(), 0);
+}
+
+#[test_case]
+fn test_mmap_errors() {
+    let space = AddressSpace::new().expect("no memory for address space");
+    let flags = MAP_PRIVATE | MAP_ANONYMOUS;
+    let fixed = flags | MAP_FIXED;
+    let rw = PROT_READ | PROT_WRITE;
+
+    assert_eq!(mmap(&space, 0, 0, rw, flags), Err(VmError::InvalidArgument));
+    assert_eq!(
+        mmap(&space, 0, PAGE_SIZE, 8, flags),
+        Err(VmError::InvalidArgument)
+    );
+    assert_eq!(
+        mmap(&space, 0, PAGE_SIZE, rw, MAP_SHARED | MAP_ANONYMOUS),
+        Err(VmError::InvalidArgument)
+    );
+    assert_eq!(
+        mmap(&space, 0, PAGE_SIZE, rw, MAP_PRIVATE),
+        Err(VmError::NotAnonymous)
+    );
+    assert_eq!(
+        mmap(&space, TEST_ADDR + 1, PAGE_SIZE, rw, fixed),
+        Err(VmError::Misaligned)
+    );
+    assert_eq!(
+        mmap(&space, 0, PAGE_SIZE, rw, fixed),
+        Err(VmError::OutOfMemory)
+    );
+    assert_eq!(
+        mmap(&space, 0, u64::MAX, rw, flags),
+        Err(VmError::OutOfMemory)
+    );
+
+    // Kernel memory cannot be mapped over
+    let kernel = AddressSpace::new as *const () as u64 & !(PAGE_SIZE - 1);
+    assert_eq!(
+        mmap(&space, kernel, PAGE_SIZE, rw, fixed),
+        Err(VmError::OutOfMemory)
+    );
+
+    // A fixed mapping replaces what was there, unless told not to
+    assert_eq!(
+        mmap(&space, TEST_ADDR, 2 * PAGE_SIZE, rw, fixed),
+        Ok(TEST_ADDR)
+    );
+    assert!(fault(&space, VirtAddr::new(TEST_ADDR), Access::Write));
+    assert_eq!(
+        mmap(
+            &space,
+            TEST_ADDR + PAGE_SIZE,
+            PAGE_SIZE,
+            rw,
+            flags | MAP_FIXED_NOREPLACE
+        ),
+        Err(VmError::Overlap)
+    );
+    assert_eq!(
+        mmap(&space, TEST_ADDR, PAGE_SIZE, PROT_READ, fixed),
+        Ok(TEST_ADDR)
+    );
+    assert!(space.translate(VirtAddr::new(TEST_ADDR)).is_none());
+    assert_eq!(space.regions().iter().count(), 2);
+
+    // A hint that is in use is ignored
+    let addr = mmap(&space, TEST_ADDR, PAGE_SIZE, rw, flags).expect("mmap failed");
+    assert_ne!(addr, TEST_ADDR);
+
+    assert_eq!(
+        munmap(&space, TEST_ADDR + 1, PAGE_SIZE),
+        Err(VmError::Misaligned)
+    );
+    assert_eq!(munmap(&space, TEST_ADDR, 0), Err(VmError::InvalidArgument));
+    assert_eq!(
+        munmap(&space, USER_SPACE_END, PAGE_SIZE),
+        Err(VmError::InvalidArgument)
+    );
+    assert_eq!(
+        mprotect(&space, TEST_ADDR + 1, PAGE_SIZE, rw),
+        Err(VmError::Misaligned)
+    );
+    assert_eq!(
+        mprotect(&space, TEST_ADDR, PAGE_SIZE, 8),
+        Err(VmError::InvalidArgument)
+    );
+}
+
+#[test_case]
+fn test_brk() {
+    let space = AddressSpace::new().expect("no memory for address space");
+    assert_eq!(brk(&space, TEST_ADDR), 0, "no heap without an image");
+
+    space.regions().set_heap_start(TEST_ADDR + 10);
+    let start = TEST_ADDR + PAGE_SIZE;
+    assert_eq!(brk(&space, 0), start);
+    assert_eq!(brk(&space, start - 1), start);
+
+    assert_eq!(
+        brk(&space, start + 2 * PAGE_SIZE + 1),
+        start + 2 * PAGE_SIZE + 1
+    );
+    assert!(fault(
+        &space,
+        VirtAddr::new(start + 2 * PAGE_SIZE),
+        Access::Write
+    ));
+    assert_eq!(
+        space.regions().find(start).map(|region| region.kind),
+        Some(Kind::Heap)
+    );
+
+    // Shrinking frees the pages above the new break
+    let before = crate::memory::free_frame_count();
+    assert_eq!(brk(&space, start + PAGE_SIZE), start + PAGE_SIZE);
+    assert!(space
+        .translate(VirtAddr::new(start + 2 * PAGE_SIZE))
+        .is_none());
+    assert_eq!(crate::memory::free_frame_count(), before + 1);
+
+    // The heap cannot grow into a mapping
+    let flags = MAP_PRIVATE | MAP_ANONYMOUS | MAP_FIXED;
+    let above = start + 4 * PAGE_SIZE;
+    assert_eq!(mmap(&space, above, PAGE_SIZE, PROT_READ, flags), Ok(above));
+    assert_eq!(brk(&space, above + PAGE_SIZE), start + PAGE_SIZE);
+}
+
+#[test_case]
+fn test_user_program_mmap() {
+    use crate::elf::{self, PF_R, PF_X, TEST_BASE};
+    use crate::process::{self, ExitStatus};
+    use crate::signal::SIGSEGV;
+
+    // mov eax, SYS_MMAP; xor edi, edi; mov esi, 0x1000; mov edx, prot
+    // mov r10d, MAP_PRIVATE | MAP_ANONYMOUS; mov r8, -1; xor r9d, r9d
+    // syscall; mov byte [rax], 42; movzx edi, byte [rax]
+    // xor eax, eax; syscall; exits with the byte written
+    let program = |prot: u8| {
+        let code = [
+            0xb8, 0x06, 0x00, 0x00, 0x00, 0x31, 0xff, 0xbe, 0x00, 0x10, 0x00, 0x00, 0xba, prot,
+            0x00, 0x00, 0x00, 0x41, 0xba, 0x22, 0x00, 0x00, 0x00, 0x49, 0xc7, 0xc0, 0xff, 0xff,
+            0xff, 0xff, 0x45, 0x31, 0xc9, 0x0f, 0x05, 0xc6, 0x00, 0x2a, 0x0f, 0xb6, 0x38, 0x31,
+            0xc0, 0x0f, 0x05, 0xeb, 0xfe,
+        ];
+        let image = elf::test_image(TEST_BASE, &[(PF_R | PF_X, TEST_BASE, &code, 64)]);
+        let process = elf::spawn("mmap", &image, &["mmap"], &[]).expect("spawn failed");
+        let pid = process.pid();
+        process::waitpid(Some(pid), false).expect("waitpid failed")
+    };
+
+    let (_, status) =
+        program((PROT_READ | PROT_WRITE) as u8).expect("blocking wait returned nothing");
+    assert_eq!(status, ExitStatus::Exited(42));
+
+    // Writing to read only memory kills the process
+    let (_, status) = program(PROT_READ as u8).expect("blocking wait returned nothing");
+    let signaled = ExitStatus::Signaled {
+        signal: SIGSEGV,
+        core: true,
+    };
+    assert_eq!(status, signaled);
+}
@@ -0,0 +1,175 @@
+// watchdog.rs - Lockup detection through the per-CPU APIC timer tick
+
+use crate::backtrace::Backtrace;
+use crate::trap::TrapFrame;
+use crate::{apic, percpu, serial_emergency_println, smp};
+
+use core::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+
+//////////////////////////////
+// Statics/Constants
+//////////////////////////////
+
+/*
+ *  Every CPU counts the ticks of its APIC timer and, on each tick, checks
+ *  the count of its buddy, the CPU with the next id. A CPU spinning with
+ *  interrupts disabled stops ticking. Once its buddy has seen no change for
+ *  `LOCKUP_THRESHOLD_SECS` it sends the stuck CPU an NMI, which gets through
+ *  regardless, and the NMI handler dumps the interrupted state to serial.
+ *
+ *    CPU0 --watches--> CPU1 --watches--> CPU2 --watches--> CPU0
+ *
+ *  Halted CPUs keep ticking, so idle is not mistaken for a lockup. With a
+ *  single CPU nobody is watching.
+ */
+pub const LOCKUP_THRESHOLD_SECS: u64 = 5;
+
+////////////////////////////////
+// Structs, Types, Traits, Impl
+////////////////////////////////
+
+pub struct CpuWatchdog {
+    // Timer ticks taken by this CPU
+    ticks: AtomicU64,
+    // Tick count of the buddy at our last tick, and for how many of our own
+    // ticks it has not changed
+    buddy_ticks: AtomicU64,
+    buddy_stalled: AtomicU64,
+    // Set by the buddy right before it sends the NMI
+    dump_requested: AtomicBool,
+}
+
+impl CpuWatchdog {
+    pub const INIT: CpuWatchdog = CpuWatchdog {
+        ticks: AtomicU64::new(0),
+        buddy_ticks: AtomicU64::new(0),
+        buddy_stalled: AtomicU64::new(0),
+        dump_requested: AtomicBool::new(false),
+    };
+
+    pub fn ticks(&self) -> u64 {
+        self.ticks.load(Ordering::Relaxed)
+    }
+}
+
+//////////////////////////////
+// API
+//////////////////////////////
+
+// Called from the APIC timer interrupt of every CPU
+pub fn tick() {
+    let this = percpu::this_cpu();
+    let watchdog = &this.watchdog;
+    watchdog.ticks.fetch_add(1, Ordering::Relaxed);
+
+    let cpus = smp::cpu_count();
+    if cpus < 2 {
+        return;
+    }
+    let buddy_id = (this.id() + 1) % cpus;
+    let buddy_apic_id = match smp::apic_id(buddy_id) {
+        Some(apic_id) => apic_id,
+        None => return, // Still booting
+    };
+    let buddy = &percpu::cpu(buddy_id).watchdog;
+
+    let ticks = buddy.ticks();
+    if ticks == 0 || ticks != watchdog.buddy_ticks.swap(ticks, Ordering::Relaxed) {
+        watchdog.buddy_stalled.store(0, Ordering::Relaxed);
+        return;
+    }
+
+    // Only report once per lockup, the count resets when the buddy recovers
+    let stalled = watchdog.buddy_stalled.fetch_add(1, Ordering::Relaxed) + 1;
+    if stalled == LOCKUP_THRESHOLD_SECS * u64::from(apic::TIMER_HZ) {
+        buddy.dump_requested.store(true, Ordering::SeqCst);
+        apic::send_nmi(buddy_apic_id);
+    }
+}
+
+// Count as progress on this CPU, for code that legitimately keeps interrupts
+// disabled for long, e.g. the debugger waiting for input
+pub fn touch() {
+    percpu::this_cpu()
+        .watchdog
+        .ticks
+        .fetch_add(1, Ordering::Relaxed);
+}
+
+// Called from the NMI handler with the interrupted register state. Returns
+// false if the NMI was not sent by the watchdog.
+pub fn handle_nmi(frame: &TrapFrame) -> bool {
+    let watchdog = &percpu::this_cpu().watchdog;
+    if !watchdog.dump_requested.swap(false, Ordering::SeqCst) {
+        return false;
+    }
+
+    serial_emergency_println!(
+        "WATCHDOG: CPU {} made no progress for {}s",
+        percpu::cpu_id(),
+        LOCKUP_THRESHOLD_SECS
+    );
+    dump_registers(frame);
+    serial_emergency_println!(
+        "{}",
+        Backtrace::from_registers(frame.rip, frame.rbp, frame.cs)
+    );
+    true
+}
+
+fn dump_registers(frame: &TrapFrame) {
+    use x86_64::registers::control::{Cr0, Cr2, Cr3, Cr4};
+
+    serial_emergency_println!(
+        "RIP {:#018x}  CS {:#06x}  RFLAGS {:#018x}",
+        frame.rip,
+        frame.cs,
+        frame.rflags
+    );
+    serial_emergency_println!("RSP {:#018x}  SS {:#06x}", frame.rsp, frame.ss);
+    serial_emergency_println!(
+        "RAX {:#018x}  RBX {:#018x}  RCX {:#018x}  RDX {:#018x}",
+        frame.rax,
+        frame.rbx,
+        frame.rcx,
+        frame.rdx
+    );
+    serial_emergency_println!(
+        "RSI {:#018x}  RDI {:#018x}  RBP {:#018x}",
+        frame.rsi,
+        frame.rdi,
+        frame.rbp
+    );
+    serial_emergency_println!(
+        "R8  {:#018x}  R9  {:#018x}  R10 {:#018x}  R11 {:#018x}",
+        frame.r8,
+        frame.r9,
+        frame.r10,
+        frame.r11
+    );
+    serial_emergency_println!(
+        "R12 {:#018x}  R13 {:#018x}  R14 {:#018x}  R15 {:#018x}",
+        frame.r12,
+        frame.r13,
+        frame.r14,
+        frame.r15
+    );
+    serial_emergency_println!(
+        "CR0 {:#018x}  CR2 {:#018x}  CR3 {:#018x}  CR4 {:#018x}",
+        Cr0::read_raw(),
+        Cr2::read().as_u64(),
+        Cr3::read().0.start_address().as_u64(),
+        Cr4::read_raw()
+    );
+}
+
+//////////////////////////////
+// Tests
+//////////////////////////////
+
+#[test_case]
+fn test_tick_is_counted_on_this_cpu() {
+    let before = percpu::this_cpu().watchdog.ticks();
+    tick();
+    assert_eq!(percpu::this_cpu().watchdog.ticks(), before + 1);
+}
@@ -0,0 +1,124 @@
+// common/mod.rs - Running test code in ring 3, shared by the user mode tests
+
+use ansi_rgb::{green, red, Foreground};
+use astra_os::interrupts::SYSCALL_VECTOR;
+use astra_os::{exit_qemu, memory, percpu, serial_println, syscall, trap, usermode, QemuExitCode};
+use bootloader::BootInfo;
+use lazy_static::lazy_static;
+use x86_64::structures::idt::{InterruptDescriptorTable, InterruptStackFrame, PageFaultErrorCode};
+use x86_64::structures::paging::{FrameAllocator, Mapper, Page, PageTableFlags, Size4KiB};
+use x86_64::{PrivilegeLevel, VirtAddr};
+
+const USER_CODE_ADDR: u64 = 0x5555_0000_0000;
+const USER_STACK_ADDR: u64 = 0x5555_0001_0000;
+
+lazy_static! {
+    static ref TEST_IDT: InterruptDescriptorTable = {
+        let mut idt = InterruptDescriptorTable::new();
+        idt.breakpoint
+            .set_handler_fn(test_breakpoint_handler)
+            .set_privilege_level(PrivilegeLevel::Ring3);
+        unsafe {
+            idt[usize::from(SYSCALL_VECTOR)]
+                .set_handler_addr(trap::syscall_entry())
+                .set_privilege_level(PrivilegeLevel::Ring3);
+        }
+        idt.invalid_opcode
+            .set_handler_fn(test_invalid_opcode_handler);
+        idt.general_protection_fault
+            .set_handler_fn(test_general_protection_fault_handler);
+        idt.page_fault.set_handler_fn(test_page_fault_handler);
+        idt
+    };
+}
+
+// Run `code` in ring 3 with a stack page of its own. The test passes once
+// it executes `int3` on that stack, any fault fails it.
+pub fn run_in_user_mode(boot_info: &'static BootInfo, code: &[u8]) -> ! {
+    percpu::init(0);
+    astra_os::gdt::init();
+    syscall::init_cpu();
+    TEST_IDT.load();
+    // Ring 3 runs with interrupts enabled, keep the unconfigured PICs quiet
+    unsafe { astra_os::interrupts::PICS.lock().write_masks(0xff, 0xff) };
+
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let mut mapper = unsafe { memory::init(phys_mem_offset) };
+    let mut frame_allocator =
+        unsafe { memory::BootInfoFrameAllocator::init(&boot_info.memory_map) };
+
+    let user = PageTableFlags::PRESENT | PageTableFlags::USER_ACCESSIBLE;
+    let code_frame = frame_allocator.allocate_frame().expect("out of frames");
+    unsafe {
+        let dest = memory::phys_to_virt(code_frame.start_address()).as_mut_ptr::<u8>();
+        core::ptr::copy_nonoverlapping(code.as_ptr(), dest, code.len());
+    }
+    let stack_frame = frame_allocator.allocate_frame().expect("out of frames");
+
+    let code_page = Page::<Size4KiB>::containing_address(VirtAddr::new(USER_CODE_ADDR));
+    let stack_page = Page::<Size4KiB>::containing_address(VirtAddr::new(USER_STACK_ADDR));
+    unsafe {
+        mapper
+            .map_to(code_page, code_frame, user, &mut frame_allocator)
+            .expect("mapping user code failed")
+            .flush();
+        mapper
+            .map_to(
+                stack_page,
+                stack_frame,
+                user | PageTableFlags::WRITABLE,
+                &mut frame_allocator,
+            )
+            .expect("mapping user stack failed")
+            .flush();
+    }
+
+    let stack_top = stack_page.start_address() + stack_page.size();
+    unsafe { usermode::enter_user_mode(code_page.start_address(), stack_top) }
+}
+
+extern "x86-interrupt" fn test_breakpoint_handler(stack_frame: InterruptStackFrame) {
+    let user_mode = stack_frame.code_segment & 0b11 == 3;
+    let user_stack = stack_frame.stack_pointer.as_u64() == USER_STACK_ADDR + 4096;
+    if user_mode && user_stack {
+        serial_println!("{}", "[ ok ]".fg(green()));
+        exit_qemu(QemuExitCode::Success);
+    } else {
+        serial_println!("{}", "[ failed ]".fg(red()));
+        serial_println!("Breakpoint outside of user mode: {:#?}", stack_frame);
+        exit_qemu(QemuExitCode::Failed);
+    }
+    loop {}
+}
+
+// The test code's way of failing, `ud2`
+extern "x86-interrupt" fn test_invalid_opcode_handler(stack_frame: InterruptStackFrame) {
+    serial_println!("{}", "[ failed ]".fg(red()));
+    serial_println!("Invalid opcode: {:#?}", stack_frame);
+    exit_qemu(QemuExitCode::Failed);
+    loop {}
+}
+
+extern "x86-interrupt" fn test_general_protection_fault_handler(
+    stack_frame: InterruptStackFrame,
+    error_code: u64,
+) {
+    serial_println!("{}", "[ failed ]".fg(red()));
+    serial_println!(
+        "General protection fault {:#x}: {:#?}",
+        error_code,
+        stack_frame
+    );
+    exit_qemu(QemuExitCode::Failed);
+    loop {}
+}
+
+extern "x86-interrupt" fn test_page_fault_handler(
+    stack_frame: InterruptStackFrame,
+    error_code: PageFaultErrorCode,
+) {
+    serial_println!("{}", "[ failed ]".fg(red()));
+    serial_println!("Page fault {:?}: {:#?}", error_code, stack_frame);
+    exit_qemu(QemuExitCode::Failed);
+    loop {}
+}
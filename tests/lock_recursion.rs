@@ -0,0 +1,78 @@
+// lock_recursion.rs - Test that locking an IrqSpinlock twice panics
+
+#![no_std]
+#![no_main]
+
+use ansi_rgb::{green, red, Foreground};
+use astra_os::spinlock::{self, IrqSpinlock};
+use astra_os::{exit_qemu, serial_print, serial_println, QemuExitCode};
+use core::fmt::{self, Write};
+use core::panic::PanicInfo;
+
+static LOCK: IrqSpinlock<u32> = IrqSpinlock::new("recursion test lock", 0);
+
+#[no_mangle]
+pub extern "C" fn _start() -> ! {
+    serial_print!("lock_recursion::lock_twice:. . . . ");
+
+    // Release builds do not check, the second lock would spin forever
+    if !spinlock::checks_enabled() {
+        serial_println!("{}", "[ skipped, release build ]".fg(green()));
+        exit_qemu(QemuExitCode::Success);
+        loop {}
+    }
+
+    // Held locks are tracked per CPU
+    astra_os::percpu::init(0);
+
+    let _first = LOCK.lock();
+    let _second = LOCK.lock();
+
+    serial_println!("{}", "[ Locked twice without panicking ]".fg(red()));
+    exit_qemu(QemuExitCode::Failed);
+    loop {}
+}
+
+// Remembers whether the text written to it contains `needle`
+struct Contains {
+    needle: &'static str,
+    buffer: [u8; 128],
+    len: usize,
+    found: bool,
+}
+
+impl Write for Contains {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        for &byte in s.as_bytes() {
+            if self.len == self.buffer.len() {
+                self.buffer.copy_within(1.., 0);
+                self.len -= 1;
+            }
+            self.buffer[self.len] = byte;
+            self.len += 1;
+            self.found |= self.buffer[..self.len].ends_with(self.needle.as_bytes());
+        }
+        Ok(())
+    }
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    let mut message = Contains {
+        needle: LOCK.name(),
+        buffer: [0; 128],
+        len: 0,
+        found: false,
+    };
+    let _ = write!(message, "{}", info.message());
+
+    if message.found {
+        serial_println!("{}", "[ ok ]".fg(green()));
+        exit_qemu(QemuExitCode::Success);
+    } else {
+        serial_println!("{}", "[ failed ]".fg(red()));
+        serial_println!("Error: {}", info);
+        exit_qemu(QemuExitCode::Failed);
+    }
+    loop {}
+}
@@ -0,0 +1,37 @@
+// syscall.rs - Test system calls from ring 3 through syscall and int 0x80
+
+#![no_std]
+#![no_main]
+#![feature(abi_x86_interrupt)]
+
+mod common;
+
+use astra_os::serial_print;
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+
+// Both paths must return -ENOSYS for an unknown number, int3 on success
+const USER_CODE: [u8; 29] = [
+    0xb8, 0xff, 0xff, 0x00, 0x00, // mov eax, 0xffff
+    0x0f, 0x05, //                   syscall
+    0x48, 0x83, 0xf8, 0xda, //       cmp rax, -38
+    0x75, 0x0e, //                   jne fail
+    0xb8, 0xff, 0xff, 0x00, 0x00, // mov eax, 0xffff
+    0xcd, 0x80, //                   int 0x80
+    0x48, 0x83, 0xf8, 0xda, //       cmp rax, -38
+    0x75, 0x01, //                   jne fail
+    0xcc, //                         int3
+    0x0f, 0x0b, //             fail: ud2
+];
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    serial_print!("syscall::syscall_from_user_mode:. . . . ");
+    common::run_in_user_mode(boot_info, &USER_CODE)
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    astra_os::test_panic_handler(info);
+}
@@ -0,0 +1,26 @@
+// user_mode.rs - Test entering ring 3 and trapping back into the kernel
+
+#![no_std]
+#![no_main]
+#![feature(abi_x86_interrupt)]
+
+mod common;
+
+use astra_os::serial_print;
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+
+// int3; jmp $
+const USER_CODE: [u8; 3] = [0xcc, 0xeb, 0xfe];
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    serial_print!("user_mode::enter_user_mode:. . . . ");
+    common::run_in_user_mode(boot_info, &USER_CODE)
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    astra_os::test_panic_handler(info);
+}